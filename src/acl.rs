@@ -0,0 +1,301 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Per-user permissions on services and channels.
+//!
+//! A grant gives a user `none`, `read` or `write` access to a target, which
+//! is either the id of a service or channel, or a tag prefixed with `tag:`
+//! (eg. `tag:kids-room`) matching every service or channel carrying that tag.
+//! Write access implies read access.
+//!
+//! A grant overrides the default access, including to restrict it. The most
+//! specific grant wins: a grant on the channel, then on its service, then the
+//! highest of the grants on their tags.
+//!
+//! Users without a grant for a target get the `acl::default_access` level,
+//! which is `write` by default so that boxes behave as before until an admin
+//! restricts it. Admins always have write access to everything.
+//!
+//! Grants are stored in `acl.sqlite` in the profile, and kept in memory to
+//! check accesses.
+
+use config_store::ConfigService;
+use rusqlite::{ self, Connection };
+use std::sync::{ Mutex, RwLock };
+
+const TAG_PREFIX: &'static str = "tag:";
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Access {
+    None,
+    Read,
+    Write,
+}
+
+impl Access {
+    pub fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Access::None),
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Access::None => "none",
+            Access::Read => "read",
+            Access::Write => "write",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    pub user: i32,
+    /// A service or channel id, or `tag:<tag>`.
+    pub target: String,
+    /// "none", "read" or "write".
+    pub access: String,
+}
+
+/// The targets delegated credentials (API keys and guest passes) are limited
+/// to, on top of the permissions of their user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scope {
+    /// The targets that can be read.
+    pub read: Vec<String>,
+    /// The targets that can be read and written.
+    pub write: Vec<String>,
+}
+
+impl Scope {
+    /// Whether the scope gives `access` on the service or channel identified
+    /// by `ids` and carrying `tags`.
+    pub fn allows(&self, access: Access, ids: &[String], tags: &[String]) -> bool {
+        let matches = |targets: &[String]| {
+            targets.iter().any(|target| target_matches(target, ids, tags))
+        };
+        match access {
+            Access::None => true,
+            Access::Read => matches(&self.read) || matches(&self.write),
+            Access::Write => matches(&self.write),
+        }
+    }
+}
+
+pub struct Acl {
+    db: Mutex<Connection>,
+    default_access: Access,
+    /// All the grants, as stored in the database.
+    grants: RwLock<Vec<Grant>>,
+}
+
+impl Acl {
+    /// Opens the database at `path` and creates it if not available yet.
+    pub fn new(path: &str, config: &ConfigService) -> Self {
+        let db = Connection::open(path).unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS grants (
+                    user_id     INTEGER NOT NULL,
+                    target      TEXT NOT NULL,
+                    access      TEXT NOT NULL,
+                    UNIQUE(user_id, target)
+            )", &[]).unwrap();
+
+        let default_access = config.get_or_set_default("acl", "default_access", "write");
+        let default_access = Access::from_str(&default_access).unwrap_or_else(|| {
+            warn!("Invalid value for acl::default_access: {}, using none", default_access);
+            Access::None
+        });
+
+        let acl = Acl {
+            db: Mutex::new(db),
+            default_access: default_access,
+            grants: RwLock::new(vec![]),
+        };
+        acl.reload().unwrap();
+        acl
+    }
+
+    /// Adds or replaces the grant for the (user, target) pair.
+    pub fn set_grant(&self, grant: &Grant) -> rusqlite::Result<()> {
+        {
+            let db = self.db.lock().unwrap();
+            try!(db.execute("INSERT OR REPLACE INTO grants VALUES ($1, $2, $3)",
+                            &[&grant.user, &grant.target, &grant.access]));
+        }
+        self.reload()
+    }
+
+    pub fn remove_grant(&self, user: i32, target: &str) -> rusqlite::Result<()> {
+        {
+            let db = self.db.lock().unwrap();
+            try!(db.execute("DELETE FROM grants WHERE user_id=$1 AND target=$2",
+                            &[&user, &target]));
+        }
+        self.reload()
+    }
+
+    // Refreshes the grants kept in memory from the database.
+    fn reload(&self) -> rusqlite::Result<()> {
+        let grants = try!(self.get_grants(None));
+        *self.grants.write().unwrap() = grants;
+        Ok(())
+    }
+
+    /// Gets the grants of `user`, or of all users.
+    pub fn get_grants(&self, user: Option<i32>) -> rusqlite::Result<Vec<Grant>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = try!(db.prepare("SELECT user_id, target, access FROM grants
+                                        WHERE ($1 IS NULL OR user_id = $1)
+                                        ORDER BY user_id, target"));
+        let rows = try!(stmt.query(&[&user]));
+        let mut grants = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            grants.push(Grant {
+                user: row.get(0),
+                target: row.get(1),
+                access: row.get(2),
+            });
+        }
+        Ok(grants)
+    }
+
+    /// Whether every user has write access to everything, in which case there
+    /// is no need to check individual targets.
+    pub fn is_unrestricted(&self) -> bool {
+        self.default_access == Access::Write &&
+        self.grants.read().unwrap().iter().all(|grant| {
+            Access::from_str(&grant.access) == Some(Access::Write)
+        })
+    }
+
+    /// Returns the access `user` has on the service or channel identified
+    /// by `ids`, from the most specific (eg. a channel id and then the id of
+    /// its service), and carrying `tags`.
+    pub fn access(&self, user: i32, ids: &[String], tags: &[String]) -> Access {
+        let grants = self.grants.read().unwrap();
+        let grants: Vec<&Grant> = grants.iter().filter(|grant| grant.user == user).collect();
+        let access_of = |grant: &&Grant| {
+            Access::from_str(&grant.access).unwrap_or_else(|| {
+                error!("Invalid access {} granted to user {}", grant.access, user);
+                Access::None
+            })
+        };
+
+        for id in ids {
+            if let Some(grant) = grants.iter().find(|grant| grant.target == *id) {
+                return access_of(grant);
+            }
+        }
+
+        grants.iter().filter(|grant| {
+                         grant.target.starts_with(TAG_PREFIX) && target_matches(&grant.target, ids, tags)
+                     })
+                     .map(access_of)
+                     .max()
+                     .unwrap_or(self.default_access)
+    }
+}
+
+/// Whether `target`, a service or channel id or `tag:<tag>`, designates the
+/// service or channel identified by `ids` and carrying `tags`.
+pub fn target_matches(target: &str, ids: &[String], tags: &[String]) -> bool {
+    if target.starts_with(TAG_PREFIX) {
+        tags.iter().any(|tag| *tag == target[TAG_PREFIX.len()..])
+    } else {
+        ids.iter().any(|id| id == target)
+    }
+}
+
+#[cfg(test)]
+describe! acl {
+    before_each {
+        use config_store::ConfigService;
+        use std::fs;
+        use uuid::Uuid;
+
+        let id = Uuid::new_v4().to_simple_string();
+        let config_file_name = format!("conftest-{}.tmp", id);
+        let db_file_name = format!("acl_test-{}.sqlite", id);
+        let config = ConfigService::new(&config_file_name);
+        config.set("acl", "default_access", "none");
+        let acl = Acl::new(&db_file_name, &config);
+
+        let grant = |user: i32, target: &str, access: &str| Grant {
+            user: user,
+            target: target.to_owned(),
+            access: access.to_owned(),
+        };
+        let door = vec!["setter:door".to_owned(), "service:door".to_owned()];
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+        fs::remove_file(db_file_name).unwrap_or(());
+    }
+
+    it "should use the default access without grants" {
+        assert_eq!(acl.access(1, &door, &[]), Access::None);
+        assert!(!acl.is_unrestricted());
+    }
+
+    it "should grant access by id" {
+        acl.set_grant(&grant(1, "service:door", "read")).unwrap();
+        assert_eq!(acl.access(1, &door, &[]), Access::Read);
+        assert_eq!(acl.access(2, &door, &[]), Access::None);
+
+        acl.set_grant(&grant(1, "service:door", "write")).unwrap();
+        assert_eq!(acl.access(1, &door, &[]), Access::Write);
+        assert_eq!(acl.get_grants(Some(1)).unwrap().len(), 1);
+    }
+
+    it "should grant access by tag" {
+        acl.set_grant(&grant(1, "tag:kids-room", "write")).unwrap();
+        assert_eq!(acl.access(1, &door, &["kids-room".to_owned()]), Access::Write);
+        assert_eq!(acl.access(1, &door, &["kitchen".to_owned()]), Access::None);
+    }
+
+    it "should let grants override the default access" {
+        config.set("acl", "default_access", "write");
+        let acl = Acl::new(&db_file_name, &config);
+        assert!(acl.is_unrestricted());
+
+        acl.set_grant(&grant(1, "service:door", "none")).unwrap();
+        assert!(!acl.is_unrestricted());
+        assert_eq!(acl.access(1, &door, &[]), Access::None);
+        assert_eq!(acl.access(2, &door, &[]), Access::Write);
+
+        // A grant on the channel is more specific than one on its service.
+        acl.set_grant(&grant(1, "setter:door", "read")).unwrap();
+        assert_eq!(acl.access(1, &door, &[]), Access::Read);
+    }
+
+    it "should prefer id grants over tag grants" {
+        acl.set_grant(&grant(1, "tag:kids-room", "write")).unwrap();
+        acl.set_grant(&grant(1, "tag:upstairs", "read")).unwrap();
+        let tags = vec!["kids-room".to_owned(), "upstairs".to_owned()];
+        assert_eq!(acl.access(1, &door, &tags), Access::Write);
+
+        acl.set_grant(&grant(1, "service:door", "read")).unwrap();
+        assert_eq!(acl.access(1, &door, &tags), Access::Read);
+    }
+
+    it "should only give write access to the write scope" {
+        let scope = Scope { read: vec!["service:door".to_owned()], write: vec!["tag:lights".to_owned()] };
+        assert!(scope.allows(Access::Read, &door, &[]));
+        assert!(!scope.allows(Access::Write, &door, &[]));
+        assert!(scope.allows(Access::Write, &door, &["lights".to_owned()]));
+        assert!(!scope.allows(Access::Read, &["service:lamp".to_owned()], &[]));
+    }
+
+    it "should remove grants" {
+        acl.set_grant(&grant(1, "service:door", "read")).unwrap();
+        acl.remove_grant(1, "service:door").unwrap();
+        assert_eq!(acl.access(1, &door, &[]), Access::None);
+        assert_eq!(acl.get_grants(None).unwrap(), vec![]);
+    }
+}
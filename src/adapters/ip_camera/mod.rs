@@ -23,6 +23,9 @@ use self::api::*;
 use self::upnp_listener::IpCameraUpnpListener;
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use super::presence::{ set_last_seen, timestamp };
+use upnp::{ UpnpFilter, UpnpListenerGuard };
+use upnp_events::UpnpSubscription;
 
 const CUSTOM_PROPERTY_MANUFACTURER: &'static str = "manufacturer";
 const CUSTOM_PROPERTY_MODEL: &'static str = "model";
@@ -34,6 +37,8 @@ static ADAPTER_NAME: &'static str = "IP Camera adapter";
 static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
 static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 static SNAPSHOT_DIR: &'static str = "snapshots";
+/// The service the D-Link cameras advertise, and answer searches for.
+static UPNP_SERVICE_TYPE: &'static str = "urn:cellvision:service:Null:1";
 
 pub type IpCameraServiceMap = Arc<Mutex<IpCameraServiceMapInternal>>;
 
@@ -41,10 +46,41 @@ pub struct IpCameraServiceMapInternal {
     getters: HashMap<Id<Getter>, Arc<IpCamera>>,
     setters: HashMap<Id<Setter>, Arc<IpCamera>>,
     snapshot_root: String,
+    /// The cameras which left the network, with when they were last seen.
+    departed: HashMap<String, i64>,
+    /// The subscriptions to the events of the cameras, by UDN.
+    subscriptions: HashMap<String, UpnpSubscription>,
+}
+
+impl IpCameraServiceMapInternal {
+    fn camera(&self, udn: &str) -> Option<Arc<IpCamera>> {
+        self.getters.values().find(|camera| camera.udn == udn).cloned()
+    }
+
+    fn remove_camera(&mut self, udn: &str) {
+        let getters: Vec<Id<Getter>> = self.getters.iter()
+                                                   .filter(|&(_, camera)| camera.udn == udn)
+                                                   .map(|(id, _)| id.clone())
+                                                   .collect();
+        for id in getters {
+            self.getters.remove(&id);
+        }
+        let setters: Vec<Id<Setter>> = self.setters.iter()
+                                                   .filter(|&(_, camera)| camera.udn == udn)
+                                                   .map(|(id, _)| id.clone())
+                                                   .collect();
+        for id in setters {
+            self.setters.remove(&id);
+        }
+        self.departed.remove(udn);
+        self.subscriptions.remove(udn);
+    }
 }
 
 pub struct IPCameraAdapter {
     services: IpCameraServiceMap,
+    // Keeps the cameras coming from UPnP for as long as the adapter lives.
+    _upnp_listener: UpnpListenerGuard,
 }
 
 impl IPCameraAdapter {
@@ -59,22 +95,27 @@ impl IPCameraAdapter {
             getters: HashMap::new(),
             setters: HashMap::new(),
             snapshot_root: controller.get_profile().path_for(SNAPSHOT_DIR),
+            departed: HashMap::new(),
+            subscriptions: HashMap::new(),
         }));
+        // The UPNP listener will add camera service for discovered cameras
+        let upnp = controller.get_upnp_manager();
+        let listener = IpCameraUpnpListener::new(adapt, services.clone(), &controller.get_config(), &upnp);
+        let upnp_listener = upnp.register_listener("IpCameraTaxonomy".to_owned(),
+                                                   UpnpFilter::any().service_type(UPNP_SERVICE_TYPE),
+                                                   listener);
+
         let ip_camera_adapter = Arc::new(IPCameraAdapter {
-            services: services.clone(),
+            services: services,
+            _upnp_listener: upnp_listener,
         });
 
         try!(adapt.add_adapter(ip_camera_adapter));
 
-        // The UPNP listener will add camera service for discovered cameras
-        let upnp = controller.get_upnp_manager();
-        let listener = IpCameraUpnpListener::new(adapt, services, &controller.get_config());
-        upnp.add_listener("IpCameraTaxonomy".to_owned(), listener);
-
         // The UPNP service searches for ssdp:all which the D-Link cameras
         // don't seem to respond to. So we search for this instead, which
         // they do respond to.
-        upnp.search(Some("urn:cellvision:service:Null:1".to_owned())).unwrap();
+        upnp.search(Some(UPNP_SERVICE_TYPE.to_owned())).unwrap();
         Ok(())
     }
 
@@ -95,7 +136,7 @@ impl IPCameraAdapter {
         service.tags.insert(tag_id!(&format!("name:{}", name)));
 
         // Since the upnp_discover will be called about once very 3 minutes we want to ignore
-        // discoveries if the camera is already registered.
+        // discoveries if the camera is already registered, besides noting that it's there.
         if let Err(error) = adapt.add_service(service) {
             if let Error::InternalError(ref internal_error) = error {
                 if let InternalError::DuplicateService(_) = *internal_error {
@@ -103,6 +144,7 @@ impl IPCameraAdapter {
                            model_name,
                            url,
                            udn);
+                    Self::device_seen(adapt, services, udn);
                     return Ok(());
                 }
             }
@@ -230,6 +272,72 @@ impl IPCameraAdapter {
 
         Ok(())
     }
+
+    /// Marks the camera with `udn` as available again, if it was gone.
+    pub fn device_seen(adapt: &Arc<AdapterManager>, services: IpCameraServiceMap, udn: &str) {
+        let departed = services.lock().unwrap().departed.remove(udn);
+        if departed.is_some() {
+            info!("IpCamera {} is available again", udn);
+            if let Err(err) = set_last_seen(adapt, &create_service_id(udn), None) {
+                warn!("Unable to mark IpCamera {} available: {:?}", udn, err);
+            }
+        }
+    }
+
+    /// Keeps `subscription` to the events of the camera with `udn`, for as
+    /// long as the camera is known. Returns false if it already had one.
+    pub fn add_subscription(services: IpCameraServiceMap, udn: &str, subscription: UpnpSubscription) -> bool {
+        let mut serv = services.lock().unwrap();
+        if serv.subscriptions.contains_key(udn) {
+            return false;
+        }
+        serv.subscriptions.insert(udn.to_owned(), subscription);
+        true
+    }
+
+    /// Whether we're subscribed to the events of the camera with `udn`.
+    pub fn is_subscribed(services: IpCameraServiceMap, udn: &str) -> bool {
+        services.lock().unwrap().subscriptions.contains_key(udn)
+    }
+
+    /// Marks the camera with `udn`, last seen at `last_seen`, as gone from
+    /// the network. Returns whether the camera was known.
+    pub fn device_gone(adapt: &Arc<AdapterManager>, services: IpCameraServiceMap, udn: &str,
+        last_seen: i64) -> bool
+    {
+        {
+            let mut serv = services.lock().unwrap();
+            if serv.camera(udn).is_none() {
+                return false;
+            }
+            serv.departed.insert(udn.to_owned(), last_seen);
+        }
+        info!("IpCamera {} is unavailable", udn);
+        if let Err(err) = set_last_seen(adapt, &create_service_id(udn), Some(timestamp(last_seen))) {
+            warn!("Unable to mark IpCamera {} unavailable: {:?}", udn, err);
+        }
+        true
+    }
+
+    /// Removes the cameras which left the network more than
+    /// `ip_camera;removal_grace_seconds` before `now`.
+    pub fn remove_departed(adapt: &Arc<AdapterManager>, services: IpCameraServiceMap,
+        config: &Arc<ConfigService>, now: i64)
+    {
+        let grace: i64 = config.get_or_set_default_parsed("ip_camera", "removal_grace_seconds", "86400");
+        let mut serv = services.lock().unwrap();
+        let expired: Vec<String> = serv.departed.iter()
+                                                .filter(|&(_, last_seen)| now - *last_seen >= grace)
+                                                .map(|(udn, _)| udn.clone())
+                                                .collect();
+        for udn in expired {
+            serv.remove_camera(&udn);
+            match adapt.remove_service(&create_service_id(&udn)) {
+                Ok(_) => info!("Removed IpCamera {}, unavailable for {}s", udn, grace),
+                Err(err) => warn!("Unable to remove IpCamera {}: {:?}", udn, err)
+            }
+        }
+    }
 }
 
 impl Adapter for IPCameraAdapter {
@@ -7,29 +7,76 @@
 
 extern crate url;
 
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 
 use foxbox_taxonomy::manager::*;
 
 use config_store::ConfigService;
 use super::IPCameraAdapter;
 use super::IpCameraServiceMap;
-use upnp::{UpnpListener, UpnpService};
+use super::UPNP_SERVICE_TYPE;
+use upnp::{GoneReason, UpnpLease, UpnpListener, UpnpManager, UpnpService};
+use upnp_events::{event_url, UpnpEvent, UpnpEventListener};
 
 pub struct IpCameraUpnpListener {
     manager: Arc<AdapterManager>,
     services: IpCameraServiceMap,
     config: Arc<ConfigService>,
+    // Weak, as the UPnP manager keeps this listener.
+    upnp: Weak<UpnpManager>,
 }
 
 impl IpCameraUpnpListener {
-    pub fn new(manager: &Arc<AdapterManager>, services: IpCameraServiceMap, config: &Arc<ConfigService>) -> Box<Self> {
+    pub fn new(manager: &Arc<AdapterManager>, services: IpCameraServiceMap, config: &Arc<ConfigService>,
+               upnp: &Arc<UpnpManager>) -> Box<Self> {
         Box::new(IpCameraUpnpListener {
             manager: manager.clone(),
             services: services,
             config: config.clone(),
+            upnp: Arc::downgrade(upnp),
         })
     }
+
+    /// Subscribes to the events of the camera, for the cameras which send
+    /// some. An event tells that the camera is still there, before its next
+    /// advertisement does.
+    fn subscribe(&self, service: &UpnpService, udn: &str) {
+        if IPCameraAdapter::is_subscribed(self.services.clone(), udn) {
+            return;
+        }
+        let url = match event_url(service, UPNP_SERVICE_TYPE) {
+            Some(url) => url,
+            None => return
+        };
+        let upnp = match self.upnp.upgrade() {
+            Some(upnp) => upnp,
+            None => return
+        };
+        let listener = Arc::new(IpCameraEventListener {
+            manager: self.manager.clone(),
+            services: self.services.clone(),
+            udn: udn.to_owned(),
+        });
+        match upnp.subscribe(&url, listener) {
+            Ok(subscription) => {
+                IPCameraAdapter::add_subscription(self.services.clone(), udn, subscription);
+            },
+            Err(err) => warn!("Unable to subscribe to the events of IpCamera {}: {}", udn, err)
+        }
+    }
+}
+
+struct IpCameraEventListener {
+    manager: Arc<AdapterManager>,
+    services: IpCameraServiceMap,
+    udn: String,
+}
+
+impl UpnpEventListener for IpCameraEventListener {
+    fn upnp_event(&self, event: &UpnpEvent) {
+        debug!("IpCamera {} sent the UPnP event {:?}", self.udn, event.properties);
+        IPCameraAdapter::device_seen(&self.manager, self.services.clone(), &self.udn);
+    }
 }
 
 impl UpnpListener for IpCameraUpnpListener {
@@ -71,6 +118,21 @@ impl UpnpListener for IpCameraUpnpListener {
 
         IPCameraAdapter::init_service(&self.manager, self.services.clone(), &self.config,
                                       &udn, &url, &name, &manufacturer, &model_name).unwrap();
+        self.subscribe(service, &udn);
         true
     }
+
+    fn upnp_gone(&self, lease: &UpnpLease, _reason: GoneReason) -> bool {
+        // Same as the UDN of the description.
+        let udn = if lease.device_id.starts_with("uuid:") {
+            &lease.device_id[5..]
+        } else {
+            &lease.device_id[..]
+        };
+        IPCameraAdapter::device_gone(&self.manager, self.services.clone(), udn, lease.last_seen)
+    }
+
+    fn upnp_maintain(&self, now: i64) {
+        IPCameraAdapter::remove_departed(&self.manager, self.services.clone(), &self.config, now);
+    }
 }
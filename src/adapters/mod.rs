@@ -18,6 +18,9 @@ mod ip_camera;
 /// An adapter dedicated to the Philips Hue
 mod philips_hue;
 
+/// The presence of the devices on the network.
+mod presence;
+
 /// An adapter providing access to Thinkerbell.
 mod thinkerbell;
 
@@ -19,14 +19,20 @@ use std::thread;
 use super::{ HueAction, http, PhilipsHueAdapter };
 use traits::Controller;
 use transformable_channels::mpsc::*;
-use upnp::{ UpnpListener, UpnpManager, UpnpService };
+use upnp::{ GoneReason, UpnpFilter, UpnpLease, UpnpListener, UpnpListenerGuard, UpnpManager,
+            UpnpService };
 
 static UPNP_MODEL_PATH: &'static str = "/root/device/modelName";
 static UPNP_MODEL_NAME: &'static str = "Philips hue bridge";
+// The bridges search answers give the first, and their description the second.
+static UPNP_DEVICE_TYPES: [&'static str; 2] = ["urn:schemas-upnp-org:device:basic:1",
+                                               "urn:schemas-upnp-org:device:Basic:1"];
 
 pub struct Discovery<C> {
     adapter: PhilipsHueAdapter<C>,
     upnp_manager: Arc<Mutex<Arc<UpnpManager>>>,
+    // Keeps the bridges coming from UPnP for as long as the discovery lives.
+    _upnp_listener: UpnpListenerGuard,
 }
 
 impl<C: Controller> Discovery<C> {
@@ -34,10 +40,14 @@ impl<C: Controller> Discovery<C> {
     {
         let upnp = adapter.controller.get_upnp_manager();
         let listener = PhilipsHueUpnpListener::new(adapter.clone());
-        upnp.add_listener("PhilipsHueTaxonomy".to_owned(), listener);
+        let filter = UPNP_DEVICE_TYPES.iter().fold(UpnpFilter::any(), |filter, device_type| {
+            filter.device_type(device_type)
+        });
+        let upnp_listener = upnp.register_listener("PhilipsHueTaxonomy".to_owned(), filter, listener);
         Discovery {
             adapter: adapter,
             upnp_manager: Arc::new(Mutex::new(upnp)),
+            _upnp_listener: upnp_listener,
         }
     }
 
@@ -132,21 +142,34 @@ impl<C: Controller> UpnpListener for PhilipsHueUpnpListener<C> {
             debug!("Unexpected IP address format");
         }
 
-        let id;
-        if serial.len() == 12 {
-            // Turn serial into actual Hue ID by inserting "fffe" mid string
-            let prefix = &serial[0..6];
-            let suffix = &serial[6..];
-            id = format!("{}fffe{}", prefix, suffix);
-        } else {
-            id = serial.clone();
-        }
-
         let tx = self.adapter.tx.lock().unwrap();
-        let _ = tx.send(HueAction::AddHub(id.to_owned(), ip.to_owned()));
+        let _ = tx.send(HueAction::AddHub(hub_id(serial), ip.to_owned()));
 
         true
     }
+
+    // The bridges end their UDN with their serial number, like
+    // uuid:2f402f80-da50-11e1-9b23-00178825681a. The main loop ignores the
+    // devices which aren't known bridges.
+    fn upnp_gone(&self, lease: &UpnpLease, _reason: GoneReason) -> bool {
+        let serial = match lease.device_id.rsplit('-').next() {
+            Some(serial) if serial.len() == 12 => serial,
+            _ => return false
+        };
+        let tx = self.adapter.tx.lock().unwrap();
+        let _ = tx.send(HueAction::HubGone(hub_id(serial), lease.last_seen));
+        true
+    }
+}
+
+/// Turns a bridge serial number into its actual Hue ID, by inserting "fffe"
+/// mid string.
+fn hub_id(serial: &str) -> String {
+    if serial.len() == 12 {
+        format!("{}fffe{}", &serial[0..6], &serial[6..])
+    } else {
+        serial.to_owned()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -176,6 +199,12 @@ fn parse_nupnp_response(content: String) -> Vec<NupnpEntry> {
     }
 }
 
+#[test]
+fn hub_ids_are_made_from_serial_numbers() {
+    assert_eq!(hub_id("00178825681a"), "001788fffe25681a");
+    assert_eq!(hub_id("001788"), "001788");
+}
+
 #[test]
 fn nupnp_results_are_properly_parsed() {
     let json = r#"[{"id":"001788","internalipaddress":"192.168.5.129"}]"#;
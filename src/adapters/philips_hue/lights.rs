@@ -11,8 +11,9 @@
 use foxbox_taxonomy::api::Error;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::services::*;
-use foxbox_taxonomy::values::{ Type };
+use foxbox_taxonomy::values::{ TimeStamp, Type };
 use super::*;
+use super::super::presence;
 use super::hub_api::HubApi;
 use std::collections::HashSet;
 use std::sync::Arc;
@@ -145,4 +146,17 @@ impl Light {
         self.api.set_light_power(&self.light_id, on);
     }
 
+    pub fn hub_id(&self) -> &str {
+        &self.hub_id
+    }
+
+    /// Reports that the bridge of the light left the network at `last_seen`,
+    /// or came back with `None`.
+    pub fn set_last_seen(&self, manager: &AdapterManager, last_seen: Option<TimeStamp>) {
+        if let Err(err) = presence::set_last_seen(manager, &self.service_id, last_seen) {
+            warn!("Unable to update the presence of Hue light {} on bridge {}: {:?}",
+                self.light_id, self.hub_id, err);
+        }
+    }
+
 }
@@ -22,10 +22,11 @@ use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::values::{ OnOff, Type, TypeError, Value };
 
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::sync::{ Arc, Mutex };
 use std::thread;
 use self::hub::Hub;
+use super::presence::timestamp;
 use self::lights::Light;
 use traits::Controller;
 use transformable_channels::mpsc::*;
@@ -42,6 +43,7 @@ pub enum HueAction {
     AddLight(String, String),       // Hub id, light id
     RemoveHub(String),              // Hub id
     RemoveLight(String, String),    // Hub id, light id
+    HubGone(String, i64),           // Hub id, last seen
     StopAdapter,
 }
 
@@ -99,6 +101,8 @@ impl<C: Controller> PhilipsHueAdapter<C> {
 
             let mut hubs: HashMap<String, Arc<Mutex<Hub<C>>>> = HashMap::new();
             let mut lights: HashMap<String, Arc<Mutex<Light>>> = HashMap::new();
+            // The hubs which left the network.
+            let mut departed_hubs: HashSet<String> = HashSet::new();
 
             let discovery = discovery::Discovery::new(adapter.clone());
 
@@ -114,6 +118,15 @@ impl<C: Controller> PhilipsHueAdapter<C> {
                         if is_known_hub {
                             let mut hub = hubs.get_mut(&hub_id).unwrap().lock().unwrap();
                             hub.update_ip(&hub_ip);
+                            if departed_hubs.remove(&hub_id) {
+                                info!("Philips Hue bridge {} is available again", hub_id);
+                                for light in lights.values() {
+                                    let light = light.lock().unwrap();
+                                    if light.hub_id() == hub_id {
+                                        light.set_last_seen(&manager, None);
+                                    }
+                                }
+                            }
                         } else {
                             let new_hub = Hub::new(adapter.clone(), &hub_id, &hub_ip);
                             new_hub.start();
@@ -157,6 +170,19 @@ impl<C: Controller> PhilipsHueAdapter<C> {
                             warn!("Ignoring request to remove unknown Hue hub");
                         }
                     },
+                    HueAction::HubGone(hub_id, last_seen) => {
+                        debug!("HueAction::HubGone({}) received", hub_id);
+                        if !hubs.contains_key(&hub_id) || !departed_hubs.insert(hub_id.clone()) {
+                            continue;
+                        }
+                        info!("Philips Hue bridge {} is unavailable", hub_id);
+                        for light in lights.values() {
+                            let light = light.lock().unwrap();
+                            if light.hub_id() == hub_id {
+                                light.set_last_seen(&manager, Some(timestamp(last_seen)));
+                            }
+                        }
+                    },
                     // TODO: Currently unused, but required for teardown
                     HueAction::StopAdapter => {
                         debug!("HueAction::StopAdapter received");
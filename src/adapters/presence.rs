@@ -0,0 +1,41 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reports the devices which left the network through the `last_seen` of
+//! the channels of their service: the time they were last seen while they
+//! are away, and `None` once they're back.
+
+use chrono::{ TimeZone, UTC };
+use foxbox_taxonomy::api::{ API, Error };
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::selector::{ GetterSelector, SetterSelector };
+use foxbox_taxonomy::services::{ Channel, Id, ServiceId };
+use foxbox_taxonomy::values::TimeStamp;
+
+/// `seconds` since the epoch, as a `TimeStamp`.
+pub fn timestamp(seconds: i64) -> TimeStamp {
+    TimeStamp::from_datetime(UTC.timestamp(seconds, 0))
+}
+
+/// Sets the `last_seen` of the channels of `service`. The channels are
+/// registered again, with their tags, since the manager doesn't update them
+/// in place.
+pub fn set_last_seen(manager: &AdapterManager, service: &Id<ServiceId>, last_seen: Option<TimeStamp>)
+                     -> Result<(), Error> {
+    for channel in manager.get_getter_channels(vec![GetterSelector::new().with_parent(service.clone())]) {
+        if channel.last_seen == last_seen {
+            continue;
+        }
+        try!(manager.remove_getter(&channel.id));
+        try!(manager.add_getter(Channel { last_seen: last_seen.clone(), ..channel }));
+    }
+    for channel in manager.get_setter_channels(vec![SetterSelector::new().with_parent(service.clone())]) {
+        if channel.last_seen == last_seen {
+            continue;
+        }
+        try!(manager.remove_setter(&channel.id));
+        try!(manager.add_setter(Channel { last_seen: last_seen.clone(), ..channel }));
+    }
+    Ok(())
+}
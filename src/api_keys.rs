@@ -0,0 +1,335 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Long-lived API keys for scripts and devices.
+//!
+//! Keys are created by admins and act on behalf of their creator, restricted
+//! to a set of endpoints (eg. "channels/get", or "websocket" for the
+//! websocket), sets of targets they can read and write using the ACL syntax
+//! (service or channel ids, or `tag:<tag>`) and optionally to some client IP
+//! addresses. Empty sets mean no restriction, and the targets a key can write
+//! can be read too.
+//!
+//! Only a SHA-256 hash of the keys is stored, in `api_keys.sqlite` in the
+//! profile; the key itself is only returned when it is created.
+
+extern crate crypto;
+
+use self::crypto::digest::Digest;
+use self::crypto::sha2::Sha256;
+use acl::Scope;
+use rand::Rng;
+use rand::os::OsRng;
+use rusqlite::{ self, Connection };
+use rustc_serialize::hex::ToHex;
+use serde_json;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// All API keys start with this prefix, which sets them apart from session tokens.
+pub const API_KEY_PREFIX: &'static str = "fbk_";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// The user the key acts on behalf of.
+    pub user: i32,
+    pub endpoints: Vec<String>,
+    pub read_targets: Vec<String>,
+    pub write_targets: Vec<String>,
+    pub allowed_ips: Vec<String>,
+    /// Seconds since the epoch.
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub last_used: Option<i64>,
+}
+
+impl ApiKey {
+    pub fn allows_endpoint(&self, endpoint: &str) -> bool {
+        self.endpoints.is_empty() || self.endpoints.iter().any(|allowed| allowed == endpoint)
+    }
+
+    /// The targets the key is limited to, if any.
+    pub fn scope(&self) -> Option<Scope> {
+        if self.read_targets.is_empty() && self.write_targets.is_empty() {
+            return None;
+        }
+        Some(Scope { read: self.read_targets.clone(), write: self.write_targets.clone() })
+    }
+}
+
+/// A newly created key, which can't be retrieved later on.
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+/// The body of a key creation request.
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub expires: Option<i64>,
+    pub endpoints: Option<Vec<String>>,
+    pub read_targets: Option<Vec<String>>,
+    pub write_targets: Option<Vec<String>>,
+    /// The write targets, as named before keys had read targets.
+    pub targets: Option<Vec<String>>,
+    pub allowed_ips: Option<Vec<String>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ApiKeyError {
+    Unknown,
+    Expired,
+    ForbiddenAddress,
+    ForbiddenEndpoint,
+}
+
+fn hash_key(key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(key);
+    hasher.result_str()
+}
+
+pub struct ApiKeys {
+    db: Mutex<Connection>,
+}
+
+impl ApiKeys {
+    /// Opens the database at `path` and creates it if not available yet.
+    pub fn new(path: &str) -> Self {
+        let db = Connection::open(path).unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS api_keys (
+                    id          TEXT NOT NULL PRIMARY KEY,
+                    hash        TEXT NOT NULL UNIQUE,
+                    name        TEXT NOT NULL,
+                    user_id     INTEGER NOT NULL,
+                    endpoints   TEXT NOT NULL,
+                    targets     TEXT NOT NULL,
+                    allowed_ips TEXT NOT NULL,
+                    created     INTEGER NOT NULL,
+                    expires     INTEGER,
+                    last_used   INTEGER,
+                    read_targets TEXT NOT NULL DEFAULT '[]'
+            )", &[]).unwrap();
+        // The `targets` column holds the write targets. Databases created
+        // before keys had read targets get the column, which fails once it
+        // is there.
+        let _ = db.execute("ALTER TABLE api_keys ADD COLUMN read_targets TEXT NOT NULL DEFAULT '[]'", &[]);
+
+        ApiKeys {
+            db: Mutex::new(db),
+        }
+    }
+
+    /// Creates a key for `user` and returns it along with its description.
+    pub fn create(&self, request: NewApiKey, user: i32, now: i64)
+        -> rusqlite::Result<(String, ApiKey)> {
+        let mut bytes = [0u8; 32];
+        OsRng::new().unwrap().fill_bytes(&mut bytes);
+        let key = format!("{}{}", API_KEY_PREFIX, bytes.to_hex());
+
+        let api_key = ApiKey {
+            id: Uuid::new_v4().to_simple_string(),
+            name: request.name,
+            user: user,
+            endpoints: request.endpoints.unwrap_or_else(Vec::new),
+            read_targets: request.read_targets.unwrap_or_else(Vec::new),
+            write_targets: request.write_targets.or(request.targets).unwrap_or_else(Vec::new),
+            allowed_ips: request.allowed_ips.unwrap_or_else(Vec::new),
+            created: now,
+            expires: request.expires,
+            last_used: None,
+        };
+
+        let db = self.db.lock().unwrap();
+        try!(db.execute("INSERT INTO api_keys (id, hash, name, user_id, endpoints, targets, allowed_ips,
+                                               created, expires, read_targets)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                        &[&api_key.id, &hash_key(&key), &api_key.name, &api_key.user,
+                          &serde_json::to_string(&api_key.endpoints).unwrap(),
+                          &serde_json::to_string(&api_key.write_targets).unwrap(),
+                          &serde_json::to_string(&api_key.allowed_ips).unwrap(),
+                          &api_key.created, &api_key.expires,
+                          &serde_json::to_string(&api_key.read_targets).unwrap()]));
+        Ok((key, api_key))
+    }
+
+    /// Revokes a key, returning false if there is no such key.
+    pub fn revoke(&self, id: &str) -> rusqlite::Result<bool> {
+        let db = self.db.lock().unwrap();
+        let count = try!(db.execute("DELETE FROM api_keys WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+
+    pub fn list(&self) -> rusqlite::Result<Vec<ApiKey>> {
+        self.select("1 = $1", &1)
+    }
+
+    fn select(&self, condition: &str, param: &rusqlite::types::ToSql)
+        -> rusqlite::Result<Vec<ApiKey>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = try!(db.prepare(&format!(
+            "SELECT id, name, user_id, endpoints, targets, allowed_ips, created, expires, last_used,
+                    read_targets
+             FROM api_keys WHERE {} ORDER BY created", condition)));
+        let rows = try!(stmt.query(&[param]));
+        let mut keys = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            let endpoints: String = row.get(3);
+            let write_targets: String = row.get(4);
+            let allowed_ips: String = row.get(5);
+            let read_targets: String = row.get(9);
+            keys.push(ApiKey {
+                id: row.get(0),
+                name: row.get(1),
+                user: row.get(2),
+                endpoints: serde_json::from_str(&endpoints).unwrap_or_else(|_| Vec::new()),
+                read_targets: serde_json::from_str(&read_targets).unwrap_or_else(|_| Vec::new()),
+                write_targets: serde_json::from_str(&write_targets).unwrap_or_else(|_| Vec::new()),
+                allowed_ips: serde_json::from_str(&allowed_ips).unwrap_or_else(|_| Vec::new()),
+                created: row.get(6),
+                expires: row.get(7),
+                last_used: row.get(8),
+            });
+        }
+        Ok(keys)
+    }
+
+    /// Checks that `key` is valid for a request to `endpoint` coming from
+    /// `client`, records its use and returns its description.
+    pub fn verify(&self, key: &str, client: Option<IpAddr>, endpoint: &str, now: i64)
+        -> Result<ApiKey, ApiKeyError> {
+        let api_key = match self.select("hash = $1", &hash_key(key)) {
+            Ok(mut keys) => match keys.pop() {
+                Some(api_key) => api_key,
+                None => return Err(ApiKeyError::Unknown)
+            },
+            Err(err) => {
+                error!("Unable to read the API keys: {}", err);
+                return Err(ApiKeyError::Unknown);
+            }
+        };
+
+        if api_key.expires.map_or(false, |expires| expires <= now) {
+            return Err(ApiKeyError::Expired);
+        }
+
+        if !api_key.allowed_ips.is_empty() {
+            let client = client.map(|client| format!("{}", client));
+            if !client.map_or(false, |client| api_key.allowed_ips.contains(&client)) {
+                return Err(ApiKeyError::ForbiddenAddress);
+            }
+        }
+
+        if !api_key.allows_endpoint(endpoint) {
+            return Err(ApiKeyError::ForbiddenEndpoint);
+        }
+
+        let db = self.db.lock().unwrap();
+        if let Err(err) = db.execute("UPDATE api_keys SET last_used=$1 WHERE id=$2",
+                                     &[&now, &api_key.id]) {
+            warn!("Unable to record the use of API key {}: {}", api_key.id, err);
+        }
+
+        Ok(ApiKey { last_used: Some(now), .. api_key })
+    }
+}
+
+#[cfg(test)]
+describe! api_keys {
+    before_each {
+        use std::fs;
+        use std::net::{ IpAddr, Ipv4Addr };
+        use uuid::Uuid;
+
+        let db_file_name = format!("api_keys_test-{}.sqlite", Uuid::new_v4().to_simple_string());
+        let keys = ApiKeys::new(&db_file_name);
+        let request = |expires: Option<i64>, allowed_ips: Option<Vec<String>>| NewApiKey {
+            name: "tablet".to_owned(),
+            expires: expires,
+            endpoints: Some(vec!["channels/get".to_owned()]),
+            read_targets: None,
+            write_targets: None,
+            targets: None,
+            allowed_ips: allowed_ips,
+        };
+        let client = Some(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)));
+    }
+
+    after_each {
+        fs::remove_file(db_file_name).unwrap_or(());
+    }
+
+    it "should verify keys and record their use" {
+        let (key, api_key) = keys.create(request(None, None), 1, 100).unwrap();
+        assert!(key.starts_with(API_KEY_PREFIX));
+
+        let verified = keys.verify(&key, client, "channels/get", 200).unwrap();
+        assert_eq!(verified.id, api_key.id);
+        assert_eq!(verified.user, 1);
+        assert_eq!(keys.list().unwrap()[0].last_used, Some(200));
+
+        assert_eq!(keys.verify("fbk_nope", client, "channels/get", 200),
+                   Err(ApiKeyError::Unknown));
+        assert_eq!(keys.verify(&key, client, "channels/set", 200),
+                   Err(ApiKeyError::ForbiddenEndpoint));
+    }
+
+    it "should limit keys to their read and write targets" {
+        use acl::{ Access, Scope };
+
+        let (_, api_key) = keys.create(request(None, None), 1, 100).unwrap();
+        assert_eq!(api_key.scope(), None);
+
+        let (key, _) = keys.create(NewApiKey {
+            read_targets: Some(vec!["tag:kitchen".to_owned()]),
+            write_targets: Some(vec!["service:lights".to_owned()]),
+            .. request(None, None)
+        }, 1, 100).unwrap();
+        let scope = keys.verify(&key, client, "channels/get", 200).unwrap().scope().unwrap();
+        assert_eq!(scope, Scope {
+            read: vec!["tag:kitchen".to_owned()],
+            write: vec!["service:lights".to_owned()]
+        });
+        let kitchen = ["service:oven".to_owned()];
+        let tags = ["kitchen".to_owned()];
+        assert!(scope.allows(Access::Read, &kitchen, &tags));
+        assert!(!scope.allows(Access::Write, &kitchen, &tags));
+        assert!(scope.allows(Access::Write, &["service:lights".to_owned()], &[]));
+
+        // The targets of older clients are write targets.
+        let (_, api_key) = keys.create(NewApiKey {
+            targets: Some(vec!["service:lights".to_owned()]),
+            .. request(None, None)
+        }, 1, 100).unwrap();
+        assert_eq!(api_key.write_targets, vec!["service:lights".to_owned()]);
+    }
+
+    it "should reject expired keys" {
+        let (key, _) = keys.create(request(Some(150), None), 1, 100).unwrap();
+        assert!(keys.verify(&key, client, "channels/get", 149).is_ok());
+        assert_eq!(keys.verify(&key, client, "channels/get", 150), Err(ApiKeyError::Expired));
+    }
+
+    it "should restrict keys to some addresses" {
+        let (key, _) = keys.create(request(None, Some(vec!["192.168.0.3".to_owned()])), 1, 100)
+                           .unwrap();
+        assert_eq!(keys.verify(&key, client, "channels/get", 200),
+                   Err(ApiKeyError::ForbiddenAddress));
+        assert_eq!(keys.verify(&key, None, "channels/get", 200),
+                   Err(ApiKeyError::ForbiddenAddress));
+    }
+
+    it "should revoke keys" {
+        let (key, api_key) = keys.create(request(None, None), 1, 100).unwrap();
+        assert!(keys.revoke(&api_key.id).unwrap());
+        assert!(!keys.revoke(&api_key.id).unwrap());
+        assert_eq!(keys.verify(&key, client, "channels/get", 200), Err(ApiKeyError::Unknown));
+    }
+}
@@ -0,0 +1,233 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Append-only log of the calls changing the state of the box.
+//!
+//! Every setter call and tag change is recorded with the user who did it, the
+//! client address, the request payload and the result for each target.
+//! Thinkerbell rules, which are managed through setters, are recorded as
+//! `add_rule`, `set_rule_enabled` and `remove_rule`, and the changes to the
+//! configuration of the box (permissions, API keys, guest passes, client
+//! certificates and tunnel credential) are recorded too, without service.
+//! The log is stored in `audit_log.sqlite` in the profile and pruned
+//! according to the `audit::max_entries` and `audit::max_age_days` settings.
+
+use config_store::ConfigService;
+use rusqlite::{ self, Connection };
+use std::sync::Mutex;
+use time;
+
+/// One recorded operation on a single target.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the epoch.
+    pub timestamp: i64,
+    pub user: Option<i32>,
+    /// The id of the guest pass used, for the calls made by guests.
+    pub guest_pass: Option<String>,
+    pub client: String,
+    /// The taxonomy api method, eg. "send_values" or "add_service_tags", or
+    /// the configuration change, eg. "set_grant".
+    pub action: String,
+    pub service: Option<String>,
+    pub target: String,
+    /// The request body, holding the selectors and values.
+    pub payload: String,
+    /// "ok", or the error for this target.
+    pub result: String,
+}
+
+/// Restricts the entries returned by `AuditLog::query`.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub user: Option<i32>,
+    pub service: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<u32>,
+}
+
+const DEFAULT_QUERY_LIMIT: u32 = 100;
+
+pub struct AuditLog {
+    db: Mutex<Connection>,
+    max_entries: i64,
+    max_age: i64,
+}
+
+impl AuditLog {
+    /// Opens the database at `path` and creates it if not available yet.
+    pub fn new(path: &str, config: &ConfigService) -> Self {
+        let db = Connection::open(path).unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS audit_log (
+                    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp   INTEGER NOT NULL,
+                    user_id     INTEGER,
+                    guest_pass  TEXT,
+                    client      TEXT NOT NULL,
+                    action      TEXT NOT NULL,
+                    service     TEXT,
+                    target      TEXT NOT NULL,
+                    payload     TEXT NOT NULL,
+                    result      TEXT NOT NULL
+            )", &[]).unwrap();
+
+        let max_entries = config.get_or_set_default("audit", "max_entries", "10000")
+                                .parse().unwrap_or(10000);
+        let max_age_days: i64 = config.get_or_set_default("audit", "max_age_days", "90")
+                                      .parse().unwrap_or(90);
+
+        AuditLog {
+            db: Mutex::new(db),
+            max_entries: max_entries,
+            max_age: max_age_days * 24 * 3600,
+        }
+    }
+
+    /// Appends `entries` to the log and prunes the entries that are past the
+    /// retention limits.
+    pub fn record(&self, entries: &[AuditEntry]) {
+        if let Err(err) = self.try_record(entries) {
+            error!("Unable to write to the audit log: {}", err);
+        }
+    }
+
+    fn try_record(&self, entries: &[AuditEntry]) -> rusqlite::Result<()> {
+        let db = self.db.lock().unwrap();
+        for entry in entries {
+            info!("audit: user {:?} (guest pass {:?}) from {} {} {} -> {}",
+                  entry.user, entry.guest_pass, entry.client, entry.action, entry.target,
+                  entry.result);
+            try!(db.execute("INSERT INTO audit_log (timestamp, user_id, guest_pass, client, action,
+                                                    service, target, payload, result)
+                             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                            &[&entry.timestamp, &entry.user, &entry.guest_pass, &entry.client,
+                              &entry.action, &entry.service, &entry.target, &entry.payload,
+                              &entry.result]));
+        }
+
+        let oldest = time::get_time().sec - self.max_age;
+        try!(db.execute("DELETE FROM audit_log WHERE timestamp < $1", &[&oldest]));
+        try!(db.execute("DELETE FROM audit_log WHERE id <= (SELECT MAX(id) FROM audit_log) - $1",
+                        &[&self.max_entries]));
+        Ok(())
+    }
+
+    /// Returns the entries matching `filter`, most recent first.
+    pub fn query(&self, filter: &AuditFilter) -> rusqlite::Result<Vec<AuditEntry>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = try!(db.prepare(
+            "SELECT timestamp, user_id, guest_pass, client, action, service, target, payload,
+                    result
+             FROM audit_log
+             WHERE ($1 IS NULL OR user_id = $1)
+               AND ($2 IS NULL OR service = $2)
+               AND ($3 IS NULL OR timestamp >= $3)
+               AND ($4 IS NULL OR timestamp <= $4)
+             ORDER BY id DESC LIMIT $5"));
+        let limit = filter.limit.unwrap_or(DEFAULT_QUERY_LIMIT) as i64;
+        let rows = try!(stmt.query(&[&filter.user, &filter.service, &filter.from, &filter.to,
+                                     &limit]));
+        let mut entries = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            entries.push(AuditEntry {
+                timestamp: row.get(0),
+                user: row.get(1),
+                guest_pass: row.get(2),
+                client: row.get(3),
+                action: row.get(4),
+                service: row.get(5),
+                target: row.get(6),
+                payload: row.get(7),
+                result: row.get(8),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+describe! audit_log {
+    before_each {
+        use config_store::ConfigService;
+        use std::fs;
+        use time;
+        use uuid::Uuid;
+
+        let id = Uuid::new_v4().to_simple_string();
+        let config_file_name = format!("conftest-{}.tmp", id);
+        let db_file_name = format!("audit_test-{}.sqlite", id);
+        let config = ConfigService::new(&config_file_name);
+
+        let entry = |user: i32, service: &str, timestamp: i64| AuditEntry {
+            timestamp: timestamp,
+            user: Some(user),
+            guest_pass: None,
+            client: "127.0.0.1:1234".to_owned(),
+            action: "send_values".to_owned(),
+            service: Some(service.to_owned()),
+            target: format!("setter:{}", service),
+            payload: "[]".to_owned(),
+            result: "ok".to_owned(),
+        };
+        let now = time::get_time().sec;
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+        fs::remove_file(db_file_name).unwrap_or(());
+    }
+
+    it "should filter entries by user and service" {
+        let log = AuditLog::new(&db_file_name, &config);
+        log.record(&[entry(1, "light", now), entry(2, "light", now), entry(1, "door", now)]);
+
+        let all = log.query(&AuditFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        // Most recent first.
+        assert_eq!(all[0], entry(1, "door", now));
+
+        let user1 = log.query(&AuditFilter { user: Some(1), .. AuditFilter::default() }).unwrap();
+        assert_eq!(user1.len(), 2);
+
+        let lights = log.query(&AuditFilter {
+            user: Some(1),
+            service: Some("light".to_owned()),
+            .. AuditFilter::default()
+        }).unwrap();
+        assert_eq!(lights, vec![entry(1, "light", now)]);
+    }
+
+    it "should record the guest pass of guests" {
+        let log = AuditLog::new(&db_file_name, &config);
+        let guest = AuditEntry {
+            user: None,
+            guest_pass: Some("0123abcd".to_owned()),
+            .. entry(1, "door", now)
+        };
+        log.record(&[guest.clone()]);
+        assert_eq!(log.query(&AuditFilter::default()).unwrap(), vec![guest]);
+    }
+
+    it "should filter entries by time range" {
+        let log = AuditLog::new(&db_file_name, &config);
+        log.record(&[entry(1, "light", now - 100), entry(1, "light", now)]);
+
+        let recent = log.query(&AuditFilter { from: Some(now - 10), .. AuditFilter::default() })
+                        .unwrap();
+        assert_eq!(recent, vec![entry(1, "light", now)]);
+    }
+
+    it "should apply the retention limits" {
+        config.set("audit", "max_entries", "2");
+        config.set("audit", "max_age_days", "1");
+        let log = AuditLog::new(&db_file_name, &config);
+        log.record(&[entry(1, "old", now - 2 * 24 * 3600)]);
+        log.record(&[entry(1, "a", now), entry(1, "b", now), entry(1, "c", now)]);
+
+        let all = log.query(&AuditFilter::default()).unwrap();
+        assert_eq!(all, vec![entry(1, "c", now), entry(1, "b", now)]);
+    }
+}
@@ -8,6 +8,7 @@ use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{ Mutex, RwLock };
 
 type ConfigNameSpace = BTreeMap<String, String>;
@@ -146,6 +147,18 @@ impl ConfigService {
         })
     }
 
+    /// Like `get_or_set_default`, parsed as a `T`. Invalid values are
+    /// replaced by the default, with a warning.
+    pub fn get_or_set_default_parsed<T: FromStr>(&self, namespace: &str, property: &str, default: &str) -> T {
+        self.get_or_set_default(namespace, property, default).parse().unwrap_or_else(|_| {
+            warn!("Invalid value for {}::{}, using {}", namespace, property, default);
+            match default.parse() {
+                Ok(value) => value,
+                Err(_) => panic!("Invalid default value {} for {}::{}", default, namespace, property)
+            }
+        })
+    }
+
     pub fn set(&self, namespace: &str, property: &str, value: &str) {
         self.store.write().unwrap().set(namespace, property, value);
     }
@@ -209,6 +222,14 @@ describe! config {
             assert_eq!(foo_bar, "default");
         }
 
+        it "should parse the values" {
+            assert_eq!(config.get_or_set_default_parsed::<u32>("foo", "count", "3"), 3);
+            config.set("foo", "count", "12");
+            assert_eq!(config.get_or_set_default_parsed::<u32>("foo", "count", "3"), 12);
+            config.set("foo", "count", "lots");
+            assert_eq!(config.get_or_set_default_parsed::<u32>("foo", "count", "3"), 3);
+        }
+
         it "should return None on non-existent namespaces" {
             config.set("foo", "bar", "baz");
             assert_eq!(config.get("foofoo", "bar"), None);
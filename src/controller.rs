@@ -5,24 +5,37 @@
 extern crate serde_json;
 extern crate mio;
 
+use acl::Acl;
+use api_keys::ApiKeys;
 use adapters::AdapterManager;
+use audit::AuditLog;
 use config_store::ConfigService;
+use dns_sd::DnsSdAdvertiser;
+use event_log::EventLog;
 use foxbox_taxonomy::manager::AdapterManager as TaxoManager;
-use foxbox_users::UsersManager;
+use foxbox_taxonomy::api::User;
+use foxbox_users::{ ReadFilter, UsersManager };
+use guest_tokens::GuestTokens;
 use http_server::HttpServer;
+use network_monitor::NetworkStatus;
 use profile_service::{ ProfilePath, ProfileService };
 use std::collections::hash_map::HashMap;
 use std::io;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
-use std::sync::{ Arc, Mutex };
+use std::sync::{ Arc, Mutex, RwLock };
 use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
 use std::vec::IntoIter;
 use upnp::UpnpManager;
-use tls::{ CertificateManager, CertificateRecord, SniSslContextProvider, TlsOption };
+use tls::{ CertificateManager, CertificateRecord, CertificateWatcher, ClientAuth,
+           ClientCertificates, SniSslContextProvider, TlsOption, TlsPolicy,
+           WatchSettings };
 use traits::Controller;
-use ws_server::WsServer;
+use tunnel_controller::{ TunnelClients, TunnelStatus };
+use tunnel_credentials::TunnelCredentials;
+use ws_server::{ replay_events, SlowConsumerPolicy, WsQueue, WsServer, WsSettings, WsViewer };
 use ws;
 
 #[derive(Clone)]
@@ -30,11 +43,22 @@ pub struct FoxBox {
     pub verbose: bool,
     tls_option: TlsOption,
     certificate_manager: CertificateManager,
+    client_auth: ClientAuth,
     hostname: String,
     http_port: u16,
     ws_port: u16,
-    websockets: Arc<Mutex<HashMap<ws::util::Token, ws::Sender>>>,
+    network_status: Arc<RwLock<NetworkStatus>>,
+    tunnel_status: Arc<RwLock<Option<TunnelStatus>>>,
+    tunnel_credentials: Arc<TunnelCredentials>,
+    tunnel_clients: TunnelClients,
+    websockets: Arc<Mutex<HashMap<ws::util::Token, (ws::Sender, WsQueue, WsViewer)>>>,
+    slow_consumer_policy: SlowConsumerPolicy,
+    event_log: Arc<Mutex<EventLog>>,
     pub config: Arc<ConfigService>,
+    audit_log: Arc<AuditLog>,
+    acl: Arc<Acl>,
+    api_keys: Arc<ApiKeys>,
+    guest_tokens: Arc<GuestTokens>,
     upnp: Arc<UpnpManager>,
     users_manager: Arc<UsersManager>,
     profile_service: Arc<ProfileService>,
@@ -54,14 +78,49 @@ impl FoxBox {
         let certificate_directory = PathBuf::from(
             config.get_or_set_default("foxbox", "certificate_directory", "certs/"));
 
+        let ws_settings = WsSettings::from_config(&config);
+
+        let client_certificates = Arc::new(
+            ClientCertificates::new(&profile_service.path_for("client_ca"), &config));
+        let client_auth = ClientAuth::new(client_certificates, &config);
+
+        let tls_policy = TlsPolicy::from_config(&config);
+        if tls_option == TlsOption::Enabled {
+            info!("TLS policy: {}", tls_policy);
+        }
+
+        // Serving TLS without the configured policy or client authentication
+        // would silently weaken it.
+        let context_provider = match SniSslContextProvider::with_client_auth(client_auth.clone(),
+                                                                             tls_policy) {
+            Ok(provider) => provider,
+            Err(err) if tls_option == TlsOption::Enabled => {
+                panic!("Unable to set up TLS: {}", err)
+            },
+            Err(_) => SniSslContextProvider::new()
+        };
+
         FoxBox {
-            certificate_manager: CertificateManager::new(certificate_directory, Box::new(SniSslContextProvider::new())),
+            certificate_manager: CertificateManager::new(certificate_directory,
+                                                         Box::new(context_provider)),
+            client_auth: client_auth,
             tls_option: tls_option,
             websockets: Arc::new(Mutex::new(HashMap::new())),
+            slow_consumer_policy: ws_settings.slow_consumer,
+            event_log: Arc::new(Mutex::new(EventLog::new(ws_settings.replay_buffer_size))),
             verbose: verbose,
             hostname: hostname,
             http_port: http_port,
             ws_port: ws_port,
+            network_status: Arc::new(RwLock::new(NetworkStatus::default())),
+            tunnel_status: Arc::new(RwLock::new(None)),
+            tunnel_credentials: Arc::new(
+                TunnelCredentials::new(&profile_service.path_for("tunnel_credential.json"))),
+            tunnel_clients: TunnelClients::new(),
+            audit_log: Arc::new(AuditLog::new(&profile_service.path_for("audit_log.sqlite"), &config)),
+            acl: Arc::new(Acl::new(&profile_service.path_for("acl.sqlite"), &config)),
+            api_keys: Arc::new(ApiKeys::new(&profile_service.path_for("api_keys.sqlite"))),
+            guest_tokens: Arc::new(GuestTokens::new(&profile_service.path_for("guest_tokens.sqlite"))),
             config: config,
             upnp: Arc::new(UpnpManager::new()),
             users_manager: Arc::new(UsersManager::new(&profile_service.path_for("users_db.sqlite"))),
@@ -78,7 +137,8 @@ impl Controller for FoxBox {
         let mut event_loop = mio::EventLoop::new().unwrap();
 
         {
-            Arc::get_mut(&mut self.upnp).unwrap().start().unwrap();
+            let event_port = self.config.get_or_set_default_parsed("upnp", "event_port", "0");
+            Arc::get_mut(&mut self.upnp).unwrap().start(event_port).unwrap();
         }
 
         // Create the taxonomy based AdapterManager
@@ -88,10 +148,18 @@ impl Controller for FoxBox {
         let mut adapter_manager = AdapterManager::new(self.clone());
         adapter_manager.start(&taxo_manager);
 
+        if self.get_tls_enabled() {
+            CertificateWatcher::new(self.certificate_manager.clone(),
+                                    WatchSettings::from_config(&self.config)).start();
+        }
+
         HttpServer::new(self.clone()).start(&taxo_manager);
         WsServer::start(self.clone());
+        let mut dns_sd = DnsSdAdvertiser::start(self.clone());
 
-        self.upnp.search(None).unwrap();
+        // Repeated for the devices which rarely advertise themselves.
+        let search_interval = self.config.get_or_set_default_parsed("upnp", "search_interval_seconds", "300");
+        self.upnp.schedule_search("ssdp:all", Duration::from_secs(search_interval));
 
         event_loop.run(&mut FoxBoxEventLoop {
             controller: self.clone(),
@@ -99,6 +167,9 @@ impl Controller for FoxBox {
         }).unwrap();
 
         debug!("Stopping controller");
+        if let Some(ref mut dns_sd) = dns_sd {
+            dns_sd.stop();
+        }
         adapter_manager.stop();
         taxo_manager.stop();
     }
@@ -119,8 +190,20 @@ impl Controller for FoxBox {
         ("::", self.ws_port).to_socket_addrs()
     }
 
-    fn add_websocket(&mut self, socket: ws::Sender) {
-        self.websockets.lock().unwrap().insert(socket.token(), socket);
+    fn add_websocket(&mut self, socket: ws::Sender, queue: WsQueue, viewer: WsViewer, since: Option<u64>)
+        -> ws::Result<()> {
+        // Broadcasts keep the event log locked while sending, so none can
+        // get between the replay and the registration of the socket.
+        let event_log = self.event_log.lock().unwrap();
+        if let Some(since) = since {
+            let acl = &self.acl;
+            try!(replay_events(&socket, event_log.since(since), since, |event| {
+                serde_json::from_str(event).map(|event| viewer.may_receive(acl, &event))
+                                           .unwrap_or(false)
+            }));
+        }
+        self.websockets.lock().unwrap().insert(socket.token(), (socket, queue, viewer));
+        Ok(())
     }
 
     fn remove_websocket(&mut self, socket: ws::Sender) {
@@ -128,14 +211,59 @@ impl Controller for FoxBox {
     }
 
     fn broadcast_to_websockets(&self, data: serde_json::value::Value) {
-        let serialized = serde_json::to_string(&data).unwrap_or("{}".to_owned());
-        debug!("broadcast_to_websockets {}", serialized.clone());
-        for socket in self.websockets.lock().unwrap().values() {
-            match socket.send(serialized.clone()) {
-                Ok(_) => (),
-                Err(err) => error!("Error sending to socket: {}", err)
+        let mut slow_consumers = Vec::new();
+        {
+            // Keep the event log locked while sending, so that sockets get
+            // the events in sequence order.
+            let mut event_log = self.event_log.lock().unwrap();
+            let websockets = self.websockets.lock().unwrap();
+            let viewers: Vec<(&ws::Sender, &WsQueue)> = websockets.values()
+                .filter_map(|&(ref socket, ref queue, ref viewer)| {
+                    if viewer.may_receive(&self.acl, &data) { Some((socket, queue)) } else { None }
+                }).collect();
+            let serialized = event_log.push(data);
+            debug!("broadcast_to_websockets {}", serialized.clone());
+            for (socket, queue) in viewers {
+                match queue.send(socket, serialized.clone()) {
+                    Ok(true) => (),
+                    Ok(false) | Err(ws::Error { kind: ws::ErrorKind::Queue(_), .. }) => {
+                        warn!("Websocket event queue full for socket {:?}", socket.token());
+                        if self.slow_consumer_policy == SlowConsumerPolicy::Disconnect {
+                            slow_consumers.push(socket.clone());
+                        }
+                    },
+                    Err(err) => error!("Error sending to socket: {}", err)
+                }
             }
         }
+
+        for socket in slow_consumers {
+            info!("Disconnecting slow ws consumer {:?}", socket.token());
+            self.websockets.lock().unwrap().remove(&socket.token());
+            if let Err(err) = socket.close(ws::CloseCode::Again) {
+                error!("Error closing socket: {}", err);
+            }
+        }
+    }
+
+    fn websockets_count(&self) -> usize {
+        self.websockets.lock().unwrap().len()
+    }
+
+    fn get_audit_log(&self) -> Arc<AuditLog> {
+        self.audit_log.clone()
+    }
+
+    fn get_acl(&self) -> Arc<Acl> {
+        self.acl.clone()
+    }
+
+    fn get_api_keys(&self) -> Arc<ApiKeys> {
+        self.api_keys.clone()
+    }
+
+    fn get_guest_tokens(&self) -> Arc<GuestTokens> {
+        self.guest_tokens.clone()
     }
 
     fn get_config(&self) -> Arc<ConfigService> {
@@ -154,10 +282,30 @@ impl Controller for FoxBox {
         self.users_manager.clone()
     }
 
+    fn is_admin(&self, user: &User) -> bool {
+        if !cfg!(feature = "authentication") {
+            return true;
+        }
+
+        match *user {
+            User::Id(id) => {
+                match self.users_manager.get_db().read(ReadFilter::Id(id)) {
+                    Ok(users) => users.first().map_or(false, |user| user.is_admin),
+                    Err(_) => false
+                }
+            },
+            User::None => false
+        }
+    }
+
     fn get_certificate_manager(&self) -> CertificateManager {
         self.certificate_manager.clone()
     }
 
+    fn get_client_auth(&self) -> ClientAuth {
+        self.client_auth.clone()
+    }
+
     /// Every box should create a self signed certificate for a local name.
     /// The fingerprint of that certificate becomes the box's identifier,
     /// which is used to create the public DNS zone and local
@@ -174,6 +322,30 @@ impl Controller for FoxBox {
     fn get_hostname(&self) -> String  {
         self.hostname.clone()
     }
+
+    fn get_network_status(&self) -> NetworkStatus {
+        self.network_status.read().unwrap().clone()
+    }
+
+    fn set_network_status(&self, status: NetworkStatus) {
+        *self.network_status.write().unwrap() = status;
+    }
+
+    fn get_tunnel_status(&self) -> Option<TunnelStatus> {
+        self.tunnel_status.read().unwrap().clone()
+    }
+
+    fn set_tunnel_status(&self, status: Option<TunnelStatus>) {
+        *self.tunnel_status.write().unwrap() = status;
+    }
+
+    fn get_tunnel_credentials(&self) -> Arc<TunnelCredentials> {
+        self.tunnel_credentials.clone()
+    }
+
+    fn get_tunnel_clients(&self) -> TunnelClients {
+        self.tunnel_clients.clone()
+    }
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,818 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Advertises the box on the local network with DNS-SD over multicast DNS,
+//! so that apps can find it, and check its certificate, without any access to
+//! the registration server.
+//!
+//! Two services are published under the instance name of the box:
+//!
+//! - `_foxbox._tcp`, the API itself;
+//! - `_https._tcp`, or `_http._tcp` when TLS is disabled, for generic
+//!   browsers.
+//!
+//! Both carry TXT entries with the API version (`api`), the fingerprint of
+//! the box certificate (`fingerprint`), the TLS status (`tls`) and the port
+//! of the WebSocket server (`ws_port`). The records are recomputed every
+//! `dns_sd::refresh_seconds`, and published again when they change, eg. when
+//! the box certificate gets regenerated.
+//!
+//! When avahi is running, the services are published through a static
+//! service file in `dns_sd::avahi_services_dir`, and avahi takes care of
+//! probing, conflicts and answering queries. The box then needs write access
+//! to that directory, which is only writable by root by default.
+//!
+//! Otherwise a minimal responder answers the queries for our services and
+//! announces the records when they change. It doesn't probe the instance
+//! name nor handle conflicts with other hosts. It listens on UDP port 5353,
+//! which needs no privileges, with `SO_REUSEADDR` and `SO_REUSEPORT` so that
+//! it can share the port with another mDNS daemon.
+//!
+//! The host name itself (and its addresses) is owned by the system mDNS
+//! daemon, which we configure through `multicast_dns::HostManager`, so the
+//! addresses are only sent as additional records.
+
+use config_store::ConfigService;
+use multicast::bind_multicast;
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr, UdpSocket };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+use traits::Controller;
+
+const MDNS_PORT: u16 = 5353;
+
+const API_VERSION: &'static str = "v1";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+const AVAHI_SOCKET: &'static str = "/var/run/avahi-daemon/socket";
+const AVAHI_SERVICE_FILE: &'static str = "foxbox.service";
+
+// The TTLs recommended by RFC 6762: records mentioning host names expire
+// sooner than the others.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+pub struct DnsSdSettings {
+    pub enabled: bool,
+    /// The instance name, defaulting to the host name of the box.
+    pub instance_name: Option<String>,
+    pub refresh_seconds: u64,
+    /// Where avahi reads its static services from.
+    pub avahi_services_dir: PathBuf,
+}
+
+impl DnsSdSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        let instance_name = config.get_or_set_default("dns_sd", "instance_name", "");
+
+        DnsSdSettings {
+            enabled: config.get_or_set_default("dns_sd", "enabled", "true") == "true",
+            instance_name: if instance_name.is_empty() { None } else { Some(instance_name) },
+            refresh_seconds: config.get_or_set_default_parsed("dns_sd", "refresh_seconds", "30"),
+            avahi_services_dir: PathBuf::from(config.get_or_set_default("dns_sd", "avahi_services_dir",
+                                                                        "/etc/avahi/services")),
+        }
+    }
+}
+
+/// A domain name, as a list of labels. Instance names may contain dots, so
+/// names can't simply be strings.
+type Name = Vec<String>;
+
+fn mdns_group() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 251)
+}
+
+fn name(dotted: &str) -> Name {
+    dotted.split('.').filter(|label| !label.is_empty()).map(|label| label.to_owned()).collect()
+}
+
+fn same_name(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn encode_name(name: &[String], out: &mut Vec<u8>) {
+    for label in name {
+        // Labels are limited to 63 bytes.
+        let bytes = &label.as_bytes()[..cmp::min(label.len(), 63)];
+        out.push(bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+    out.push(0);
+}
+
+fn push_u16(value: u16, out: &mut Vec<u8>) {
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+fn push_u32(value: u32, out: &mut Vec<u8>) {
+    push_u16((value >> 16) as u16, out);
+    push_u16(value as u16, out);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    name: Name,
+    record_type: u16,
+    /// Set for the records only this box publishes.
+    unique: bool,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+impl Record {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_name(&self.name, out);
+        push_u16(self.record_type, out);
+        push_u16(if self.unique { CLASS_IN | CACHE_FLUSH } else { CLASS_IN }, out);
+        push_u32(self.ttl, out);
+        push_u16(self.rdata.len() as u16, out);
+        out.extend_from_slice(&self.rdata);
+    }
+
+    fn goodbye(&self) -> Record {
+        Record { ttl: 0, ..self.clone() }
+    }
+}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    /// eg. `_foxbox._tcp`.
+    pub service_type: String,
+    pub port: u16,
+}
+
+/// Everything the box advertises.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advertisement {
+    pub instance_name: String,
+    /// eg. `foxbox.local`.
+    pub hostname: String,
+    pub services: Vec<Service>,
+    pub txt: Vec<String>,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Advertisement {
+    /// Computes the advertisement from the current state of the box.
+    pub fn from_controller<T: Controller>(controller: &T, settings: &DnsSdSettings) -> Self {
+        let hostname = controller.get_hostname();
+        let tls_enabled = controller.get_tls_enabled();
+        let port = |addrs: io::Result<::std::vec::IntoIter<SocketAddr>>| {
+            addrs.ok().and_then(|mut addrs| addrs.next()).map_or(0, |addr| addr.port())
+        };
+        let http_port = port(controller.http_as_addrs());
+        let ws_port = port(controller.ws_as_addrs());
+        let fingerprint = controller.get_box_certificate()
+                                    .map(|record| record.get_certificate_fingerprint())
+                                    .unwrap_or_else(|_| String::new());
+
+        let instance_name = settings.instance_name.clone().unwrap_or_else(|| {
+            hostname.split('.').next().unwrap_or("foxbox").to_owned()
+        });
+
+        Advertisement {
+            instance_name: instance_name,
+            hostname: hostname,
+            services: vec![
+                Service { service_type: "_foxbox._tcp".to_owned(), port: http_port },
+                Service {
+                    service_type: if tls_enabled { "_https._tcp" } else { "_http._tcp" }.to_owned(),
+                    port: http_port
+                },
+            ],
+            txt: vec![
+                format!("api={}", API_VERSION),
+                format!("fingerprint={}", fingerprint),
+                format!("tls={}", if tls_enabled { "on" } else { "off" }),
+                format!("ws_port={}", ws_port),
+            ],
+            addresses: controller.get_network_status().ip_addrs.iter()
+                                 .filter_map(|addr| addr.parse().ok())
+                                 .collect(),
+        }
+    }
+
+    fn service_name(service: &Service) -> Name {
+        let mut service_name = name(&service.service_type);
+        service_name.push("local".to_owned());
+        service_name
+    }
+
+    fn instance(&self, service: &Service) -> Name {
+        let mut instance = vec![self.instance_name.clone()];
+        instance.extend(Advertisement::service_name(service));
+        instance
+    }
+
+    fn enumeration_record(&self, service: &Service) -> Record {
+        let mut rdata = vec![];
+        encode_name(&Advertisement::service_name(service), &mut rdata);
+        Record {
+            name: name("_services._dns-sd._udp.local"),
+            record_type: TYPE_PTR,
+            unique: false,
+            ttl: OTHER_TTL,
+            rdata: rdata,
+        }
+    }
+
+    fn ptr_record(&self, service: &Service) -> Record {
+        let mut rdata = vec![];
+        encode_name(&self.instance(service), &mut rdata);
+        Record {
+            name: Advertisement::service_name(service),
+            record_type: TYPE_PTR,
+            unique: false,
+            ttl: OTHER_TTL,
+            rdata: rdata,
+        }
+    }
+
+    fn srv_record(&self, service: &Service) -> Record {
+        let mut rdata = vec![];
+        push_u16(0, &mut rdata); // Priority.
+        push_u16(0, &mut rdata); // Weight.
+        push_u16(service.port, &mut rdata);
+        encode_name(&name(&self.hostname), &mut rdata);
+        Record {
+            name: self.instance(service),
+            record_type: TYPE_SRV,
+            unique: true,
+            ttl: HOST_TTL,
+            rdata: rdata,
+        }
+    }
+
+    fn txt_record(&self, service: &Service) -> Record {
+        let mut rdata = vec![];
+        for entry in &self.txt {
+            let bytes = &entry.as_bytes()[..cmp::min(entry.len(), 255)];
+            rdata.push(bytes.len() as u8);
+            rdata.extend_from_slice(bytes);
+        }
+        Record {
+            name: self.instance(service),
+            record_type: TYPE_TXT,
+            unique: true,
+            ttl: OTHER_TTL,
+            rdata: rdata,
+        }
+    }
+
+    fn address_records(&self) -> Vec<Record> {
+        self.addresses.iter().map(|address| {
+            let (record_type, rdata) = match *address {
+                IpAddr::V4(ref ip) => (TYPE_A, ip.octets().to_vec()),
+                IpAddr::V6(ref ip) => {
+                    let mut rdata = vec![];
+                    for segment in &ip.segments() {
+                        push_u16(*segment, &mut rdata);
+                    }
+                    (TYPE_AAAA, rdata)
+                }
+            };
+            Record {
+                name: name(&self.hostname),
+                record_type: record_type,
+                // Shared with the system mDNS daemon.
+                unique: false,
+                ttl: HOST_TTL,
+                rdata: rdata,
+            }
+        }).collect()
+    }
+
+    /// All the records, as announced.
+    fn records(&self) -> Vec<Record> {
+        let mut records = vec![];
+        for service in &self.services {
+            records.push(self.enumeration_record(service));
+            records.push(self.ptr_record(service));
+            records.push(self.srv_record(service));
+            records.push(self.txt_record(service));
+        }
+        records.extend(self.address_records());
+        records
+    }
+
+    /// Returns the answers and additional records for `questions`.
+    fn answer(&self, questions: &[Question]) -> (Vec<Record>, Vec<Record>) {
+        let mut answers = vec![];
+        let mut additional = vec![];
+
+        fn add(records: &mut Vec<Record>, record: Record) {
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+        let matches = |question: &Question, record_type: u16| {
+            question.record_type == record_type || question.record_type == TYPE_ANY
+        };
+
+        for question in questions {
+            for service in &self.services {
+                if same_name(&question.name, &name("_services._dns-sd._udp.local")) &&
+                   matches(question, TYPE_PTR) {
+                    add(&mut answers, self.enumeration_record(service));
+                }
+                if same_name(&question.name, &Advertisement::service_name(service)) &&
+                   matches(question, TYPE_PTR) {
+                    add(&mut answers, self.ptr_record(service));
+                    add(&mut additional, self.srv_record(service));
+                    add(&mut additional, self.txt_record(service));
+                    for record in self.address_records() {
+                        add(&mut additional, record);
+                    }
+                }
+                if same_name(&question.name, &self.instance(service)) {
+                    if matches(question, TYPE_SRV) {
+                        add(&mut answers, self.srv_record(service));
+                        for record in self.address_records() {
+                            add(&mut additional, record);
+                        }
+                    }
+                    if matches(question, TYPE_TXT) {
+                        add(&mut answers, self.txt_record(service));
+                    }
+                }
+            }
+        }
+
+        additional.retain(|record| !answers.contains(record));
+        (answers, additional)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Question {
+    name: Name,
+    record_type: u16,
+}
+
+/// The parts of a message the responder cares about. The records of the
+/// other sections are ignored.
+struct Message {
+    is_response: bool,
+    questions: Vec<Question>,
+}
+
+macro_rules! try_opt {
+    ($expr:expr) => (match $expr {
+        Some(value) => value,
+        None => return None
+    })
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    if offset + 2 > packet.len() {
+        return None;
+    }
+    Some((packet[offset] as u16) << 8 | packet[offset + 1] as u16)
+}
+
+/// Reads the name at `offset`, following compression pointers, and returns it
+/// with the offset right after it.
+fn read_name(packet: &[u8], offset: usize) -> Option<(Name, usize)> {
+    let mut labels = vec![];
+    let mut position = offset;
+    let mut end = None;
+    // Bounds the number of pointers, so that loops can't hang us.
+    let mut jumps = 0;
+
+    loop {
+        let length = *try_opt!(packet.get(position)) as usize;
+        if length & 0xc0 == 0xc0 {
+            let pointer = (try_opt!(read_u16(packet, position)) & 0x3fff) as usize;
+            if end.is_none() {
+                end = Some(position + 2);
+            }
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            position = pointer;
+        } else if length == 0 {
+            return Some((labels, end.unwrap_or(position + 1)));
+        } else {
+            if position + 1 + length > packet.len() {
+                return None;
+            }
+            let label = &packet[position + 1..position + 1 + length];
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            position += 1 + length;
+        }
+    }
+}
+
+/// Parses the header and the questions of a message, ignoring malformed
+/// packets.
+fn parse_message(packet: &[u8]) -> Option<Message> {
+    let flags = try_opt!(read_u16(packet, 2));
+    let question_count = try_opt!(read_u16(packet, 4));
+
+    let mut questions = vec![];
+    let mut offset = 12;
+    for _ in 0..question_count {
+        let (name, end) = try_opt!(read_name(packet, offset));
+        let record_type = try_opt!(read_u16(packet, end));
+        try_opt!(read_u16(packet, end + 2)); // Class.
+        questions.push(Question {
+            name: name,
+            record_type: record_type,
+        });
+        offset = end + 4;
+    }
+
+    Some(Message {
+        is_response: flags & 0x8000 != 0,
+        questions: questions,
+    })
+}
+
+/// Builds a multicast response, without questions.
+fn build_response(answers: &[Record], additional: &[Record]) -> Vec<u8> {
+    let mut packet = vec![];
+    push_u16(0, &mut packet); // Id.
+    push_u16(FLAGS_RESPONSE, &mut packet);
+    push_u16(0, &mut packet);
+    push_u16(answers.len() as u16, &mut packet);
+    push_u16(0, &mut packet);
+    push_u16(additional.len() as u16, &mut packet);
+    for record in answers.iter().chain(additional.iter()) {
+        record.encode(&mut packet);
+    }
+    packet
+}
+
+/// Whether avahi is running, in which case it owns the mDNS port.
+fn avahi_running() -> bool {
+    Path::new(AVAHI_SOCKET).exists()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+/// The avahi static service file publishing `advertisement`.
+fn avahi_service_file(advertisement: &Advertisement) -> String {
+    let mut file = String::new();
+    file.push_str("<?xml version=\"1.0\" standalone='no'?>\n");
+    file.push_str("<!DOCTYPE service-group SYSTEM \"avahi-service.dtd\">\n");
+    file.push_str("<service-group>\n");
+    file.push_str(&format!("  <name>{}</name>\n", escape_xml(&advertisement.instance_name)));
+    for service in &advertisement.services {
+        file.push_str("  <service>\n");
+        file.push_str(&format!("    <type>{}</type>\n", escape_xml(&service.service_type)));
+        file.push_str(&format!("    <port>{}</port>\n", service.port));
+        for entry in &advertisement.txt {
+            file.push_str(&format!("    <txt-record>{}</txt-record>\n", escape_xml(entry)));
+        }
+        file.push_str("  </service>\n");
+    }
+    file.push_str("</service-group>\n");
+    file
+}
+
+/// Where the advertisement goes: an avahi service file, or our own responder.
+enum Publisher {
+    Avahi(PathBuf),
+    Responder(UdpSocket),
+}
+
+impl Publisher {
+    fn multicast(socket: &UdpSocket, records: &[Record]) {
+        if records.is_empty() {
+            return;
+        }
+        let destination = SocketAddr::new(IpAddr::V4(mdns_group()), MDNS_PORT);
+        if let Err(err) = socket.send_to(&build_response(records, &[]), destination) {
+            warn!("Unable to send mDNS response: {}", err);
+        }
+    }
+
+    /// Publishes `advertisement`, replacing `previous`.
+    fn publish(&self, advertisement: &Advertisement, previous: Option<&Advertisement>)
+               -> io::Result<()> {
+        info!("Advertising {} with DNS-SD: {:?}", advertisement.instance_name, advertisement.txt);
+        match *self {
+            Publisher::Avahi(ref directory) => {
+                // Avahi only reads the files ending in ".service", and picks
+                // up their changes by itself, so the file is written aside
+                // and then renamed into place.
+                let temporary = directory.join(format!("{}.tmp", AVAHI_SERVICE_FILE));
+                {
+                    let mut file = try!(File::create(&temporary));
+                    try!(file.write_all(avahi_service_file(advertisement).as_bytes()));
+                }
+                fs::rename(&temporary, directory.join(AVAHI_SERVICE_FILE))
+            },
+            Publisher::Responder(ref socket) => {
+                // Withdraws the records that don't exist anymore. The unique
+                // ones are replaced through the cache flush bit.
+                if let Some(previous) = previous {
+                    let records = advertisement.records();
+                    let withdrawn: Vec<Record> = previous.records().into_iter()
+                        .filter(|old| !old.unique && !records.contains(old))
+                        .map(|old| old.goodbye())
+                        .collect();
+                    Publisher::multicast(socket, &withdrawn);
+                }
+                Publisher::multicast(socket, &advertisement.records());
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers the queries received within a second.
+    fn process(&self, advertisement: &Advertisement) {
+        let socket = match *self {
+            Publisher::Responder(ref socket) => socket,
+            Publisher::Avahi(_) => {
+                thread::sleep(Duration::from_secs(1));
+                return;
+            }
+        };
+        let mut buffer = [0u8; 9000];
+        let (size, source) = match socket.recv_from(&mut buffer) {
+            Ok(result) => result,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => return,
+            Err(err) => {
+                warn!("Unable to receive mDNS queries: {}", err);
+                thread::sleep(Duration::from_secs(1));
+                return;
+            }
+        };
+        // Only answers the mDNS queriers, which send from the mDNS port.
+        if source.port() != MDNS_PORT {
+            return;
+        }
+        let message = match parse_message(&buffer[..size]) {
+            Some(message) => message,
+            None => return
+        };
+        if message.is_response {
+            return;
+        }
+        let (answers, additional) = advertisement.answer(&message.questions);
+        if answers.is_empty() {
+            return;
+        }
+        let destination = SocketAddr::new(IpAddr::V4(mdns_group()), MDNS_PORT);
+        if let Err(err) = socket.send_to(&build_response(&answers, &additional), destination) {
+            warn!("Unable to send mDNS response: {}", err);
+        }
+    }
+
+    fn withdraw(&self, advertisement: &Advertisement) {
+        match *self {
+            Publisher::Avahi(ref directory) => {
+                let file = directory.join(AVAHI_SERVICE_FILE);
+                if let Err(err) = fs::remove_file(&file) {
+                    warn!("Unable to remove the avahi service file {:?}: {}", file, err);
+                }
+            },
+            Publisher::Responder(ref socket) => {
+                let records: Vec<Record> = advertisement.records().iter()
+                                                        .map(|record| record.goodbye())
+                                                        .collect();
+                Publisher::multicast(socket, &records);
+            }
+        }
+    }
+}
+
+/// The running advertiser. Dropping it leaves the records in the caches of
+/// the network until they expire, so it should be stopped instead.
+pub struct DnsSdAdvertiser {
+    stop_flag: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DnsSdAdvertiser {
+    /// Starts advertising the box, through avahi if it is running, unless
+    /// `dns_sd::enabled` is false or the mDNS port can't be bound.
+    pub fn start<T: Controller>(controller: T) -> Option<Self> {
+        let settings = DnsSdSettings::from_config(&controller.get_config());
+        if !settings.enabled {
+            info!("DNS-SD advertisement is disabled");
+            return None;
+        }
+
+        let publisher = if avahi_running() {
+            Publisher::Avahi(settings.avahi_services_dir.clone())
+        } else {
+            let socket = match bind_multicast(mdns_group(), MDNS_PORT) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    error!("Unable to listen for mDNS queries: {}", err);
+                    return None;
+                }
+            };
+            // Wakes up the thread to check the stop flag and refresh the
+            // records.
+            socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            Publisher::Responder(socket)
+        };
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let thread_stop_flag = stop_flag.clone();
+
+        let thread = thread::Builder::new().name("DnsSdAdvertiser".to_owned())
+            .spawn(move || {
+                let refresh = Duration::from_secs(settings.refresh_seconds);
+                let mut advertisement: Option<Advertisement> = None;
+                let mut last_refresh: Option<Instant> = None;
+
+                while !thread_stop_flag.load(Ordering::Acquire) {
+                    if last_refresh.map_or(true, |last| last.elapsed() >= refresh) {
+                        let current = Advertisement::from_controller(&controller, &settings);
+                        if advertisement.as_ref() != Some(&current) {
+                            match publisher.publish(&current, advertisement.as_ref()) {
+                                Ok(()) => advertisement = Some(current),
+                                Err(err) => error!("Unable to publish the services through avahi in {:?}: {}",
+                                                   settings.avahi_services_dir, err)
+                            }
+                        }
+                        last_refresh = Some(Instant::now());
+                    }
+                    match advertisement {
+                        Some(ref advertisement) => publisher.process(advertisement),
+                        None => thread::sleep(Duration::from_secs(1))
+                    }
+                }
+                if let Some(ref advertisement) = advertisement {
+                    publisher.withdraw(advertisement);
+                }
+            }).unwrap();
+
+        Some(DnsSdAdvertiser {
+            stop_flag: stop_flag,
+            thread: Some(thread),
+        })
+    }
+
+    /// Withdraws the records from the network and stops answering queries.
+    pub fn stop(&mut self) {
+        self.stop_flag.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+describe! dns_sd {
+    before_each {
+        use std::net::IpAddr;
+
+        let advertisement = Advertisement {
+            instance_name: "Living room".to_owned(),
+            hostname: "foxbox.local".to_owned(),
+            services: vec![
+                Service { service_type: "_foxbox._tcp".to_owned(), port: 3000 },
+                Service { service_type: "_https._tcp".to_owned(), port: 3000 },
+            ],
+            txt: vec!["api=v1".to_owned(), "fingerprint=abcd".to_owned(),
+                      "tls=on".to_owned(), "ws_port=4000".to_owned()],
+            addresses: vec!["192.168.0.4".parse::<IpAddr>().unwrap()],
+        };
+
+        fn query_packet(name: &[u8], record_type: u16) -> Vec<u8> {
+            let mut packet = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+            packet.extend_from_slice(name);
+            packet.extend_from_slice(&[(record_type >> 8) as u8, record_type as u8, 0, 1]);
+            packet
+        }
+    }
+
+    it "should encode names as labels" {
+        let mut encoded = vec![];
+        encode_name(&advertisement.instance(&advertisement.services[0]), &mut encoded);
+        assert_eq!(encoded, b"\x0bLiving room\x07_foxbox\x04_tcp\x05local\x00".to_vec());
+
+        // Labels longer than 63 bytes are truncated.
+        let mut encoded = vec![];
+        encode_name(&[::std::iter::repeat('a').take(70).collect::<String>()], &mut encoded);
+        assert_eq!(encoded[0], 63);
+        assert_eq!(encoded.len(), 65);
+    }
+
+    it "should encode records" {
+        let mut encoded = vec![];
+        advertisement.srv_record(&advertisement.services[0]).encode(&mut encoded);
+        let (name, end) = read_name(&encoded, 0).unwrap();
+        assert_eq!(name, advertisement.instance(&advertisement.services[0]));
+        // Type, class with the cache flush bit, ttl and data length.
+        assert_eq!(&encoded[end..end + 10], &[0, 33, 0x80, 1, 0, 0, 0, 120, 0, 20]);
+        // Priority, weight and port, then the host name.
+        assert_eq!(&encoded[end + 10..end + 16], &[0, 0, 0, 0, 0x0b, 0xb8]);
+        assert_eq!(read_name(&encoded, end + 16).unwrap().0, name("foxbox.local"));
+    }
+
+    it "should parse compressed questions" {
+        let mut packet = query_packet(b"\x07_foxbox\x04_tcp\x05local\x00", TYPE_PTR);
+        // A second question, pointing to the name of the first one.
+        packet[5] = 2;
+        packet.extend_from_slice(&[0xc0, 12, 0, 16, 0x80, 1]);
+
+        let query = parse_message(&packet).unwrap();
+        assert!(!query.is_response);
+        assert_eq!(query.questions.len(), 2);
+        assert_eq!(query.questions[0].name, name("_foxbox._tcp.local"));
+        assert_eq!(query.questions[1].name, name("_foxbox._tcp.local"));
+        assert_eq!(query.questions[1].record_type, TYPE_TXT);
+    }
+
+    it "should reject malformed packets" {
+        assert!(parse_message(&[0x12, 0x34]).is_none());
+        // A name pointing to itself.
+        assert!(parse_message(&query_packet(b"\xc0\x0c", TYPE_PTR)).is_none());
+        // A label running past the end of the packet.
+        assert!(parse_message(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 9, b'a']).is_none());
+        // A question without its type and class.
+        assert!(parse_message(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]).is_none());
+    }
+
+    it "should build responses the parser reads back" {
+        let query = parse_message(&query_packet(b"\x07_foxbox\x04_tcp\x05local\x00", TYPE_PTR)).unwrap();
+        let (answers, additional) = advertisement.answer(&query.questions);
+        let packet = build_response(&answers, &additional);
+
+        let response = parse_message(&packet).unwrap();
+        assert!(response.is_response);
+        assert!(response.questions.is_empty());
+        assert_eq!(read_u16(&packet, 6), Some(answers.len() as u16));
+        assert_eq!(read_u16(&packet, 10), Some(additional.len() as u16));
+
+        let mut offset = 12;
+        for record in answers.iter().chain(additional.iter()) {
+            let (name, end) = read_name(&packet, offset).unwrap();
+            assert_eq!(name, record.name);
+            assert_eq!(read_u16(&packet, end), Some(record.record_type));
+            let length = read_u16(&packet, end + 8).unwrap() as usize;
+            assert_eq!(&packet[end + 10..end + 10 + length], &record.rdata[..]);
+            offset = end + 10 + length;
+        }
+        assert_eq!(offset, packet.len());
+    }
+
+    it "should answer service browsing" {
+        let query = parse_message(&query_packet(b"\x07_foxbox\x04_tcp\x05local\x00", TYPE_PTR)).unwrap();
+        let (answers, additional) = advertisement.answer(&query.questions);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].record_type, TYPE_PTR);
+        let types: Vec<u16> = additional.iter().map(|record| record.record_type).collect();
+        assert_eq!(types, vec![TYPE_SRV, TYPE_TXT, TYPE_A]);
+    }
+
+    it "should enumerate the service types" {
+        let query = parse_message(&query_packet(b"\x09_services\x07_dns-sd\x04_udp\x05LOCAL\x00", TYPE_PTR)).unwrap();
+        let (answers, _) = advertisement.answer(&query.questions);
+        assert_eq!(answers.len(), 2);
+    }
+
+    it "should publish the txt entries" {
+        let record = advertisement.txt_record(&advertisement.services[1]);
+        assert_eq!(&record.rdata[..7], b"\x06api=v1");
+        assert!(record.unique);
+    }
+
+    it "should ignore other services" {
+        let query = parse_message(&query_packet(b"\x04_ipp\x04_tcp\x05local\x00", TYPE_PTR)).unwrap();
+        let (answers, _) = advertisement.answer(&query.questions);
+        assert!(answers.is_empty());
+    }
+
+    it "should escape the avahi service file" {
+        let mut advertisement = advertisement.clone();
+        advertisement.instance_name = "Bob's <box>".to_owned();
+        let file = avahi_service_file(&advertisement);
+        assert!(file.contains("<name>Bob&apos;s &lt;box&gt;</name>"));
+        assert!(file.contains("<type>_foxbox._tcp</type>"));
+        assert!(file.contains("<txt-record>fingerprint=abcd</txt-record>"));
+    }
+}
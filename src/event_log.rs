@@ -0,0 +1,124 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keeps the most recent events broadcast to the websockets, so that clients
+//! reconnecting with `?since=<seq>` can receive what they missed.
+//!
+//! Every event gets a `seq` field holding a monotonic sequence number. Sequence
+//! numbers start over at 1 when the box restarts, so a client asking for a
+//! sequence number we never issued is told that a gap occurred.
+
+use serde_json;
+use serde_json::value::Value;
+use std::collections::VecDeque;
+
+/// The events a reconnecting client needs to catch up.
+#[derive(Debug, PartialEq)]
+pub enum EventReplay {
+    /// Every event after the requested one is still available.
+    Complete(Vec<String>),
+    /// Some events were dropped from the buffer (or the box restarted).
+    /// `oldest` is the sequence number of the first available event.
+    Gap { oldest: u64, events: Vec<String> },
+}
+
+pub struct EventLog {
+    capacity: usize,
+    next_seq: u64,
+    events: VecDeque<(u64, String)>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            capacity: capacity,
+            next_seq: 1,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Stamps `event` with the next sequence number, records it and returns
+    /// its serialized form.
+    pub fn push(&mut self, mut event: Value) -> String {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if let Value::Object(ref mut map) = event {
+            map.insert("seq".to_owned(), Value::U64(seq));
+        }
+        let serialized = serde_json::to_string(&event).unwrap_or("{}".to_owned());
+
+        if self.capacity > 0 {
+            if self.events.len() == self.capacity {
+                self.events.pop_front();
+            }
+            self.events.push_back((seq, serialized.clone()));
+        }
+
+        serialized
+    }
+
+    /// Returns the events that were pushed after the event `since`.
+    pub fn since(&self, since: u64) -> EventReplay {
+        let events: Vec<String> = self.events.iter()
+                                             .filter(|&&(seq, _)| seq > since)
+                                             .map(|&(_, ref event)| event.clone())
+                                             .collect();
+
+        let oldest = match self.events.front() {
+            Some(&(seq, _)) => seq,
+            None => self.next_seq
+        };
+
+        if since >= self.next_seq || since + 1 < oldest {
+            EventReplay::Gap { oldest: oldest, events: events }
+        } else {
+            EventReplay::Complete(events)
+        }
+    }
+}
+
+#[cfg(test)]
+describe! event_log {
+    before_each {
+        use serde_json;
+
+        let mut log = EventLog::new(3);
+    }
+
+    it "should add increasing sequence numbers" {
+        assert_eq!(log.push(json_value!({ type: "a" })), r#"{"seq":1,"type":"a"}"#);
+        assert_eq!(log.push(json_value!({ type: "b" })), r#"{"seq":2,"type":"b"}"#);
+    }
+
+    it "should replay the events after the given one" {
+        log.push(json_value!({ type: "a" }));
+        log.push(json_value!({ type: "b" }));
+        assert_eq!(log.since(1), EventReplay::Complete(vec![r#"{"seq":2,"type":"b"}"#.to_owned()]));
+        assert_eq!(log.since(2), EventReplay::Complete(vec![]));
+    }
+
+    it "should report a gap when events were dropped" {
+        for _ in 0..5 {
+            log.push(json_value!({ type: "a" }));
+        }
+        match log.since(1) {
+            EventReplay::Gap { oldest, events } => {
+                assert_eq!(oldest, 3);
+                assert_eq!(events.len(), 3);
+            },
+            other => panic!("Expected a gap, got {:?}", other)
+        }
+        assert_eq!(log.since(2), EventReplay::Complete(vec![
+            r#"{"seq":3,"type":"a"}"#.to_owned(),
+            r#"{"seq":4,"type":"a"}"#.to_owned(),
+            r#"{"seq":5,"type":"a"}"#.to_owned()
+        ]));
+    }
+
+    it "should report a gap for unknown sequence numbers" {
+        log.push(json_value!({ type: "a" }));
+        assert_eq!(log.since(42), EventReplay::Gap { oldest: 1, events: vec![] });
+    }
+}
@@ -0,0 +1,260 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Time-limited guest access.
+//!
+//! Admins mint guest passes giving access to a fixed set of getters and
+//! setters during a validity window, without creating a user account. The
+//! token handed to the guest is signed with a secret generated by the box,
+//! and the passes are kept in `guest_tokens.sqlite` in the profile so that
+//! they can be listed and revoked. Expired passes are pruned automatically.
+
+extern crate crypto;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::{ Mac, MacResult };
+use self::crypto::sha2::Sha256;
+use rand::Rng;
+use rand::os::OsRng;
+use rusqlite::{ self, Connection };
+use rustc_serialize::base64::{ FromBase64, ToBase64, URL_SAFE };
+use rustc_serialize::hex::{ FromHex, ToHex };
+use serde_json;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// All guest tokens start with this prefix, which sets them apart from session tokens.
+pub const GUEST_TOKEN_PREFIX: &'static str = "fbg_";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GuestPass {
+    pub id: String,
+    pub name: String,
+    pub getters: Vec<String>,
+    pub setters: Vec<String>,
+    /// Seconds since the epoch.
+    pub not_before: i64,
+    pub expires: i64,
+}
+
+/// A newly created pass with its token, which can't be retrieved later on.
+#[derive(Debug, Serialize)]
+pub struct CreatedGuestPass {
+    pub token: String,
+    pub pass: GuestPass,
+}
+
+/// The body of a pass creation request.
+#[derive(Debug, Deserialize)]
+pub struct NewGuestPass {
+    pub name: String,
+    pub getters: Option<Vec<String>>,
+    pub setters: Option<Vec<String>>,
+    pub not_before: Option<i64>,
+    pub expires: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GuestTokenError {
+    Invalid,
+    NotYetValid,
+    Expired,
+    Revoked,
+}
+
+pub struct GuestTokens {
+    db: Mutex<Connection>,
+    secret: Vec<u8>,
+}
+
+impl GuestTokens {
+    /// Opens the database at `path` and creates it, along with the signing
+    /// secret, if not available yet.
+    pub fn new(path: &str) -> Self {
+        let db = Connection::open(path).unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS passes (
+                    id          TEXT NOT NULL PRIMARY KEY,
+                    name        TEXT NOT NULL,
+                    getters     TEXT NOT NULL,
+                    setters     TEXT NOT NULL,
+                    not_before  INTEGER NOT NULL,
+                    expires     INTEGER NOT NULL
+            )", &[]).unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS secret (value TEXT NOT NULL)", &[]).unwrap();
+
+        let stored: Option<String> = db.query_row("SELECT value FROM secret", &[], |row| row.get(0))
+                                       .ok();
+        let secret = match stored.and_then(|secret| secret.from_hex().ok()) {
+            Some(secret) => secret,
+            None => {
+                let mut secret = vec![0u8; 32];
+                OsRng::new().unwrap().fill_bytes(&mut secret);
+                db.execute("DELETE FROM secret", &[]).unwrap();
+                db.execute("INSERT INTO secret VALUES ($1)", &[&secret.to_hex()]).unwrap();
+                secret
+            }
+        };
+
+        GuestTokens {
+            db: Mutex::new(db),
+            secret: secret,
+        }
+    }
+
+    fn sign(&self, data: &str) -> MacResult {
+        let mut hmac = Hmac::new(Sha256::new(), &self.secret);
+        hmac.input(data.as_bytes());
+        hmac.result()
+    }
+
+    /// Creates a pass and returns the token to give to the guest.
+    pub fn create(&self, request: NewGuestPass, now: i64)
+        -> rusqlite::Result<(String, GuestPass)> {
+        let pass = GuestPass {
+            id: Uuid::new_v4().to_simple_string(),
+            name: request.name,
+            getters: request.getters.unwrap_or_else(Vec::new),
+            setters: request.setters.unwrap_or_else(Vec::new),
+            not_before: request.not_before.unwrap_or(now),
+            expires: request.expires,
+        };
+
+        let db = self.db.lock().unwrap();
+        try!(db.execute("INSERT INTO passes VALUES ($1, $2, $3, $4, $5, $6)",
+                        &[&pass.id, &pass.name,
+                          &serde_json::to_string(&pass.getters).unwrap(),
+                          &serde_json::to_string(&pass.setters).unwrap(),
+                          &pass.not_before, &pass.expires]));
+
+        let claims = format!("{}.{}", pass.id, pass.expires);
+        let signature = self.sign(&claims).code().to_base64(URL_SAFE);
+        Ok((format!("{}{}.{}", GUEST_TOKEN_PREFIX, claims, signature), pass))
+    }
+
+    /// Revokes a pass, returning false if there is no such pass.
+    pub fn revoke(&self, id: &str) -> rusqlite::Result<bool> {
+        let db = self.db.lock().unwrap();
+        let count = try!(db.execute("DELETE FROM passes WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+
+    /// Lists the passes after pruning the expired ones.
+    pub fn list(&self, now: i64) -> rusqlite::Result<Vec<GuestPass>> {
+        let db = self.db.lock().unwrap();
+        try!(db.execute("DELETE FROM passes WHERE expires <= $1", &[&now]));
+
+        let mut stmt = try!(db.prepare("SELECT id, name, getters, setters, not_before, expires
+                                        FROM passes ORDER BY not_before"));
+        let rows = try!(stmt.query(&[]));
+        let mut passes = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            passes.push(Self::pass_from_row(&row));
+        }
+        Ok(passes)
+    }
+
+    fn pass_from_row(row: &rusqlite::Row) -> GuestPass {
+        let getters: String = row.get(2);
+        let setters: String = row.get(3);
+        GuestPass {
+            id: row.get(0),
+            name: row.get(1),
+            getters: serde_json::from_str(&getters).unwrap_or_else(|_| Vec::new()),
+            setters: serde_json::from_str(&setters).unwrap_or_else(|_| Vec::new()),
+            not_before: row.get(4),
+            expires: row.get(5),
+        }
+    }
+
+    /// Checks the signature and validity window of `token`, and returns its pass.
+    pub fn verify(&self, token: &str, now: i64) -> Result<GuestPass, GuestTokenError> {
+        if !token.starts_with(GUEST_TOKEN_PREFIX) {
+            return Err(GuestTokenError::Invalid);
+        }
+        let token = &token[GUEST_TOKEN_PREFIX.len()..];
+
+        let (claims, signature) = match token.rfind('.') {
+            Some(index) => (&token[..index], &token[index + 1..]),
+            None => return Err(GuestTokenError::Invalid)
+        };
+        let signature = match signature.from_base64() {
+            Ok(signature) => signature,
+            Err(_) => return Err(GuestTokenError::Invalid)
+        };
+        // MacResult comparisons run in constant time.
+        if self.sign(claims) != MacResult::new(&signature) {
+            return Err(GuestTokenError::Invalid);
+        }
+
+        let id = claims.split('.').next().unwrap_or("");
+        let db = self.db.lock().unwrap();
+        let pass = match db.query_row("SELECT id, name, getters, setters, not_before, expires
+                                       FROM passes WHERE id=$1", &[&id],
+                                      |row| Self::pass_from_row(&row)) {
+            Ok(pass) => pass,
+            Err(_) => return Err(GuestTokenError::Revoked)
+        };
+
+        if pass.expires <= now {
+            return Err(GuestTokenError::Expired);
+        }
+        if pass.not_before > now {
+            return Err(GuestTokenError::NotYetValid);
+        }
+        Ok(pass)
+    }
+}
+
+#[cfg(test)]
+describe! guest_tokens {
+    before_each {
+        use std::fs;
+        use uuid::Uuid;
+
+        let db_file_name = format!("guest_tokens_test-{}.sqlite", Uuid::new_v4().to_simple_string());
+        let tokens = GuestTokens::new(&db_file_name);
+        let request = |not_before: Option<i64>, expires: i64| NewGuestPass {
+            name: "cleaner".to_owned(),
+            getters: None,
+            setters: Some(vec!["setter:door".to_owned()]),
+            not_before: not_before,
+            expires: expires,
+        };
+    }
+
+    after_each {
+        fs::remove_file(db_file_name).unwrap_or(());
+    }
+
+    it "should verify tokens within their validity window" {
+        let (token, pass) = tokens.create(request(Some(100), 200), 50).unwrap();
+        assert_eq!(tokens.verify(&token, 99), Err(GuestTokenError::NotYetValid));
+        assert_eq!(tokens.verify(&token, 100), Ok(pass));
+        assert_eq!(tokens.verify(&token, 200), Err(GuestTokenError::Expired));
+    }
+
+    it "should reject tampered tokens" {
+        let (token, _) = tokens.create(request(None, 200), 50).unwrap();
+        let tampered = token.replace(".200.", ".999.");
+        assert_eq!(tokens.verify(&tampered, 100), Err(GuestTokenError::Invalid));
+        assert_eq!(tokens.verify("fbg_nope", 100), Err(GuestTokenError::Invalid));
+    }
+
+    it "should keep the secret across restarts" {
+        let (token, _) = tokens.create(request(None, 200), 50).unwrap();
+        let reopened = GuestTokens::new(&db_file_name);
+        assert!(reopened.verify(&token, 100).is_ok());
+    }
+
+    it "should revoke and prune passes" {
+        let (token, pass) = tokens.create(request(None, 200), 50).unwrap();
+        tokens.create(request(None, 100), 50).unwrap();
+        assert_eq!(tokens.list(150).unwrap(), vec![pass.clone()]);
+
+        assert!(tokens.revoke(&pass.id).unwrap());
+        assert_eq!(tokens.verify(&token, 150), Err(GuestTokenError::Revoked));
+        assert_eq!(tokens.list(150).unwrap(), vec![]);
+    }
+}
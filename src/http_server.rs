@@ -9,16 +9,20 @@ use iron::{ AfterMiddleware, Chain, Handler,
             Response, ServerFactory };
 use iron_cors::CORS;
 use iron::error::{ IronError };
+use iron::headers::{ ContentType, Host, Location };
 use iron::method::Method;
 use iron::status::Status;
 use mount::Mount;
+use rate_limiter::RateLimit;
 use router::NoRoute;
+use security_headers::SecurityHeaders;
+use serde_json;
 use static_router;
-use std::net::SocketAddr;
+use std::net::{ SocketAddr, ToSocketAddrs };
 use std::sync::Arc;
 use std::thread;
 use taxonomy_router;
-use tls::SniServerFactory;
+use tls::{ CertificateManager, HttpChallenges, SniServerFactory };
 use traits::Controller;
 
 const THREAD_COUNT: usize = 8;
@@ -55,6 +59,86 @@ impl Handler for Ping {
     }
 }
 
+/// Serves the responses to the pending ACME http-01 challenges.
+struct AcmeChallenge {
+    challenges: HttpChallenges
+}
+
+impl Handler for AcmeChallenge {
+    fn handle (&self, req: &mut Request) -> IronResult<Response> {
+        match req.url.path.first().and_then(|token| self.challenges.get(token)) {
+            Some(key_authorization) => Ok(Response::with((Status::Ok, key_authorization))),
+            None => Ok(Response::with(Status::NotFound))
+        }
+    }
+}
+
+/// Redirects every request to the same resource over HTTPS. Only the names
+/// of the box are kept from the Host header, other hosts are redirected to
+/// the box's hostname so that the redirect can't be used to send clients
+/// elsewhere.
+struct HttpsRedirect {
+    hostname: String,
+    certificate_manager: CertificateManager,
+    port: u16
+}
+
+impl HttpsRedirect {
+    fn is_own_name(&self, hostname: &str) -> bool {
+        let hostname = hostname.to_lowercase();
+        hostname == self.hostname.to_lowercase() ||
+        self.certificate_manager.get_certificates().keys()
+                                .any(|name| name.to_lowercase() == hostname)
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle (&self, req: &mut Request) -> IronResult<Response> {
+        let hostname = match req.headers.get::<Host>() {
+            Some(host) if self.is_own_name(&host.hostname) => host.hostname.clone(),
+            _ => self.hostname.clone()
+        };
+        let mut location = if self.port == 443 {
+            format!("https://{}/{}", hostname, req.url.path.join("/"))
+        } else {
+            format!("https://{}:{}/{}", hostname, self.port, req.url.path.join("/"))
+        };
+        if let Some(ref query) = req.url.query {
+            location.push('?');
+            location.push_str(query);
+        }
+        // 308 keeps the method and body of the request, unlike 301.
+        let mut response = Response::with(Status::PermanentRedirect);
+        response.headers.set(Location(location));
+        Ok(response)
+    }
+}
+
+/// Reports runtime information useful for monitoring the box.
+struct BoxStatus<T: Controller> {
+    controller: T
+}
+
+impl<T: Controller> Handler for BoxStatus<T> {
+    fn handle (&self, _: &mut Request) -> IronResult<Response> {
+        let renewal = self.controller.get_certificate_manager().get_renewal_status();
+        let network = self.controller.get_network_status();
+        let tunnel = self.controller.get_tunnel_status();
+        let body = json!({
+            websockets: json_value!({ active: self.controller.websockets_count() }),
+            certificates: json_value!({ renewal: renewal, warning: renewal.is_failing() }),
+            network: json_value!({ status: network, warning: network.is_failing() }),
+            tunnel: json_value!({
+                status: tunnel,
+                warning: tunnel.as_ref().map_or(false, |tunnel| tunnel.is_failing())
+            })
+        });
+        let mut response = Response::with((Status::Ok, body));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+}
+
 pub struct HttpServer<T: Controller> {
     controller: T
 }
@@ -72,14 +156,25 @@ impl<T: Controller> HttpServer<T> {
         let mut mount = Mount::new();
         mount.mount("/", static_router::create(users_manager.clone()))
              .mount("/ping", Ping)
+             .mount("/status", BoxStatus { controller: self.controller.clone() })
+             .mount("/.well-known/acme-challenge", AcmeChallenge {
+                 challenges: self.controller.get_certificate_manager().get_http_challenges()
+             })
              .mount("/api/v1", taxonomy_chain)
              .mount("/users", users_manager.get_router_chain());
 
         let mut chain = Chain::new(mount);
+        let rate_limit = RateLimit::new(&self.controller.get_config(), users_manager.clone(),
+                                        self.controller.get_tunnel_clients());
+        chain.link_before(rate_limit.clone());
         chain.link_after(Custom404);
+        chain.link_after(rate_limit);
+        chain.link_after(SecurityHeaders::new(&self.controller.get_config(),
+                                              self.controller.get_tls_enabled()));
 
         let cors = CORS::new(vec![
             (vec![Method::Get], "ping".to_owned()),
+            (vec![Method::Get], "status".to_owned()),
             (vec![Method::Get, Method::Post, Method::Put, Method::Delete],
              "services/:service/:command".to_owned()),
             (vec![Method::Get], "services/list".to_owned()),
@@ -91,8 +186,15 @@ impl<T: Controller> HttpServer<T> {
             (vec![Method::Get, Method::Post], "api/v1/channels/setters".to_owned()),
             (vec![Method::Put], "api/v1/channels/get".to_owned()),
             (vec![Method::Put], "api/v1/channels/set".to_owned()),
-            (vec![Method::Post, Method::Delete], "api/v1/channel/getters/tags".to_owned()),
-            (vec![Method::Post, Method::Delete], "api/v1/channel/setters/tags".to_owned())
+            (vec![Method::Post, Method::Delete], "api/v1/channels/getter/tags".to_owned()),
+            (vec![Method::Post, Method::Delete], "api/v1/channels/setter/tags".to_owned()),
+            (vec![Method::Get], "api/v1/audit".to_owned()),
+            (vec![Method::Get, Method::Put, Method::Delete], "api/v1/permissions".to_owned()),
+            (vec![Method::Get, Method::Post, Method::Delete], "api/v1/keys".to_owned()),
+            (vec![Method::Get, Method::Post, Method::Delete], "api/v1/guests".to_owned()),
+            (vec![Method::Get, Method::Post, Method::Delete], "api/v1/client-certificates".to_owned()),
+            (vec![Method::Get, Method::Post], "api/v1/tunnel/credential".to_owned()),
+            (vec![Method::Get, Method::Post], "api/v1/upnp/devices".to_owned())
         ]);
         chain.link_after(cors);
 
@@ -100,12 +202,44 @@ impl<T: Controller> HttpServer<T> {
 
         if self.controller.get_tls_enabled() {
             let mut certificate_manager = self.controller.get_certificate_manager();
-            let server_factory = SniServerFactory::new(&mut certificate_manager);
+            let server_factory = SniServerFactory::new(&mut certificate_manager,
+                                                       self.controller.get_client_auth());
+            self.start_redirect_server(addrs[0].port());
             start_server(addrs, chain, server_factory);
         } else {
             start_server(addrs, chain, HttpServerFactory {});
         }
     }
+
+    /// Starts the plain HTTP server redirecting to HTTPS, if enabled. It also
+    /// serves the ACME http-01 challenges, which are always done over HTTP.
+    fn start_redirect_server(&self, https_port: u16) {
+        let config = self.controller.get_config();
+        if config.get_or_set_default("http", "redirect_enabled", "false") != "true" {
+            return;
+        }
+        let port: u16 = config.get_or_set_default_parsed("http", "redirect_port", "80");
+        let addrs: Vec<_> = match ("::", port).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(err) => {
+                error!("Unable to start the HTTPS redirect server: {}", err);
+                return;
+            }
+        };
+
+        let mut mount = Mount::new();
+        mount.mount("/", HttpsRedirect {
+                 hostname: self.controller.get_hostname(),
+                 certificate_manager: self.controller.get_certificate_manager(),
+                 port: https_port
+             })
+             .mount("/.well-known/acme-challenge", AcmeChallenge {
+                 challenges: self.controller.get_certificate_manager().get_http_challenges()
+             });
+
+        info!("Redirecting HTTP requests on port {} to HTTPS", port);
+        start_server(addrs, Chain::new(mount), HttpServerFactory {});
+    }
 }
 
 fn start_server<TListener, T>(addrs: Vec<SocketAddr>, chain: Chain, factory: T)
@@ -141,6 +275,135 @@ describe! ping {
     }
 }
 
+#[cfg(test)]
+describe! acme_challenge {
+    before_each {
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{ request, response };
+        use mount::Mount;
+        use tls::HttpChallenges;
+        use super::AcmeChallenge;
+
+        let challenges = HttpChallenges::new();
+        challenges.set("token", "token.thumbprint");
+        let mut mount = Mount::new();
+        mount.mount("/.well-known/acme-challenge", AcmeChallenge { challenges: challenges });
+    }
+
+    it "should serve the key authorization of pending challenges" {
+        let response = request::get("http://localhost:3000/.well-known/acme-challenge/token",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+        assert_eq!(response::extract_body_to_string(response), "token.thumbprint");
+    }
+
+    it "should respond with 404 to unknown tokens" {
+        let response = request::get("http://localhost:3000/.well-known/acme-challenge/other",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NotFound);
+    }
+}
+
+#[cfg(test)]
+describe! https_redirect {
+    before_each {
+        use iron::Headers;
+        use iron::headers::{ Host, Location };
+        use iron::status::Status;
+        use iron_test::request;
+        use tls::CertificateManager;
+        use super::HttpsRedirect;
+
+        let redirect = HttpsRedirect {
+            hostname: "foxbox.local".to_owned(),
+            certificate_manager: CertificateManager::new_for_test(),
+            port: 443
+        };
+    }
+
+    it "should redirect to the same resource over https" {
+        let response = request::get("http://foxbox.local/api/v1/services?q=1",
+                                    Headers::new(),
+                                    &redirect).unwrap();
+        assert_eq!(response.status.unwrap(), Status::PermanentRedirect);
+        assert_eq!(response.headers.get::<Location>().unwrap().0,
+                   "https://foxbox.local/api/v1/services?q=1");
+    }
+
+    it "should keep non default https ports" {
+        let redirect = HttpsRedirect { port: 3000, ..redirect };
+        let response = request::get("http://foxbox.local/", Headers::new(), &redirect).unwrap();
+        assert_eq!(response.headers.get::<Location>().unwrap().0, "https://foxbox.local:3000/");
+    }
+
+    it "should not redirect to other hosts" {
+        let mut headers = Headers::new();
+        headers.set(Host { hostname: "evil.example.com".to_owned(), port: None });
+        let response = request::get("http://evil.example.com/login", headers, &redirect).unwrap();
+        assert_eq!(response.headers.get::<Location>().unwrap().0, "https://foxbox.local/login");
+    }
+
+    it "should keep the names of the box certificates" {
+        use tls::CertificateRecord;
+        use std::path::PathBuf;
+
+        redirect.certificate_manager.add_certificate(CertificateRecord::new_for_test(
+            "remote.example.com".to_owned(),
+            PathBuf::from("/test/cert.pem"),
+            PathBuf::from("/test/privkey.pem"),
+            "010203040506070809000a0b0c0d0e0f".to_owned()
+        ).unwrap());
+        let mut headers = Headers::new();
+        headers.set(Host { hostname: "remote.example.com".to_owned(), port: None });
+        let response = request::get("http://remote.example.com/", headers, &redirect).unwrap();
+        assert_eq!(response.headers.get::<Location>().unwrap().0, "https://remote.example.com/");
+    }
+}
+
+#[cfg(test)]
+describe! box_status {
+    before_each {
+        use iron::Headers;
+        use iron_test::{ request, response };
+        use mount::Mount;
+        use serde_json;
+        use stubs::controller::ControllerStub;
+        use super::BoxStatus;
+
+        let mut mount = Mount::new();
+        mount.mount("/status", BoxStatus { controller: ControllerStub::new() });
+
+        let response = request::get("http://localhost:3000/status",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    }
+
+    it "should report the number of active websockets" {
+        assert_eq!(status.lookup("websockets.active").unwrap().as_u64(), Some(0));
+    }
+
+    it "should report the certificate renewal status" {
+        assert_eq!(status.lookup("certificates.warning").unwrap().as_boolean(), Some(false));
+        assert_eq!(status.lookup("certificates.renewal.failures").unwrap().as_u64(), Some(0));
+    }
+
+    it "should report the network status" {
+        assert_eq!(status.lookup("network.status.failures").unwrap().as_u64(), Some(0));
+        // The stub has no address.
+        assert_eq!(status.lookup("network.warning").unwrap().as_boolean(), Some(true));
+    }
+
+    it "should report a disabled tunnel" {
+        assert!(status.lookup("tunnel.status").unwrap().is_null());
+        assert_eq!(status.lookup("tunnel.warning").unwrap().as_boolean(), Some(false));
+    }
+}
+
 #[cfg(test)]
 describe! http_server {
     before_each {
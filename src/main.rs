@@ -21,6 +21,9 @@
 
 #![feature(associated_consts)]
 
+// For the resource limits of supervised processes
+#![feature(process_exec)]
+
 extern crate chrono;
 extern crate core;
 extern crate docopt;
@@ -73,19 +76,32 @@ extern crate tempdir;
 // Need to be declared first so to let the macros be visible from other modules.
 #[macro_use]
 mod utils;
+mod acl;
 mod adapters;
+mod api_keys;
+mod audit;
 mod config_store;
 mod controller;
+mod dns_sd;
+mod event_log;
+mod guest_tokens;
 mod http_server;
 mod managed_process;
+mod multicast;
+mod network_monitor;
 mod profile_service;
+mod rate_limiter;
 mod registration;
+mod security_headers;
 mod upnp;
+mod upnp_events;
 mod static_router;
 mod taxonomy_router;
 mod tls;
 mod traits;
 mod tunnel_controller;
+mod tunnel_credentials;
+mod tunnel_protocol;
 mod ws_server;
 
 #[cfg(test)]
@@ -106,7 +122,7 @@ use multicast_dns::host::HostManager;
 use profile_service::ProfilePath;
 use std::env;
 use std::sync::atomic::{ AtomicBool, Ordering, ATOMIC_BOOL_INIT };
-use tls::TlsOption;
+use tls::{ create_dns_provider, TlsOption };
 use traits::Controller;
 
 docopt!(Args derive Debug, "
@@ -121,7 +137,7 @@ Options:
     -r, --register <url>     Change the url of the registration endpoint. [default: http://knilxof.org:4242]
     -i, --iface <iface>      Specify the local IP interface.
     -t, --tunnel <tunnel>    Set the tunnel endpoint's hostname. If omitted, the tunnel is disabled.
-    -s, --tunnel-secret <secret>       Set the tunnel shared secret. [default: secret]
+    -s, --tunnel-secret <secret>       Deprecated, same as -c 'tunnel;pagekite_secret;<secret>'.
         --disable-tls                  Run as a plain HTTP server, disabling encryption.
         --dns-domain <domain>          Set the top level domain for public DNS [default: box.knilxof.org]
         --dns-api <url>                Set the DNS API endpoint [default: https://knilxof.org:5300]
@@ -135,7 +151,7 @@ Options:
         flag_register: String,
         flag_iface: Option<String>,
         flag_tunnel: Option<String>,
-        flag_tunnel_secret: String,
+        flag_tunnel_secret: Option<String>,
         flag_disable_tls: bool,
         flag_dns_domain: String,
         flag_dns_api: String,
@@ -247,6 +263,16 @@ fn main() {
             None => ProfilePath::Default
         });
 
+    // The deployments still giving the pagekite secret on the command line
+    // keep their pagekite tunnel, unless they chose a backend.
+    if let Some(ref secret) = args.flag_tunnel_secret {
+        warn!("--tunnel-secret is deprecated, use -c 'tunnel;pagekite_secret;<secret>'");
+        controller.config.set_override("tunnel", "pagekite_secret", secret);
+        if controller.config.get("tunnel", "backend").is_none() {
+            controller.config.set_override("tunnel", "backend", "pagekite");
+        }
+    }
+
     // Override config values
     {
         if let Some(flags) = args.flag_config {
@@ -278,23 +304,32 @@ fn main() {
     // by LetsEncrypt during the validation phase using a dns-01 challenge.
     // See: https://letsencrypt.github.io/acme-spec/#dns
     //
-    // Once the names have been created in the DNS server, a LetsEncrypt client will
-    // issue certificates for each name - the local name will be the common name of
-    // the certificate, and every other name will be a subject alternative name.
+    // Once the names have been created in the DNS server, the built-in ACME client
+    // will request certificates for each name - the local name will be the common
+    // name of the certificate, and every other name will be a subject alternative
+    // name. The ACME directory and challenge type are set in the `acme` section of
+    // the configuration.
+    //
+    // The records are published through the DNS API given with `--dns-api` by
+    // default, or with RFC 2136 dynamic updates to a self hosted DNS server
+    // (see the `dns` section of the configuration).
+    let dns_provider = create_dns_provider(&controller.config,
+                                           controller.get_certificate_manager(),
+                                           args.flag_dns_api)
+        .expect("Invalid DNS provider configuration");
     let registrar = registration::Registrar::new(controller.get_certificate_manager(),
                                                  args.flag_dns_domain,
                                                  args.flag_register,
-                                                 args.flag_dns_api);
+                                                 dns_provider);
 
     // Start the tunnel.
     let mut tunnel: Option<Tunnel> = None;
     if let Some(tunnel_url) = args.flag_tunnel {
         tunnel = Some(Tunnel::new(TunnelConfig::new(tunnel_url,
-                                                    args.flag_tunnel_secret,
                                                     args.flag_port,
                                                     args.flag_wsport,
                                                     registrar.get_remote_dns_name())));
-        tunnel.as_mut().unwrap().start().unwrap();
+        tunnel.as_mut().unwrap().start(&controller).unwrap();
     }
 
     registrar.start(args.flag_iface, &tunnel,
@@ -324,6 +359,7 @@ describe! main {
             assert_eq!(args.flag_dns_api, "https://knilxof.org:5300");
             assert_eq!(args.flag_iface, None);
             assert_eq!(args.flag_tunnel, None);
+            assert_eq!(args.flag_tunnel_secret, None);
             assert_eq!(args.flag_config, None);
             assert_eq!(args.flag_help, false);
         }
@@ -337,6 +373,7 @@ describe! main {
                                "-r", "http://foo.bar:6868/register",
                                "-i", "eth99",
                                "-t", "tunnel.host",
+                               "-s", "secret",
                                "-c", "ns;key;value"];
 
            let args: super::super::Args = super::super::Args::docopt().argv(argv().into_iter())
@@ -349,6 +386,7 @@ describe! main {
             assert_eq!(args.flag_register, "http://foo.bar:6868/register");
             assert_eq!(args.flag_iface.unwrap(), "eth99");
             assert_eq!(args.flag_tunnel.unwrap(), "tunnel.host");
+            assert_eq!(args.flag_tunnel_secret.unwrap(), "secret");
             assert_eq!(args.flag_config.unwrap(), vec!["ns;key;value"]);
         }
 
@@ -361,6 +399,7 @@ describe! main {
                                "--register", "http://foo.bar:6868/register",
                                "--iface", "eth99",
                                "--tunnel", "tunnel.host",
+                               "--tunnel-secret", "secret",
                                "--config", "ns;key;value"];
 
             let args: super::super::Args = super::super::Args::docopt().argv(argv().into_iter())
@@ -373,6 +412,7 @@ describe! main {
             assert_eq!(args.flag_register, "http://foo.bar:6868/register");
             assert_eq!(args.flag_iface.unwrap(), "eth99");
             assert_eq!(args.flag_tunnel.unwrap(), "tunnel.host");
+            assert_eq!(args.flag_tunnel_secret.unwrap(), "secret");
             assert_eq!(args.flag_config.unwrap(), vec!["ns;key;value"]);
         }
     }
@@ -2,149 +2,530 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-// Assumes Unix
-use libc::{self,  c_int};
+//! Supervision of the helper processes of the box.
+//!
+//! A `Supervisor` starts a process, captures its output into the foxbox log,
+//! and restarts it according to its `RestartPolicy`, backing off
+//! exponentially while it keeps failing. The bookkeeping lives in
+//! `RestartTracker`, so that tasks which aren't processes, like the tunnel,
+//! restart the same way and report the same `SupervisorStatus`.
 
+// Assumes Unix
+use config_store::ConfigService;
+use libc::{ self, c_int };
+use rand;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{ BufRead, BufReader, Error, ErrorKind, Read, Result };
+use std::os::unix::process::{ CommandExt, ExitStatusExt };
+use std::process::{ Command, Stdio };
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
 use std::thread;
 use std::thread::JoinHandle;
-use std::sync::{ Arc, Mutex };
-use std::process::Child;
-use std::io::{ Error, ErrorKind, Result };
-use std::time::{ SystemTime, Duration, UNIX_EPOCH };
+use std::time::{ Duration, Instant };
+use time;
 
 /// Unix exit statuses
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub struct ExitStatus(c_int);
 
-const RESTART_TIME_THRESHOLD: f64 = 5.0; // seconds
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RestartPolicy {
+    Always,
+    /// Restarts unless the process exited successfully.
+    OnFailure,
+    Never,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> ::std::result::Result<Self, String> {
+        match policy {
+            "always" => Ok(RestartPolicy::Always),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "never" => Ok(RestartPolicy::Never),
+            _ => Err(format!("Unknown restart policy {}", policy))
+        }
+    }
+}
+
+/// An exponential backoff, randomized so that boxes failing together don't
+/// retry together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    /// In seconds, doubled with each consecutive failure up to `max_delay`.
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    /// The largest random addition to the delay, as a fraction of it.
+    pub jitter: f64,
+}
+
+impl Backoff {
+    /// The delay after `failures` consecutive failures, before the jitter.
+    pub fn base_delay(&self, failures: u32) -> u64 {
+        let exponent = cmp::min(failures.saturating_sub(1), 20);
+        cmp::min(self.initial_delay << exponent, self.max_delay)
+    }
+
+    pub fn delay(&self, failures: u32) -> Duration {
+        let base = self.base_delay(failures) * 1000;
+        let jitter = (base as f64 * self.jitter * rand::random::<f64>()) as u64;
+        Duration::from_millis(base + jitter)
+    }
+}
+
+/// A limit applied to a supervised process with `setrlimit`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResourceLimit {
+    /// In bytes.
+    AddressSpace(u64),
+    /// In seconds.
+    CpuTime(u64),
+    OpenFiles(u64),
+    /// In bytes, 0 disables core dumps.
+    CoreSize(u64),
+}
+
+impl ResourceLimit {
+    /// Reads the limits set in the `namespace` of the configuration. There
+    /// are none by default.
+    pub fn from_config(config: &ConfigService, namespace: &str) -> Vec<ResourceLimit> {
+        let keys: [(&str, fn(u64) -> ResourceLimit); 4] = [
+            ("memory_limit_bytes", ResourceLimit::AddressSpace),
+            ("cpu_limit_seconds", ResourceLimit::CpuTime),
+            ("open_files_limit", ResourceLimit::OpenFiles),
+            ("core_size_limit_bytes", ResourceLimit::CoreSize),
+        ];
+        keys.iter().filter_map(|&(key, limit)| {
+            config.get(namespace, key).and_then(|value| match value.parse() {
+                Ok(value) => Some(limit(value)),
+                Err(_) => {
+                    warn!("Invalid value for {}::{}, ignoring it", namespace, key);
+                    None
+                }
+            })
+        }).collect()
+    }
+
+    fn apply(&self) -> Result<()> {
+        let (resource, value) = match *self {
+            ResourceLimit::AddressSpace(value) => (libc::RLIMIT_AS, value),
+            ResourceLimit::CpuTime(value) => (libc::RLIMIT_CPU, value),
+            ResourceLimit::OpenFiles(value) => (libc::RLIMIT_NOFILE, value),
+            ResourceLimit::CoreSize(value) => (libc::RLIMIT_CORE, value),
+        };
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        unsafe { try!(c_rv(libc::setrlimit(resource, &limit))); }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SupervisorSettings {
+    pub policy: RestartPolicy,
+    pub backoff: Backoff,
+    /// Gives up after that many consecutive failures, 0 to never give up.
+    pub max_restarts: u32,
+    /// Failing `crash_loop_restarts` times within `crash_loop_window`
+    /// seconds is a crash loop, restarted after the longest delay only.
+    pub crash_loop_restarts: u32,
+    pub crash_loop_window: u64,
+    /// A process running for longer than that, in seconds, is healthy again.
+    pub stable_after: u64,
+    pub limits: Vec<ResourceLimit>,
+}
+
+impl SupervisorSettings {
+    /// Reads the settings from the `namespace` of the configuration.
+    pub fn from_config(config: &ConfigService, namespace: &str, default_policy: RestartPolicy) -> Self {
+        let default_policy_name = match default_policy {
+            RestartPolicy::Always => "always",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Never => "never",
+        };
+
+        SupervisorSettings {
+            policy: config.get_or_set_default_parsed(namespace, "restart_policy", default_policy_name),
+            backoff: Backoff {
+                initial_delay: config.get_or_set_default_parsed(namespace, "retry_delay_seconds", "1"),
+                max_delay: config.get_or_set_default_parsed(namespace, "max_retry_delay_seconds", "300"),
+                jitter: config.get_or_set_default_parsed(namespace, "retry_jitter", "0.2"),
+            },
+            max_restarts: config.get_or_set_default_parsed(namespace, "max_restarts", "0"),
+            crash_loop_restarts: config.get_or_set_default_parsed(namespace, "crash_loop_restarts", "5"),
+            crash_loop_window: config.get_or_set_default_parsed(namespace, "crash_loop_seconds", "60"),
+            stable_after: config.get_or_set_default_parsed(namespace, "stable_seconds", "10"),
+            limits: ResourceLimit::from_config(config, namespace),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SupervisorState {
+    Running,
+    /// Waiting before restarting.
+    Backoff,
+    /// Ended, and not restarted by the policy.
+    Exited,
+    /// Gave up restarting.
+    Failed,
+    Stopped,
+}
+
+impl fmt::Display for SupervisorState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SupervisorState::Running => "running",
+            SupervisorState::Backoff => "backoff",
+            SupervisorState::Exited => "exited",
+            SupervisorState::Failed => "failed",
+            SupervisorState::Stopped => "stopped",
+        })
+    }
+}
+
+/// The state of a supervised process or task, exposed for health checks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SupervisorStatus {
+    pub state: String,
+    pub pid: Option<u32>,
+    /// Seconds since the epoch.
+    pub running_since: Option<i64>,
+    pub next_restart: Option<i64>,
+    pub restarts: u32,
+    /// The number of consecutive failures.
+    pub failures: u32,
+    pub crash_loop: bool,
+    pub last_exit: Option<String>,
+    pub last_error: Option<String>,
+}
+
+impl SupervisorStatus {
+    pub fn is_failing(&self) -> bool {
+        self.failures > 0
+    }
+
+    fn set_state(&mut self, state: SupervisorState) {
+        self.state = state.to_string();
+    }
+}
+
+/// Decides when a task is restarted, and keeps its `SupervisorStatus`.
+pub struct RestartTracker {
+    settings: SupervisorSettings,
+    recent_failures: VecDeque<Instant>,
+}
+
+impl RestartTracker {
+    pub fn new(settings: SupervisorSettings) -> Self {
+        RestartTracker {
+            settings: settings,
+            recent_failures: VecDeque::new(),
+        }
+    }
+
+    pub fn started(&self, status: &mut SupervisorStatus, pid: Option<u32>) {
+        status.set_state(SupervisorState::Running);
+        status.pid = pid;
+        status.running_since = Some(time::get_time().sec);
+        status.next_restart = None;
+    }
+
+    /// Records that the task works, which resets the backoff.
+    pub fn stable(&mut self, status: &mut SupervisorStatus) {
+        status.failures = 0;
+        status.crash_loop = false;
+        status.last_error = None;
+        self.recent_failures.clear();
+    }
+
+    /// Records the end of the task, with the error if it failed. Returns the
+    /// delay before restarting it, or `None` if it isn't restarted.
+    pub fn exited(&mut self, status: &mut SupervisorStatus, result: ::std::result::Result<(), String>)
+                  -> Option<Duration> {
+        status.pid = None;
+        status.running_since = None;
+
+        let restart = match result {
+            Ok(_) => {
+                status.last_exit = Some("success".to_owned());
+                self.settings.policy == RestartPolicy::Always
+            },
+            Err(err) => {
+                status.last_exit = Some(err.clone());
+                status.last_error = Some(err);
+                status.failures += 1;
+                self.record_failure(status);
+                self.settings.policy != RestartPolicy::Never
+            }
+        };
+
+        if !restart {
+            status.set_state(if status.failures > 0 { SupervisorState::Failed } else { SupervisorState::Exited });
+            return None;
+        }
+        if self.settings.max_restarts > 0 && status.failures > self.settings.max_restarts {
+            error!("Giving up after {} consecutive failures", status.failures);
+            status.set_state(SupervisorState::Failed);
+            return None;
+        }
+
+        let delay = if status.crash_loop {
+            Duration::from_secs(self.settings.backoff.max_delay)
+        } else {
+            self.settings.backoff.delay(status.failures)
+        };
+        status.restarts += 1;
+        status.set_state(SupervisorState::Backoff);
+        status.next_restart = Some(time::get_time().sec + delay.as_secs() as i64);
+        Some(delay)
+    }
+
+    pub fn stopped(&self, status: &mut SupervisorStatus) {
+        status.set_state(SupervisorState::Stopped);
+        status.pid = None;
+        status.running_since = None;
+        status.next_restart = None;
+    }
 
-fn seconds_since_epoch() -> f64 {
-    let now = SystemTime::now();
-    now.duration_since(UNIX_EPOCH).unwrap().as_secs() as f64
+    fn record_failure(&mut self, status: &mut SupervisorStatus) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.settings.crash_loop_window);
+        self.recent_failures.push_back(now);
+        while self.recent_failures.front().map_or(false, |failure| now.duration_since(*failure) > window) {
+            self.recent_failures.pop_front();
+        }
+        let threshold = self.settings.crash_loop_restarts as usize;
+        if threshold > 0 && self.recent_failures.len() >= threshold && !status.crash_loop {
+            error!("Crash loop detected: {} failures within {}s", self.recent_failures.len(),
+                   self.settings.crash_loop_window);
+            status.crash_loop = true;
+        }
+    }
 }
 
-pub struct ManagedProcess {
-    kill_signal: Arc<Mutex<u32>>,
-    pid:         Arc<Mutex<Option<u32>>>,
-    thread:      JoinHandle<()>
+/// Logs each line of `output` with the tag of its process.
+fn capture_output<R: Read + Send + 'static>(tag: String, output: R, is_error: bool) -> Result<()> {
+    try!(thread::Builder::new().name(format!("{}Output", tag)).spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(ref line) if is_error => warn!("[{}] {}", tag, line),
+                Ok(ref line) => info!("[{}] {}", tag, line),
+                Err(_) => break
+            }
+        }
+    }));
+    Ok(())
 }
 
-impl ManagedProcess {
+fn describe_exit(status: ::std::process::ExitStatus) -> ::std::result::Result<(), String> {
+    if status.success() {
+        return Ok(());
+    }
+    match (status.code(), status.signal()) {
+        (Some(code), _) => Err(format!("exited with status {}", code)),
+        (None, Some(signal)) => Err(format!("killed by signal {}", signal)),
+        (None, None) => Err("exited".to_owned())
+    }
+}
 
-    /// Create a new ManagedProcess and start it.
+fn sleep_unless_stopped(delay: Duration, stop_signal: &Mutex<bool>) {
+    let wake_at = Instant::now() + delay;
+    while Instant::now() < wake_at && !*stop_signal.lock().unwrap() {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+pub struct Supervisor {
+    name: String,
+    // Locked along with `pid` while starting the process, so that a
+    // shutdown can't miss it.
+    stop_signal: Arc<Mutex<bool>>,
+    pid: Arc<Mutex<Option<u32>>>,
+    status: Arc<Mutex<SupervisorStatus>>,
+    thread: JoinHandle<()>
+}
+
+impl Supervisor {
+
+    /// Start the process made by `command` and supervise it. Its output is
+    /// logged with `name` as tag.
     ///
     /// # Examples
     ///
     /// ```
-    /// use tunnel_controller::ManagedProcess;
+    /// use managed_process::{ RestartPolicy, Supervisor, SupervisorSettings };
     /// use std::process::Command;
     ///
-    /// let process = ManagedProcess::start(|| {
-    ///     Command::new("echo")
-    ///             .arg("Hello")
-    ///             .arg("World")
-    ///             .spawn()
+    /// let settings = SupervisorSettings::from_config(&config, "helper", RestartPolicy::OnFailure);
+    /// let supervisor = Supervisor::start("Helper", settings, || {
+    ///     let mut command = Command::new("echo");
+    ///     command.arg("Hello").arg("World");
+    ///     command
     /// });
     ///
     /// ```
-    pub fn start<F: 'static>(spawn: F) -> Result<ManagedProcess>
-        where F: Fn() -> Result<Child> + Send {
+    pub fn start<F: 'static>(name: &str, settings: SupervisorSettings, command: F) -> Result<Supervisor>
+        where F: Fn() -> Command + Send {
 
         let pid = Arc::new(Mutex::new(None));
+        let stop_signal = Arc::new(Mutex::new(false));
+        let status = Arc::new(Mutex::new(SupervisorStatus::default()));
 
-        // Uses a u32 Mutex to avoid the compiler complaining that you can use an AtomicBool.
-        // In this case we want a bool like thing _and_ a lock.
-        let kill_signal = Arc::new(Mutex::new(0));
-
-        let shared_kill_signal  = kill_signal.clone();
+        let shared_stop_signal = stop_signal.clone();
         let shared_pid = pid.clone();
+        let shared_status = status.clone();
+        let tag = name.to_owned();
 
-        let thread = thread::spawn(move || {
-            // Artificial start/end time for first run
-            let mut start_time = RESTART_TIME_THRESHOLD as f64;
-            let mut end_time = 0 as f64;
-            let mut backoff = 0;
-            let mut starts = 0;
+        let thread = try!(thread::Builder::new().name(format!("{}Supervisor", name)).spawn(move || {
+            let stable_after = Duration::from_secs(settings.stable_after);
+            let limits = settings.limits.clone();
+            let mut tracker = RestartTracker::new(settings);
 
             loop {
                 let mut child_process;
+                let start_time;
 
                 {
-                    let kill_signal = shared_kill_signal.lock().unwrap();
+                    let stop_signal = shared_stop_signal.lock().unwrap();
                     let mut pid = shared_pid.lock().unwrap();
 
-                    if *kill_signal == 1 {
+                    if *stop_signal {
                         *pid = None;
                         debug!("Received process kill signal");
                         break;
                     }
 
-                    if (end_time - start_time) < RESTART_TIME_THRESHOLD {
-                       backoff += 1;
-                       let backoff_seconds = (backoff * backoff) / 2;
-                       info!("Backing off creating a new process for {} seconds", backoff_seconds);
-                       thread::sleep(Duration::new(backoff_seconds, 0));
-                    } else {
-                        backoff = 0;
-                    }
-
-                    info!("Starting process. Restarted {} times", starts);
-                    start_time = seconds_since_epoch();
-                    child_process = spawn().unwrap();
+                    let mut command = command();
+                    let limits = limits.clone();
+                    command.stdout(Stdio::piped())
+                           .stderr(Stdio::piped())
+                           .before_exec(move || {
+                               for limit in &limits {
+                                   try!(limit.apply());
+                               }
+                               Ok(())
+                           });
+
+                    let restarts = shared_status.lock().unwrap().restarts;
+                    info!("Starting {}. Restarted {} times", tag, restarts);
+                    let result = command.spawn().and_then(|mut child| {
+                        if let Some(stdout) = child.stdout.take() {
+                            try!(capture_output(tag.clone(), stdout, false));
+                        }
+                        if let Some(stderr) = child.stderr.take() {
+                            try!(capture_output(tag.clone(), stderr, true));
+                        }
+                        Ok(child)
+                    });
+                    start_time = Instant::now();
+                    child_process = match result {
+                        Ok(child) => child,
+                        Err(err) => {
+                            error!("Unable to start {}: {}", tag, err);
+                            let delay = tracker.exited(&mut shared_status.lock().unwrap(),
+                                                       Err(err.to_string()));
+                            drop(pid);
+                            drop(stop_signal);
+                            match delay {
+                                Some(delay) => {
+                                    sleep_unless_stopped(delay, &shared_stop_signal);
+                                    continue;
+                                },
+                                None => return
+                            }
+                        }
+                    };
                     *pid = Some(child_process.id());
+                    tracker.started(&mut shared_status.lock().unwrap(), Some(child_process.id()));
                 }
 
-                starts += 1;
-
-                info!("Started managed process pid: {}", child_process.id());
-                child_process.wait().unwrap();
-                end_time = seconds_since_epoch();
+                info!("Started {} pid: {}", tag, child_process.id());
+                // Fails when the shutdown reaped the process first.
+                let result = match child_process.wait() {
+                    Ok(exit_status) => describe_exit(exit_status),
+                    Err(err) => Err(err.to_string())
+                };
+                *shared_pid.lock().unwrap() = None;
+
+                let mut status = shared_status.lock().unwrap();
+                if *shared_stop_signal.lock().unwrap() {
+                    tracker.stopped(&mut status);
+                    break;
+                }
+                if start_time.elapsed() >= stable_after {
+                    tracker.stable(&mut status);
+                }
+                if let Err(ref err) = result {
+                    warn!("{} {}", tag, err);
+                }
+                let delay = tracker.exited(&mut status, result);
+                drop(status);
+                match delay {
+                    Some(delay) => {
+                        info!("Restarting {} in {}s", tag, delay.as_secs());
+                        sleep_unless_stopped(delay, &shared_stop_signal);
+                    },
+                    None => break
+                }
             }
-        });
+        }));
 
-        Ok(ManagedProcess {
-            kill_signal: kill_signal,
-            pid:  pid,
+        Ok(Supervisor {
+            name: name.to_owned(),
+            stop_signal: stop_signal,
+            pid: pid,
+            status: status,
             thread: thread
         })
     }
 
+    pub fn status(&self) -> SupervisorStatus {
+        self.status.lock().unwrap().clone()
+    }
+
     /// Get the current process ID or None if no process is running
     fn get_pid(&self) -> Option<u32> {
         *self.pid.lock().unwrap()
     }
 
-    /// Shut the ManagedProcess down safely. Equivalent to sending SIGKILL to the
+    /// Shut the Supervisor down safely. Equivalent to sending SIGKILL to the
     /// running process if it is currently alive
     ///
     /// # Examples
     ///
     /// ```
-    /// use tunnel_controller::ManagedProcess;
+    /// use managed_process::{ RestartPolicy, Supervisor, SupervisorSettings };
     /// use std::process::Command;
     ///
-    /// let process = ManagedProcess::start(|| {
-    ///     Command::new("sleep")
-    ///             .arg("10000")
-    ///             .spawn()
-    /// });
+    /// let settings = SupervisorSettings::from_config(&config, "helper", RestartPolicy::Always);
+    /// let supervisor = Supervisor::start("Helper", settings, || {
+    ///     let mut command = Command::new("sleep");
+    ///     command.arg("10000");
+    ///     command
+    /// }).unwrap();
     ///
-    /// process.shutdown().unwrap();
+    /// supervisor.shutdown().unwrap();
     ///
     /// ```
     pub fn shutdown(self) -> Result<()> {
 
         {
-            let mut kill_signal = self.kill_signal.lock().unwrap();
-            *kill_signal = 1;
+            let mut stop_signal = self.stop_signal.lock().unwrap();
+            *stop_signal = true;
         }
 
         // If there is no assigned pid, the process is not running.
         let pid = self.get_pid();
 
         if pid.is_none() {
-            self.join_thread();
-            return Ok(());
+            return self.join_thread();
         }
 
         let pid = pid.unwrap() as i32;
@@ -156,20 +537,27 @@ impl ManagedProcess {
 
         if status.is_some() {
             // Process is already exited
-            self.join_thread();
-            return Ok(());
+            return self.join_thread();
         }
 
-        debug!("Sending SIGKILL to pid: {}", pid);
+        debug!("Sending SIGKILL to {} pid: {}", self.name, pid);
         unsafe { try!(c_rv(libc::kill(pid, libc::SIGKILL))); }
 
-        self.join_thread();
-        Ok(())
+        self.join_thread()
     }
 
     /// Wait for the thread to exit
-    fn join_thread(self) -> () {
-        self.thread.join().unwrap();
+    fn join_thread(self) -> Result<()> {
+        let name = self.name;
+        let result = self.thread.join();
+        // Also records a shutdown while backing off.
+        let mut status = self.status.lock().unwrap();
+        if status.state == SupervisorState::Backoff.to_string() ||
+           status.state == SupervisorState::Running.to_string() {
+            status.set_state(SupervisorState::Stopped);
+            status.next_restart = None;
+        }
+        result.map_err(|_| Error::new(ErrorKind::Other, format!("The supervisor of {} panicked", name)))
     }
 }
 
@@ -184,6 +572,8 @@ fn try_wait(id: i32) -> Option<ExitStatus> {
         Ok(0)  => None,
         Ok(n) if n == id => Some(ExitStatus(status)),
         Ok(n)  => panic!("Unknown pid: {}", n),
+        // Reaped by the supervisor thread already.
+        Err(ref e) if e.raw_os_error() == Some(libc::ECHILD) => Some(ExitStatus(0)),
         Err(e) => panic!("Unknown waitpid error: {}", e)
     }
 }
@@ -211,21 +601,37 @@ fn c_rv_retry<F>(mut f: F) -> Result<c_int>
     }
 }
 
+#[cfg(test)]
+fn test_settings(policy: RestartPolicy) -> SupervisorSettings {
+    SupervisorSettings {
+        policy: policy,
+        backoff: Backoff {
+            initial_delay: 0,
+            max_delay: 0,
+            jitter: 0.0,
+        },
+        max_restarts: 0,
+        crash_loop_restarts: 0,
+        crash_loop_window: 60,
+        stable_after: 10,
+        limits: vec![],
+    }
+}
+
 // TODO: Reactivate this test on mac os in https://github.com/fxbox/foxbox/issues/354
 #[test]
 #[cfg_attr(target_os = "macos", ignore)]
 fn test_managed_process_restart() {
     use std::sync::mpsc::channel;
-    use std::process::Command;
 
     let (counter_tx, counter_rx) = channel();
 
-    let process = ManagedProcess::start(move || {
+    let process = Supervisor::start("Sleep", test_settings(RestartPolicy::Always), move || {
         counter_tx.send(1).unwrap();
 
-        Command::new("sleep")
-                .arg("0")
-                .spawn()
+        let mut command = Command::new("sleep");
+        command.arg("0");
+        command
     }).unwrap();
 
     let mut count = 0;
@@ -241,14 +647,107 @@ fn test_managed_process_restart() {
 
 #[test]
 fn test_managed_process_shutdown() {
-    use std::process::Command;
     // Ideally need a timeout. The test should be, if shutdown doesn't happen immediately,
     // something's broken.
-    let process = ManagedProcess::start(|| {
-        Command::new("sleep")
-                .arg("1000")
-                .spawn()
+    let process = Supervisor::start("Sleep", test_settings(RestartPolicy::Always), || {
+        let mut command = Command::new("sleep");
+        command.arg("1000");
+        command
     }).unwrap();
 
     process.shutdown().unwrap();
 }
+
+#[test]
+#[cfg_attr(target_os = "macos", ignore)]
+fn test_managed_process_policy() {
+    let process = Supervisor::start("False", test_settings(RestartPolicy::Never), || {
+        Command::new("false")
+    }).unwrap();
+
+    while process.status().state != "failed" {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let status = process.status();
+    assert_eq!(status.failures, 1);
+    assert_eq!(status.restarts, 0);
+    assert_eq!(status.last_error, Some("exited with status 1".to_owned()));
+    process.shutdown().unwrap();
+}
+
+#[test]
+fn test_resource_limits() {
+    use std::fs;
+    use uuid::Uuid;
+
+    let config_file_name = format!("conftest-{}.tmp", Uuid::new_v4().to_simple_string());
+    let config = ConfigService::new(&config_file_name);
+    config.set("helper", "open_files_limit", "64");
+    config.set("helper", "core_size_limit_bytes", "none");
+    let settings = SupervisorSettings::from_config(&config, "helper", RestartPolicy::Never);
+    assert_eq!(settings.limits, vec![ResourceLimit::OpenFiles(64)]);
+    fs::remove_file(config_file_name).unwrap_or(());
+
+    let process = Supervisor::start("Limited", settings, || {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("test $(ulimit -n) = 64");
+        command
+    }).unwrap();
+    while process.status().state != "exited" && process.status().state != "failed" {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(process.status().last_error, None);
+    process.shutdown().unwrap();
+}
+
+#[test]
+fn test_backoff() {
+    let backoff = Backoff {
+        initial_delay: 1,
+        max_delay: 300,
+        jitter: 0.5,
+    };
+    assert_eq!(backoff.base_delay(1), 1);
+    assert_eq!(backoff.base_delay(4), 8);
+    assert_eq!(backoff.base_delay(40), 300);
+
+    for _ in 0..10 {
+        let delay = backoff.delay(4);
+        assert!(delay >= Duration::from_secs(8) && delay <= Duration::from_secs(12));
+    }
+}
+
+#[test]
+fn test_restart_tracker() {
+    let mut settings = test_settings(RestartPolicy::OnFailure);
+    settings.crash_loop_restarts = 3;
+    settings.max_restarts = 4;
+    let mut tracker = RestartTracker::new(settings);
+    let mut status = SupervisorStatus::default();
+
+    tracker.started(&mut status, Some(42));
+    assert_eq!(status.state, "running");
+    assert!(tracker.exited(&mut status, Err("exited with status 1".to_owned())).is_some());
+    assert_eq!(status.state, "backoff");
+    assert_eq!(status.pid, None);
+
+    tracker.exited(&mut status, Err("crashed".to_owned()));
+    assert!(!status.crash_loop);
+    tracker.exited(&mut status, Err("crashed".to_owned()));
+    assert!(status.crash_loop);
+    assert_eq!(status.restarts, 3);
+
+    tracker.stable(&mut status);
+    assert!(!status.is_failing());
+    assert!(!status.crash_loop);
+
+    for _ in 0..4 {
+        assert!(tracker.exited(&mut status, Err("crashed".to_owned())).is_some());
+    }
+    assert_eq!(tracker.exited(&mut status, Err("crashed".to_owned())), None);
+    assert_eq!(status.state, "failed");
+
+    tracker.stable(&mut status);
+    assert_eq!(tracker.exited(&mut status, Ok(())), None);
+    assert_eq!(status.state, "exited");
+}
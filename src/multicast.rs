@@ -0,0 +1,69 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! UDP sockets listening to a multicast group.
+//!
+//! Discovery protocols listen on well known ports that other daemons (eg.
+//! avahi, minissdpd) usually listen on too, so the sockets are bound with
+//! `SO_REUSEADDR` and `SO_REUSEPORT`, which the standard library can't do.
+
+use libc;
+use std::io;
+use std::mem;
+use std::net::{ Ipv4Addr, UdpSocket };
+use std::os::unix::io::FromRawFd;
+
+fn set_option(fd: libc::c_int, option: libc::c_int) -> io::Result<()> {
+    let one: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, option, &one as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Binds a socket shared with the other processes on `port`, and joins the
+/// multicast `group` on the default interface.
+pub fn bind_multicast(group: Ipv4Addr, port: u16) -> io::Result<UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owns the descriptor from now on, closing it on errors.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    try!(set_option(fd, libc::SO_REUSEADDR));
+    try!(set_option(fd, libc::SO_REUSEPORT));
+
+    let mut address: libc::sockaddr_in = unsafe { mem::zeroed() };
+    address.sin_family = libc::AF_INET as libc::sa_family_t;
+    address.sin_port = port.to_be();
+    let result = unsafe {
+        libc::bind(fd, &address as *const libc::sockaddr_in as *const libc::sockaddr,
+                   mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    try!(socket.join_multicast_v4(&group, &Ipv4Addr::new(0, 0, 0, 0)));
+    try!(socket.set_multicast_loop_v4(true));
+    Ok(socket)
+}
+
+#[cfg(test)]
+describe! multicast {
+    it "should share the port with other sockets" {
+        use std::net::Ipv4Addr;
+
+        let group = Ipv4Addr::new(239, 255, 42, 42);
+        let first = bind_multicast(group, 42424).unwrap();
+        let second = bind_multicast(group, 42424).unwrap();
+        assert_eq!(first.local_addr().unwrap().port(), 42424);
+        assert_eq!(second.local_addr().unwrap().port(), 42424);
+    }
+}
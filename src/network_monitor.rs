@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Detection of changes to the network configuration of the box.
+//!
+//! On Linux, the monitor listens to the address and link notifications of a
+//! netlink socket. Elsewhere, or when netlink is not available, it simply
+//! wakes up periodically. Either way, it only tells when the addresses may
+//! have changed: it's up to the caller to compare them with the ones it knows.
+
+use std::thread;
+use std::time::Duration;
+
+/// The state of the network as seen by the registrar, exposed for health
+/// checks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NetworkStatus {
+    /// The preferred address.
+    pub ip_addr: Option<String>,
+    pub ip_addrs: Vec<String>,
+    /// Seconds since the epoch.
+    pub last_change: Option<i64>,
+    pub last_registration: Option<i64>,
+    pub next_registration: Option<i64>,
+    /// The number of consecutive failed registrations.
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl NetworkStatus {
+    pub fn is_failing(&self) -> bool {
+        self.failures > 0 || self.ip_addr.is_none()
+    }
+}
+
+pub struct NetworkMonitor {
+    netlink: Option<netlink::NetlinkSocket>,
+    poll_interval: Duration,
+}
+
+impl NetworkMonitor {
+    /// Creates a monitor, which checks the network every `poll_interval`
+    /// when netlink can't be used.
+    pub fn new(poll_interval: Duration) -> Self {
+        NetworkMonitor {
+            netlink: match netlink::NetlinkSocket::new() {
+                Ok(socket) => Some(socket),
+                Err(err) => {
+                    warn!("Unable to monitor the network with netlink ({}), polling it instead", err);
+                    None
+                }
+            },
+            poll_interval: poll_interval,
+        }
+    }
+
+    /// Waits for at most `timeout`, returning early with `true` if the
+    /// addresses may have changed.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        let result = match self.netlink {
+            Some(ref socket) => socket.wait(timeout),
+            None => return self.poll(timeout)
+        };
+        match result {
+            Ok(changed) => changed,
+            Err(err) => {
+                warn!("Unable to monitor the network with netlink ({}), polling it instead", err);
+                self.netlink = None;
+                self.poll(timeout)
+            }
+        }
+    }
+
+    fn poll(&self, timeout: Duration) -> bool {
+        if timeout < self.poll_interval {
+            thread::sleep(timeout);
+            false
+        } else {
+            thread::sleep(self.poll_interval);
+            true
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    extern crate libc;
+
+    use std::io;
+    use std::mem;
+    use std::time::Duration;
+
+    const AF_NETLINK: libc::c_int = 16;
+    const NETLINK_ROUTE: libc::c_int = 0;
+    const RTMGRP_LINK: u32 = 0x001;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x010;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+    #[repr(C)]
+    struct SockaddrNl {
+        nl_family: libc::sa_family_t,
+        nl_pad: libc::c_ushort,
+        nl_pid: u32,
+        nl_groups: u32,
+    }
+
+    pub struct NetlinkSocket {
+        fd: libc::c_int,
+    }
+
+    impl NetlinkSocket {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::socket(AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, NETLINK_ROUTE) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Closes the socket if binding fails.
+            let socket = NetlinkSocket { fd: fd };
+
+            let address = SockaddrNl {
+                nl_family: AF_NETLINK as libc::sa_family_t,
+                nl_pad: 0,
+                nl_pid: 0,
+                nl_groups: RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR,
+            };
+            let result = unsafe {
+                libc::bind(fd, &address as *const SockaddrNl as *const libc::sockaddr,
+                           mem::size_of::<SockaddrNl>() as libc::socklen_t)
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+
+        /// Waits for notifications for at most `timeout`, and returns whether
+        /// there were any. The notifications themselves are discarded.
+        pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+            let timeout = (timeout.as_secs() * 1000) as libc::c_int +
+                          (timeout.subsec_nanos() / 1_000_000) as libc::c_int;
+            let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                return if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) };
+            }
+            if ready == 0 {
+                return Ok(false);
+            }
+
+            let mut buffer = vec![0u8; 8192];
+            loop {
+                let size = unsafe {
+                    libc::recv(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(),
+                               libc::MSG_DONTWAIT)
+                };
+                if size == 0 {
+                    return Ok(true);
+                }
+                if size < 0 {
+                    let err = io::Error::last_os_error();
+                    // The kernel drops notifications when the socket buffer
+                    // is full, which only means that something changed.
+                    return match err.raw_os_error() {
+                        Some(libc::EAGAIN) | Some(libc::ENOBUFS) => Ok(true),
+                        _ => Err(err)
+                    };
+                }
+            }
+        }
+    }
+
+    impl Drop for NetlinkSocket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod netlink {
+    use std::io;
+    use std::time::Duration;
+
+    pub struct NetlinkSocket;
+
+    impl NetlinkSocket {
+        pub fn new() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Other, "netlink is only available on Linux"))
+        }
+
+        pub fn wait(&self, _: Duration) -> io::Result<bool> {
+            unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+describe! network_monitor {
+    it "should report missing addresses and failures" {
+        let mut status = NetworkStatus::default();
+        assert!(status.is_failing());
+        status.ip_addr = Some("192.168.0.4".to_owned());
+        assert!(!status.is_failing());
+        status.failures = 1;
+        assert!(status.is_failing());
+    }
+
+    it "should wake up periodically when polling" {
+        use std::time::Duration;
+
+        let monitor = NetworkMonitor::new(Duration::from_millis(10));
+        assert!(!monitor.poll(Duration::from_millis(1)));
+        assert!(monitor.poll(Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,425 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Rate limiting middleware for the HTTP server.
+//!
+//! Requests are limited with token buckets, keyed by the user id of the
+//! session token when it has a valid signature, or by the client IP otherwise,
+//! so that forged tokens can't be used to get fresh buckets. The requests
+//! relayed by the builtin tunnel come from the loopback, so they are keyed on
+//! the remote client the tunnel frontend reports instead; the pagekite ones
+//! still share the loopback address. Requests
+//! to `/users` (login, setup...) use a stricter bucket, and clients failing to
+//! authenticate too many times are locked out, the lockout doubling with each
+//! further failure. Limited requests are answered with a `429` status and a
+//! `Retry-After` header.
+//!
+//! The limits are read from the "rate_limit" config namespace.
+
+use config_store::ConfigService;
+use foxbox_users::{ SessionToken, UsersManager };
+use iron::{ AfterMiddleware, BeforeMiddleware, headers, IronError, IronResult, Request, Response };
+use iron::modifiers::Header;
+use iron::status::Status;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use tunnel_controller::TunnelClients;
+
+header! { (RetryAfter, "Retry-After") => [u64] }
+
+// Buckets and failures idle for that long are forgotten.
+const IDLE_ENTRY_SECONDS: u64 = 600;
+const MAX_LOCKOUT_SECONDS: u64 = 3600;
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[derive(Debug)]
+struct TooManyRequests;
+
+impl fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for TooManyRequests {
+    fn description(&self) -> &str {
+        "Too many requests"
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+/// Token buckets refilled at `rate` tokens per second, holding up to `burst` tokens.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate: rate,
+            burst: burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, or returns how long to wait
+    /// until one is available.
+    pub fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > 1000 {
+            let idle: Vec<String> = buckets.iter().filter(|&(_, bucket)| {
+                now.duration_since(bucket.last_update) > Duration::from_secs(IDLE_ENTRY_SECONDS)
+            }).map(|(key, _)| key.clone()).collect();
+            for key in idle {
+                buckets.remove(&key);
+            }
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(TokenBucket {
+            tokens: self.burst,
+            last_update: now,
+        });
+
+        let elapsed = as_secs_f64(now.duration_since(bucket.last_update));
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_update = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.rate;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// Locks clients out after `max_failures` failed authentications. The lockout
+/// lasts `lockout` and doubles with each further failure.
+pub struct AuthLockout {
+    max_failures: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl AuthLockout {
+    pub fn new(max_failures: u32, lockout: Duration) -> Self {
+        AuthLockout {
+            max_failures: max_failures,
+            lockout: lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns how long `client` is still locked out for, if it is.
+    pub fn check(&self, client: &IpAddr, now: Instant) -> Result<(), Duration> {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(client).and_then(|entry| entry.locked_until) {
+            Some(until) if until > now => Err(until.duration_since(now)),
+            _ => Ok(())
+        }
+    }
+
+    pub fn failure(&self, client: &IpAddr, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() > 1000 {
+            let idle: Vec<IpAddr> = failures.iter().filter(|&(_, entry)| {
+                now.duration_since(entry.last_failure) > Duration::from_secs(IDLE_ENTRY_SECONDS)
+            }).map(|(client, _)| *client).collect();
+            for client in idle {
+                failures.remove(&client);
+            }
+        }
+
+        let entry = failures.entry(*client).or_insert(Failures {
+            count: 0,
+            locked_until: None,
+            last_failure: now,
+        });
+        entry.count += 1;
+        entry.last_failure = now;
+
+        if entry.count >= self.max_failures {
+            let exponent = entry.count - self.max_failures;
+            let seconds = if exponent >= 32 {
+                MAX_LOCKOUT_SECONDS
+            } else {
+                (self.lockout.as_secs() << exponent).min(MAX_LOCKOUT_SECONDS)
+            };
+            warn!("Locking out {} for {} seconds after {} failed authentications",
+                  client, seconds, entry.count);
+            entry.locked_until = Some(now + Duration::from_secs(seconds));
+        }
+    }
+
+    pub fn success(&self, client: &IpAddr) {
+        self.failures.lock().unwrap().remove(client);
+    }
+}
+
+struct RateLimitInner {
+    api: RateLimiter,
+    users: RateLimiter,
+    lockout: AuthLockout,
+    users_manager: Arc<UsersManager>,
+    tunnel_clients: TunnelClients,
+}
+
+/// The Iron middleware, to be linked both before and after the handler.
+#[derive(Clone)]
+pub struct RateLimit {
+    inner: Arc<RateLimitInner>,
+}
+
+impl RateLimit {
+    pub fn new(config: &ConfigService, users_manager: Arc<UsersManager>, tunnel_clients: TunnelClients) -> Self {
+        let lockout_seconds: u64 = config.get_or_set_default_parsed("rate_limit", "auth_lockout_seconds", "30");
+
+        RateLimit {
+            inner: Arc::new(RateLimitInner {
+                api: RateLimiter::new(config.get_or_set_default_parsed("rate_limit", "requests_per_second", "10"),
+                                      config.get_or_set_default_parsed("rate_limit", "burst", "50")),
+                users: RateLimiter::new(config.get_or_set_default_parsed("rate_limit", "users_requests_per_second", "0.5"),
+                                        config.get_or_set_default_parsed("rate_limit", "users_burst", "10")),
+                lockout: AuthLockout::new(config.get_or_set_default_parsed("rate_limit", "auth_max_failures", "5"),
+                                          Duration::from_secs(lockout_seconds)),
+                users_manager: users_manager,
+                tunnel_clients: tunnel_clients,
+            })
+        }
+    }
+
+    /// The address of the client, looking through the tunnel.
+    fn client_address(&self, remote: &SocketAddr) -> IpAddr {
+        self.inner.tunnel_clients.client_for(remote).unwrap_or_else(|| remote.ip())
+    }
+
+    fn is_users_request(req: &Request) -> bool {
+        req.url.path.first().map_or(false, |segment| segment == "users")
+    }
+
+    fn limited(wait: Duration) -> IronError {
+        let mut seconds = wait.as_secs();
+        if wait.subsec_nanos() > 0 {
+            seconds += 1;
+        }
+        IronError::new(TooManyRequests, (Status::TooManyRequests, Header(RetryAfter(seconds))))
+    }
+
+    fn after_users_request(&self, req: &Request, status: Option<Status>) {
+        if !Self::is_users_request(req) {
+            return;
+        }
+
+        let client = self.client_address(&req.remote_addr);
+        match status {
+            Some(Status::Unauthorized) => self.inner.lockout.failure(&client, Instant::now()),
+            Some(status) if status.is_success() => self.inner.lockout.success(&client),
+            _ => {}
+        }
+    }
+}
+
+impl BeforeMiddleware for RateLimit {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let now = Instant::now();
+        let client = self.client_address(&req.remote_addr);
+
+        if Self::is_users_request(req) {
+            if let Err(wait) = self.inner.lockout.check(&client, now) {
+                return Err(Self::limited(wait));
+            }
+            return self.inner.users.take(&format!("{}", client), now)
+                                   .map_err(Self::limited);
+        }
+
+        let key = match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+            Some(&headers::Authorization(headers::Bearer { ref token }))
+                if self.inner.users_manager.verify_token(token).is_ok() => {
+                match SessionToken::from_string(token) {
+                    Ok(token) => format!("user:{}", token.claims.id),
+                    Err(_) => format!("{}", client)
+                }
+            },
+            _ => format!("{}", client)
+        };
+
+        self.inner.api.take(&key, now).map_err(|wait| {
+            info!("Rate limiting requests from {}", key);
+            Self::limited(wait)
+        })
+    }
+}
+
+impl AfterMiddleware for RateLimit {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.after_users_request(req, res.status);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.after_users_request(req, err.response.status);
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+describe! rate_limiter {
+    before_each {
+        use std::time::{ Duration, Instant };
+
+        let now = Instant::now();
+    }
+
+    it "should allow bursts then limit" {
+        let limiter = RateLimiter::new(1.0, 2.0);
+        assert!(limiter.take("a", now).is_ok());
+        assert!(limiter.take("a", now).is_ok());
+        assert_eq!(limiter.take("a", now), Err(Duration::from_millis(1000)));
+        // Other keys have their own bucket.
+        assert!(limiter.take("b", now).is_ok());
+    }
+
+    it "should refill buckets over time" {
+        let limiter = RateLimiter::new(2.0, 1.0);
+        assert!(limiter.take("a", now).is_ok());
+        assert!(limiter.take("a", now).is_err());
+        assert!(limiter.take("a", now + Duration::from_millis(500)).is_ok());
+    }
+
+    it "should key forged session tokens on the client address" {
+        use config_store::ConfigService;
+        use foxbox_users::UsersManager;
+        use iron::{ Chain, Headers, IronResult, Request, Response };
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+        use iron_test::request;
+        use std::fs;
+        use std::sync::Arc;
+        use uuid::Uuid;
+
+        fn ok(_: &mut Request) -> IronResult<Response> {
+            Ok(Response::with(Status::Ok))
+        }
+
+        let id = Uuid::new_v4().to_simple_string();
+        let config_file_name = format!("conftest-{}.tmp", id);
+        let users_file_name = format!("users_test-{}.sqlite", id);
+        let config = ConfigService::new(&config_file_name);
+        config.set("rate_limit", "requests_per_second", "0.01");
+        config.set("rate_limit", "burst", "1");
+        let mut chain = Chain::new(ok);
+        chain.link_before(RateLimit::new(&config, Arc::new(UsersManager::new(&users_file_name)),
+                                         TunnelClients::new()));
+
+        // Token payloads are { "id": 2, "name": "admin" } and { "id": 3, "name": "user" },
+        // signed with another secret.
+        let tokens = ["eyJ0eXAiOiJKV1QiLCJraWQiOm51bGwsImFsZyI6IkhTMjU2In0.eyJpZCI\
+                       6MiwibmFtZSI6ImFkbWluIn0.JNtvokupDl2hdqB+vER15y89qigPc4FviZfJOSR1Vso",
+                      "eyJ0eXAiOiJKV1QiLCJraWQiOm51bGwsImFsZyI6IkhTMjU2In0.eyJpZCI\
+                       6MywibmFtZSI6InVzZXIifQ.JNtvokupDl2hdqB+vER15y89qigPc4FviZfJOSR1Vso"];
+        let results: Vec<bool> = tokens.iter().map(|token| {
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer { token: token.to_string() }));
+            request::get("http://localhost:3000/api/v1/services", headers, &chain).is_ok()
+        }).collect();
+        assert_eq!(results, vec![true, false]);
+
+        fs::remove_file(config_file_name).unwrap_or(());
+        fs::remove_file(users_file_name).unwrap_or(());
+    }
+
+    it "should key the tunneled requests on the remote client" {
+        use config_store::ConfigService;
+        use foxbox_users::UsersManager;
+        use iron::{ Chain, Headers, IronResult, Request, Response };
+        use iron::status::Status;
+        use iron_test::request;
+        use std::fs;
+        use std::sync::Arc;
+        use uuid::Uuid;
+
+        fn unauthorized(_: &mut Request) -> IronResult<Response> {
+            Ok(Response::with(Status::Unauthorized))
+        }
+
+        let id = Uuid::new_v4().to_simple_string();
+        let config_file_name = format!("conftest-{}.tmp", id);
+        let users_file_name = format!("users_test-{}.sqlite", id);
+        let config = ConfigService::new(&config_file_name);
+        config.set("rate_limit", "users_burst", "100");
+        config.set("rate_limit", "auth_max_failures", "2");
+        let clients = TunnelClients::new();
+        let rate_limit = RateLimit::new(&config, Arc::new(UsersManager::new(&users_file_name)),
+                                        clients.clone());
+        let mut chain = Chain::new(unauthorized);
+        chain.link_before(rate_limit.clone());
+        chain.link_after(rate_limit);
+
+        let login = |chain: &Chain| {
+            match request::post("http://localhost:3000/users/login", Headers::new(), "", chain) {
+                Ok(response) => response.status,
+                Err(err) => err.response.status
+            }
+        };
+
+        // iron_test requests come from 127.0.0.1:3000, like a tunneled stream.
+        let local = "127.0.0.1:3000".parse().unwrap();
+        {
+            let _attacker = clients.register(local, "203.0.113.5".parse().unwrap());
+            assert_eq!(login(&chain), Some(Status::Unauthorized));
+            assert_eq!(login(&chain), Some(Status::Unauthorized));
+            assert_eq!(login(&chain), Some(Status::TooManyRequests));
+        }
+        {
+            let _other = clients.register(local, "198.51.100.7".parse().unwrap());
+            assert_eq!(login(&chain), Some(Status::Unauthorized));
+        }
+        // Neither are the local clients locked out.
+        assert_eq!(clients.client_for(&local), None);
+        assert_eq!(login(&chain), Some(Status::Unauthorized));
+
+        fs::remove_file(config_file_name).unwrap_or(());
+        fs::remove_file(users_file_name).unwrap_or(());
+    }
+
+    it "should lock out after too many failures with backoff" {
+        use std::net::{ IpAddr, Ipv4Addr };
+
+        let client = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let lockout = AuthLockout::new(2, Duration::from_secs(10));
+
+        lockout.failure(&client, now);
+        assert!(lockout.check(&client, now).is_ok());
+
+        lockout.failure(&client, now);
+        assert_eq!(lockout.check(&client, now), Err(Duration::from_secs(10)));
+        assert!(lockout.check(&client, now + Duration::from_secs(10)).is_ok());
+
+        lockout.failure(&client, now);
+        assert_eq!(lockout.check(&client, now), Err(Duration::from_secs(20)));
+
+        lockout.success(&client);
+        assert!(lockout.check(&client, now).is_ok());
+    }
+}
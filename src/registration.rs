@@ -3,8 +3,9 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 /// This manages registration of the foxbox with the discovery endpoint.
-/// For now it simply register itselfs every N minutes with the endpoint,
-/// after trying more aggressively at first run.
+/// It registers itself every N minutes with the endpoint and the DNS server,
+/// right away when the IP address changes, and retries failed registrations
+/// with an exponential backoff.
 
 extern crate get_if_addrs;
 extern crate hyper;
@@ -13,21 +14,53 @@ use self::hyper::Client;
 use self::hyper::header::Connection;
 use self::hyper::status::StatusCode;
 use self::get_if_addrs::{ IfAddr, Interface };
+use config_store::ConfigService;
+use network_monitor::{ NetworkMonitor, NetworkStatus };
 use serde_json;
-use std::io::Read;
+use std::cmp;
+use std::fs::File;
+use std::io::{ self, Read };
+use std::net::{ Ipv4Addr, Ipv6Addr };
 use std::time::Duration;
 use std::thread;
-use tls::{ CertificateManager, DnsRecord, get_san_cert_for, register_dns_record };
+use time;
+use std::sync::Arc;
+use tls::{ AcmeSettings, CertificateManager, CertificateRenewer, DnsProvider, DnsRecord,
+           get_san_cert_for, NotifyFn, RenewalSettings, RenewalStatus, RenewFn };
 use traits::Controller;
 use tunnel_controller:: { Tunnel };
 
 const REGISTRATION_INTERVAL_IN_MINUTES: u32 = 1;
 
+pub struct RegistrationSettings {
+    /// How often the network is checked when it can't be monitored, in seconds.
+    pub poll_interval: i64,
+    /// The delay before retrying a failed registration, doubled with each
+    /// failure up to `max_retry_delay`.
+    pub retry_delay: i64,
+    pub max_retry_delay: i64,
+}
+
+impl RegistrationSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        RegistrationSettings {
+            poll_interval: config.get_or_set_default_parsed("registration", "poll_interval_seconds", "30"),
+            retry_delay: config.get_or_set_default_parsed("registration", "retry_delay_seconds", "10"),
+            max_retry_delay: config.get_or_set_default_parsed("registration", "max_retry_delay_seconds", "600"),
+        }
+    }
+
+    fn retry_delay(&self, failures: u32) -> i64 {
+        let exponent = cmp::min(failures.saturating_sub(1), 20);
+        cmp::min(self.retry_delay << exponent, self.max_retry_delay)
+    }
+}
+
 pub struct Registrar {
     certificate_manager: CertificateManager,
     top_level_domain: String,
     registration_endpoint: String,
-    dns_api_endpoint: String,
+    dns_provider: Arc<DnsProvider>,
 }
 
 #[derive(Serialize, Debug)]
@@ -36,18 +69,99 @@ struct RegistrationRequest {
     client: String,
 
     local_ip: String,
+    // All the local addresses, IPv4 first, for the clients to choose from.
+    local_ips: Vec<String>,
+}
+
+/// The addresses of the box that can be reached from the local network.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalAddresses {
+    pub ipv4: Vec<Ipv4Addr>,
+    /// Global addresses first, then unique local ones.
+    pub ipv6: Vec<Ipv6Addr>,
+}
+
+impl LocalAddresses {
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// The address to use when only one can be, preferably IPv4.
+    pub fn primary(&self) -> Option<String> {
+        self.to_strings().into_iter().next()
+    }
+
+    /// The address to publish as AAAA record: a global one, which isn't a
+    /// temporary address since these are dropped after a while.
+    pub fn global_ipv6(&self) -> Option<&Ipv6Addr> {
+        self.ipv6.iter().find(|ip| is_ipv6_global(ip))
+    }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.ipv4.iter().map(|ip| format!("{}", ip))
+                 .chain(self.ipv6.iter().map(|ip| format!("{}", ip)))
+                 .collect()
+    }
+}
+
+/// Global unicast addresses (2000::/3).
+fn is_ipv6_global(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+/// Unique local addresses (fc00::/7).
+fn is_ipv6_unique_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xfe00 == 0xfc00
+}
+
+// Address flags of /proc/net/if_inet6.
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+/// The temporary (privacy extension) and deprecated IPv6 addresses listed
+/// in `/proc/net/if_inet6`, which shouldn't be handed out.
+fn parse_if_inet6(content: &str) -> Vec<Ipv6Addr> {
+    content.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[0].len() != 32 {
+            return None;
+        }
+        let flags = match u32::from_str_radix(fields[4], 16) {
+            Ok(flags) if flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED) != 0 => flags,
+            _ => return None
+        };
+        let mut segments = [0u16; 8];
+        for (i, segment) in segments.iter_mut().enumerate() {
+            *segment = match u16::from_str_radix(&fields[0][i * 4..i * 4 + 4], 16) {
+                Ok(segment) => segment,
+                Err(_) => return None
+            };
+        }
+        debug!("Skipping the IPv6 address {} with flags {:x}", fields[0], flags);
+        Some(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                           segments[4], segments[5], segments[6], segments[7]))
+    }).collect()
+}
+
+fn temporary_ipv6_addresses() -> Vec<Ipv6Addr> {
+    let mut content = String::new();
+    match File::open("/proc/net/if_inet6").and_then(|mut file| file.read_to_string(&mut content)) {
+        Ok(_) => parse_if_inet6(&content),
+        // Not on Linux, the addresses can't be told apart.
+        Err(_) => vec![]
+    }
 }
 
 impl Registrar {
     pub fn new(certificate_manager: CertificateManager,
                top_level_domain: String,
                registration_endpoint: String,
-               dns_api_endpoint: String) -> Registrar {
+               dns_provider: Arc<DnsProvider>) -> Registrar {
         Registrar {
             certificate_manager: certificate_manager,
             top_level_domain: top_level_domain,
             registration_endpoint: format!("{}/register", registration_endpoint),
-            dns_api_endpoint: dns_api_endpoint,
+            dns_provider: dns_provider,
         }
     }
 
@@ -69,7 +183,8 @@ impl Registrar {
         format!("remote.{}", self.get_common_name())
     }
 
-    fn register_with_registration_server(&self, ip_addr: String, http_scheme: &str, box_port: u16, tunnel_enabled: bool) -> () {
+    fn register_with_registration_server(&self, addresses: &LocalAddresses, http_scheme: &str, box_port: u16,
+                                         tunnel_enabled: bool) -> Result<(), String> {
         let message = json!({
             local_origin: format!("{}://{}:{}", http_scheme, self.get_local_dns_name(), box_port),
             tunnel_origin: if tunnel_enabled {
@@ -82,12 +197,13 @@ impl Registrar {
         let body = match serde_json::to_string(&RegistrationRequest {
             message: message,
             client: self.get_fingerprint(),
-            local_ip: ip_addr,
+            local_ip: addresses.primary().unwrap_or_else(String::new),
+            local_ips: addresses.to_strings(),
         }) {
             Ok(body) => body,
             Err(_) => {
                 error!("registration server: Serialization error. Will not send registration request.");
-                return;
+                return Err("Serialization error".to_owned());
             }
         };
 
@@ -97,81 +213,157 @@ impl Registrar {
             .body(&body)
             .send();
 
-        // Sanity checks, mostly to debug errors since we don't try
-        // to recover from failures.
-        if let Ok(mut response) = res {
-            if response.status == StatusCode::Ok {
+        match res {
+            Ok(mut response) => {
+                if response.status != StatusCode::Ok {
+                    warn!("registration server: {} responded with {}", self.registration_endpoint,
+                          response.status);
+                    return Err(format!("Registration server responded with {}", response.status));
+                }
                 let mut body = String::new();
                 if let Ok(_) = response.read_to_string(&mut body) {
                     info!("registration server responded with: {}", body);
                 } else {
                     warn!("registration server: Unable to read answer from {}", self.registration_endpoint);
                 }
+                Ok(())
+            },
+            Err(err) => {
+                warn!("registration server: Unable to send request to {}", self.registration_endpoint);
+                Err(format!("Registration server unreachable: {}", err))
             }
-        } else {
-            warn!("registration server: Unable to send request to {}", self.registration_endpoint);
         }
     }
 
-    /// Registers the boxes local IP address as an A record with the DNS server, and
-    /// registers a CNAME record for the tunnel endpoint using the box's assigned
+    /// Registers the boxes local IP addresses as A and AAAA records with the DNS
+    /// server, and registers a CNAME record for the tunnel endpoint using the box's assigned
     /// names (local.<fingerprint>.box.knilxof.org and
     /// remote.<fingerprint>.box.knilxof.org).  The remote name (tunnel name), is
     /// only configured if the tunnel_frontend option is non-None.
-    fn register_with_dns_server(&self, ip_addr: String, tunnel_frontend: Option<String>) {
-        let client_certificate = self.certificate_manager.get_box_certificate().unwrap();
+    fn register_with_dns_server(&self, addresses: &LocalAddresses, tunnel_frontend: Option<String>)
+        -> Result<(), String> {
+        let mut failed = vec![];
 
         let local_name = self.get_local_dns_name();
-        // Create entry for local DNS
-        info!("DNS server: Creating DNS entry for {}", local_name);
-        let result = register_dns_record(
-            client_certificate.clone(),
-            &DnsRecord {
-                record_type: "A",
+        // Create entries for local DNS. The records hold a single address,
+        // and unique local IPv6 addresses don't belong in the public DNS.
+        let records = vec![
+            ("A", addresses.ipv4.first().map(|ip| format!("{}", ip))),
+            ("AAAA", addresses.global_ipv6().map(|ip| format!("{}", ip))),
+        ];
+        for (record_type, ip_addr) in records {
+            let ip_addr = match ip_addr {
+                Some(ip_addr) => ip_addr,
+                None => continue
+            };
+            info!("DNS server: Creating {} DNS entry for {}", record_type, local_name);
+            let result = self.dns_provider.set_record(&DnsRecord {
+                record_type: record_type,
                 name: &local_name,
                 value: &ip_addr,
-            },
-            &self.dns_api_endpoint.clone(),
-        );
+            });
 
-        if let Err(_) = result {
-            warn!("DNS server: Could not create DNS entry for {}", local_name);
+            if let Err(_) = result {
+                warn!("DNS server: Could not create {} DNS entry for {}", record_type, local_name);
+                failed.push(format!("{} {}", record_type, local_name));
+            }
         }
 
         if let Some(tunnel_frontend) = tunnel_frontend {
             let remote_name = self.get_remote_dns_name();
             info!("DNS server: Creating DNS entry for {}", remote_name);
-            let result = register_dns_record(
-                client_certificate.clone(),
-                &DnsRecord {
-                    record_type: "CNAME",
-                    name: &remote_name,
-                    value: &tunnel_frontend,
-                },
-                &self.dns_api_endpoint.clone(),
-            );
+            let result = self.dns_provider.set_record(&DnsRecord {
+                record_type: "CNAME",
+                name: &remote_name,
+                value: &tunnel_frontend,
+            });
 
             if let Err(_) = result {
                 warn!("DNS server: Could not create DNS entry for {}", remote_name);
+                failed.push(format!("CNAME {}", remote_name));
             }
         }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Could not create DNS entries for {:?}", failed))
+        }
     }
 
-    fn register_certificates(&self) {
-        if self.certificate_manager.get_certificate(&self.get_local_dns_name()).is_none() {
-            let domains = vec![self.get_local_dns_name(), self.get_remote_dns_name()];
+    /// Registers `addresses` with the registration server and the DNS server.
+    fn register(&self, addresses: &LocalAddresses, http_scheme: &str, box_port: u16,
+                tunnel_frontend: &Option<String>) -> Result<(), String> {
+        let registration = self.register_with_registration_server(
+            addresses,
+            http_scheme,
+            box_port,
+            tunnel_frontend.is_some()
+        );
+        let dns = self.register_with_dns_server(addresses, tunnel_frontend.clone());
+        registration.and(dns)
+    }
+
+    /// Gets the LetsEncrypt certificate of the box names, unless it already has it.
+    fn register_certificates(&self, acme_settings: &AcmeSettings) -> Result<(), String> {
+        if self.certificate_manager.get_certificate(&self.get_local_dns_name()).is_some() {
+            return Ok(());
+        }
 
-            info!("Getting/renewing LetsEncrypt certificate for: {:?}", domains);
-            let rx = get_san_cert_for(
-                domains.into_iter(),
-                self.certificate_manager.clone(),
-                self.certificate_manager.get_box_certificate().unwrap(),
-                self.dns_api_endpoint.clone()
-            );
+        let domains = vec![self.get_local_dns_name(), self.get_remote_dns_name()];
+        info!("Getting/renewing LetsEncrypt certificate for: {:?}", domains);
+        let box_certificate = match self.certificate_manager.get_box_certificate() {
+            Ok(certificate) => certificate,
+            Err(err) => return Err(format!("Unable to get the box certificate: {}", err))
+        };
+        let rx = get_san_cert_for(
+            domains.into_iter(),
+            self.certificate_manager.clone(),
+            box_certificate,
+            self.dns_provider.clone(),
+            acme_settings.clone()
+        );
 
-            rx.recv().unwrap().unwrap();
-            self.certificate_manager.reload().unwrap();
+        match rx.recv() {
+            Ok(Ok(_)) => {},
+            Ok(Err(err)) => return Err(format!("Unable to get the LetsEncrypt certificate: {}", err)),
+            Err(_) => return Err("The certificate request was interrupted".to_owned())
         }
+        self.certificate_manager.reload().map_err(|err| {
+            format!("Unable to load the LetsEncrypt certificate: {}", err)
+        })
+    }
+
+    /// Starts renewing the LetsEncrypt certificates ahead of their expiry,
+    /// notifying the websocket clients when renewals fail.
+    fn start_certificate_renewal<T: Controller>(&self, controller: &T) {
+        let certificate_manager = self.certificate_manager.clone();
+        let dns_provider = self.dns_provider.clone();
+        let acme_settings = AcmeSettings::from_config(&controller.get_config());
+        let renew: RenewFn = Box::new(move |names: Vec<String>| {
+            let box_certificate = try!(certificate_manager.get_box_certificate());
+            let rx = get_san_cert_for(names.into_iter(),
+                                      certificate_manager.clone(),
+                                      box_certificate,
+                                      dns_provider.clone(),
+                                      acme_settings.clone());
+            rx.recv().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "The certificate request was interrupted"))
+            })
+        });
+
+        let notifier = controller.clone();
+        let notify: NotifyFn = Box::new(move |status: &RenewalStatus| {
+            notifier.broadcast_to_websockets(json_value!({
+                type: "core/certificates/renewal-failed",
+                status: status
+            }));
+        });
+
+        CertificateRenewer::new(self.certificate_manager.clone(),
+                                RenewalSettings::from_config(&controller.get_config()),
+                                renew,
+                                notify).start();
     }
 
     pub fn start<T: Controller>(self,
@@ -182,21 +374,18 @@ impl Registrar {
         info!("registration server: Starting registration with {}",
                 self.registration_endpoint);
 
-        let ip_addr = self.get_ip_addr(&iface);
-        if ip_addr == None {
-            // TODO: retry later, in case we're racing with the network
-            // configuration. https://github.com/fxbox/foxbox/issues/347
-            return;
-        }
-
-        info!("Got ip address: {}", ip_addr.clone().unwrap());
-
         let tunnel_frontend = if let Some(ref tunnel) = *tunnel {
             tunnel.get_frontend_name()
         } else {
             None
         };
         let enabled_tls = controller.get_tls_enabled();
+        let acme_settings = AcmeSettings::from_config(&controller.get_config());
+        let settings = RegistrationSettings::from_config(&controller.get_config());
+
+        if enabled_tls {
+            self.start_certificate_renewal(controller);
+        }
 
         let http_scheme = if enabled_tls {
             "https"
@@ -204,118 +393,169 @@ impl Registrar {
             "http"
         };
 
-        // Spawn a thread to register every REGISTRATION_INTERVAL_IN_MINUTES.
+        let controller = controller.clone();
+
+        // Spawn a thread registering every REGISTRATION_INTERVAL_IN_MINUTES,
+        // and whenever the IP address changes.
         thread::Builder::new().name("Registrar".to_owned())
             .spawn(move || {
-                let tunnel_configured = tunnel_frontend.clone().is_some();
-
-                if enabled_tls {
-                    self.register_certificates();
-                }
+                let mut monitor = NetworkMonitor::new(Duration::from_secs(settings.poll_interval as u64));
+                let mut certificates_registered = false;
+                let mut status = NetworkStatus::default();
+                let mut next_registration = time::get_time().sec;
 
                 loop {
-                    // TODO: If the ip address changes, we need to update the dns server and
-                    // registration server with the new IP address.
-                    // https://github.com/fxbox/foxbox/issues/348
-                    self.register_with_registration_server(
-                        ip_addr.clone().unwrap(),
-                        http_scheme,
-                        box_port,
-                        tunnel_configured
-                    );
-                    self.register_with_dns_server(ip_addr.clone().unwrap(), tunnel_frontend.clone());
-
-                    // Go to sleep.
-                    thread::sleep(Duration::from_secs(REGISTRATION_INTERVAL_IN_MINUTES as u64 * 60))
+                    let addresses = self.get_ip_addrs(&iface);
+                    let now = time::get_time().sec;
+
+                    if addresses.to_strings() != status.ip_addrs {
+                        info!("IP addresses changed from {:?} to {:?}", status.ip_addrs,
+                              addresses.to_strings());
+                        status.ip_addr = addresses.primary();
+                        status.ip_addrs = addresses.to_strings();
+                        status.last_change = Some(now);
+                        status.failures = 0;
+                        next_registration = now;
+                    }
+
+                    if !addresses.is_empty() {
+                        if now >= next_registration {
+                            // Failures to get the certificates are retried
+                            // with the failed registrations.
+                            let certificates = if enabled_tls && !certificates_registered {
+                                let result = self.register_certificates(&acme_settings);
+                                if let Err(ref err) = result {
+                                    error!("{}", err);
+                                }
+                                certificates_registered = result.is_ok();
+                                result
+                            } else {
+                                Ok(())
+                            };
+
+                            let result = self.register(&addresses, http_scheme, box_port, &tunnel_frontend);
+                            match certificates.and(result) {
+                                Ok(_) => {
+                                    status.failures = 0;
+                                    status.last_error = None;
+                                    status.last_registration = Some(now);
+                                    next_registration = now + REGISTRATION_INTERVAL_IN_MINUTES as i64 * 60;
+                                },
+                                Err(err) => {
+                                    status.failures += 1;
+                                    status.last_error = Some(err);
+                                    next_registration = now + settings.retry_delay(status.failures);
+                                    warn!("Registration failed {} times, retrying in {}s",
+                                          status.failures, next_registration - now);
+                                }
+                            }
+                            status.next_registration = Some(next_registration);
+                        }
+                    } else {
+                        // Waits for an address.
+                        status.next_registration = None;
+                        next_registration = now + settings.poll_interval;
+                    }
+                    controller.set_network_status(status.clone());
+
+                    // Go to sleep until the next registration, or a change.
+                    let delay = cmp::max(next_registration - now, 1) as u64;
+                    monitor.wait(Duration::from_secs(delay));
                 }
             }).unwrap();
     }
 
-    /// return the host IP address of the first valid interface.
+    /// Returns the IPv4 addresses, and the global and unique local IPv6
+    /// addresses, of the valid interfaces.
     /// want_iface is an options string for the interface you want.
-    pub fn get_ip_addr(&self, want_iface: &Option<String>) -> Option<String> {
-        // Look for an ipv4 interface on eth* or wlan*.
-        if let Ok(ifaces) = get_if_addrs::get_if_addrs() {
-            if ifaces.is_empty() {
+    pub fn get_ip_addrs(&self, want_iface: &Option<String>) -> LocalAddresses {
+        match get_if_addrs::get_if_addrs() {
+            Ok(ifaces) => {
+                let addresses = self.get_ip_addrs_from_ifaces(&ifaces, want_iface,
+                                                              &temporary_ipv6_addresses());
+                if addresses.is_empty() {
+                    error!("No IP interfaces found!");
+                }
+                addresses
+            },
+            Err(_) => {
                 error!("No IP interfaces found!");
-                return None;
+                LocalAddresses::default()
             }
-
-            self.get_ip_addr_from_ifaces(&ifaces, want_iface)
-        } else {
-            error!("No IP interfaces found!");
-            None
         }
     }
 
     /// This is a private function that to which we pass the ifaces
     /// This is so that we can shim get_if_addrs() in tests with a
-    /// pre-set list of interfaces.
-    fn get_ip_addr_from_ifaces(&self, ifaces: &[Interface],
-                               want_iface: &Option<String>) -> Option<String> {
-
-        let mut ip_addr: Option<String> = None;
-        let mut ipv6_addr: Option<String> = None;
-
-        for iface in ifaces {
-            match want_iface.as_ref() {
-                None =>
-                    // Whitelist known good iface
-                    if !(iface.name.starts_with("eth") ||
-                         iface.name.starts_with("wlan") ||
-                         iface.name.starts_with("en") ||
-                         iface.name.starts_with("em") ||
-                         iface.name.starts_with("wlp3s")) {
-                        continue;
-                    },
-                    Some(iface_name) =>
-                        if &iface.name != iface_name {
-                            continue;
-                        }
-            }
-            if let IfAddr::V4(ref v4) = iface.addr {
-                ip_addr = Some(format!("{}", v4.ip));
-                break;
-            } else if ipv6_addr.is_none() {
-                if let IfAddr::V6(ref v6) = iface.addr {
-                    ipv6_addr = Some(format!("{}", v6.ip));
-                }
+    /// pre-set list of interfaces. The `temporary` IPv6 addresses are left out.
+    fn get_ip_addrs_from_ifaces(&self, ifaces: &[Interface], want_iface: &Option<String>,
+                                temporary: &[Ipv6Addr]) -> LocalAddresses {
+        let mut addresses = LocalAddresses::default();
+        let mut unique_local = vec![];
+
+        for iface in ifaces.iter().filter(|iface| Self::is_wanted_iface(iface, want_iface)) {
+            match iface.addr {
+                IfAddr::V4(ref v4) => addresses.ipv4.push(v4.ip),
+                IfAddr::V6(ref v6) if temporary.contains(&v6.ip) => {}
+                IfAddr::V6(ref v6) if is_ipv6_global(&v6.ip) => addresses.ipv6.push(v6.ip),
+                IfAddr::V6(ref v6) if is_ipv6_unique_local(&v6.ip) => unique_local.push(v6.ip),
+                // Link local addresses are useless without their scope.
+                IfAddr::V6(_) => {}
             }
         }
+        addresses.ipv6.extend(unique_local);
+        addresses
+    }
 
-        if ip_addr.is_none() {
-            if ipv6_addr.is_none() {
-                error!("No IP interfaces found!");
-            } else {
-                ip_addr = ipv6_addr;
-            }
+    fn is_wanted_iface(iface: &Interface, want_iface: &Option<String>) -> bool {
+        match want_iface.as_ref() {
+            // Whitelist known good iface
+            None => iface.name.starts_with("eth") ||
+                    iface.name.starts_with("wlan") ||
+                    iface.name.starts_with("en") ||
+                    iface.name.starts_with("em") ||
+                    iface.name.starts_with("wlp3s"),
+            Some(iface_name) => &iface.name == iface_name
         }
-        ip_addr
     }
-
 }
 
 #[cfg(test)]
 describe! registrar {
 
     before_each {
-        use tls::CertificateManager;
+        use std::sync::Arc;
+        use tls::{ CertificateManager, KnilxofDnsProvider };
         let registrar = Registrar::new(
             CertificateManager::new_for_test(),
             "box.knilxof.org".to_owned(),
             "http://knilxof.org:4242/".to_owned(),
-            "https://knilxof.org:5300".to_owned()
+            Arc::new(KnilxofDnsProvider::new(CertificateManager::new_for_test(),
+                                             "https://knilxof.org:5300".to_owned()))
         );
     }
 
     it "should return an IP address when a machine has network interfaces" {
         use regex::Regex;
 
-        let ip = registrar.get_ip_addr(&None).unwrap();
+        let ip = registrar.get_ip_addrs(&None).primary().unwrap();
         let ipv4_regex = Regex::new(r"^(\d{1,3}\.){3}\d{1,3}$").unwrap();
         assert!(ipv4_regex.is_match(ip.as_str()));
     }
 
+    it "should back off exponentially when registrations fail" {
+        let settings = RegistrationSettings {
+            poll_interval: 30,
+            retry_delay: 10,
+            max_retry_delay: 60,
+        };
+        assert_eq!(settings.retry_delay(1), 10);
+        assert_eq!(settings.retry_delay(2), 20);
+        assert_eq!(settings.retry_delay(3), 40);
+        assert_eq!(settings.retry_delay(4), 60);
+        assert_eq!(settings.retry_delay(100), 60);
+    }
+
     describe! ipv4 {
         before_each {
             use super::super::get_if_addrs::*;
@@ -358,24 +598,24 @@ describe! registrar {
         }
 
         it "should default to eth0" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &None).unwrap();
+            let ip_addr = registrar.get_ip_addrs_from_ifaces(&interfaces, &None, &[]).primary().unwrap();
             assert_eq!(ip_addr, "192.168.0.4");
         }
 
         it "should retrieve address from eth0" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &Some("eth0".to_owned()))
-                .unwrap();
+            let ip_addr = registrar.get_ip_addrs_from_ifaces(&interfaces, &Some("eth0".to_owned()), &[])
+                .primary().unwrap();
             assert_eq!(ip_addr, "192.168.0.4");
         }
 
         it "should retrieve address from wlan0" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &Some("wlan0".to_owned()))
-                .unwrap();
+            let ip_addr = registrar.get_ip_addrs_from_ifaces(&interfaces, &Some("wlan0".to_owned()), &[])
+                .primary().unwrap();
             assert_eq!(ip_addr, "192.168.0.14");
         }
 
         it "should retrieve address from docker0" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &Some("docker0".to_owned())).unwrap();
+            let ip_addr = registrar.get_ip_addrs_from_ifaces(&interfaces, &Some("docker0".to_owned()), &[]).primary().unwrap();
             assert_eq!(ip_addr, "172.18.1.42");
         }
     }
@@ -385,16 +625,18 @@ describe! registrar {
             use super::super::get_if_addrs::*;
             use std::net::{ Ipv4Addr, Ipv6Addr };
 
+            let v6 = |name: &str, ip: Ipv6Addr| Interface {
+                name: name.to_owned(),
+                addr: IfAddr::V6(Ifv6Addr {
+                    ip: ip,
+                    netmask: Ipv6Addr::new(0xffff,0xffff,0xffff,0xffff,0,0,0,0),
+                    broadcast: None
+                })
+            };
+
             let interfaces: Vec<Interface> = vec![
-                Interface {
-                    name: "eth0".to_owned(),
-                    addr: IfAddr::V6(Ifv6Addr {
-                        ip: Ipv6Addr::new(0,0,0,0,0,0xffff,0xc0a8,0x4),
-                        netmask: Ipv6Addr::new(0xffff,0xffff,0xffff,0xffff,
-                                               0xffff,0xffff,0xffff,0xffff),
-                        broadcast: None
-                    })
-                },
+                v6("eth0", Ipv6Addr::new(0xfe80,0,0,0,0,0,0,4)),
+                v6("eth0", Ipv6Addr::new(0xfd00,0,0,0,0,0,0,4)),
                 Interface {
                     name: "eth0".to_owned(),
                     addr: IfAddr::V4(Ifv4Addr {
@@ -403,39 +645,48 @@ describe! registrar {
                         broadcast: Some(Ipv4Addr::new(192,160,0,255))
                     })
                 },
-                Interface {
-                    name: "eth1".to_owned(),
-                    addr: IfAddr::V6(Ifv6Addr {
-                        ip: Ipv6Addr::new(0,0,0,0,0,0xffff,0xc0a8,0x4),
-                        netmask: Ipv6Addr::new(0xffff,0xffff,0xffff,0xffff,
-                                               0xffff,0xffff,0xffff,0xffff),
-                        broadcast: None
-                    })
-                }
+                v6("eth0", Ipv6Addr::new(0x2001,0xdb8,0,0,0,0,0,4)),
+                v6("eth1", Ipv6Addr::new(0xfe80,0,0,0,0,0,0,5)),
+                v6("eth1", Ipv6Addr::new(0,0,0,0,0,0xffff,0xc0a8,0x5)),
+                v6("eth1", Ipv6Addr::new(0xfd00,0,0,0,0,0,0,5)),
             ];
         }
 
-        it "should return default IPv4" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &None)
-                .unwrap();
+        it "should return IPv4 and eth0 by default" {
+            let ip_addr = registrar.get_ip_addrs_from_ifaces(&interfaces, &None, &[]).primary().unwrap();
             assert_eq!(ip_addr, "192.168.0.4");
         }
 
-        it "should return IPv6" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &Some("eth1".to_owned()))
-                .unwrap();
-            assert_eq!(ip_addr, "::ffff:192.168.0.4");
+        it "should list global addresses before unique local ones" {
+            let addresses = registrar.get_ip_addrs_from_ifaces(&interfaces, &Some("eth0".to_owned()), &[]);
+            assert_eq!(addresses.to_strings(), vec!["192.168.0.4", "2001:db8::4", "fd00::4"]);
         }
 
-        it "should return IPv4 if both are specified" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &Some("eth0".to_owned()))
-                .unwrap();
-            assert_eq!(ip_addr, "192.168.0.4");
+        it "should ignore link local and mapped addresses" {
+            let addresses = registrar.get_ip_addrs_from_ifaces(&interfaces, &Some("eth1".to_owned()), &[]);
+            assert_eq!(addresses.primary().unwrap(), "fd00::5");
+            assert_eq!(addresses.ipv6.len(), 1);
+            assert!(addresses.ipv4.is_empty());
+            // Unique local addresses aren't published in the DNS.
+            assert_eq!(addresses.global_ipv6(), None);
         }
 
-        it "should return IPv4 and eth0 by default" {
-            let ip_addr = registrar.get_ip_addr_from_ifaces(&interfaces, &None).unwrap();
-            assert_eq!(ip_addr, "192.168.0.4");
+        it "should leave out the temporary addresses" {
+            let temporary = [Ipv6Addr::new(0x2001,0xdb8,0,0,0,0,0,4)];
+            let mut interfaces = interfaces;
+            interfaces.push(v6("eth0", Ipv6Addr::new(0x2001,0xdb8,0,0,0,0,0,6)));
+            let addresses = registrar.get_ip_addrs_from_ifaces(&interfaces, &Some("eth0".to_owned()), &temporary);
+            assert_eq!(addresses.to_strings(), vec!["192.168.0.4", "2001:db8::6", "fd00::4"]);
+            assert_eq!(addresses.global_ipv6(), Some(&Ipv6Addr::new(0x2001,0xdb8,0,0,0,0,0,6)));
+        }
+
+        it "should find the temporary and deprecated addresses" {
+            let content = "20010db8000000000000000000000004 02 40 00 01     eth0\n\
+                           20010db8000000000000000000000005 02 40 00 20     eth0\n\
+                           20010db8000000000000000000000006 02 40 00 00     eth0\n\
+                           fe800000000000000000000000000004 02 40 20 80     eth0\n";
+            assert_eq!(super::super::parse_if_inet6(content),
+                       vec![Ipv6Addr::new(0x2001,0xdb8,0,0,0,0,0,4), Ipv6Addr::new(0x2001,0xdb8,0,0,0,0,0,5)]);
         }
     }
 }
@@ -0,0 +1,166 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Security headers middleware for the HTTP server.
+//!
+//! Adds `Strict-Transport-Security` when TLS is enabled, `X-Frame-Options`
+//! and `Referrer-Policy` to every response, and a `Content-Security-Policy`
+//! to the HTML pages of the web UIs.
+//!
+//! The headers are read from the "security_headers" config namespace, an
+//! empty value disabling the corresponding header.
+
+use config_store::ConfigService;
+use iron::{ AfterMiddleware, IronError, IronResult, Request, Response };
+use iron::headers::ContentType;
+use iron::mime::{ Mime, SubLevel, TopLevel };
+
+// Matches the policy of the `static/main` and `static/setup` pages, which
+// connect to the websocket port too.
+const DEFAULT_CSP: &'static str = "default-src 'self'; style-src 'self' 'unsafe-inline'; \
+                                   connect-src *; object-src 'none'; img-src 'self' blob:; \
+                                   frame-ancestors 'none'";
+
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    hsts: Option<String>,
+    content_security_policy: Option<String>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &ConfigService, tls_enabled: bool) -> Self {
+        let get = |key: &str, default: &str| -> Option<String> {
+            let value = config.get_or_set_default("security_headers", key, default);
+            if value.is_empty() { None } else { Some(value) }
+        };
+
+        let hsts_max_age: u64 = get("hsts_max_age", "31536000").and_then(|value| {
+            value.parse().map_err(|_| {
+                warn!("Invalid value for security_headers::hsts_max_age, disabling HSTS");
+            }).ok()
+        }).unwrap_or(0);
+
+        SecurityHeaders {
+            // Browsers ignore it over plain HTTP anyway.
+            hsts: if tls_enabled && hsts_max_age > 0 {
+                Some(format!("max-age={}", hsts_max_age))
+            } else {
+                None
+            },
+            content_security_policy: get("content_security_policy", DEFAULT_CSP),
+            frame_options: get("frame_options", "DENY"),
+            referrer_policy: get("referrer_policy", "no-referrer"),
+        }
+    }
+
+    fn is_html(res: &Response) -> bool {
+        match res.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Text, SubLevel::Html, _))) => true,
+            _ => false
+        }
+    }
+
+    fn add_headers(&self, res: &mut Response) {
+        let mut headers = vec![
+            ("Strict-Transport-Security", &self.hsts),
+            ("X-Frame-Options", &self.frame_options),
+            ("Referrer-Policy", &self.referrer_policy),
+        ];
+        if Self::is_html(res) {
+            headers.push(("Content-Security-Policy", &self.content_security_policy));
+        }
+
+        for (name, value) in headers {
+            if let Some(ref value) = *value {
+                res.headers.set_raw(name, vec![value.clone().into_bytes()]);
+            }
+        }
+    }
+}
+
+impl AfterMiddleware for SecurityHeaders {
+    fn after(&self, _: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.add_headers(&mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, _: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.add_headers(&mut err.response);
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+describe! security_headers {
+    before_each {
+        use config_store::ConfigService;
+        use iron::{ Chain, Headers, IronResult, Request, Response };
+        use iron::headers::ContentType;
+        use iron::mime::{ Mime, SubLevel, TopLevel };
+        use iron::status::Status;
+        use iron_test::request;
+        use std::fs;
+        use uuid::Uuid;
+
+        let config_file_name = format!("conftest-{}.tmp", Uuid::new_v4().to_simple_string());
+        let config = ConfigService::new(&config_file_name);
+
+        fn html(_: &mut Request) -> IronResult<Response> {
+            let mut response = Response::with((Status::Ok, "<html></html>"));
+            response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+            Ok(response)
+        }
+        fn json(_: &mut Request) -> IronResult<Response> {
+            let mut response = Response::with((Status::Ok, "{}"));
+            response.headers.set(ContentType::json());
+            Ok(response)
+        }
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+    }
+
+    it "should add the content security policy to html pages only" {
+        let mut chain = Chain::new(html);
+        chain.link_after(SecurityHeaders::new(&config, true));
+        let response = request::get("http://localhost:3000/", Headers::new(), &chain).unwrap();
+        assert!(response.headers.get_raw("Content-Security-Policy").is_some());
+        assert_eq!(response.headers.get_raw("X-Frame-Options").unwrap()[0], b"DENY".to_vec());
+        assert_eq!(response.headers.get_raw("Referrer-Policy").unwrap()[0],
+                   b"no-referrer".to_vec());
+
+        let mut chain = Chain::new(json);
+        chain.link_after(SecurityHeaders::new(&config, true));
+        let response = request::get("http://localhost:3000/", Headers::new(), &chain).unwrap();
+        assert!(response.headers.get_raw("Content-Security-Policy").is_none());
+        assert!(response.headers.get_raw("X-Frame-Options").is_some());
+    }
+
+    it "should only add hsts when tls is enabled" {
+        let mut chain = Chain::new(json);
+        chain.link_after(SecurityHeaders::new(&config, true));
+        let response = request::get("http://localhost:3000/", Headers::new(), &chain).unwrap();
+        assert_eq!(response.headers.get_raw("Strict-Transport-Security").unwrap()[0],
+                   b"max-age=31536000".to_vec());
+
+        let mut chain = Chain::new(json);
+        chain.link_after(SecurityHeaders::new(&config, false));
+        let response = request::get("http://localhost:3000/", Headers::new(), &chain).unwrap();
+        assert!(response.headers.get_raw("Strict-Transport-Security").is_none());
+    }
+
+    it "should not add disabled headers" {
+        config.set("security_headers", "frame_options", "");
+        config.set("security_headers", "hsts_max_age", "0");
+        let mut chain = Chain::new(html);
+        chain.link_after(SecurityHeaders::new(&config, true));
+        let response = request::get("http://localhost:3000/", Headers::new(), &chain).unwrap();
+        assert!(response.headers.get_raw("X-Frame-Options").is_none());
+        assert!(response.headers.get_raw("Strict-Transport-Security").is_none());
+        assert!(response.headers.get_raw("Content-Security-Policy").is_some());
+    }
+}
@@ -4,8 +4,14 @@
 
 extern crate rand;
 
+use acl::Acl;
+use api_keys::ApiKeys;
+use audit::AuditLog;
 use config_store::ConfigService;
-use foxbox_users::UsersManager;
+use foxbox_taxonomy::api::User;
+use foxbox_users::{ ReadFilter, SessionToken, UserBuilder, UsersManager };
+use guest_tokens::GuestTokens;
+use network_monitor::NetworkStatus;
 use profile_service::{ ProfilePath, ProfileService };
 use std::vec::IntoIter;
 use serde_json;
@@ -13,16 +19,31 @@ use std::io;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{ Arc, RwLock };
 use std::sync::atomic::AtomicBool;
-use tls::{ CertificateManager, CertificateRecord, SniSslContextProvider };
+use tls::{ CertificateManager, CertificateRecord, ClientAuth, ClientCertificates,
+           SniSslContextProvider };
 use traits::Controller;
+use tunnel_controller::{ TunnelClients, TunnelStatus };
+use tunnel_credentials::TunnelCredentials;
 use upnp::UpnpManager;
 use ws;
+use ws_server::{ WsQueue, WsViewer };
 
 #[derive(Clone)]
 pub struct ControllerStub {
     pub config: Arc<ConfigService>,
+    audit_log: Arc<AuditLog>,
+    acl: Arc<Acl>,
+    api_keys: Arc<ApiKeys>,
+    guest_tokens: Arc<GuestTokens>,
+    client_auth: ClientAuth,
+    tunnel_credentials: Arc<TunnelCredentials>,
+    tunnel_clients: TunnelClients,
+    upnp: Arc<UpnpManager>,
+    users_manager: Arc<UsersManager>,
+    /// The ids of the admin users, or None if every user is an admin.
+    admins: Arc<RwLock<Option<Vec<i32>>>>,
     profile_service: Arc<ProfileService>
 }
 
@@ -30,13 +51,49 @@ impl ControllerStub {
     pub fn new() -> Self {
         let path = format!("/tmp/{}", rand::random::<i32>());
         let profile_service = ProfileService::new(ProfilePath::Custom(path));
+        let config = Arc::new(
+            ConfigService::new(&profile_service.path_for("foxbox.conf"))
+        );
         ControllerStub {
-            config: Arc::new(
-                ConfigService::new(&profile_service.path_for("foxbox.conf"))
+            audit_log: Arc::new(
+                AuditLog::new(&profile_service.path_for("audit_log.sqlite"), &config)
             ),
+            acl: Arc::new(Acl::new(&profile_service.path_for("acl.sqlite"), &config)),
+            api_keys: Arc::new(ApiKeys::new(&profile_service.path_for("api_keys.sqlite"))),
+            guest_tokens: Arc::new(GuestTokens::new(&profile_service.path_for("guest_tokens.sqlite"))),
+            client_auth: ClientAuth::new(
+                Arc::new(ClientCertificates::new(&profile_service.path_for("client_ca"), &config)),
+                &config),
+            tunnel_credentials: Arc::new(
+                TunnelCredentials::new(&profile_service.path_for("tunnel_credential.json"))
+            ),
+            tunnel_clients: TunnelClients::new(),
+            upnp: Arc::new(UpnpManager::new()),
+            users_manager: Arc::new(UsersManager::new(&profile_service.path_for("users_db.sqlite"))),
+            admins: Arc::new(RwLock::new(None)),
+            config: config,
             profile_service: Arc::new(profile_service)
         }
     }
+
+    /// Restricts the admin endpoints to the users in `admins`.
+    pub fn set_admins(&self, admins: Vec<i32>) {
+        *self.admins.write().unwrap() = Some(admins);
+    }
+
+    /// Creates the user `id` and returns a session token signed for them.
+    pub fn session_token(&self, id: i32, name: &str) -> String {
+        let db = self.users_manager.get_db();
+        let user = UserBuilder::new().id(id)
+                                     .name(name.to_owned())
+                                     .email(format!("{}@example.com", name))
+                                     .password("password12345".to_owned())
+                                     .finalize()
+                                     .unwrap();
+        db.create(&user).unwrap();
+        let user = db.read(ReadFilter::Id(id)).unwrap().pop().unwrap();
+        SessionToken::from_user(&user).unwrap()
+    }
 }
 
 impl Default for ControllerStub {
@@ -57,18 +114,43 @@ impl Controller for ControllerStub {
         ("localhost", 4000).to_socket_addrs()
     }
 
-    fn add_websocket(&mut self, socket: ws::Sender) {}
+    fn add_websocket(&mut self, socket: ws::Sender, queue: WsQueue, viewer: WsViewer, since: Option<u64>)
+        -> ws::Result<()> {
+        Ok(())
+    }
     fn remove_websocket(&mut self, socket: ws::Sender) {}
     fn broadcast_to_websockets(&self, data: serde_json::value::Value) {}
+    fn websockets_count(&self) -> usize {
+        0
+    }
 
+    fn get_audit_log(&self) -> Arc<AuditLog> {
+        self.audit_log.clone()
+    }
+    fn get_acl(&self) -> Arc<Acl> {
+        self.acl.clone()
+    }
+    fn get_api_keys(&self) -> Arc<ApiKeys> {
+        self.api_keys.clone()
+    }
+    fn get_guest_tokens(&self) -> Arc<GuestTokens> {
+        self.guest_tokens.clone()
+    }
     fn get_config(&self) -> Arc<ConfigService> {
         self.config.clone()
     }
     fn get_upnp_manager(&self) -> Arc<UpnpManager> {
-        Arc::new(UpnpManager::new())
+        self.upnp.clone()
     }
     fn get_users_manager(&self) -> Arc<UsersManager> {
-        Arc::new(UsersManager::new(&self.profile_service.path_for("unused")))
+        self.users_manager.clone()
+    }
+    fn is_admin(&self, user: &User) -> bool {
+        match (self.admins.read().unwrap().as_ref(), user) {
+            (None, _) => true,
+            (Some(admins), &User::Id(id)) => admins.contains(&id),
+            (Some(_), &User::None) => false
+        }
     }
     fn get_profile(&self) -> &ProfileService {
         &self.profile_service
@@ -81,6 +163,26 @@ impl Controller for ControllerStub {
         String::from("localhost")
     }
 
+    fn get_network_status(&self) -> NetworkStatus {
+        NetworkStatus::default()
+    }
+
+    fn set_network_status(&self, _: NetworkStatus) {}
+
+    fn get_tunnel_status(&self) -> Option<TunnelStatus> {
+        None
+    }
+
+    fn set_tunnel_status(&self, _: Option<TunnelStatus>) {}
+
+    fn get_tunnel_credentials(&self) -> Arc<TunnelCredentials> {
+        self.tunnel_credentials.clone()
+    }
+
+    fn get_tunnel_clients(&self) -> TunnelClients {
+        self.tunnel_clients.clone()
+    }
+
     fn get_box_certificate(&self) -> io::Result<CertificateRecord> {
         CertificateRecord::new_for_test("foxbox.local".to_owned(),
                                         PathBuf::from("a/file.pem"),
@@ -88,6 +190,10 @@ impl Controller for ControllerStub {
                                         "abcdef".to_owned())
     }
 
+    fn get_client_auth(&self) -> ClientAuth {
+        self.client_auth.clone()
+    }
+
     fn get_certificate_manager(&self) -> CertificateManager {
        CertificateManager::new(PathBuf::from(current_dir!()), Box::new(SniSslContextProvider::new()))
     }
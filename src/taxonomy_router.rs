@@ -4,38 +4,170 @@
 
 extern crate serde_json;
 
+use acl::{ Access, Acl, Grant, Scope };
+use api_keys::{ API_KEY_PREFIX, ApiKeyError, ApiKeys, CreatedApiKey, NewApiKey };
+use audit::{ AuditEntry, AuditFilter, AuditLog };
 use foxbox_taxonomy::manager::*;
-use foxbox_taxonomy::api::{ API, Error, TargetMap, User };
+use foxbox_taxonomy::api::{ API, Error, InternalError, TargetMap, Targetted, User };
 use foxbox_taxonomy::values::{ Binary, Value };
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 
 use foxbox_users::AuthEndpoint;
-use foxbox_users::SessionToken;
+use foxbox_users::{ SessionToken, UsersManager };
+
+use guest_tokens::{ CreatedGuestPass, GUEST_TOKEN_PREFIX, GuestTokens, NewGuestPass };
 
 use iron::{ Handler, headers, IronResult, Request, Response };
+use iron::typemap::Key;
 use iron::headers::ContentType;
 use iron::method::Method;
 use iron::prelude::Chain;
 use iron::request::Body;
 use iron::status::Status;
 
+use serde::Serialize;
+use std::fmt;
 use std::io::{ Error as IOError, Read };
 use std::sync::Arc;
+use time;
+use tls::{ CertificateManager, ClientAuth, NewClientCertificate };
 use traits::Controller;
+use tunnel_credentials::{ TunnelCredentialInfo, TunnelCredentials };
+use upnp::UpnpManager;
+use url::form_urlencoded;
+
+/// The endpoints guests can use, with the getters and setters of their pass.
+const GUEST_ENDPOINTS: [&'static str; 4] = [
+    "channels/getters", "channels/setters", "channels/get", "channels/set"
+];
 
 /// This is a specialized Router for the taxonomy API.
 /// It handles all the calls under the api/v1/ url space.
+#[derive(Clone)]
 pub struct TaxonomyRouter {
-    api: Arc<AdapterManager>
+    api: Arc<AdapterManager>,
+    audit: Arc<AuditLog>,
+    acl: Arc<Acl>,
+    api_keys: Arc<ApiKeys>,
+    guest_tokens: Arc<GuestTokens>,
+    client_auth: ClientAuth,
+    certificate_manager: CertificateManager,
+    tunnel_credentials: Arc<TunnelCredentials>,
+    upnp: Arc<UpnpManager>,
+    users_manager: Arc<UsersManager>,
+    is_admin: Arc<Fn(&User) -> bool + Send + Sync>,
+    /// Whether requests need credentials, in which case anonymous callers
+    /// are refused.
+    authentication: bool
+}
+
+/// The user of the client certificate of the connection a request came on,
+/// attached to the request by the `AuthDispatcher`.
+struct ClientCertificateUser;
+
+impl Key for ClientCertificateUser {
+    type Value = i32;
+}
+
+/// The origin of a request.
+struct Caller {
+    user: User,
+    /// The targets the credentials are limited to, if any.
+    scope: Option<Scope>,
+    /// The id of the guest pass, for guests.
+    guest_pass: Option<String>,
+    /// Whether the request was made with delegated credentials (an API key
+    /// or a guest token), which can't be used on admin endpoints.
+    delegated: bool,
+    /// Whether the caller can use the admin endpoints and access everything.
+    admin: bool,
+}
+
+impl Caller {
+    fn with_user(user: User) -> Self {
+        Caller {
+            user: user,
+            scope: None,
+            guest_pass: None,
+            delegated: false,
+            admin: false,
+        }
+    }
+}
+
+/// A service or channel whose access is controlled by the ACL.
+trait AclTarget {
+    fn acl_id(&self) -> String;
+    /// Returns the ids and tags that grants may refer to.
+    fn acl_scope(&self, api: &AdapterManager) -> (Vec<String>, Vec<String>);
+    /// The service recorded with the changes to the target in the audit log.
+    fn service_id(&self) -> String;
+}
+
+impl AclTarget for Service {
+    fn acl_id(&self) -> String {
+        self.id.to_string()
+    }
+
+    fn service_id(&self) -> String {
+        self.id.to_string()
+    }
+
+    fn acl_scope(&self, _: &AdapterManager) -> (Vec<String>, Vec<String>) {
+        let tags = self.tags.iter().map(|tag| tag.to_string()).collect();
+        (vec![self.id.to_string()], tags)
+    }
+}
+
+impl<IO> AclTarget for Channel<IO> where IO: IOMechanism {
+    fn acl_id(&self) -> String {
+        self.id.to_string()
+    }
+
+    fn service_id(&self) -> String {
+        self.service.to_string()
+    }
+
+    // Channels also get the permissions granted on their service.
+    fn acl_scope(&self, api: &AdapterManager) -> (Vec<String>, Vec<String>) {
+        let mut tags: Vec<String> = self.tags.iter().map(|tag| tag.to_string()).collect();
+        for service in api.get_services(vec![ServiceSelector::new().with_id(self.service.clone())]) {
+            tags.extend(service.tags.iter().map(|tag| tag.to_string()));
+        }
+        (vec![self.id.to_string(), self.service.to_string()], tags)
+    }
 }
 
 type GetterResultMap = ResultMap<Id<Getter>, Option<Value>, Error>;
+type SetterResultMap = ResultMap<Id<Setter>, (), Error>;
 
 impl TaxonomyRouter {
-    pub fn new(adapter_api: &Arc<AdapterManager>) -> Self {
+    pub fn new(adapter_api: &Arc<AdapterManager>,
+               audit: Arc<AuditLog>,
+               acl: Arc<Acl>,
+               api_keys: Arc<ApiKeys>,
+               guest_tokens: Arc<GuestTokens>,
+               client_auth: ClientAuth,
+               certificate_manager: CertificateManager,
+               tunnel_credentials: Arc<TunnelCredentials>,
+               upnp: Arc<UpnpManager>,
+               users_manager: Arc<UsersManager>,
+               is_admin: Arc<Fn(&User) -> bool + Send + Sync>,
+               authentication: bool) -> Self {
         TaxonomyRouter {
-            api: adapter_api.clone()
+            api: adapter_api.clone(),
+            audit: audit,
+            acl: acl,
+            api_keys: api_keys,
+            guest_tokens: guest_tokens,
+            client_auth: client_auth,
+            certificate_manager: certificate_manager,
+            tunnel_credentials: tunnel_credentials,
+            upnp: upnp,
+            users_manager: users_manager,
+            is_admin: is_admin,
+            authentication: authentication
         }
     }
 
@@ -62,6 +194,14 @@ impl TaxonomyRouter {
         Ok(response)
     }
 
+    fn build_json_response<S: Serialize>(&self, obj: &S) -> IronResult<Response> {
+        let serialized = itry!(serde_json::to_string(obj));
+        let mut response = Response::with(serialized);
+        response.status = Some(Status::Ok);
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+
     fn build_parse_error(&self, obj: &ParseError) -> IronResult<Response> {
         let mut response = Response::with(format!("{}", obj));
         response.status = Some(Status::BadRequest);
@@ -75,6 +215,441 @@ impl TaxonomyRouter {
         Ok(s)
     }
 
+    // Admin endpoints need an admin user, and are not available to delegated
+    // credentials.
+    fn is_admin_caller(&self, caller: &Caller) -> bool {
+        caller.admin
+    }
+
+    fn audit_entry(caller: &Caller, client: &str, action: &str, payload: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp: time::get_time().sec,
+            user: match caller.user {
+                User::Id(id) => Some(id),
+                User::None => None
+            },
+            guest_pass: caller.guest_pass.clone(),
+            client: client.to_owned(),
+            action: action.to_owned(),
+            service: None,
+            target: String::new(),
+            payload: payload.to_owned(),
+            result: String::new()
+        }
+    }
+
+    // Records one audit entry per setter of the result map. Thinkerbell rules
+    // are managed through setters, which get their own actions.
+    fn audit_setters(&self, caller: &Caller, client: &str, payload: &str, results: &SetterResultMap) {
+        let entries: Vec<AuditEntry> = results.iter().map(|(id, result)| {
+            let channel = self.api.get_setter_channels(vec![SetterSelector::new().with_id(id.clone())])
+                                  .into_iter()
+                                  .next();
+            let action = match channel.as_ref().map(|channel| &channel.mechanism.kind) {
+                Some(&ChannelKind::AddThinkerbellRule) => "add_rule",
+                Some(&ChannelKind::ThinkerbellRuleOn) => "set_rule_enabled",
+                Some(&ChannelKind::RemoveThinkerbellRule) => "remove_rule",
+                _ => "send_values"
+            };
+            AuditEntry {
+                service: channel.map(|channel| channel.service.to_string()),
+                target: id.to_string(),
+                result: match *result {
+                    Ok(_) => "ok".to_owned(),
+                    Err(ref err) => format!("{:?}", err)
+                },
+                .. Self::audit_entry(caller, client, action, payload)
+            }
+        }).collect();
+        self.audit.record(&entries);
+    }
+
+    // Records a change to the configuration of the box, like a grant or a
+    // credential, which belongs to no service.
+    fn audit_change(&self, caller: &Caller, client: &str, action: &str, target: &str, payload: &str,
+                    result: String) {
+        self.audit.record(&[AuditEntry {
+            target: target.to_owned(),
+            result: result,
+            .. Self::audit_entry(caller, client, action, payload)
+        }]);
+    }
+
+    fn audit_result<T, E: fmt::Display>(result: &Result<T, E>) -> String {
+        match *result {
+            Ok(_) => "ok".to_owned(),
+            Err(ref err) => format!("{}", err)
+        }
+    }
+
+    fn get_audit(&self, req: &Request, caller: &Caller) -> IronResult<Response> {
+        if !self.is_admin_caller(caller) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+
+        let mut filter = AuditFilter::default();
+        let query = req.url.query.clone().unwrap_or_else(String::new);
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let valid = match &key as &str {
+                "user" => value.parse().map(|v| filter.user = Some(v)).is_ok(),
+                "service" => { filter.service = Some(value.clone()); true },
+                "from" => value.parse().map(|v| filter.from = Some(v)).is_ok(),
+                "to" => value.parse().map(|v| filter.to = Some(v)).is_ok(),
+                "limit" => value.parse().map(|v| filter.limit = Some(v)).is_ok(),
+                _ => true
+            };
+            if !valid {
+                return Ok(Response::with((Status::BadRequest,
+                                          format!("Invalid value for {}: {}", key, value))));
+            }
+        }
+
+        match self.audit.query(&filter) {
+            Ok(entries) => self.build_json_response(&entries),
+            Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+        }
+    }
+
+    // Returns the id of the user to check permissions for, or None if the
+    // caller's user can access everything. Anonymous callers only get here
+    // when authentication is disabled.
+    fn restricted_user(&self, caller: &Caller) -> Option<i32> {
+        match caller.user {
+            User::Id(id) if !caller.admin && !self.acl.is_unrestricted() => Some(id),
+            _ => None
+        }
+    }
+
+    fn is_restricted(&self, caller: &Caller) -> bool {
+        caller.scope.is_some() || self.restricted_user(caller).is_some()
+    }
+
+    // Splits `targets` into the ones `caller` has `access` on, and the others.
+    fn partition<T: AclTarget>(&self, caller: &Caller, targets: Vec<T>, access: Access)
+        -> (Vec<T>, Vec<T>) {
+        if !self.is_restricted(caller) {
+            return (targets, vec![]);
+        }
+
+        let restricted_user = self.restricted_user(caller);
+        targets.into_iter().partition(|target| {
+            let (ids, tags) = target.acl_scope(&self.api);
+            let in_scope = caller.scope.as_ref().map_or(true, |scope| {
+                scope.allows(access, &ids, &tags)
+            });
+            in_scope && restricted_user.map_or(true, |id| self.acl.access(id, &ids, &tags) >= access)
+        })
+    }
+
+    fn permission_error(id: &str, access: Access) -> Error {
+        Error::InternalError(InternalError::GenericError(
+            format!("Permission denied: no {} access to {}", access.as_str(), id)))
+    }
+
+    // Manages the ACL grants, for admins only.
+    // GET lists the grants, optionally for `?user=<id>`, PUT adds or replaces
+    // the grant in the body and DELETE removes the grant `?user=<id>&target=<target>`.
+    fn manage_permissions(&self, req: &mut Request, caller: &Caller, client: &str) -> IronResult<Response> {
+        if !self.is_admin_caller(caller) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+
+        let mut grant_user = None;
+        let mut target = None;
+        let query = req.url.query.clone().unwrap_or_else(String::new);
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &key as &str {
+                "user" => match value.parse() {
+                    Ok(id) => grant_user = Some(id),
+                    Err(_) => return Ok(Response::with((Status::BadRequest,
+                                                        format!("Invalid user: {}", value))))
+                },
+                "target" => target = Some(value),
+                _ => {}
+            }
+        }
+
+        let result = match req.method {
+            Method::Get => {
+                return match self.acl.get_grants(grant_user) {
+                    Ok(grants) => self.build_json_response(&grants),
+                    Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+                };
+            },
+            Method::Put => {
+                let source = itry!(Self::read_body_to_string(&mut req.body));
+                let grant: Grant = match serde_json::from_str(&source) {
+                    Ok(grant) => grant,
+                    Err(err) => return self.build_parse_error(&ParseError::json(err))
+                };
+                match Access::from_str(&grant.access) {
+                    Some(_) => {},
+                    None => return Ok(Response::with((Status::BadRequest,
+                                                   format!("Invalid access: {}", grant.access))))
+                }
+                let result = self.acl.set_grant(&grant);
+                self.audit_change(caller, client, "set_grant", &grant.target, &source,
+                                  Self::audit_result(&result));
+                result
+            },
+            Method::Delete => {
+                match (grant_user, target) {
+                    (Some(grant_user), Some(target)) => {
+                        let result = self.acl.remove_grant(grant_user, &target);
+                        self.audit_change(caller, client, "remove_grant", &target, &query,
+                                          Self::audit_result(&result));
+                        result
+                    },
+                    _ => return Ok(Response::with((Status::BadRequest,
+                                                   "Expected a user and a target")))
+                }
+            },
+            _ => return Ok(Response::with((Status::MethodNotAllowed,
+                                           format!("Bad method: {}", req.method))))
+        };
+
+        match result {
+            Ok(_) => Ok(Response::with(Status::NoContent)),
+            Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+        }
+    }
+
+    // Manages the API keys, for admins only.
+    // GET lists the keys, POST creates a key for the current user and returns
+    // it, and DELETE revokes the key `?id=<id>`.
+    fn manage_api_keys(&self, req: &mut Request, caller: &Caller, client: &str) -> IronResult<Response> {
+        if !self.is_admin_caller(caller) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+
+        let result = match req.method {
+            Method::Get => self.api_keys.list().map(|keys| self.build_json_response(&keys)),
+            Method::Post => {
+                let user = match caller.user {
+                    User::Id(id) => id,
+                    User::None => return Ok(Response::with((Status::BadRequest,
+                                                            "API keys need an authenticated user")))
+                };
+                let source = itry!(Self::read_body_to_string(&mut req.body));
+                let request: NewApiKey = match serde_json::from_str(&source) {
+                    Ok(request) => request,
+                    Err(err) => return self.build_parse_error(&ParseError::json(err))
+                };
+                let result = self.api_keys.create(request, user, time::get_time().sec);
+                let target = result.as_ref().map(|&(_, ref api_key)| api_key.id.clone())
+                                   .unwrap_or_else(|_| String::new());
+                self.audit_change(caller, client, "create_api_key", &target, &source,
+                                  Self::audit_result(&result));
+                result.map(|(key, api_key)| {
+                    self.build_json_response(&CreatedApiKey { key: key, api_key: api_key })
+                })
+            },
+            Method::Delete => {
+                let id = match Self::query_param(req, "id") {
+                    Some(id) => id,
+                    None => return Ok(Response::with((Status::BadRequest, "Expected an id")))
+                };
+                let result = self.api_keys.revoke(&id);
+                self.audit_change(caller, client, "revoke_api_key", &id, "", Self::audit_result(&result));
+                result.map(|revoked| {
+                    Ok(Response::with(if revoked { Status::NoContent } else { Status::NotFound }))
+                })
+            },
+            _ => return Ok(Response::with((Status::MethodNotAllowed,
+                                           format!("Bad method: {}", req.method))))
+        };
+
+        match result {
+            Ok(response) => response,
+            Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+        }
+    }
+
+    // Manages the guest passes, for admins only.
+    // GET lists the passes that are not expired, POST creates a pass and
+    // returns its token, and DELETE revokes the pass `?id=<id>`.
+    fn manage_guest_passes(&self, req: &mut Request, caller: &Caller, client: &str) -> IronResult<Response> {
+        if !self.is_admin_caller(caller) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+
+        let now = time::get_time().sec;
+        let result = match req.method {
+            Method::Get => self.guest_tokens.list(now).map(|passes| self.build_json_response(&passes)),
+            Method::Post => {
+                let source = itry!(Self::read_body_to_string(&mut req.body));
+                let request: NewGuestPass = match serde_json::from_str(&source) {
+                    Ok(request) => request,
+                    Err(err) => return self.build_parse_error(&ParseError::json(err))
+                };
+                if request.expires <= request.not_before.unwrap_or(now) {
+                    return Ok(Response::with((Status::BadRequest,
+                                              "A pass must expire after it becomes valid")));
+                }
+                let result = self.guest_tokens.create(request, now);
+                let target = result.as_ref().map(|&(_, ref pass)| pass.id.clone())
+                                   .unwrap_or_else(|_| String::new());
+                self.audit_change(caller, client, "create_guest_pass", &target, &source,
+                                  Self::audit_result(&result));
+                result.map(|(token, pass)| {
+                    self.build_json_response(&CreatedGuestPass { token: token, pass: pass })
+                })
+            },
+            Method::Delete => {
+                let id = match Self::query_param(req, "id") {
+                    Some(id) => id,
+                    None => return Ok(Response::with((Status::BadRequest, "Expected an id")))
+                };
+                let result = self.guest_tokens.revoke(&id);
+                self.audit_change(caller, client, "revoke_guest_pass", &id, "", Self::audit_result(&result));
+                result.map(|revoked| {
+                    Ok(Response::with(if revoked { Status::NoContent } else { Status::NotFound }))
+                })
+            },
+            _ => return Ok(Response::with((Status::MethodNotAllowed,
+                                           format!("Bad method: {}", req.method))))
+        };
+
+        match result {
+            Ok(response) => response,
+            Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+        }
+    }
+
+    // Manages the tunnel credential of the box, for admins only.
+    // GET describes the credential, without its secret, and POST replaces the
+    // secret, reconnecting the tunnel to enroll the new one.
+    fn manage_tunnel_credential(&self, req: &mut Request, caller: &Caller, client: &str)
+        -> IronResult<Response> {
+        if !self.is_admin_caller(caller) || caller.delegated {
+            return Ok(Response::with(Status::Forbidden));
+        }
+
+        let fingerprint = match self.certificate_manager.get_box_certificate() {
+            Ok(certificate) => certificate.get_certificate_fingerprint(),
+            Err(err) => return Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+        };
+        let result = match req.method {
+            Method::Get => self.tunnel_credentials.get(&fingerprint),
+            Method::Post => {
+                let result = self.tunnel_credentials.rotate(&fingerprint);
+                self.audit_change(caller, client, "rotate_tunnel_credential", &fingerprint, "",
+                                  Self::audit_result(&result));
+                result
+            },
+            _ => return Ok(Response::with((Status::MethodNotAllowed,
+                                           format!("Bad method: {}", req.method))))
+        };
+
+        match result {
+            Ok(credential) => self.build_json_response(&TunnelCredentialInfo::from(&credential)),
+            Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+        }
+    }
+
+    // Reports the UPnP devices on the network, for admins only. GET lists the
+    // devices known from their advertisements, and POST starts a new search
+    // without waiting for the answers, which GET lists once they arrived.
+    fn manage_upnp_devices(&self, req: &mut Request, caller: &Caller) -> IronResult<Response> {
+        if !self.is_admin_caller(caller) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+
+        match req.method {
+            Method::Get => self.build_json_response(&self.upnp.get_devices()),
+            Method::Post => {
+                if self.upnp.rescan("ssdp:all") {
+                    Ok(Response::with(Status::Accepted))
+                } else {
+                    Ok(Response::with((Status::ServiceUnavailable, "UPnP discovery isn't running")))
+                }
+            },
+            _ => Ok(Response::with((Status::MethodNotAllowed, format!("Bad method: {}", req.method))))
+        }
+    }
+
+    // Manages the client certificates. Users list their own certificates, and
+    // admins all of them. POST issues a certificate for the current user and
+    // returns it as a PKCS#12 bundle protected by the given password, and
+    // DELETE revokes the certificate `?id=<id>`.
+    fn manage_client_certificates(&self, req: &mut Request, caller: &Caller, client: &str)
+        -> IronResult<Response> {
+        let user = match caller.user {
+            User::Id(id) if !caller.delegated => id,
+            _ => return Ok(Response::with(Status::Forbidden))
+        };
+        let is_admin = self.is_admin_caller(caller);
+        let certificates = &self.client_auth.certificates;
+
+        match req.method {
+            Method::Get => {
+                match certificates.list(if is_admin { None } else { Some(user) }) {
+                    Ok(list) => self.build_json_response(&list),
+                    Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+                }
+            },
+            Method::Post => {
+                let source = itry!(Self::read_body_to_string(&mut req.body));
+                let request: NewClientCertificate = match serde_json::from_str(&source) {
+                    Ok(request) => request,
+                    Err(err) => return self.build_parse_error(&ParseError::json(err))
+                };
+                // The payload holds the password of the bundle, and isn't recorded.
+                let result = certificates.issue(request, user, time::get_time().sec);
+                let target = result.as_ref().map(|&(ref certificate, _)| certificate.id.clone())
+                                   .unwrap_or_else(|_| String::new());
+                self.audit_change(caller, client, "issue_client_certificate", &target, "",
+                                  Self::audit_result(&result));
+                match result {
+                    Ok((certificate, pkcs12)) => {
+                        let mut response = Response::with((Status::Ok, pkcs12));
+                        response.headers.set(ContentType("application/x-pkcs12".parse().unwrap()));
+                        response.headers.set_raw("Content-Disposition", vec![
+                            format!("attachment; filename=\"{}.p12\"", certificate.id).into_bytes()
+                        ]);
+                        Ok(response)
+                    },
+                    Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+                }
+            },
+            Method::Delete => {
+                let id = match Self::query_param(req, "id") {
+                    Some(id) => id,
+                    None => return Ok(Response::with((Status::BadRequest, "Expected an id")))
+                };
+                let result = certificates.get(&id).and_then(|certificate| {
+                    match certificate {
+                        None => Ok(Status::NotFound),
+                        Some(ref certificate) if certificate.user != user && !is_admin => {
+                            Ok(Status::Forbidden)
+                        },
+                        Some(_) => {
+                            let result = certificates.revoke(&id);
+                            self.audit_change(caller, client, "revoke_client_certificate", &id, "",
+                                              Self::audit_result(&result));
+                            result.map(|revoked| {
+                                if revoked { Status::NoContent } else { Status::NotFound }
+                            })
+                        }
+                    }
+                });
+                match result {
+                    Ok(status) => Ok(Response::with(status)),
+                    Err(err) => Ok(Response::with((Status::InternalServerError, format!("{}", err))))
+                }
+            },
+            _ => Ok(Response::with((Status::MethodNotAllowed,
+                                    format!("Bad method: {}", req.method))))
+        }
+    }
+
+    fn query_param(req: &Request, name: &str) -> Option<String> {
+        let query = req.url.query.clone().unwrap_or_else(String::new);
+        form_urlencoded::parse(query.as_bytes()).into_iter()
+                                                .find(|&(ref key, _)| key == name)
+                                                .map(|(_, value)| value)
+    }
+
     // Checks if a getter result map is a binary payload.
     fn get_binary(&self, map: &GetterResultMap) -> Option<Binary> {
         // For now, consider as binary a result map with a single element that
@@ -104,21 +679,77 @@ impl Handler for TaxonomyRouter {
 
     #[allow(cyclomatic_complexity)]
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        let user: User = match req.headers.clone().get::
+        // We are handling urls relative to the mounter set up in http_server.rs
+        // That means that for a full url like http://localhost/api/v1/services
+        // the req.url.path will only contain ["services"]
+        let path = req.url.path.clone();
+        let client = format!("{}", req.remote_addr);
+
+        let caller = match req.headers.clone().get::
             <headers::Authorization<headers::Bearer>>() {
-            Some(&headers::Authorization(headers::Bearer { ref token })) => {
+            Some(&headers::Authorization(headers::Bearer { ref token }))
+                if token.starts_with(API_KEY_PREFIX) => {
+                match self.api_keys.verify(token, Some(req.remote_addr.ip()), &path.join("/"),
+                                           time::get_time().sec) {
+                    Ok(api_key) => Caller {
+                        user: User::Id(api_key.user),
+                        scope: api_key.scope(),
+                        guest_pass: None,
+                        delegated: true,
+                        admin: false
+                    },
+                    Err(ApiKeyError::ForbiddenAddress) |
+                    Err(ApiKeyError::ForbiddenEndpoint) => return Ok(Response::with(Status::Forbidden)),
+                    Err(_) => return Ok(Response::with(Status::Unauthorized))
+                }
+            },
+            Some(&headers::Authorization(headers::Bearer { ref token }))
+                if token.starts_with(GUEST_TOKEN_PREFIX) => {
+                let endpoint = path.join("/");
+                if !GUEST_ENDPOINTS.iter().any(|allowed| *allowed == endpoint) {
+                    return Ok(Response::with(Status::Forbidden));
+                }
+                match self.guest_tokens.verify(token, time::get_time().sec) {
+                    // Guests can read the getters and write the setters of their pass.
+                    Ok(pass) => Caller {
+                        user: User::None,
+                        scope: Some(Scope { read: pass.getters, write: pass.setters }),
+                        guest_pass: Some(pass.id),
+                        delegated: true,
+                        admin: false
+                    },
+                    Err(_) => return Ok(Response::with(Status::Unauthorized))
+                }
+            },
+            // Only the signature makes the id of the token trustworthy.
+            Some(&headers::Authorization(headers::Bearer { ref token }))
+                if self.users_manager.verify_token(token).is_ok() => {
                 match SessionToken::from_string(token) {
-                    Ok(token) => User::Id(token.claims.id),
+                    Ok(token) => Caller::with_user(User::Id(token.claims.id)),
                     Err(_) => return Ok(Response::with(Status::Unauthorized))
                 }
             },
-            _ => User::None
+            Some(&headers::Authorization(headers::Bearer { .. })) => {
+                return Ok(Response::with(Status::Unauthorized));
+            },
+            // Connections made with a client certificate act as its user.
+            _ => match req.extensions.get::<ClientCertificateUser>() {
+                Some(&id) => Caller::with_user(User::Id(id)),
+                None => Caller::with_user(User::None)
+            }
         };
-
-        // We are handling urls relative to the mounter set up in http_server.rs
-        // That means that for a full url like http://localhost/api/v1/services
-        // the req.url.path will only contain ["services"]
-        let path = req.url.path.clone();
+        // Anonymous requests only get this far on the paths the session
+        // middleware doesn't cover.
+        if self.authentication && !caller.delegated {
+            if let User::None = caller.user {
+                return Ok(Response::with(Status::Unauthorized));
+            }
+        }
+        let caller = Caller {
+            admin: !caller.delegated && (self.is_admin)(&caller.user),
+            .. caller
+        };
+        let user = caller.user.clone();
 
         /// Generates the code for a generic HTTP call, where we use an empty
         /// taxonomy selector for GET requests, and a decoded json body for POST ones.
@@ -133,14 +764,20 @@ impl Handler for TaxonomyRouter {
                         Method::Get => {
                             // On a GET, just send the full taxonomy content for
                             // this kind of selector.
-                            self.build_response(&self.api.$call(vec![$sel::new()]))
+                            let (readable, _) = self.partition(&caller,
+                                self.api.$call(vec![$sel::new()]), Access::Read);
+                            self.build_response(&readable)
                         },
                         Method::Post => {
                             let source = itry!(Self::read_body_to_string(&mut req.body));
                             match Path::new().push_str("body",
                                 |path| Vec::<$sel>::from_str_at(path, &source as &str))
                             {
-                                Ok(arg) => self.build_response(&self.api.$call(arg)),
+                                Ok(arg) => {
+                                    let (readable, _) = self.partition(&caller,
+                                        self.api.$call(arg), Access::Read);
+                                    self.build_response(&readable)
+                                },
                                 Err(err) => self.build_parse_error(&err)
                             }
                         },
@@ -151,44 +788,11 @@ impl Handler for TaxonomyRouter {
             })
         }
 
-        macro_rules! simple {
-            ($api:ident, $arg:ident, $call:ident) => (self.build_response(&$api.$call($arg, user)))
-        }
-
-        macro_rules! binary {
-            ($api:ident, $arg:ident, $call:ident) => ({
-                        let res = $api.$call($arg, user);
-                        if let Some(payload) = self.get_binary(&res) {
-                            self.build_binary_response(&payload)
-                        } else {
-                            self.build_response(&res)
-                        }
-                    })
-        }
-
         // Generates the code to process a given HTTP call with a json body.
-        macro_rules! payload_api {
-            ($call:ident, $param:ty, $path:expr, $method:expr, $action:ident) => (
-                if path == $path && req.method == $method {
-                    type Selectors = $param;
-                    return {
-                        let api = &self.api;
-                        let source = itry!(Self::read_body_to_string(&mut req.body));
-                        match Path::new().push_str("body",
-                            |path| Selectors::from_str_at(path, &source as &str))
-                        {
-                            Ok(arg) => $action!(api, arg, $call),
-                            Err(err) => self.build_parse_error(&err)
-                        }
-                    }
-                }
-            )
-        }
-
-        // Generates the code to process a given HTTP call with a json body.
-        // This version takes 2 parameters for the internal call.
+        // This version takes 2 parameters for the internal call, the first
+        // one selecting targets that $resolve returns for permission checks.
         macro_rules! payload_api2 {
-            ($call:ident, $name1:ident => $param1:ty, $name2:ident => $param2:ty, $path:expr, $method:expr) => (
+            ($call:ident, $resolve:ident, $name1:ident => $param1:ty, $name2:ident => $param2:ty, $path:expr, $method:expr) => (
                 if path == $path && req.method == $method {
                     type Param1 = $param1;
                     type Param2 = $param2;
@@ -208,7 +812,23 @@ impl Handler for TaxonomyRouter {
                             Err(err) => return self.build_parse_error(&err),
                             Ok(val) => val
                         };
-                        self.build_response(&self.api.$call(arg_1, arg_2))
+                        let (allowed, denied) = self.partition(&caller, self.api.$resolve(arg_1.clone()),
+                                                               Access::Write);
+                        if !denied.is_empty() {
+                            let ids: Vec<String> = denied.iter().map(|target| target.acl_id()).collect();
+                            return Ok(Response::with((Status::Forbidden,
+                                format!("Permission denied: no write access to {}", ids.join(", ")))));
+                        }
+                        let result = self.api.$call(arg_1, arg_2);
+                        // Tags can't fail to be set on the targets that exist.
+                        let entries: Vec<AuditEntry> = allowed.iter().map(|target| AuditEntry {
+                            service: Some(target.service_id()),
+                            target: target.acl_id(),
+                            result: "ok".to_owned(),
+                            .. Self::audit_entry(&caller, &client, stringify!($call), &source)
+                        }).collect();
+                        self.audit.record(&entries);
+                        self.build_response(&result)
                     }
                 }
             )
@@ -224,68 +844,237 @@ impl Handler for TaxonomyRouter {
         // Fetching and getting values.
         // We can't use a GET http method here because the Fetch() DOM api
         // doesn't allow bodies with GET and HEAD requests.
-        payload_api!(fetch_values, Vec<GetterSelector>, ["channels", "get"], Method::Put, binary);
-        payload_api!(send_values, TargetMap<SetterSelector, Value>, ["channels", "set"], Method::Put, simple);
+        // Getters the user is not allowed to read get a permission error.
+        if path == ["channels", "get"] && req.method == Method::Put {
+            type Selectors = Vec<GetterSelector>;
+            let source = itry!(Self::read_body_to_string(&mut req.body));
+            return match Path::new().push_str("body",
+                |path| Selectors::from_str_at(path, &source as &str))
+            {
+                Ok(arg) => {
+                    let (allowed, denied) = self.partition(&caller,
+                        self.api.get_getter_channels(arg.clone()), Access::Read);
+                    let mut res = if denied.is_empty() {
+                        self.api.fetch_values(arg, user)
+                    } else {
+                        let selectors = allowed.into_iter().map(|channel| {
+                            GetterSelector::new().with_id(channel.id)
+                        }).collect();
+                        self.api.fetch_values(selectors, user)
+                    };
+                    for channel in denied {
+                        let err = Self::permission_error(&channel.acl_id(), Access::Read);
+                        res.insert(channel.id, Err(err));
+                    }
+
+                    if let Some(payload) = self.get_binary(&res) {
+                        self.build_binary_response(&payload)
+                    } else {
+                        self.build_response(&res)
+                    }
+                },
+                Err(err) => self.build_parse_error(&err)
+            };
+        }
+
+        // Setting values is recorded in the audit log, with a result per setter.
+        // Setters the user is not allowed to write get a permission error.
+        if path == ["channels", "set"] && req.method == Method::Put {
+            type Selectors = TargetMap<SetterSelector, Value>;
+            let source = itry!(Self::read_body_to_string(&mut req.body));
+            return match Path::new().push_str("body",
+                |path| Selectors::from_str_at(path, &source as &str))
+            {
+                Ok(arg) => {
+                    let mut denied = vec![];
+                    let arg = if !self.is_restricted(&caller) {
+                        arg
+                    } else {
+                        arg.into_iter().map(|target| {
+                            let (allowed, forbidden) = self.partition(&caller,
+                                self.api.get_setter_channels(target.select.clone()), Access::Write);
+                            denied.extend(forbidden);
+                            Targetted {
+                                select: allowed.into_iter().map(|channel| {
+                                    SetterSelector::new().with_id(channel.id)
+                                }).collect(),
+                                payload: target.payload
+                            }
+                        }).collect()
+                    };
+                    let mut result = self.api.send_values(arg, user.clone());
+                    for channel in denied {
+                        let err = Self::permission_error(&channel.acl_id(), Access::Write);
+                        result.insert(channel.id, Err(err));
+                    }
+                    self.audit_setters(&caller, &client, &source, &result);
+                    self.build_response(&result)
+                },
+                Err(err) => self.build_parse_error(&err)
+            };
+        }
 
         // Adding tags.
-        payload_api2!(add_service_tags,
+        payload_api2!(add_service_tags, get_services,
                       services => Vec<ServiceSelector>,
                       tags => Vec<Id<TagId>>,
                       ["services", "tags"], Method::Post);
-        payload_api2!(add_getter_tags,
+        payload_api2!(add_getter_tags, get_getter_channels,
                       getters => Vec<GetterSelector>,
                       tags => Vec<Id<TagId>>,
                       ["channels", "getter", "tags"], Method::Post);
-        payload_api2!(add_setter_tags,
+        payload_api2!(add_setter_tags, get_setter_channels,
                       setters => Vec<SetterSelector>,
                       tags => Vec<Id<TagId>>,
                       ["channels", "setter", "tags"], Method::Post);
 
         // Removing tags.
-        payload_api2!(remove_service_tags,
+        payload_api2!(remove_service_tags, get_services,
                       services => Vec<ServiceSelector>,
                       tags => Vec<Id<TagId>>,
                       ["services", "tags"], Method::Delete);
-        payload_api2!(remove_getter_tags,
+        payload_api2!(remove_getter_tags, get_getter_channels,
                       getters => Vec<GetterSelector>,
                       tags => Vec<Id<TagId>>,
                       ["channels", "getter", "tags"], Method::Delete);
-        payload_api2!(remove_setter_tags,
+        payload_api2!(remove_setter_tags, get_setter_channels,
                       setters => Vec<SetterSelector>,
                       tags => Vec<Id<TagId>>,
                       ["channels", "setter", "tags"], Method::Delete);
 
+        // Audit log, for admins only.
+        if path == ["audit"] && req.method == Method::Get {
+            return self.get_audit(req, &caller);
+        }
+
+        // Permissions management, for admins only.
+        if path == ["permissions"] {
+            return self.manage_permissions(req, &caller, &client);
+        }
+
+        // API keys management, for admins only.
+        if path == ["keys"] {
+            return self.manage_api_keys(req, &caller, &client);
+        }
+
+        // Guest passes management, for admins only.
+        if path == ["guests"] {
+            return self.manage_guest_passes(req, &caller, &client);
+        }
+
+        // Client certificates management.
+        if path == ["client-certificates"] {
+            return self.manage_client_certificates(req, &caller, &client);
+        }
+
+        // Tunnel credential management, for admins only.
+        if path == ["tunnel", "credential"] {
+            return self.manage_tunnel_credential(req, &caller, &client);
+        }
+
+        // UPnP devices report and rescan, for admins only.
+        if path == ["upnp", "devices"] {
+            return self.manage_upnp_devices(req, &caller);
+        }
+
         // Fallthrough, returning a 404.
         Ok(Response::with((Status::NotFound,
                            format!("Unknown url: {}", req.url))))
     }
 }
 
+/// The (http methods, url path) of the handle() method, which need to be
+/// authenticated.
+fn auth_endpoints() -> Vec<AuthEndpoint> {
+    // Keep this list in sync with all the (url path, http method) from
+    // the handle() method and with the CORS chain in http_server.rs
+    vec![
+        AuthEndpoint(vec![Method::Get, Method::Post], "services".to_owned()),
+        AuthEndpoint(vec![Method::Post, Method::Delete], "services/tags".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post], "channels/getters".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post], "channels/setters".to_owned()),
+        AuthEndpoint(vec![Method::Put], "channels/get".to_owned()),
+        AuthEndpoint(vec![Method::Put], "channels/set".to_owned()),
+        AuthEndpoint(vec![Method::Post, Method::Delete], "channels/getter/tags".to_owned()),
+        AuthEndpoint(vec![Method::Post, Method::Delete], "channels/setter/tags".to_owned()),
+        AuthEndpoint(vec![Method::Get], "audit".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Put, Method::Delete], "permissions".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post, Method::Delete], "keys".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post, Method::Delete], "guests".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post, Method::Delete],
+                     "client-certificates".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post], "tunnel/credential".to_owned()),
+        AuthEndpoint(vec![Method::Get, Method::Post], "upnp/devices".to_owned())
+    ]
+}
+
 pub fn create<T>(controller: T, adapter_api: &Arc<AdapterManager>) -> Chain
     where T: Controller {
-    let router = TaxonomyRouter::new(adapter_api);
-
-    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
-        // Keep this list in sync with all the (url path, http method) from
-        // the handle() method and with the CORS chain in http_server.rs
-        vec![
-            AuthEndpoint(vec![Method::Get, Method::Post], "services".to_owned()),
-            AuthEndpoint(vec![Method::Post, Method::Delete], "services/tags".to_owned()),
-            AuthEndpoint(vec![Method::Get, Method::Post], "channels/getters".to_owned()),
-            AuthEndpoint(vec![Method::Get, Method::Post], "channels/setters".to_owned()),
-            AuthEndpoint(vec![Method::Get], "channels/get".to_owned()),
-            AuthEndpoint(vec![Method::Put], "channels/set".to_owned()),
-            AuthEndpoint(vec![Method::Post, Method::Delete], "channel/getters/tags".to_owned()),
-            AuthEndpoint(vec![Method::Post, Method::Delete], "channel/setters/tags".to_owned())
-        ]
+    create_with_authentication(controller, adapter_api,
+                               cfg!(feature = "authentication") && !cfg!(test))
+}
+
+fn create_with_authentication<T>(controller: T, adapter_api: &Arc<AdapterManager>,
+                                 authentication: bool) -> Chain
+    where T: Controller {
+    let admins = controller.clone();
+    let router = TaxonomyRouter::new(adapter_api,
+                                     controller.get_audit_log(),
+                                     controller.get_acl(),
+                                     controller.get_api_keys(),
+                                     controller.get_guest_tokens(),
+                                     controller.get_client_auth(),
+                                     controller.get_certificate_manager(),
+                                     controller.get_tunnel_credentials(),
+                                     controller.get_upnp_manager(),
+                                     controller.get_users_manager(),
+                                     Arc::new(move |user: &User| admins.is_admin(user)),
+                                     authentication);
+
+    let auth_endpoints = if authentication {
+        auth_endpoints()
     } else {
         vec![]
     };
 
-    let mut chain = Chain::new(router);
-    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+    let mut authenticated = Chain::new(router.clone());
+    authenticated.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    Chain::new(AuthDispatcher {
+        router: router,
+        authenticated: authenticated,
+        client_auth: controller.get_client_auth()
+    })
+}
 
-    chain
+/// Sends the requests made with API keys, guest tokens or client certificates
+/// straight to the router, which verifies them itself, and the other ones
+/// through the session tokens middleware. The user of the client certificate
+/// comes from the TLS connection of the request, not from its address.
+struct AuthDispatcher {
+    router: TaxonomyRouter,
+    authenticated: Chain,
+    client_auth: ClientAuth
+}
+
+impl Handler for AuthDispatcher {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Some(id) = self.client_auth.connection_user(time::get_time().sec) {
+            req.extensions.insert::<ClientCertificateUser>(id);
+        }
+        let direct = match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+            Some(&headers::Authorization(headers::Bearer { ref token })) => {
+                token.starts_with(API_KEY_PREFIX) || token.starts_with(GUEST_TOKEN_PREFIX)
+            },
+            _ => req.extensions.contains::<ClientCertificateUser>()
+        };
+
+        if direct {
+            self.router.handle(req)
+        } else {
+            self.authenticated.handle(req)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -304,8 +1093,9 @@ describe! taxonomy_router {
         let taxo_manager = Arc::new(AdapterManager::new(None));
         clock::Clock::init(&taxo_manager).unwrap();
 
+        let controller = ControllerStub::new();
         let mut mount = Mount::new();
-        mount.mount("/api/v1", create(ControllerStub::new(), &taxo_manager));
+        mount.mount("/api/v1", create(controller.clone(), &taxo_manager));
     }
 
     it "should return the list of services from a GET request" {
@@ -328,6 +1118,359 @@ describe! taxonomy_router {
 
         assert_eq!(body, s);
     }
+
+    it "should record tag changes in the audit log" {
+        use audit::AuditFilter;
+        use traits::Controller;
+
+        let controller = ControllerStub::new();
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller.clone(), &taxo_manager));
+
+        request::post("http://localhost:3000/api/v1/services/tags",
+                      Headers::new(),
+                      r#"{"services":[{"id":"service:clock@link.mozilla.org"}],"tags":["kitchen"]}"#,
+                      &mount).unwrap();
+
+        let entries = controller.get_audit_log().query(&AuditFilter::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "add_service_tags");
+        assert_eq!(entries[0].target, "service:clock@link.mozilla.org");
+        assert_eq!(entries[0].service, Some("service:clock@link.mozilla.org".to_owned()));
+        assert_eq!(entries[0].result, "ok");
+
+        let response = request::get("http://localhost:3000/api/v1/audit?service=none",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body, "[]");
+    }
+
+    it "should record configuration changes in the audit log" {
+        use audit::AuditFilter;
+        use traits::Controller;
+
+        request::put("http://localhost:3000/api/v1/permissions",
+                     Headers::new(),
+                     r#"{"user":2,"target":"tag:kids-room","access":"read"}"#,
+                     &mount).unwrap();
+        request::delete("http://localhost:3000/api/v1/permissions?user=2&target=tag%3Akids-room",
+                        Headers::new(),
+                        &mount).unwrap();
+
+        let entries = controller.get_audit_log().query(&AuditFilter::default()).unwrap();
+        let mut actions: Vec<(String, String, String)> = entries.into_iter().map(|entry| {
+            (entry.action, entry.target, entry.result)
+        }).collect();
+        actions.sort();
+        assert_eq!(actions, vec![
+            ("remove_grant".to_owned(), "tag:kids-room".to_owned(), "ok".to_owned()),
+            ("set_grant".to_owned(), "tag:kids-room".to_owned(), "ok".to_owned()),
+        ]);
+    }
+
+    it "should only show the audit log to admins" {
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+
+        let controller = ControllerStub::new();
+        controller.set_admins(vec![2]);
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller.clone(), &taxo_manager));
+
+        let token = controller.session_token(3, "user");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::get("http://localhost:3000/api/v1/audit", headers, &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+
+        let token = controller.session_token(2, "admin");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::get("http://localhost:3000/api/v1/audit", headers, &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+    }
+
+    it "should refuse forged session tokens" {
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+
+        // Token payload is { "id": 2, "name": "admin" }, signed with another secret.
+        let token = "eyJ0eXAiOiJKV1QiLCJraWQiOm51bGwsImFsZyI6IkhTMjU2In0.eyJpZCI\
+                     6MiwibmFtZSI6ImFkbWluIn0.JNtvokupDl2hdqB+vER15y89qigPc4FviZfJOSR1Vso";
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::get("http://localhost:3000/api/v1/audit", headers, &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Unauthorized);
+    }
+
+    it "should require authentication on every route" {
+        use iron::method::Method;
+        use iron::status::Status;
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create_with_authentication(controller.clone(), &taxo_manager, true));
+
+        let routes = [
+            (Method::Get, "services"), (Method::Post, "services"),
+            (Method::Post, "services/tags"), (Method::Delete, "services/tags"),
+            (Method::Get, "channels/getters"), (Method::Post, "channels/getters"),
+            (Method::Get, "channels/setters"), (Method::Post, "channels/setters"),
+            (Method::Put, "channels/get"), (Method::Put, "channels/set"),
+            (Method::Post, "channels/getter/tags"), (Method::Delete, "channels/getter/tags"),
+            (Method::Post, "channels/setter/tags"), (Method::Delete, "channels/setter/tags"),
+            (Method::Get, "audit"),
+            (Method::Get, "permissions"), (Method::Put, "permissions"), (Method::Delete, "permissions"),
+            (Method::Get, "keys"), (Method::Post, "keys"), (Method::Delete, "keys"),
+            (Method::Get, "guests"), (Method::Post, "guests"), (Method::Delete, "guests"),
+            (Method::Get, "client-certificates"), (Method::Post, "client-certificates"),
+            (Method::Delete, "client-certificates"),
+            (Method::Get, "tunnel/credential"), (Method::Post, "tunnel/credential"),
+            (Method::Get, "upnp/devices"), (Method::Post, "upnp/devices")
+        ];
+        let unprotected: Vec<String> = routes.iter().filter(|&&(ref method, path)| {
+            let url = format!("http://localhost:3000/api/v1/{}", path);
+            let result = match *method {
+                Method::Get => request::get(&url, Headers::new(), &mount),
+                Method::Post => request::post(&url, Headers::new(), "{}", &mount),
+                Method::Put => request::put(&url, Headers::new(), "{}", &mount),
+                _ => request::delete(&url, Headers::new(), &mount)
+            };
+            let status = match result {
+                Ok(response) => response.status,
+                Err(err) => err.response.status
+            };
+            status != Some(Status::Unauthorized)
+        }).map(|&(ref method, path)| format!("{} {}", method, path)).collect();
+        assert_eq!(unprotected, Vec::<String>::new());
+    }
+
+    it "should manage permission grants" {
+        use iron::status::Status;
+
+        let response = request::put("http://localhost:3000/api/v1/permissions",
+                                    Headers::new(),
+                                    r#"{"user":2,"target":"tag:kids-room","access":"read"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NoContent);
+
+        let response = request::put("http://localhost:3000/api/v1/permissions",
+                                    Headers::new(),
+                                    r#"{"user":2,"target":"tag:kids-room","access":"all"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+
+        let response = request::get("http://localhost:3000/api/v1/permissions?user=2",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body, r#"[{"user":2,"target":"tag:kids-room","access":"read"}]"#);
+
+        request::delete("http://localhost:3000/api/v1/permissions?user=2&target=tag%3Akids-room",
+                        Headers::new(),
+                        &mount).unwrap();
+        let response = request::get("http://localhost:3000/api/v1/permissions",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body, "[]");
+    }
+
+    it "should enforce the permissions of users who are not admins" {
+        use acl::Grant;
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+        use traits::Controller;
+
+        let controller = ControllerStub::new();
+        controller.set_admins(vec![2]);
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller.clone(), &taxo_manager));
+
+        let token = controller.session_token(3, "user");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+
+        for path in &["permissions", "keys", "guests", "upnp/devices"] {
+            let response = request::get(&format!("http://localhost:3000/api/v1/{}", path),
+                                        headers.clone(),
+                                        &mount).unwrap();
+            assert_eq!(response.status.unwrap(), Status::Forbidden);
+        }
+
+        // Without grants, users get the default write access.
+        let response = request::get("http://localhost:3000/api/v1/channels/getters",
+                                    headers.clone(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let getters: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(getters.as_array().unwrap().len(), 3);
+
+        controller.get_acl().set_grant(&Grant {
+            user: 3,
+            target: "service:clock@link.mozilla.org".to_owned(),
+            access: "none".to_owned()
+        }).unwrap();
+
+        let response = request::get("http://localhost:3000/api/v1/channels/getters",
+                                    headers.clone(),
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "[]");
+
+        let response = request::put("http://localhost:3000/api/v1/channels/get",
+                                    headers.clone(),
+                                    r#"[{"id":"getter:timestamp.clock@link.mozilla.org"}]"#,
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert!(body.contains("Permission denied: no read access to getter:timestamp.clock@link.mozilla.org"));
+
+        let response = request::post("http://localhost:3000/api/v1/services/tags",
+                                     headers,
+                                     r#"{"services":[{"id":"service:clock@link.mozilla.org"}],"tags":["kitchen"]}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+    }
+
+    it "should accept scoped API keys" {
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+
+        let token = controller.session_token(2, "admin");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::post("http://localhost:3000/api/v1/keys",
+                                     headers,
+                                     r#"{"name":"tablet","endpoints":["services"]}"#,
+                                     &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let created: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let key = created.find("key").unwrap().as_string().unwrap().to_owned();
+        assert_eq!(created.lookup("api_key.user").unwrap().as_i64(), Some(2));
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: key.clone() }));
+        let response = request::get("http://localhost:3000/api/v1/services",
+                                    headers.clone(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+
+        let response = request::get("http://localhost:3000/api/v1/channels/getters",
+                                    headers.clone(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: "fbk_unknown".to_owned() }));
+        let response = request::get("http://localhost:3000/api/v1/services",
+                                    headers,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Unauthorized);
+    }
+
+    it "should limit guests to the channels of their pass" {
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+
+        let response = request::post("http://localhost:3000/api/v1/guests",
+                                     Headers::new(),
+                                     r#"{"name":"cleaner","getters":["getter:timestamp.clock@link.mozilla.org"],"expires":4000000000}"#,
+                                     &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let created: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let token = created.find("token").unwrap().as_string().unwrap().to_owned();
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token }));
+        let response = request::get("http://localhost:3000/api/v1/channels/getters",
+                                    headers.clone(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let getters: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(getters.as_array().unwrap().len(), 1);
+
+        let response = request::get("http://localhost:3000/api/v1/services",
+                                    headers,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+
+        let response = request::post("http://localhost:3000/api/v1/guests",
+                                     Headers::new(),
+                                     r#"{"name":"late","not_before":200,"expires":100}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+
+    it "should issue and revoke client certificates" {
+        use iron::headers::{ Authorization, Bearer, ContentType };
+        use iron::status::Status;
+
+        let response = request::get("http://localhost:3000/api/v1/client-certificates",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+
+        let token = controller.session_token(2, "admin");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::post("http://localhost:3000/api/v1/client-certificates",
+                                     headers.clone(),
+                                     r#"{"name":"phone","password":"secret"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+        assert_eq!(response.headers.get::<ContentType>().unwrap().to_string(),
+                   "application/x-pkcs12");
+
+        let response = request::get("http://localhost:3000/api/v1/client-certificates",
+                                    headers.clone(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let certificates: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let certificates = certificates.as_array().unwrap();
+        assert_eq!(certificates.len(), 1);
+        assert_eq!(certificates[0].find("user").unwrap().as_i64(), Some(2));
+        let id = certificates[0].find("id").unwrap().as_string().unwrap().to_owned();
+
+        let url = format!("http://localhost:3000/api/v1/client-certificates?id={}", id);
+        let response = request::delete(&url, headers.clone(), &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NoContent);
+        let response = request::delete(&url, headers, &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NotFound);
+    }
+
+    it "should report the upnp devices to admins" {
+        use iron::headers::{ Authorization, Bearer };
+        use iron::status::Status;
+        use std::time::Duration;
+        use traits::Controller;
+
+        let controller = ControllerStub::new();
+        controller.set_admins(vec![2]);
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller.clone(), &taxo_manager));
+        let url = "http://localhost:3000/api/v1/upnp/devices";
+
+        let token = controller.session_token(3, "user");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::get(url, headers.clone(), &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+        let response = request::post(url, headers, "", &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+
+        let token = controller.session_token(2, "admin");
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+        let response = request::get(url, headers.clone(), &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+        assert_eq!(response::extract_body_to_string(response), "[]");
+
+        // Rescans need the periodic search, which the stub doesn't schedule.
+        let response = request::post(url, headers.clone(), "", &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::ServiceUnavailable);
+        controller.get_upnp_manager().schedule_search("ssdp:all", Duration::from_secs(300));
+        let response = request::post(url, headers, "", &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Accepted);
+    }
 }
 
 #[cfg(test)]
@@ -437,12 +1580,11 @@ describe! binary_getter {
 
         BinaryAdapter::init(&taxo_manager).unwrap();
 
+        let controller = ControllerStub::new();
         let mut mount = Mount::new();
-        mount.mount("/api/v1", create(ControllerStub::new(), &taxo_manager));
+        mount.mount("/api/v1", create(controller.clone(), &taxo_manager));
 
-        // Token payload is { "id": 2, "name": "admin" }
-        let token = "eyJ0eXAiOiJKV1QiLCJraWQiOm51bGwsImFsZyI6IkhTMjU2In0.eyJpZCI\
-                     6MiwibmFtZSI6ImFkbWluIn0.JNtvokupDl2hdqB+vER15y89qigPc4FviZfJOSR1Vso";
+        let token = controller.session_token(2, "admin");
 
         let mut headers = Headers::new();
         headers.set(Authorization(Bearer { token: token.to_owned() }));
@@ -0,0 +1,584 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A minimal ACME v2 client (RFC 8555).
+//!
+//! It registers an account, orders a certificate for a list of names, answers
+//! the dns-01 or http-01 challenges and downloads the certificate chain. The
+//! directory URL is configurable, so that it can be pointed at the staging
+//! environment of LetsEncrypt or at a local Pebble instance for testing.
+
+use config_store::ConfigService;
+use hyper::client::{ Client, Response };
+use hyper::header::{ ContentType, Location };
+use hyper::mime::Mime;
+use openssl::crypto::hash::{ self, Type };
+use openssl::crypto::pkey::PKey;
+use openssl::x509::X509Generator;
+use openssl::x509::extension::{ AltNameOption, Extension };
+use rustc_serialize::base64::{ FromBase64, ToBase64, URL_SAFE };
+use serde_json::{ self, Value };
+use std::collections::{ BTreeMap, HashMap };
+use std::io::{ self, Read };
+use std::str::FromStr;
+use std::sync::{ Arc, RwLock };
+use std::thread;
+use std::time::Duration;
+use tls::dns_client::DnsRecord;
+use tls::dns_provider::DnsProvider;
+
+pub const LETS_ENCRYPT_DIRECTORY: &'static str = "https://acme-v02.api.letsencrypt.org/directory";
+
+const BAD_NONCE: &'static str = "urn:ietf:params:acme:error:badNonce";
+const POLL_INTERVAL_IN_SECONDS: u64 = 2;
+const MAX_POLLS: u32 = 60;
+
+fn acme_error<E: Into<Box<::std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+fn base64url(data: &[u8]) -> String {
+    data.to_base64(URL_SAFE)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChallengeType {
+    Dns01,
+    Http01,
+}
+
+impl ChallengeType {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ChallengeType::Dns01 => "dns-01",
+            ChallengeType::Http01 => "http-01",
+        }
+    }
+}
+
+impl FromStr for ChallengeType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "dns-01" => Ok(ChallengeType::Dns01),
+            "http-01" => Ok(ChallengeType::Http01),
+            _ => Err(format!("Unsupported ACME challenge type: {}", value))
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AcmeSettings {
+    pub directory_url: String,
+    pub challenge: ChallengeType,
+    /// An email address given to the CA when registering the account.
+    pub contact: Option<String>,
+    /// How long to wait after publishing a dns-01 record, so that it reaches
+    /// all the authoritative servers before the CA looks it up.
+    pub dns_propagation_delay: Duration,
+}
+
+impl AcmeSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        let dns_propagation_seconds =
+            config.get_or_set_default_parsed("acme", "dns_propagation_seconds", "30");
+
+        AcmeSettings {
+            directory_url: config.get_or_set_default("acme", "directory_url", LETS_ENCRYPT_DIRECTORY),
+            challenge: config.get_or_set_default_parsed("acme", "challenge", "dns-01"),
+            contact: config.get("acme", "contact").and_then(|contact| {
+                if contact.is_empty() { None } else { Some(contact) }
+            }),
+            dns_propagation_delay: Duration::from_secs(dns_propagation_seconds),
+        }
+    }
+}
+
+/// The responses to the pending http-01 challenges, by token. They are served
+/// under `/.well-known/acme-challenge/` by the plain HTTP server.
+#[derive(Clone, Default)]
+pub struct HttpChallenges {
+    responses: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl HttpChallenges {
+    pub fn new() -> Self {
+        HttpChallenges::default()
+    }
+
+    pub fn get(&self, token: &str) -> Option<String> {
+        checklock!(self.responses.read()).get(token).cloned()
+    }
+
+    pub fn set(&self, token: &str, key_authorization: &str) {
+        checklock!(self.responses.write()).insert(token.to_owned(), key_authorization.to_owned());
+    }
+
+    pub fn remove(&self, token: &str) {
+        checklock!(self.responses.write()).remove(token);
+    }
+}
+
+/// Makes the response to a challenge available to the ACME server.
+pub trait ChallengeResponder {
+    fn challenge_type(&self) -> ChallengeType;
+    fn present(&self, domain: &str, token: &str, key_authorization: &str) -> io::Result<()>;
+    fn cleanup(&self, _domain: &str, _token: &str) {}
+}
+
+/// Publishes the dns-01 responses as TXT records with the DNS provider, and
+/// waits for them to propagate.
+pub struct DnsChallengeResponder {
+    pub dns_provider: Arc<DnsProvider>,
+    pub propagation_delay: Duration,
+}
+
+impl ChallengeResponder for DnsChallengeResponder {
+    fn challenge_type(&self) -> ChallengeType {
+        ChallengeType::Dns01
+    }
+
+    fn present(&self, domain: &str, _: &str, key_authorization: &str) -> io::Result<()> {
+        let name = format!("_acme-challenge.{}", domain);
+        try!(self.dns_provider.set_record(&DnsRecord {
+            record_type: "TXT",
+            name: &name,
+            value: &dns_txt_value(key_authorization),
+        }));
+
+        info!("Waiting {}s for {} to propagate", self.propagation_delay.as_secs(), name);
+        thread::sleep(self.propagation_delay);
+        Ok(())
+    }
+
+    fn cleanup(&self, domain: &str, _: &str) {
+        let name = format!("_acme-challenge.{}", domain);
+        if let Err(err) = self.dns_provider.remove_record("TXT", &name) {
+            warn!("Unable to remove the challenge record {}: {}", name, err);
+        }
+    }
+}
+
+pub struct HttpChallengeResponder {
+    pub challenges: HttpChallenges,
+}
+
+impl ChallengeResponder for HttpChallengeResponder {
+    fn challenge_type(&self) -> ChallengeType {
+        ChallengeType::Http01
+    }
+
+    fn present(&self, _: &str, token: &str, key_authorization: &str) -> io::Result<()> {
+        self.challenges.set(token, key_authorization);
+        Ok(())
+    }
+
+    fn cleanup(&self, _: &str, token: &str) {
+        self.challenges.remove(token);
+    }
+}
+
+/// The JSON Web Key of an RSA key. The `BTreeMap` keeps the members in the
+/// lexicographic order required to compute the thumbprint (RFC 7638).
+pub fn jwk(key: &PKey) -> io::Result<BTreeMap<String, String>> {
+    let rsa = key.get_rsa();
+    let n = try!(rsa.n().map_err(acme_error));
+    let e = try!(rsa.e().map_err(acme_error));
+
+    let mut jwk = BTreeMap::new();
+    jwk.insert("e".to_owned(), base64url(&e.to_vec()));
+    jwk.insert("kty".to_owned(), "RSA".to_owned());
+    jwk.insert("n".to_owned(), base64url(&n.to_vec()));
+    Ok(jwk)
+}
+
+pub fn jwk_thumbprint(jwk: &BTreeMap<String, String>) -> String {
+    let json = serde_json::to_string(jwk).unwrap();
+    base64url(&hash::hash(Type::SHA256, json.as_bytes()))
+}
+
+pub fn key_authorization(token: &str, thumbprint: &str) -> String {
+    format!("{}.{}", token, thumbprint)
+}
+
+/// The value of the `_acme-challenge` TXT record for a dns-01 challenge.
+pub fn dns_txt_value(key_authorization: &str) -> String {
+    base64url(&hash::hash(Type::SHA256, key_authorization.as_bytes()))
+}
+
+/// Creates a DER encoded certificate signing request for `names`, the first
+/// one being the common name.
+pub fn create_csr(names: &[String], key: &PKey) -> io::Result<Vec<u8>> {
+    if names.is_empty() {
+        return Err(acme_error("A certificate needs at least one name"));
+    }
+
+    let alt_names = names.iter().map(|name| (AltNameOption::DNS, name.clone())).collect();
+    let request = try!(X509Generator::new()
+        .set_sign_hash(Type::SHA256)
+        .add_name("CN".to_owned(), names[0].clone())
+        .add_extension(Extension::SubjectAltName(alt_names))
+        .request(key)
+        .map_err(acme_error));
+
+    let mut pem = vec![];
+    try!(request.write_pem(&mut pem).map_err(acme_error));
+    let pem = try!(String::from_utf8(pem).map_err(acme_error));
+    let base64: String = pem.lines().filter(|line| !line.starts_with("-----")).collect();
+    base64.from_base64().map_err(acme_error)
+}
+
+/// Splits a PEM chain into its certificates.
+pub fn split_pem_chain(chain: &str) -> Vec<String> {
+    let mut certificates = vec![];
+    let mut current = String::new();
+    for line in chain.lines() {
+        if line.starts_with("-----BEGIN") {
+            current.clear();
+        }
+        current.push_str(line);
+        current.push('\n');
+        if line.starts_with("-----END") {
+            certificates.push(current.clone());
+        }
+    }
+    certificates
+}
+
+struct AcmeResponse {
+    status_ok: bool,
+    location: Option<String>,
+    body: String,
+}
+
+impl AcmeResponse {
+    fn json(&self) -> io::Result<Value> {
+        serde_json::from_str(&self.body).map_err(acme_error)
+    }
+
+    fn into_result(self, what: &str) -> io::Result<AcmeResponse> {
+        if self.status_ok {
+            Ok(self)
+        } else {
+            Err(acme_error(format!("ACME {} failed: {}", what, self.body)))
+        }
+    }
+}
+
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+pub struct AcmeClient {
+    client: Client,
+    directory: Directory,
+    account_key: PKey,
+    jwk: BTreeMap<String, String>,
+    account_url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetches the directory at `directory_url`.
+    pub fn new(directory_url: &str, account_key: PKey) -> io::Result<Self> {
+        let client = Client::new();
+        let mut body = String::new();
+        let mut response = try!(client.get(directory_url).send().map_err(acme_error));
+        try!(response.read_to_string(&mut body));
+        let directory: Value = try!(serde_json::from_str(&body).map_err(acme_error));
+
+        let url = |name: &str| -> io::Result<String> {
+            directory.find(name)
+                     .and_then(|url| url.as_string())
+                     .map(|url| url.to_owned())
+                     .ok_or_else(|| acme_error(format!("No {} in the ACME directory", name)))
+        };
+        let directory = Directory {
+            new_nonce: try!(url("newNonce")),
+            new_account: try!(url("newAccount")),
+            new_order: try!(url("newOrder")),
+        };
+
+        Ok(AcmeClient {
+            client: client,
+            directory: directory,
+            jwk: try!(jwk(&account_key)),
+            account_key: account_key,
+            account_url: None,
+            nonce: None,
+        })
+    }
+
+    pub fn thumbprint(&self) -> String {
+        jwk_thumbprint(&self.jwk)
+    }
+
+    fn store_nonce(&mut self, response: &Response) {
+        if let Some(values) = response.headers.get_raw("Replay-Nonce") {
+            self.nonce = values.first().and_then(|value| String::from_utf8(value.clone()).ok());
+        }
+    }
+
+    fn get_nonce(&mut self) -> io::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = try!(self.client.head(&self.directory.new_nonce).send().map_err(acme_error));
+        self.store_nonce(&response);
+        self.nonce.take().ok_or_else(|| acme_error("The ACME server didn't provide a nonce"))
+    }
+
+    /// Signs `payload` as a JWS (RS256) for `url`. The account is identified
+    /// by its key until it is registered, and by its URL afterwards.
+    fn sign(&mut self, url: &str, payload: &str) -> io::Result<String> {
+        let mut protected = BTreeMap::new();
+        protected.insert("alg".to_owned(), Value::String("RS256".to_owned()));
+        protected.insert("nonce".to_owned(), Value::String(try!(self.get_nonce())));
+        protected.insert("url".to_owned(), Value::String(url.to_owned()));
+        match self.account_url {
+            Some(ref account_url) => {
+                protected.insert("kid".to_owned(), Value::String(account_url.clone()));
+            },
+            None => {
+                let jwk = self.jwk.iter().map(|(key, value)| {
+                    (key.clone(), Value::String(value.clone()))
+                }).collect();
+                protected.insert("jwk".to_owned(), Value::Object(jwk));
+            }
+        }
+
+        let protected = base64url(serde_json::to_string(&protected).unwrap().as_bytes());
+        let payload = base64url(payload.as_bytes());
+        let digest = hash::hash(Type::SHA256, format!("{}.{}", protected, payload).as_bytes());
+        let signature = base64url(&self.account_key.sign_with_hash(&digest, Type::SHA256));
+
+        Ok(serde_json::to_string(&json_value!({
+            protected: protected,
+            payload: payload,
+            signature: signature
+        })).unwrap())
+    }
+
+    /// Sends a signed request, or a "POST-as-GET" request if `payload` is None.
+    /// Requests rejected because of a stale nonce are retried once.
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> io::Result<AcmeResponse> {
+        let payload = payload.map_or("".to_owned(), |payload| serde_json::to_string(payload).unwrap());
+        let content_type: Mime = "application/jose+json".parse().unwrap();
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let body = try!(self.sign(url, &payload));
+            let mut response = try!(self.client.post(url)
+                                               .header(ContentType(content_type.clone()))
+                                               .body(&body[..])
+                                               .send()
+                                               .map_err(acme_error));
+            self.store_nonce(&response);
+
+            let mut body = String::new();
+            try!(response.read_to_string(&mut body));
+            let response = AcmeResponse {
+                status_ok: response.status.is_success(),
+                location: response.headers.get::<Location>().map(|location| location.0.clone()),
+                body: body,
+            };
+
+            let bad_nonce = !response.status_ok && response.json().ok().map_or(false, |error| {
+                error.find("type").and_then(|kind| kind.as_string()) == Some(BAD_NONCE)
+            });
+            if !bad_nonce || attempt > 1 {
+                return Ok(response);
+            }
+            debug!("Retrying ACME request to {} with a fresh nonce", url);
+        }
+    }
+
+    /// Fetches `url` until the status of the resource isn't `pending` anymore.
+    fn poll(&mut self, url: &str, pending: &[&str]) -> io::Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let resource = try!(try!(self.post(url, None)).into_result("polling").and_then(|r| r.json()));
+            let status = resource.find("status").and_then(|status| status.as_string())
+                                 .unwrap_or("").to_owned();
+            if !pending.contains(&status.as_str()) {
+                return Ok(resource);
+            }
+            thread::sleep(Duration::from_secs(POLL_INTERVAL_IN_SECONDS));
+        }
+        Err(acme_error(format!("Timed out waiting for {}", url)))
+    }
+
+    /// Registers the account, or looks it up if its key is already known.
+    pub fn register_account(&mut self, contact: &Option<String>) -> io::Result<()> {
+        let mut request = BTreeMap::new();
+        request.insert("termsOfServiceAgreed".to_owned(), Value::Bool(true));
+        if let Some(ref contact) = *contact {
+            request.insert("contact".to_owned(),
+                           Value::Array(vec![Value::String(format!("mailto:{}", contact))]));
+        }
+
+        let url = self.directory.new_account.clone();
+        let response = try!(try!(self.post(&url, Some(&Value::Object(request))))
+                                .into_result("account registration"));
+        match response.location {
+            Some(location) => {
+                debug!("Using ACME account {}", location);
+                self.account_url = Some(location);
+                Ok(())
+            },
+            None => Err(acme_error("The ACME server didn't return the account URL"))
+        }
+    }
+
+    fn authorize(&mut self, url: &str, responder: &ChallengeResponder) -> io::Result<()> {
+        let authorization = try!(try!(self.post(url, None)).into_result("authorization")
+                                                           .and_then(|r| r.json()));
+        if authorization.find("status").and_then(|status| status.as_string()) == Some("valid") {
+            return Ok(());
+        }
+
+        let domain = try!(authorization.lookup("identifier.value")
+                                       .and_then(|value| value.as_string())
+                                       .ok_or_else(|| acme_error("Authorization without identifier")))
+                         .to_owned();
+        let challenge_type = responder.challenge_type().as_str();
+        let challenge = try!(authorization.find("challenges")
+            .and_then(|challenges| challenges.as_array())
+            .and_then(|challenges| challenges.iter().find(|challenge| {
+                challenge.find("type").and_then(|kind| kind.as_string()) == Some(challenge_type)
+            }))
+            .ok_or_else(|| acme_error(format!("No {} challenge offered for {}", challenge_type, domain))))
+            .clone();
+        let token = try!(challenge.find("token").and_then(|token| token.as_string())
+                                  .ok_or_else(|| acme_error("Challenge without token")));
+        let challenge_url = try!(challenge.find("url").and_then(|url| url.as_string())
+                                          .ok_or_else(|| acme_error("Challenge without URL")));
+
+        info!("Answering the {} challenge for {}", challenge_type, domain);
+        let key_authorization = key_authorization(token, &self.thumbprint());
+        try!(responder.present(&domain, token, &key_authorization));
+
+        let result = self.post(challenge_url, Some(&Value::Object(BTreeMap::new())))
+                         .and_then(|response| response.into_result("challenge"))
+                         .and_then(|_| self.poll(url, &["pending", "processing"]));
+        responder.cleanup(&domain, token);
+
+        let authorization = try!(result);
+        match authorization.find("status").and_then(|status| status.as_string()) {
+            Some("valid") => Ok(()),
+            status => Err(acme_error(format!("Authorization for {} failed ({:?}): {}",
+                                             domain, status,
+                                             serde_json::to_string(&authorization).unwrap())))
+        }
+    }
+
+    /// Orders a certificate for `names`, the first one being the common name,
+    /// and returns the PEM chain.
+    pub fn order_certificate(&mut self, names: &[String], key: &PKey,
+                             responder: &ChallengeResponder) -> io::Result<String> {
+        let identifiers = names.iter().map(|name| json_value!({ type: "dns", value: name })).collect();
+        let mut request = BTreeMap::new();
+        request.insert("identifiers".to_owned(), Value::Array(identifiers));
+
+        let url = self.directory.new_order.clone();
+        let response = try!(try!(self.post(&url, Some(&Value::Object(request))))
+                                .into_result("order"));
+        let order_url = try!(response.location.clone()
+                                     .ok_or_else(|| acme_error("The ACME server didn't return the order URL")));
+        let order = try!(response.json());
+
+        let authorizations: Vec<String> = order.find("authorizations")
+            .and_then(|authorizations| authorizations.as_array())
+            .map_or(vec![], |authorizations| {
+                authorizations.iter().filter_map(|url| url.as_string().map(|url| url.to_owned())).collect()
+            });
+        for authorization in authorizations {
+            try!(self.authorize(&authorization, responder));
+        }
+
+        let finalize = try!(order.find("finalize").and_then(|url| url.as_string())
+                                 .ok_or_else(|| acme_error("Order without finalize URL")))
+                           .to_owned();
+        let csr = base64url(&try!(create_csr(names, key)));
+        let mut request = BTreeMap::new();
+        request.insert("csr".to_owned(), Value::String(csr));
+        try!(try!(self.post(&finalize, Some(&Value::Object(request)))).into_result("finalization"));
+
+        let order = try!(self.poll(&order_url, &["pending", "ready", "processing"]));
+        let certificate = match order.find("status").and_then(|status| status.as_string()) {
+            Some("valid") => try!(order.find("certificate").and_then(|url| url.as_string())
+                                       .ok_or_else(|| acme_error("Order without certificate URL")))
+                                 .to_owned(),
+            status => return Err(acme_error(format!("Order failed ({:?}): {}", status,
+                                                    serde_json::to_string(&order).unwrap())))
+        };
+
+        let response = try!(try!(self.post(&certificate, None)).into_result("certificate download"));
+        Ok(response.body)
+    }
+}
+
+#[cfg(test)]
+describe! acme {
+    before_each {
+        use std::collections::BTreeMap;
+    }
+
+    it "should compute the JWK thumbprint" {
+        // The example of RFC 7638, section 3.1.
+        let mut jwk = BTreeMap::new();
+        jwk.insert("kty".to_owned(), "RSA".to_owned());
+        jwk.insert("e".to_owned(), "AQAB".to_owned());
+        jwk.insert("n".to_owned(), "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".to_owned());
+
+        let thumbprint = jwk_thumbprint(&jwk);
+        assert_eq!(thumbprint, "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+        assert_eq!(dns_txt_value(&key_authorization("mytoken", &thumbprint)),
+                   "rNpYJJYybMJIBeOpCvwFE23lv3m51RzbheO-HLhzGEw");
+    }
+
+    it "should read the settings" {
+        use config_store::ConfigService;
+        use std::fs;
+        use std::time::Duration;
+        use uuid::Uuid;
+
+        let config_file_name = format!("conftest-{}.tmp", Uuid::new_v4().to_simple_string());
+        let config = ConfigService::new(&config_file_name);
+        let settings = AcmeSettings::from_config(&config);
+        assert_eq!(settings.challenge, ChallengeType::Dns01);
+        assert_eq!(settings.dns_propagation_delay, Duration::from_secs(30));
+
+        config.set("acme", "dns_propagation_seconds", "120");
+        let settings = AcmeSettings::from_config(&config);
+        assert_eq!(settings.dns_propagation_delay, Duration::from_secs(120));
+        fs::remove_file(config_file_name).unwrap_or(());
+    }
+
+    it "should parse the challenge types" {
+        assert_eq!("dns-01".parse::<ChallengeType>(), Ok(ChallengeType::Dns01));
+        assert_eq!("http-01".parse::<ChallengeType>(), Ok(ChallengeType::Http01));
+        assert!("tls-sni-01".parse::<ChallengeType>().is_err());
+    }
+
+    it "should split certificate chains" {
+        let chain = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                     \n-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let certificates = split_pem_chain(chain);
+        assert_eq!(certificates.len(), 2);
+        assert_eq!(certificates[1], "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n");
+    }
+
+    it "should serve the http-01 responses" {
+        let challenges = HttpChallenges::new();
+        let responder = HttpChallengeResponder { challenges: challenges.clone() };
+        responder.present("local.box.org", "token", "token.thumbprint").unwrap();
+        assert_eq!(challenges.get("token"), Some("token.thumbprint".to_owned()));
+        responder.cleanup("local.box.org", "token");
+        assert_eq!(challenges.get("token"), None);
+    }
+}
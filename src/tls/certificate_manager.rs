@@ -8,8 +8,10 @@ use std::io::{ Error as IoError };
 use std::path::PathBuf;
 use std::sync::{ Arc, RwLock };
 
+use tls::acme::HttpChallenges;
 use tls::certificate_record::CertificateRecord;
-use tls::ssl_context::SslContextProvider;
+use tls::renewal::RenewalStatus;
+use tls::ssl_context::{ create_ssl_context, SslContextProvider };
 use tls::utils::*;
 
 const DEFAULT_BOX_NAME: &'static str = "foxbox.local";
@@ -18,6 +20,8 @@ const DEFAULT_BOX_NAME: &'static str = "foxbox.local";
 pub struct CertificateManager {
     directory: PathBuf,
     ssl_hosts: Arc<RwLock<HashMap<String, CertificateRecord>>>,
+    renewal_status: Arc<RwLock<RenewalStatus>>,
+    http_challenges: HttpChallenges,
 
     // Observer
     context_provider: Arc<Box<SslContextProvider>>
@@ -28,6 +32,8 @@ impl CertificateManager {
         CertificateManager {
             directory: directory,
             ssl_hosts: Arc::new(RwLock::new(HashMap::new())),
+            renewal_status: Arc::new(RwLock::new(RenewalStatus::default())),
+            http_challenges: HttpChallenges::new(),
             context_provider: Arc::new(context_provider),
         }
     }
@@ -42,6 +48,8 @@ impl CertificateManager {
         CertificateManager {
             directory: test_certs_directory,
             ssl_hosts: Arc::new(RwLock::new(HashMap::new())),
+            renewal_status: Arc::new(RwLock::new(RenewalStatus::default())),
+            http_challenges: HttpChallenges::new(),
             context_provider: Arc::new(Box::new(SniSslContextProvider::new()))
         }
     }
@@ -97,6 +105,44 @@ impl CertificateManager {
         Ok(())
     }
 
+    /// Reloads the certificates from the directory, keeping the current
+    /// version of the ones that can't be used in an SSL context (eg. a key
+    /// not matching its certificate), and returns their hostnames.
+    /// The certificates are swapped at once, so connections see either the
+    /// previous set or the new one.
+    pub fn reload_checked(&self) -> Result<Vec<String>, IoError> {
+        let (mut certificates, failed) = try!(load_records_from_directory(&self.directory.clone()));
+
+        let tls_policy = self.context_provider.tls_policy();
+        let mut rejected: Vec<String> = certificates.values().filter_map(|record| {
+            let result = create_ssl_context(&record.cert_file, &record.private_key_file,
+                                            &record.full_chain, &tls_policy)
+                .and_then(|mut ctx| ctx.check_private_key());
+            match result {
+                Ok(_) => None,
+                Err(err) => {
+                    error!("Rejecting the certificate for {}: {}", record.hostname, err);
+                    Some(record.hostname.clone())
+                }
+            }
+        }).collect();
+        rejected.extend(failed);
+
+        {
+            let mut current_hosts = checklock!(self.ssl_hosts.write());
+            for hostname in &rejected {
+                match current_hosts.get(hostname) {
+                    Some(record) => { certificates.insert(hostname.clone(), record.clone()); },
+                    None => { certificates.remove(hostname); }
+                }
+            }
+            *current_hosts = certificates;
+        }
+
+        self.notify_provider();
+        Ok(rejected)
+    }
+
     pub fn add_certificate(&self, certificate_record: CertificateRecord) {
         {
             checklock!(self.ssl_hosts.write())
@@ -117,6 +163,23 @@ impl CertificateManager {
         }
     }
 
+    pub fn get_certificates(&self) -> HashMap<String, CertificateRecord> {
+        checklock!(self.ssl_hosts.read()).clone()
+    }
+
+    pub fn get_renewal_status(&self) -> RenewalStatus {
+        checklock!(self.renewal_status.read()).clone()
+    }
+
+    pub fn set_renewal_status(&self, status: RenewalStatus) {
+        *checklock!(self.renewal_status.write()) = status;
+    }
+
+    /// The pending ACME http-01 challenges, shared with the HTTP server.
+    pub fn get_http_challenges(&self) -> HttpChallenges {
+        self.http_challenges.clone()
+    }
+
     #[allow(dead_code)]
     pub fn remove_certificate(&self, hostname: &str) {
         {
@@ -145,7 +208,8 @@ mod certificate_manager {
     use std::path::PathBuf;
     use std::sync::{ Arc, Mutex };
     use std::sync::mpsc::{ channel, Sender };
-    use tls::{ CertificateRecord, SslContextProvider };
+    use tls::{ CertificateRecord, SslContextProvider, TlsPolicy };
+    use tls::utils::generate_self_signed_certificate;
 
     use super::*;
 
@@ -172,6 +236,10 @@ mod certificate_manager {
         fn update(&self, _: HashMap<String, CertificateRecord>) -> () {
             self.update_called.lock().unwrap().send(true).unwrap_or(())
         }
+
+        fn tls_policy(&self) -> TlsPolicy {
+            TlsPolicy::default()
+        }
     }
 
     fn test_cert_record() -> CertificateRecord {
@@ -260,4 +328,56 @@ mod certificate_manager {
             "Did not receive notification from handler after remove"
         );
     }
+
+    #[test]
+    fn should_keep_the_current_version_of_invalid_certificates() {
+        use mktemp::Temp;
+        use std::fs;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let directory = temp_dir.to_path_buf();
+        let valid = generate_self_signed_certificate("valid.example.com", &directory).unwrap();
+        let other = generate_self_signed_certificate("other.example.com", &directory).unwrap();
+
+        let (tx_update_called, _) = channel();
+        let cert_manager = CertificateManager::new(
+            directory.clone(),
+            Box::new(TestSslContextProvider::new(tx_update_called))
+        );
+        assert_eq!(cert_manager.reload_checked().unwrap(), Vec::<String>::new());
+
+        // A key that doesn't match the certificate.
+        fs::copy(&valid.private_key_file, &other.private_key_file).unwrap();
+        assert_eq!(cert_manager.reload_checked().unwrap(), vec!["other.example.com".to_owned()]);
+        assert!(cert_manager.get_certificate("valid.example.com").is_some());
+        assert_eq!(cert_manager.get_certificate("other.example.com"), Some(other));
+    }
+
+    #[test]
+    fn should_skip_host_directories_that_cant_be_loaded() {
+        use mktemp::Temp;
+        use std::fs;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let directory = temp_dir.to_path_buf();
+        let valid = generate_self_signed_certificate("valid.example.com", &directory).unwrap();
+        let broken = generate_self_signed_certificate("broken.example.com", &directory).unwrap();
+
+        let (tx_update_called, _) = channel();
+        let cert_manager = CertificateManager::new(
+            directory.clone(),
+            Box::new(TestSslContextProvider::new(tx_update_called))
+        );
+        assert_eq!(cert_manager.reload_checked().unwrap(), Vec::<String>::new());
+
+        // A host directory missing its certificate.
+        fs::remove_file(&broken.cert_file).unwrap();
+        fs::create_dir_all(directory.join("empty.example.com")).unwrap();
+        let mut rejected = cert_manager.reload_checked().unwrap();
+        rejected.sort();
+        assert_eq!(rejected, vec!["broken.example.com".to_owned(), "empty.example.com".to_owned()]);
+        assert_eq!(cert_manager.get_certificate("valid.example.com"), Some(valid));
+        assert_eq!(cert_manager.get_certificate("broken.example.com"), Some(broken));
+        assert!(cert_manager.get_certificate("empty.example.com").is_none());
+    }
 }
@@ -3,15 +3,61 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::io;
-use std::io::{ Error, ErrorKind };
+use std::io::{ Error, ErrorKind, Read };
 use std::fs;
 use std::path::PathBuf;
 
+use openssl::bio::MemBio;
 use openssl::x509::X509;
 use openssl::crypto::hash::Type;
+use openssl_sys;
+use time;
 
 const FINGERPRINT_DIGEST: Type = Type::SHA1;
 
+const MONTHS: [&'static str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+/// Parses a date as printed by `ASN1_TIME_print` (eg. "Jun  1 12:30:05 2026 GMT")
+/// into seconds since the epoch.
+fn parse_openssl_date(date: &str) -> Option<i64> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+
+    let month = match MONTHS.iter().position(|month| *month == parts[0]) {
+        Some(month) => month as i32,
+        None => return None
+    };
+    // Hours, minutes, seconds, day and year.
+    let numbers = match parts[2].split(':').chain(vec![parts[1], parts[3]])
+                                .map(|part| part.parse::<i32>())
+                                .collect::<Result<Vec<i32>, _>>() {
+        Ok(numbers) => numbers,
+        Err(_) => return None
+    };
+    if numbers.len() != 5 {
+        return None;
+    }
+
+    let tm = time::Tm {
+        tm_sec: numbers[2],
+        tm_min: numbers[1],
+        tm_hour: numbers[0],
+        tm_mday: numbers[3],
+        tm_mon: month,
+        tm_year: numbers[4] - 1900,
+        tm_wday: 0,
+        tm_yday: 0,
+        tm_isdst: 0,
+        tm_utcoff: 0,
+        tm_nsec: 0,
+    };
+    Some(tm.to_timespec().sec)
+}
+
 pub fn vec_to_str(sha_vec: Vec<u8>) -> String {
     sha_vec.iter().fold("".to_owned(), |hash, component| {
         // Formatting is important, each byte must be printed as width '2'
@@ -69,8 +115,8 @@ impl CertificateRecord {
 
         Ok(CertificateRecord {
             hostname: hostname,
-            cert_file: fs::canonicalize(certificate_file).unwrap(),
-            private_key_file: fs::canonicalize(private_key_file).unwrap(),
+            cert_file: try!(fs::canonicalize(certificate_file)),
+            private_key_file: try!(fs::canonicalize(private_key_file)),
 
             full_chain: full_chain,
 
@@ -98,13 +144,40 @@ impl CertificateRecord {
     pub fn get_certificate_fingerprint(&self) -> String {
         self.cert_fingerprint.clone()
     }
+
+    /// Returns the end of the validity period of the certificate, in seconds
+    /// since the epoch.
+    pub fn get_not_after(&self) -> io::Result<i64> {
+        let invalid = |what: &str| {
+            Error::new(ErrorKind::InvalidData,
+                       format!("Could not read the expiry date of {:?}: {}", self.cert_file, what))
+        };
+
+        let mut file = try!(fs::File::open(&self.cert_file));
+        let x509 = try!(X509::from_pem(&mut file).map_err(|err| invalid(&format!("{}", err))));
+
+        // rust-openssl doesn't wrap the validity period yet, so we print it
+        // from the parsed certificate.
+        let mut bio = try!(MemBio::new().map_err(|err| invalid(&format!("{}", err))));
+        let printed = unsafe {
+            openssl_sys::ASN1_TIME_print(bio.get_handle(),
+                                         openssl_sys::X509_get_notAfter(x509.get_handle()))
+        };
+        if printed != 1 {
+            return Err(invalid("the date can't be printed"));
+        }
+
+        let mut date = String::new();
+        try!(bio.read_to_string(&mut date));
+        parse_openssl_date(&date).ok_or_else(|| invalid(&date))
+    }
 }
 
 #[cfg(test)]
 mod certificate_record {
     use std::path::PathBuf;
     use super::*;
-    use super::get_x509_sha1_fingerprint_from_pem;
+    use super::{ get_x509_sha1_fingerprint_from_pem, parse_openssl_date };
 
     #[test]
     fn test_fingerprint_certificate() {
@@ -117,6 +190,32 @@ mod certificate_record {
         );
     }
 
+    #[test]
+    fn test_get_not_after() {
+        let mut cert_file = PathBuf::from(current_dir!());
+        cert_file.push("test_fixtures");
+        cert_file.push("cert.pem");
+        let record = CertificateRecord {
+            cert_file: cert_file,
+            private_key_file: PathBuf::from("/test/privkey.pem"),
+            hostname: "test.example.com".to_owned(),
+            cert_fingerprint: "1234567890abcdef".to_owned(),
+            full_chain: None
+        };
+
+        // Feb  4 14:01:06 4754 GMT
+        assert_eq!(record.get_not_after().unwrap(), 87857532066);
+    }
+
+    #[test]
+    fn test_parse_openssl_date() {
+        assert_eq!(parse_openssl_date("Jan  1 00:00:00 2017 GMT"), Some(1483228800));
+        assert_eq!(parse_openssl_date("Jun 1 12:30:05 2026 GMT"), Some(1780317005));
+        assert_eq!(parse_openssl_date("Foo  1 00:00:00 2017 GMT"), None);
+        assert_eq!(parse_openssl_date("Jan  1 00:00 2017 GMT"), None);
+        assert_eq!(parse_openssl_date("Jan  1 00:00:00 2017 CET"), None);
+    }
+
     #[test]
     fn test_vec_to_str() {
         let sha_vec: Vec<u8> = vec![0, 1, 2, 3, 4, 5, 6, 255, 244, 200, 100];
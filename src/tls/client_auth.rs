@@ -0,0 +1,517 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Client certificates issued by a local certificate authority.
+//!
+//! The box runs a small CA, created on first use in the `client_ca` directory
+//! of the profile, which issues client certificates to paired devices. The
+//! common name of each certificate is its id, which maps it to the user it
+//! was issued to. When client authentication is enabled, the TLS handshake
+//! verifies the certificates against the CA and rejects the revoked ones, and
+//! the HTTPS listener records which user the connection belongs to, for the
+//! requests served on it.
+
+use config_store::ConfigService;
+use libc::{ c_char, c_int, c_void };
+use openssl::crypto::hash::Type;
+use openssl::crypto::pkey::PKey;
+use openssl::nid::Nid;
+use openssl::ssl::{ SslContext, SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER };
+use openssl::ssl::error::SslError;
+use openssl::x509::{ X509, X509Generator, X509StoreContext };
+use openssl::x509::extension::Extension;
+use openssl_sys;
+use rusqlite::{ self, Connection };
+use std::cell::RefCell;
+use std::ffi::{ CString, NulError };
+use std::fs::{ self, File, OpenOptions };
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{ Path, PathBuf };
+use std::ptr;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ ATOMIC_USIZE_INIT, AtomicUsize, Ordering };
+use time;
+use uuid::Uuid;
+
+const CA_COMMON_NAME: &'static str = "FoxBox client CA";
+const CA_VALIDITY_DAYS: u32 = 3650;
+
+// PKCS#12 bundles can only be parsed with rust-openssl 0.7, and openssl-sys
+// doesn't declare the functions creating them.
+#[allow(non_camel_case_types)]
+type PKCS12 = c_void;
+extern "C" {
+    fn PKCS12_create(pass: *const c_char, name: *const c_char, pkey: *mut openssl_sys::EVP_PKEY,
+                     cert: *mut openssl_sys::X509, ca: *mut c_void, nid_key: c_int,
+                     nid_cert: c_int, iter: c_int, mac_iter: c_int, keytype: c_int)
+                     -> *mut PKCS12;
+    fn i2d_PKCS12(p12: *mut PKCS12, out: *mut *mut u8) -> c_int;
+    fn PKCS12_free(p12: *mut PKCS12);
+}
+
+fn ssl_error(err: SslError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+fn openssl_error(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Unable to {}", what))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuthMode {
+    /// Client certificates are not requested.
+    Off,
+    /// Client certificates are verified when provided.
+    Optional,
+    /// Connections without a valid client certificate are rejected.
+    Required,
+}
+
+impl FromStr for ClientAuthMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "off" => Ok(ClientAuthMode::Off),
+            "optional" => Ok(ClientAuthMode::Optional),
+            "required" => Ok(ClientAuthMode::Required),
+            _ => Err(format!("Unknown client authentication mode: {}", value))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClientCertificate {
+    /// Also the common name of the certificate.
+    pub id: String,
+    pub user: i32,
+    pub name: String,
+    /// Seconds since the epoch.
+    pub created: i64,
+    pub expires: i64,
+    pub revoked: bool,
+}
+
+/// The body of a client certificate request. The password protects the
+/// PKCS#12 bundle.
+#[derive(Debug, Deserialize)]
+pub struct NewClientCertificate {
+    pub name: String,
+    pub password: String,
+}
+
+pub struct ClientCertificates {
+    directory: PathBuf,
+    db: Mutex<Connection>,
+    validity_days: u32,
+    ca_lock: Mutex<()>,
+}
+
+impl ClientCertificates {
+    /// Opens the database in `directory` and creates it if not available yet.
+    pub fn new(directory: &str, config: &ConfigService) -> Self {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory).unwrap_or_else(|err| {
+            if err.kind() != io::ErrorKind::AlreadyExists {
+                panic!("Unable to create directory {:?}: {}", directory, err);
+            }
+        });
+
+        let db = Connection::open(directory.join("client_certificates.sqlite")).unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS client_certificates (
+                    id          TEXT NOT NULL PRIMARY KEY,
+                    user_id     INTEGER NOT NULL,
+                    name        TEXT NOT NULL,
+                    created     INTEGER NOT NULL,
+                    expires     INTEGER NOT NULL,
+                    revoked     INTEGER NOT NULL DEFAULT 0
+            )", &[]).unwrap();
+
+        let validity_days = config.get_or_set_default_parsed("client_certificates", "validity_days", "365");
+
+        ClientCertificates {
+            directory: directory,
+            db: Mutex::new(db),
+            validity_days: validity_days,
+            ca_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn ca_cert_file(&self) -> PathBuf {
+        self.directory.join("ca_cert.pem")
+    }
+
+    fn ca_key_file(&self) -> PathBuf {
+        self.directory.join("ca_key.pem")
+    }
+
+    /// Creates the certificate authority if it doesn't exist yet.
+    pub fn ensure_ca(&self) -> io::Result<()> {
+        let _guard = self.ca_lock.lock().unwrap();
+        if self.ca_cert_file().exists() && self.ca_key_file().exists() {
+            return Ok(());
+        }
+
+        info!("Creating the client certificate authority in {:?}", self.directory);
+        let (cert, key) = try!(X509Generator::new()
+            .set_bitlength(2048)
+            .set_valid_period(CA_VALIDITY_DAYS)
+            .add_name("CN".to_owned(), CA_COMMON_NAME.to_owned())
+            .set_sign_hash(Type::SHA256)
+            .add_extension(Extension::OtherStr("basicConstraints".to_owned(),
+                                               "critical,CA:TRUE".to_owned()))
+            .add_extension(Extension::OtherStr("keyUsage".to_owned(),
+                                               "critical,keyCertSign,cRLSign".to_owned()))
+            .generate()
+            .map_err(ssl_error));
+
+        try!(write_key(&key, &self.ca_key_file()));
+        let mut file = try!(File::create(self.ca_cert_file()));
+        cert.write_pem(&mut file).map_err(ssl_error)
+    }
+
+    /// Issues a certificate to `user` and returns it along with the PKCS#12
+    /// bundle of the certificate and its key.
+    pub fn issue(&self, request: NewClientCertificate, user: i32, now: i64)
+        -> io::Result<(ClientCertificate, Vec<u8>)> {
+        try!(self.ensure_ca());
+
+        let certificate = ClientCertificate {
+            id: Uuid::new_v4().to_simple_string(),
+            user: user,
+            name: request.name,
+            created: now,
+            expires: now + self.validity_days as i64 * 24 * 3600,
+            revoked: false,
+        };
+
+        let mut key = PKey::new();
+        key.gen(2048);
+        let cert = try!(X509Generator::new()
+            .set_valid_period(self.validity_days)
+            .add_name("CN".to_owned(), certificate.id.clone())
+            .set_sign_hash(Type::SHA256)
+            .add_extension(Extension::OtherStr("basicConstraints".to_owned(),
+                                               "CA:FALSE".to_owned()))
+            .add_extension(Extension::OtherStr("keyUsage".to_owned(),
+                                               "critical,digitalSignature,keyEncipherment".to_owned()))
+            .add_extension(Extension::OtherStr("extendedKeyUsage".to_owned(),
+                                               "clientAuth".to_owned()))
+            .sign(&key)
+            .map_err(ssl_error));
+        try!(self.sign_with_ca(&cert));
+        let pkcs12 = try!(pkcs12_bundle(&cert, &key, &certificate.name, &request.password));
+
+        let db = self.db.lock().unwrap();
+        try!(db.execute("INSERT INTO client_certificates VALUES ($1, $2, $3, $4, $5, 0)",
+                        &[&certificate.id, &certificate.user, &certificate.name,
+                          &certificate.created, &certificate.expires])
+               .map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+        Ok((certificate, pkcs12))
+    }
+
+    // Replaces the self signature of `cert` by the signature of the CA.
+    fn sign_with_ca(&self, cert: &X509) -> io::Result<()> {
+        let ca_cert = try!(File::open(self.ca_cert_file()).and_then(|mut file| {
+            X509::from_pem(&mut file).map_err(ssl_error)
+        }));
+        let ca_key = try!(File::open(self.ca_key_file()).and_then(|mut file| {
+            PKey::private_key_from_pem(&mut file).map_err(ssl_error)
+        }));
+
+        unsafe {
+            let issuer = openssl_sys::X509_get_subject_name(ca_cert.get_handle());
+            if openssl_sys::X509_set_issuer_name(cert.get_handle(), issuer) != 1 {
+                return Err(openssl_error("set the issuer of the client certificate"));
+            }
+            if openssl_sys::X509_sign(cert.get_handle(), ca_key.get_handle(),
+                                      openssl_sys::EVP_sha256()) <= 0 {
+                return Err(openssl_error("sign the client certificate"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Revokes a certificate, returning false if there is no such certificate
+    /// or if it was already revoked.
+    pub fn revoke(&self, id: &str) -> rusqlite::Result<bool> {
+        let db = self.db.lock().unwrap();
+        let count = try!(db.execute("UPDATE client_certificates SET revoked=1
+                                     WHERE id=$1 AND revoked=0", &[&id]));
+        Ok(count > 0)
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<ClientCertificate>> {
+        self.select("id = $1", &id).map(|mut certificates| certificates.pop())
+    }
+
+    /// Lists the certificates of `user`, or all of them.
+    pub fn list(&self, user: Option<i32>) -> rusqlite::Result<Vec<ClientCertificate>> {
+        match user {
+            Some(user) => self.select("user_id = $1", &user),
+            None => self.select("1 = $1", &1)
+        }
+    }
+
+    fn select(&self, condition: &str, param: &rusqlite::types::ToSql)
+        -> rusqlite::Result<Vec<ClientCertificate>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = try!(db.prepare(&format!(
+            "SELECT id, user_id, name, created, expires, revoked
+             FROM client_certificates WHERE {} ORDER BY created", condition)));
+        let rows = try!(stmt.query(&[param]));
+        let mut certificates = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            let revoked: i64 = row.get(5);
+            certificates.push(ClientCertificate {
+                id: row.get(0),
+                user: row.get(1),
+                name: row.get(2),
+                created: row.get(3),
+                expires: row.get(4),
+                revoked: revoked != 0,
+            });
+        }
+        Ok(certificates)
+    }
+
+    /// The user a certificate was issued to, if it is still valid.
+    pub fn user_for(&self, common_name: &str, now: i64) -> Option<i32> {
+        let db = self.db.lock().unwrap();
+        db.query_row("SELECT user_id FROM client_certificates
+                      WHERE id=$1 AND revoked=0 AND expires>$2",
+                     &[&common_name, &now], |row| row.get(0)).ok()
+    }
+}
+
+// The key file is created readable by its owner only, so that the key is
+// never exposed, even briefly.
+fn write_key(key: &PKey, path: &Path) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true)
+                                          .mode(0o600).open(path));
+    key.write_pem(&mut file).map_err(ssl_error)
+}
+
+/// Bundles `cert` and its `key` in a PKCS#12 archive protected by `password`.
+fn pkcs12_bundle(cert: &X509, key: &PKey, name: &str, password: &str) -> io::Result<Vec<u8>> {
+    let invalid = |_: NulError| io::Error::new(io::ErrorKind::InvalidInput, "Unexpected NUL character");
+    let name = try!(CString::new(name).map_err(&invalid));
+    let password = try!(CString::new(password).map_err(&invalid));
+
+    unsafe {
+        let p12 = PKCS12_create(password.as_ptr(), name.as_ptr(), key.get_handle(),
+                                cert.get_handle(), ptr::null_mut(), 0, 0, 0, 0, 0);
+        if p12.is_null() {
+            return Err(openssl_error("create the PKCS#12 bundle"));
+        }
+
+        let len = i2d_PKCS12(p12, ptr::null_mut());
+        let mut der = vec![0u8; if len > 0 { len as usize } else { 0 }];
+        let mut out = der.as_mut_ptr();
+        let written = if len > 0 { i2d_PKCS12(p12, &mut out) } else { len };
+        PKCS12_free(p12);
+
+        if written <= 0 {
+            return Err(openssl_error("encode the PKCS#12 bundle"));
+        }
+        Ok(der)
+    }
+}
+
+/// Checks the client certificates during the TLS handshake: the chain must
+/// have been verified by OpenSSL, and the client certificate not be revoked.
+fn verify_client_certificate(preverify_ok: bool, x509_ctx: &X509StoreContext,
+                             certificates: &Arc<ClientCertificates>) -> bool {
+    if !preverify_ok {
+        return false;
+    }
+    let common_name = x509_ctx.get_current_cert().and_then(|cert| {
+        cert.subject_name().text_by_nid(Nid::CN).map(|name| name.to_string())
+    });
+    match common_name {
+        Some(ref name) if name == CA_COMMON_NAME => true,
+        Some(name) => certificates.user_for(&name, time::get_time().sec).is_some(),
+        None => false
+    }
+}
+
+// The client certificate of the connection served by the current thread,
+// with the id of its session. hyper's worker threads accept a connection
+// and serve all its requests before accepting the next one, so the requests
+// handled on a thread come from the connection it identified last, whatever
+// their address: the certificate of a connection can't be inherited by
+// another one from the same address, like the connections of the tunnel.
+thread_local!(static CONNECTION: RefCell<Option<(usize, String)>> = RefCell::new(None));
+
+static NEXT_SESSION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Keeps the client certificate of a connection recorded until it is
+/// dropped, when the connection is closed.
+pub struct ClientSession {
+    id: usize,
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        CONNECTION.with(|connection| {
+            let mut connection = connection.borrow_mut();
+            // The thread may have accepted a newer connection already.
+            if connection.as_ref().map_or(false, |&(id, _)| id == self.id) {
+                *connection = None;
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientAuth {
+    pub mode: ClientAuthMode,
+    pub certificates: Arc<ClientCertificates>,
+}
+
+impl ClientAuth {
+    pub fn new(certificates: Arc<ClientCertificates>, config: &ConfigService) -> Self {
+        let mode = config.get_or_set_default_parsed("client_certificates", "mode", "off");
+
+        ClientAuth {
+            mode: mode,
+            certificates: certificates,
+        }
+    }
+
+    /// Sets up the verification of the client certificates on `ctx`.
+    /// Connections can't be verified if this fails, so the TLS server
+    /// refuses to start.
+    pub fn configure(&self, ctx: &mut SslContext) -> io::Result<()> {
+        let verify_mode = match self.mode {
+            ClientAuthMode::Off => return Ok(()),
+            ClientAuthMode::Optional => SSL_VERIFY_PEER,
+            ClientAuthMode::Required => SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT,
+        };
+
+        try!(self.certificates.ensure_ca());
+        try!(ctx.set_CA_file(&self.certificates.ca_cert_file()).map_err(ssl_error));
+        ctx.set_verify_with_data(verify_mode, verify_client_certificate, self.certificates.clone());
+        Ok(())
+    }
+
+    /// Records the client certificate of the connection the current thread
+    /// was given, until the returned session is dropped.
+    pub fn identify(&self, common_name: Option<String>) -> Option<ClientSession> {
+        let session = match common_name {
+            Some(common_name) if self.mode != ClientAuthMode::Off => {
+                let id = NEXT_SESSION_ID.fetch_add(1, Ordering::SeqCst);
+                Some((id, common_name))
+            },
+            _ => None
+        };
+        let id = session.as_ref().map(|&(id, _)| id);
+        CONNECTION.with(|connection| *connection.borrow_mut() = session);
+        id.map(|id| ClientSession { id: id })
+    }
+
+    /// The user of the connection served by the current thread, if it was
+    /// made with a valid client certificate.
+    pub fn connection_user(&self, now: i64) -> Option<i32> {
+        if self.mode == ClientAuthMode::Off {
+            return None;
+        }
+        let common_name = CONNECTION.with(|connection| {
+            connection.borrow().as_ref().map(|&(_, ref common_name)| common_name.clone())
+        });
+        common_name.and_then(|common_name| self.certificates.user_for(&common_name, now))
+    }
+}
+
+#[cfg(test)]
+describe! client_auth {
+    before_each {
+        use config_store::ConfigService;
+        use mktemp::Temp;
+        use std::fs;
+        use std::sync::Arc;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let directory = temp_dir.to_path_buf();
+        let config = ConfigService::new(directory.join("foxbox.conf").to_str().unwrap());
+        config.set("client_certificates", "mode", "optional");
+        let certificates = Arc::new(
+            ClientCertificates::new(directory.join("client_ca").to_str().unwrap(), &config));
+        let client_auth = ClientAuth::new(certificates.clone(), &config);
+        let request = || NewClientCertificate {
+            name: "phone".to_owned(),
+            password: "secret".to_owned(),
+        };
+    }
+
+    it "should parse the modes" {
+        assert_eq!("off".parse::<ClientAuthMode>(), Ok(ClientAuthMode::Off));
+        assert_eq!("required".parse::<ClientAuthMode>(), Ok(ClientAuthMode::Required));
+        assert!("always".parse::<ClientAuthMode>().is_err());
+        assert_eq!(client_auth.mode, ClientAuthMode::Optional);
+    }
+
+    it "should issue certificates mapped to their user" {
+        let (certificate, pkcs12) = certificates.issue(request(), 2, 100).unwrap();
+        assert!(!pkcs12.is_empty());
+        assert!(certificates.ca_cert_file().exists());
+        assert_eq!(certificates.user_for(&certificate.id, 200), Some(2));
+        assert_eq!(certificates.user_for(&certificate.id, certificate.expires), None);
+        assert_eq!(certificates.list(Some(2)).unwrap(), vec![certificate.clone()]);
+        assert_eq!(certificates.list(Some(3)).unwrap(), vec![]);
+
+        let _session = client_auth.identify(Some(certificate.id.clone()));
+        assert_eq!(client_auth.connection_user(200), Some(2));
+        // A new connection without certificate.
+        client_auth.identify(None);
+        assert_eq!(client_auth.connection_user(200), None);
+    }
+
+    it "should forget the sessions of closed connections" {
+        let (certificate, _) = certificates.issue(request(), 2, 100).unwrap();
+        let session = client_auth.identify(Some(certificate.id.clone()));
+        assert_eq!(client_auth.connection_user(200), Some(2));
+        drop(session);
+        assert_eq!(client_auth.connection_user(200), None);
+
+        // Closing an older connection doesn't forget a newer one.
+        let old = client_auth.identify(Some(certificate.id.clone()));
+        let _new = client_auth.identify(Some(certificate.id.clone()));
+        drop(old);
+        assert_eq!(client_auth.connection_user(200), Some(2));
+    }
+
+    it "should only identify the connection of the current thread" {
+        use std::thread;
+
+        let (certificate, _) = certificates.issue(request(), 2, 100).unwrap();
+        let _session = client_auth.identify(Some(certificate.id.clone()));
+        let other = client_auth.clone();
+        assert_eq!(thread::spawn(move || other.connection_user(200)).join().unwrap(), None);
+        assert_eq!(client_auth.connection_user(200), Some(2));
+    }
+
+    it "should create the keys readable by their owner only" {
+        use std::os::unix::fs::PermissionsExt;
+
+        certificates.ensure_ca().unwrap();
+        let mode = fs::metadata(directory.join("client_ca").join("ca_key.pem")).unwrap()
+                                                                           .permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    it "should revoke certificates" {
+        let (certificate, _) = certificates.issue(request(), 2, 100).unwrap();
+        let _session = client_auth.identify(Some(certificate.id.clone()));
+
+        assert!(certificates.revoke(&certificate.id).unwrap());
+        assert!(!certificates.revoke(&certificate.id).unwrap());
+        assert_eq!(certificates.user_for(&certificate.id, 200), None);
+        assert_eq!(client_auth.connection_user(200), None);
+        assert!(certificates.get(&certificate.id).unwrap().unwrap().revoked);
+    }
+}
@@ -0,0 +1,144 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The DNS servers the box can publish its records with.
+//!
+//! The provider is chosen with `dns::provider` in the config:
+//!
+//! - `knilxof` (the default) uses the DNS API of the registration service,
+//!   authenticated with the box certificate.
+//! - `rfc2136` sends dynamic updates to an authoritative server, eg. a self
+//!   hosted BIND or Knot, signed with a TSIG key. The server, zone and key are
+//!   read from the `rfc2136_*` keys of the "dns" namespace.
+
+use config_store::ConfigService;
+use rustc_serialize::base64::FromBase64;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tls::certificate_manager::CertificateManager;
+use tls::dns_client::{ DnsRecord, register_dns_record };
+use tls::rfc2136::{ Rfc2136DnsProvider, TsigKey };
+
+pub trait DnsProvider : Send + Sync {
+    /// Creates the record, replacing the records of the same type and name.
+    fn set_record(&self, record: &DnsRecord) -> io::Result<()>;
+
+    /// Removes the records of the given type and name, when the provider
+    /// supports it.
+    fn remove_record(&self, _record_type: &str, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The DNS API of the registration service.
+pub struct KnilxofDnsProvider {
+    certificate_manager: CertificateManager,
+    api_endpoint: String,
+}
+
+impl KnilxofDnsProvider {
+    pub fn new(certificate_manager: CertificateManager, api_endpoint: String) -> Self {
+        KnilxofDnsProvider {
+            certificate_manager: certificate_manager,
+            api_endpoint: api_endpoint,
+        }
+    }
+}
+
+impl DnsProvider for KnilxofDnsProvider {
+    fn set_record(&self, record: &DnsRecord) -> io::Result<()> {
+        let box_certificate = try!(self.certificate_manager.get_box_certificate());
+        register_dns_record(box_certificate, record, &self.api_endpoint)
+    }
+}
+
+fn config_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn create_rfc2136_provider(config: &ConfigService) -> io::Result<Rfc2136DnsProvider> {
+    let get_required = |key: &str| -> io::Result<String> {
+        match config.get("dns", key) {
+            Some(ref value) if !value.is_empty() => Ok(value.clone()),
+            _ => Err(config_error(format!("dns::{} is required by the rfc2136 provider", key)))
+        }
+    };
+
+    let server = config.get_or_set_default("dns", "rfc2136_server", "127.0.0.1:53");
+    let server: SocketAddr = try!(server.parse().map_err(|_| {
+        config_error(format!("Invalid value for dns::rfc2136_server: {}", server))
+    }));
+    let algorithm = config.get_or_set_default("dns", "rfc2136_key_algorithm", "hmac-sha256");
+    let secret = try!(get_required("rfc2136_key_secret"));
+    let ttl = config.get_or_set_default("dns", "rfc2136_ttl", "60");
+
+    let key = TsigKey {
+        name: try!(get_required("rfc2136_key_name")),
+        algorithm: try!(algorithm.parse().map_err(config_error)),
+        secret: try!(secret.from_base64().map_err(|_| {
+            config_error("dns::rfc2136_key_secret must be base64 encoded".to_owned())
+        })),
+    };
+
+    Ok(Rfc2136DnsProvider::new(
+        server,
+        try!(get_required("rfc2136_zone")),
+        key,
+        try!(ttl.parse().map_err(|_| {
+            config_error(format!("Invalid value for dns::rfc2136_ttl: {}", ttl))
+        }))
+    ))
+}
+
+/// Creates the provider selected in the config. `api_endpoint` is the URL of
+/// the DNS API used by the `knilxof` provider.
+pub fn create_dns_provider(config: &ConfigService, certificate_manager: CertificateManager,
+                           api_endpoint: String) -> io::Result<Arc<DnsProvider>> {
+    match config.get_or_set_default("dns", "provider", "knilxof").as_ref() {
+        "knilxof" => Ok(Arc::new(KnilxofDnsProvider::new(certificate_manager, api_endpoint))),
+        "rfc2136" => {
+            let provider = try!(create_rfc2136_provider(config));
+            Ok(Arc::new(provider))
+        },
+        other => Err(config_error(format!("Unsupported DNS provider: {}", other)))
+    }
+}
+
+#[cfg(test)]
+describe! dns_provider {
+    before_each {
+        use config_store::ConfigService;
+        use std::fs;
+        use tls::CertificateManager;
+        use uuid::Uuid;
+
+        let config_file_name = format!("conftest-{}.tmp", Uuid::new_v4().to_simple_string());
+        let config = ConfigService::new(&config_file_name);
+        let endpoint = "https://knilxof.org:5300".to_owned();
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+    }
+
+    it "should default to the knilxof api" {
+        assert!(create_dns_provider(&config, CertificateManager::new_for_test(), endpoint).is_ok());
+    }
+
+    it "should require the rfc2136 settings" {
+        config.set("dns", "provider", "rfc2136");
+        config.set("dns", "rfc2136_zone", "example.org");
+        config.set("dns", "rfc2136_key_name", "foxbox");
+        assert!(create_dns_provider(&config, CertificateManager::new_for_test(), endpoint.clone()).is_err());
+
+        config.set("dns", "rfc2136_key_secret", "c2VjcmV0");
+        assert!(create_dns_provider(&config, CertificateManager::new_for_test(), endpoint).is_ok());
+    }
+
+    it "should reject unknown providers" {
+        config.set("dns", "provider", "carrier-pigeon");
+        assert!(create_dns_provider(&config, CertificateManager::new_for_test(), endpoint).is_err());
+    }
+}
@@ -3,35 +3,112 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use hyper::error::Error as HyperError;
-use hyper::net::{ HttpsListener, Openssl, Ssl };
+use hyper::net::{ HttpsListener, HttpsStream, HttpStream, NetworkListener, NetworkStream,
+                  Openssl, Ssl };
 use hyper::server::Server;
 use iron::{ Protocol, ServerFactory };
-use std::net::SocketAddr;
+use openssl::nid::Nid;
+use openssl::ssl::SslStream;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, SocketAddr };
 use std::sync::Arc;
 use tls::certificate_manager::CertificateManager;
+use tls::client_auth::{ ClientAuth, ClientSession };
 use tls::ssl_context::SslContextProvider;
 
 pub struct SniServerFactory<S: Ssl + Clone + Send> {
-    ssl: S
+    ssl: S,
+    client_auth: ClientAuth
 }
 
 impl SniServerFactory<Openssl> {
-    pub fn new(ssl: &mut CertificateManager) -> Self {
+    pub fn new(ssl: &mut CertificateManager, client_auth: ClientAuth) -> Self {
         SniServerFactory {
             ssl: Openssl {
                 context: Arc::new(ssl.get_context_provider().context().unwrap())
-            }
+            },
+            client_auth: client_auth
         }
     }
 }
 
-impl ServerFactory<HttpsListener<Openssl>> for SniServerFactory<Openssl> {
+impl ServerFactory<ClientCertListener> for SniServerFactory<Openssl> {
     fn protocol(&self) -> Protocol {
         Protocol::Https
     }
 
     fn create_server(&self, sock_addr: SocketAddr)
-        -> Result<Server<HttpsListener<Openssl>>, HyperError> {
-        Server::https(sock_addr, self.ssl.clone())
+        -> Result<Server<ClientCertListener>, HyperError> {
+        let listener = try!(HttpsListener::new(sock_addr, self.ssl.clone()));
+        Ok(Server::new(ClientCertListener {
+            listener: listener,
+            client_auth: self.client_auth.clone()
+        }))
+    }
+}
+
+/// An HTTPS listener recording the client certificate of each connection,
+/// which isn't available to the request handlers otherwise. hyper serves the
+/// connection on the thread which accepted it, where `ClientAuth` finds it.
+#[derive(Clone)]
+pub struct ClientCertListener {
+    listener: HttpsListener<Openssl>,
+    client_auth: ClientAuth
+}
+
+impl NetworkListener for ClientCertListener {
+    type Stream = ClientCertStream;
+
+    fn accept(&mut self) -> Result<Self::Stream, HyperError> {
+        let stream = try!(self.listener.accept());
+        let common_name = match stream {
+            HttpsStream::Https(ref stream) => stream.ssl().peer_certificate().and_then(|cert| {
+                cert.subject_name().text_by_nid(Nid::CN).map(|name| name.to_string())
+            }),
+            HttpsStream::Http(_) => None
+        };
+        let session = self.client_auth.identify(common_name);
+        Ok(ClientCertStream {
+            stream: stream,
+            session: session.map(Arc::new)
+        })
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// A connection of the `ClientCertListener`. Its client certificate is
+/// forgotten once the connection is closed and all its clones are dropped.
+#[derive(Clone)]
+pub struct ClientCertStream {
+    stream: HttpsStream<SslStream<HttpStream>>,
+    session: Option<Arc<ClientSession>>
+}
+
+impl Read for ClientCertStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ClientCertStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl NetworkStream for ClientCertStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.stream.close(how)
     }
 }
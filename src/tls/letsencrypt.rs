@@ -1,22 +1,27 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use mktemp::Temp;
-use std::fs::File;
+use openssl::crypto::pkey::PKey;
+use std::fs::{ self, File };
 use std::io;
 use std::io::Write;
 use std::os::unix::fs::symlink;
 use std::path::{ Path, PathBuf };
-use std::process::Command;
+use std::sync::Arc;
 use std::sync::mpsc::{ channel, Receiver };
 use std::thread;
-use tls::{ CertificateManager, CertificateRecord };
+use tls::{ AcmeClient, AcmeSettings, CertificateManager, CertificateRecord, ChallengeResponder,
+           ChallengeType, DnsChallengeResponder, DnsProvider, HttpChallengeResponder,
+           split_pem_chain };
 
-const LETS_ENCRYPT_CLIENT: &'static str = include_str!("scripts/letsencrypt.sh");
+/// The key of the ACME account, kept next to the certificates.
+const ACCOUNT_KEY_FILE: &'static str = "acme_account_key.pem";
+const ACCOUNT_KEY_BITS: usize = 4096;
 
 /// Get a SAN certificate from `LetsEncrypt` for a given list of names.
 pub fn get_san_cert_for<T>(names: T, certificate_manager: CertificateManager,
-                           box_certificate: CertificateRecord, dns_endpoint: String)
+                           box_certificate: CertificateRecord, dns_provider: Arc<DnsProvider>,
+                           settings: AcmeSettings)
         -> Receiver<io::Result<()>>
     where T: Iterator<Item=String>,
           T: DoubleEndedIterator,
@@ -28,120 +33,111 @@ pub fn get_san_cert_for<T>(names: T, certificate_manager: CertificateManager,
         tx.send(
             _get_san_cert_for(
                 names, certificate_manager,
-                &box_certificate, &dns_endpoint)
+                &box_certificate, dns_provider, &settings)
           ).unwrap();
     });
 
     rx
 }
 
+fn read_key<P: AsRef<Path>>(path: P) -> io::Result<PKey> {
+    let mut file = try!(File::open(path));
+    PKey::private_key_from_pem(&mut file).map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    })
+}
+
+/// Writes `content` to a temporary file renamed to `path` once complete, so
+/// that a half-written file is never loaded.
+fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    try!(File::create(&temp_path).and_then(|mut f| f.write_all(content)));
+    fs::rename(temp_path, path)
+}
+
+fn get_or_create_account_key(certs_dir: &Path) -> io::Result<PKey> {
+    let path = certs_dir.join(ACCOUNT_KEY_FILE);
+    if path.exists() {
+        return read_key(path);
+    }
+
+    info!("Creating a new ACME account key in {:?}", path);
+    let mut key = PKey::new();
+    key.gen(ACCOUNT_KEY_BITS);
+    let mut pem = vec![];
+    try!(key.write_pem(&mut pem).map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+    try!(write_file(&path, &pem));
+    Ok(key)
+}
+
 /// Blocking version of `get_san_cert_for`
 fn _get_san_cert_for<T>(names: T, certificate_manager: CertificateManager,
-                        box_certificate: &CertificateRecord, dns_endpoint: &str)
+                        box_certificate: &CertificateRecord, dns_provider: Arc<DnsProvider>,
+                        settings: &AcmeSettings)
     -> io::Result<()>
     where T: Iterator<Item=String>,
           T: DoubleEndedIterator,
           T: Clone + 'static {
-    let temp_dir = try!(Temp::new_dir());
-
-    let mut letsencrypt_file = temp_dir.to_path_buf();
-    {
-        letsencrypt_file.push("letsencrypt.sh");
-
-        debug!("Creating letsencrypt client");
-        try!(File::create(letsencrypt_file.clone()).and_then(|mut f| {
-            f.write_all(LETS_ENCRYPT_CLIENT.as_bytes())
-        }));
-    }
-
-    let mut domains_file = temp_dir.to_path_buf();
-    {
-        domains_file.push("domains.txt");
-
-        let domains_txt = names.clone().rev().fold("".to_owned(), |accumulator, name| {
-            format!("{} {}", name, accumulator)
-        });
-
-        debug!("Creating domains.txt for letsencrypt client");
-        try!(File::create(domains_file).and_then(|mut f| {
-            f.write_all(domains_txt.as_bytes())
-        }));
-    }
-
-    let mut dns_challenge_file = temp_dir.to_path_buf();
-    {
-        dns_challenge_file.push("dns-challenge.sh");
-
-        try!(File::create(dns_challenge_file.clone()).and_then(|mut f| {
-            f.write_all(
-                create_challenge_script(
-                    &box_certificate.cert_file,
-                    &box_certificate.private_key_file,
-                    &dns_endpoint
-                ).as_bytes()
-            )
-        }));
-
-        assert!(dns_challenge_file.as_path().exists());
-    }
-
-    let command = format!(
-        "chmod +x {} && bash {} --cron --challenge dns-01 --hook {} && cp -R {}/certs/* {}",
-        dns_challenge_file.to_str().unwrap(),
-        letsencrypt_file.to_str().unwrap(),
-        dns_challenge_file.to_str().unwrap(),
-        temp_dir.to_path_buf().to_str().unwrap(),
-        certificate_manager.get_certs_dir().to_str().unwrap());
-
-    debug!("Spawning letsencrypt client {}", command);
-    let mut child = try!(Command::new("/usr/bin/env")
-                                 .arg("sh")
-                                 .arg("-c")
-                                 .arg(command)
-                                 .spawn());
-
-    let ecode = try!(child.wait());
-
-    if !ecode.success() {
+    let names: Vec<String> = names.collect();
+    let certs_dir = certificate_manager.get_certs_dir();
+
+    let responder: Box<ChallengeResponder> = match settings.challenge {
+        ChallengeType::Dns01 => Box::new(DnsChallengeResponder {
+            dns_provider: dns_provider,
+            propagation_delay: settings.dns_propagation_delay,
+        }),
+        ChallengeType::Http01 => Box::new(HttpChallengeResponder {
+            challenges: certificate_manager.get_http_challenges(),
+        }),
+    };
+
+    // The certificate is requested for the key of the box.
+    let key = try!(read_key(&box_certificate.private_key_file));
+    let account_key = try!(get_or_create_account_key(&certs_dir));
+
+    debug!("Requesting a certificate for {:?} from {}", names, settings.directory_url);
+    let mut client = try!(AcmeClient::new(&settings.directory_url, account_key));
+    try!(client.register_account(&settings.contact));
+    let chain = try!(client.order_certificate(&names, &key, &*responder));
+
+    let certificates = split_pem_chain(&chain);
+    if certificates.is_empty() {
         return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "The LetsEncryt client failed - certificates could not be created",
+            io::ErrorKind::InvalidData,
+            "The ACME server returned an empty certificate chain",
         ))
     }
 
-    let mut names = names;
+    let mut names = names.into_iter();
 
     // SAN domain list (this is the Common Name (CN) of the cert)
     if let Some(common_name) = names.next() {
-        let certs_dir = certificate_manager.get_certs_dir();
+        let mut cert_dir = certs_dir.clone();
+        cert_dir.push(common_name.clone());
+        try!(fs::create_dir_all(&cert_dir));
+
+        let mut private_key = vec![];
+        try!(File::open(&box_certificate.private_key_file)
+                  .and_then(|mut f| io::copy(&mut f, &mut private_key)));
+        try!(write_file(&cert_dir.join("privkey.pem"), &private_key));
+        try!(write_file(&cert_dir.join("chain.pem"), certificates[1..].concat().as_bytes()));
+        try!(write_file(&cert_dir.join("fullchain.pem"), certificates.concat().as_bytes()));
+        // Written last, as its presence is what makes the directory a certificate.
+        try!(write_file(&cert_dir.join("cert.pem"), certificates[0].as_bytes()));
 
         for subject_alt_name in names {
             info!("Trying to link {:?} -> {:?}", subject_alt_name, common_name);
 
             let mut san_dir = certs_dir.clone();
             san_dir.push(subject_alt_name);
-            try!(symlink(PathBuf::from(common_name.clone()), san_dir));
+            // The link is already there when renewing.
+            if let Err(err) = symlink(PathBuf::from(common_name.clone()), san_dir) {
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err);
+                }
+            }
         }
     }
 
     Ok(())
 }
-
-fn create_challenge_script<T: AsRef<Path>>(cert_path: T, key_path: T, dns_endpoint: &str) -> String {
-    format!("#!/usr/bin/env sh
-URL_PATH=`echo $2 | perl -lpe '$_ = join \"/\", reverse split /\\./'`
-DATA=\"{{\\\"type\\\": \\\"TXT\\\", \\\"value\\\": \\\"$4\\\"}}\"
-UNAMESTR=`uname`
-if [[ \"$UNAMESTR\" == 'Darwin' ]]; then
-    openssl pkcs12 -export -inkey {key_path:?} -in {cert_path:?} -out pkcs_fmt.pkcs12 -password 'pass:nopass'
-    curl -k -v -E pkcs_fmt.pkcs12:nopass -XPOST -d\"$DATA\" {dns_endpoint}/v1/dns/$URL_PATH/_acme-challenge
-else
-    curl -k -v -E {cert_path:?} -XPOST -d\"$DATA\" {dns_endpoint}/v1/dns/$URL_PATH/_acme-challenge
-fi
-",
-
-        cert_path=cert_path.as_ref(),
-        key_path=key_path.as_ref(),
-        dns_endpoint=dns_endpoint
-    )
-}
@@ -10,20 +10,34 @@ macro_rules! checklock (
     }
 );
 
+mod acme;
 mod certificate_manager;
 mod certificate_record;
+mod client_auth;
 mod dns_client;
+mod dns_provider;
 mod https_server_factory;
 mod letsencrypt;
+mod renewal;
+mod rfc2136;
 mod ssl_context;
+mod tls_policy;
 mod utils;
+mod watcher;
 
+pub use tls::acme::*;
 pub use tls::certificate_manager::*;
 pub use tls::certificate_record::*;
+pub use tls::client_auth::*;
 pub use tls::dns_client::*;
+pub use tls::dns_provider::*;
 pub use tls::https_server_factory::*;
 pub use tls::letsencrypt::*;
+pub use tls::renewal::*;
+pub use tls::rfc2136::*;
 pub use tls::ssl_context::*;
+pub use tls::tls_policy::*;
+pub use tls::watcher::*;
 
 #[derive(Clone, Eq, PartialEq)]
 pub enum TlsOption {
@@ -0,0 +1,247 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Background renewal of the certificates obtained through ACME.
+//!
+//! The renewer periodically checks the expiry date of the certificates
+//! loaded by the `CertificateManager` that come with a full chain (the self
+//! signed ones don't), and renews the ones expiring soon. Renewed
+//! certificates are reloaded, which hot-swaps them into the SSL context
+//! provider. Failed renewals are retried with an exponential backoff, and
+//! reported through the renewal status and a notification.
+
+use config_store::ConfigService;
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use time;
+use tls::{ CertificateManager, CertificateRecord };
+
+/// Where the renewal stands, exposed for health checks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RenewalStatus {
+    /// Seconds since the epoch.
+    pub last_check: Option<i64>,
+    pub next_check: Option<i64>,
+    /// The earliest expiry date among the renewable certificates.
+    pub earliest_expiry: Option<i64>,
+    /// The number of consecutive failed renewals.
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl RenewalStatus {
+    pub fn is_failing(&self) -> bool {
+        self.failures > 0
+    }
+}
+
+pub struct RenewalSettings {
+    /// How long before their expiry certificates get renewed, in seconds.
+    pub renew_before: i64,
+    pub check_interval: i64,
+    /// The delay before retrying a failed renewal, doubled with each failure
+    /// up to `max_retry_delay`.
+    pub retry_delay: i64,
+    pub max_retry_delay: i64,
+}
+
+impl RenewalSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        let renew_before_days: i64 = config.get_or_set_default_parsed("certificates", "renew_before_days", "30");
+        let check_interval_hours: i64 = config.get_or_set_default_parsed("certificates", "check_interval_hours", "12");
+        let retry_delay_minutes: i64 = config.get_or_set_default_parsed("certificates", "retry_delay_minutes", "5");
+        let max_retry_delay_hours: i64 = config.get_or_set_default_parsed("certificates", "max_retry_delay_hours", "12");
+
+        RenewalSettings {
+            renew_before: renew_before_days * 24 * 3600,
+            check_interval: check_interval_hours * 3600,
+            retry_delay: retry_delay_minutes * 60,
+            max_retry_delay: max_retry_delay_hours * 3600,
+        }
+    }
+
+    fn retry_delay(&self, failures: u32) -> i64 {
+        let exponent = cmp::min(failures.saturating_sub(1), 20);
+        cmp::min(self.retry_delay << exponent, self.max_retry_delay)
+    }
+}
+
+/// Groups the certificates obtained through ACME by certificate file, and
+/// returns the names each one is for, the common name first.
+/// The subject alternative names are symlinks to the directory of the common
+/// name, so the certificate files of all the names resolve to that directory.
+pub fn renewable_certificates(records: &HashMap<String, CertificateRecord>)
+    -> Vec<(Vec<String>, CertificateRecord)> {
+    let mut by_file: HashMap<PathBuf, (Vec<String>, CertificateRecord)> = HashMap::new();
+    for record in records.values().filter(|record| record.full_chain.is_some()) {
+        let entry = by_file.entry(record.cert_file.clone())
+                           .or_insert_with(|| (vec![], record.clone()));
+        entry.0.push(record.hostname.clone());
+    }
+
+    let mut certificates: Vec<(Vec<String>, CertificateRecord)> = by_file.into_iter().map(|(file, (mut names, record))| {
+        let common_name = file.parent()
+                              .and_then(|dir| dir.file_name())
+                              .and_then(|name| name.to_str())
+                              .map(|name| name.to_owned());
+        names.sort_by_key(|name| (Some(name) != common_name.as_ref(), name.clone()));
+        (names, record)
+    }).collect();
+    certificates.sort_by_key(|&(ref names, _)| names.clone());
+    certificates
+}
+
+/// Renews the certificate for the given names, the common name first.
+pub type RenewFn = Box<Fn(Vec<String>) -> io::Result<()> + Send>;
+/// Called with the status when a renewal fails.
+pub type NotifyFn = Box<Fn(&RenewalStatus) + Send>;
+
+pub struct CertificateRenewer {
+    certificate_manager: CertificateManager,
+    settings: RenewalSettings,
+    renew: RenewFn,
+    notify: NotifyFn,
+}
+
+impl CertificateRenewer {
+    pub fn new(certificate_manager: CertificateManager,
+               settings: RenewalSettings,
+               renew: RenewFn,
+               notify: NotifyFn) -> Self {
+        CertificateRenewer {
+            certificate_manager: certificate_manager,
+            settings: settings,
+            renew: renew,
+            notify: notify,
+        }
+    }
+
+    /// Renews the certificates expiring soon, updates the renewal status and
+    /// returns when the next check should happen.
+    pub fn check(&self, now: i64) -> i64 {
+        let mut status = self.certificate_manager.get_renewal_status();
+        status.last_check = Some(now);
+        status.earliest_expiry = None;
+
+        let mut renewed = false;
+        let mut error = None;
+        for (names, record) in renewable_certificates(&self.certificate_manager.get_certificates()) {
+            let not_after = match record.get_not_after() {
+                Ok(not_after) => not_after,
+                Err(err) => {
+                    warn!("Unable to check the expiry of the certificate for {:?}: {}", names, err);
+                    continue;
+                }
+            };
+            status.earliest_expiry = Some(status.earliest_expiry.map_or(not_after, |earliest| {
+                cmp::min(earliest, not_after)
+            }));
+
+            if not_after - self.settings.renew_before > now {
+                continue;
+            }
+
+            info!("Renewing the certificate for {:?}, expiring at {}", names, not_after);
+            match (self.renew)(names.clone()) {
+                Ok(_) => renewed = true,
+                Err(err) => {
+                    error!("Unable to renew the certificate for {:?}: {}", names, err);
+                    error = Some(format!("{}: {}", names[0], err));
+                }
+            }
+        }
+
+        if renewed {
+            // Reloading the certificates updates the SSL context provider.
+            if let Err(err) = self.certificate_manager.reload() {
+                error!("Unable to reload the renewed certificates: {}", err);
+                error = error.or_else(|| Some(format!("Reload failed: {}", err)));
+            }
+        }
+
+        let next_check = match error {
+            Some(error) => {
+                status.failures += 1;
+                status.last_error = Some(error);
+                now + self.settings.retry_delay(status.failures)
+            },
+            None => {
+                status.failures = 0;
+                status.last_error = None;
+                now + self.settings.check_interval
+            }
+        };
+        status.next_check = Some(next_check);
+        self.certificate_manager.set_renewal_status(status.clone());
+
+        if status.is_failing() {
+            (self.notify)(&status);
+        }
+        next_check
+    }
+
+    /// Spawns a thread checking the certificates until the process exits.
+    pub fn start(self) {
+        thread::Builder::new().name("CertificateRenewer".to_owned())
+            .spawn(move || {
+                loop {
+                    let now = time::get_time().sec;
+                    let next_check = self.check(now);
+                    thread::sleep(Duration::from_secs(cmp::max(next_check - now, 1) as u64));
+                }
+            }).unwrap();
+    }
+}
+
+#[cfg(test)]
+describe! renewal {
+    before_each {
+        use std::collections::HashMap;
+        use std::path::PathBuf;
+        use tls::CertificateRecord;
+
+        let settings = RenewalSettings {
+            renew_before: 100,
+            check_interval: 1000,
+            retry_delay: 10,
+            max_retry_delay: 60,
+        };
+
+        let record = |hostname: &str, directory: &str, full_chain: bool| {
+            let mut record = CertificateRecord::new_for_test(
+                hostname.to_owned(),
+                PathBuf::from(format!("/certs/{}/cert.pem", directory)),
+                PathBuf::from(format!("/certs/{}/privkey.pem", directory)),
+                "abcdef".to_owned()).unwrap();
+            if full_chain {
+                record.full_chain = Some(PathBuf::from(format!("/certs/{}/fullchain.pem", directory)));
+            }
+            (hostname.to_owned(), record)
+        };
+    }
+
+    it "should back off exponentially" {
+        assert_eq!(settings.retry_delay(1), 10);
+        assert_eq!(settings.retry_delay(2), 20);
+        assert_eq!(settings.retry_delay(3), 40);
+        assert_eq!(settings.retry_delay(4), 60);
+        assert_eq!(settings.retry_delay(100), 60);
+    }
+
+    it "should group the names of the renewable certificates" {
+        let records: HashMap<String, CertificateRecord> = vec![
+            record("remote.box.org", "local.box.org", true),
+            record("local.box.org", "local.box.org", true),
+            record("foxbox.local", "foxbox.local", false),
+        ].into_iter().collect();
+
+        let certificates = renewable_certificates(&records);
+        assert_eq!(certificates.len(), 1);
+        assert_eq!(certificates[0].0, vec!["local.box.org".to_owned(), "remote.box.org".to_owned()]);
+    }
+}
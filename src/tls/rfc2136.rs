@@ -0,0 +1,374 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! DNS dynamic updates (RFC 2136) signed with TSIG (RFC 8945), as accepted by
+//! BIND, Knot and most authoritative servers.
+//!
+//! Each update replaces the records of a given type for a name. Updates are
+//! sent over UDP and retried a few times. The TSIG signature of the responses
+//! isn't checked: the status of an update is only used for reporting.
+
+use openssl::crypto::hash::Type;
+use openssl::crypto::hmac::hmac;
+use rand;
+use std::io;
+use std::net::{ Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket };
+use std::str::FromStr;
+use std::time::Duration;
+use time;
+use tls::dns_client::DnsRecord;
+use tls::dns_provider::DnsProvider;
+
+const OPCODE_UPDATE: u16 = 5;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+
+const TSIG_FUDGE_SECONDS: u16 = 300;
+const ATTEMPTS: u32 = 3;
+const TIMEOUT_IN_SECONDS: u64 = 5;
+
+fn dns_error<E: Into<Box<::std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    fn name(&self) -> &'static str {
+        match *self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn hash_type(&self) -> Type {
+        match *self {
+            TsigAlgorithm::HmacSha256 => Type::SHA256,
+            TsigAlgorithm::HmacSha512 => Type::SHA512,
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "hmac-sha256" => Ok(TsigAlgorithm::HmacSha256),
+            "hmac-sha512" => Ok(TsigAlgorithm::HmacSha512),
+            _ => Err(format!("Unsupported TSIG algorithm: {}", value))
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+fn push_u16(message: &mut Vec<u8>, value: u16) {
+    message.push((value >> 8) as u8);
+    message.push(value as u8);
+}
+
+fn push_u32(message: &mut Vec<u8>, value: u32) {
+    push_u16(message, (value >> 16) as u16);
+    push_u16(message, value as u16);
+}
+
+/// Appends `name` in the uncompressed, lower case, wire format.
+fn push_name(message: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    for label in name.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(dns_error(format!("Invalid domain name: {}", name)));
+        }
+        message.push(label.len() as u8);
+        message.extend(label.to_lowercase().as_bytes());
+    }
+    message.push(0);
+    Ok(())
+}
+
+/// The type and the wire format data of a record.
+fn encode_rdata(record: &DnsRecord) -> io::Result<(u16, Vec<u8>)> {
+    let mut rdata = vec![];
+    let record_type = match record.record_type {
+        "A" => {
+            let ip = try!(Ipv4Addr::from_str(record.value).map_err(dns_error));
+            rdata.extend(&ip.octets());
+            TYPE_A
+        },
+        "AAAA" => {
+            let ip = try!(Ipv6Addr::from_str(record.value).map_err(dns_error));
+            for segment in &ip.segments() {
+                push_u16(&mut rdata, *segment);
+            }
+            TYPE_AAAA
+        },
+        "CNAME" => {
+            try!(push_name(&mut rdata, record.value));
+            TYPE_CNAME
+        },
+        "TXT" => {
+            // Long values are split in several character strings.
+            for chunk in record.value.as_bytes().chunks(255) {
+                rdata.push(chunk.len() as u8);
+                rdata.extend(chunk);
+            }
+            TYPE_TXT
+        },
+        other => return Err(dns_error(format!("Unsupported record type: {}", other)))
+    };
+    Ok((record_type, rdata))
+}
+
+fn record_type_code(record_type: &str) -> io::Result<u16> {
+    match record_type {
+        "A" => Ok(TYPE_A),
+        "AAAA" => Ok(TYPE_AAAA),
+        "CNAME" => Ok(TYPE_CNAME),
+        "TXT" => Ok(TYPE_TXT),
+        other => Err(dns_error(format!("Unsupported record type: {}", other)))
+    }
+}
+
+/// Builds an unsigned update message for `zone` deleting the `record_type`
+/// records of `name`, then adding `record` if given.
+pub fn build_update(id: u16, zone: &str, name: &str, record_type: u16,
+                    record: Option<(Vec<u8>, u32)>) -> io::Result<Vec<u8>> {
+    let mut message = vec![];
+    push_u16(&mut message, id);
+    push_u16(&mut message, OPCODE_UPDATE << 11);
+    push_u16(&mut message, 1); // Zone count.
+    push_u16(&mut message, 0); // Prerequisite count.
+    push_u16(&mut message, if record.is_some() { 2 } else { 1 }); // Update count.
+    push_u16(&mut message, 0); // Additional count.
+
+    try!(push_name(&mut message, zone));
+    push_u16(&mut message, TYPE_SOA);
+    push_u16(&mut message, CLASS_IN);
+
+    // Deletes the RRset.
+    try!(push_name(&mut message, name));
+    push_u16(&mut message, record_type);
+    push_u16(&mut message, CLASS_ANY);
+    push_u32(&mut message, 0);
+    push_u16(&mut message, 0);
+
+    if let Some((rdata, ttl)) = record {
+        try!(push_name(&mut message, name));
+        push_u16(&mut message, record_type);
+        push_u16(&mut message, CLASS_IN);
+        push_u32(&mut message, ttl);
+        push_u16(&mut message, rdata.len() as u16);
+        message.extend(rdata);
+    }
+    Ok(message)
+}
+
+/// Appends a TSIG record signing `message` at `time_signed`, in seconds since
+/// the epoch.
+pub fn sign(message: &mut Vec<u8>, key: &TsigKey, time_signed: u64) -> io::Result<()> {
+    let mut key_name = vec![];
+    try!(push_name(&mut key_name, &key.name));
+    let mut algorithm_name = vec![];
+    try!(push_name(&mut algorithm_name, key.algorithm.name()));
+
+    let mut time_and_fudge = vec![];
+    push_u16(&mut time_and_fudge, (time_signed >> 32) as u16);
+    push_u32(&mut time_and_fudge, time_signed as u32);
+    push_u16(&mut time_and_fudge, TSIG_FUDGE_SECONDS);
+
+    // The MAC covers the message and the TSIG variables.
+    let mut signed = message.clone();
+    signed.extend(&key_name);
+    push_u16(&mut signed, CLASS_ANY);
+    push_u32(&mut signed, 0);
+    signed.extend(&algorithm_name);
+    signed.extend(&time_and_fudge);
+    push_u16(&mut signed, 0); // Error.
+    push_u16(&mut signed, 0); // Other data length.
+    let mac = hmac(key.algorithm.hash_type(), &key.secret, &signed);
+
+    let mut rdata = algorithm_name;
+    rdata.extend(&time_and_fudge);
+    push_u16(&mut rdata, mac.len() as u16);
+    rdata.extend(&mac);
+    rdata.push(message[0]); // Original id.
+    rdata.push(message[1]);
+    push_u16(&mut rdata, 0); // Error.
+    push_u16(&mut rdata, 0); // Other data length.
+
+    message.extend(key_name);
+    push_u16(message, TYPE_TSIG);
+    push_u16(message, CLASS_ANY);
+    push_u32(message, 0);
+    push_u16(message, rdata.len() as u16);
+    message.extend(rdata);
+
+    // One more additional record.
+    let additional = ((message[10] as u16) << 8 | message[11] as u16) + 1;
+    message[10] = (additional >> 8) as u8;
+    message[11] = additional as u8;
+    Ok(())
+}
+
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        1 => "FORMERR".to_owned(),
+        2 => "SERVFAIL".to_owned(),
+        3 => "NXDOMAIN".to_owned(),
+        4 => "NOTIMP".to_owned(),
+        5 => "REFUSED".to_owned(),
+        8 => "NXRRSET".to_owned(),
+        9 => "NOTAUTH".to_owned(),
+        10 => "NOTZONE".to_owned(),
+        other => format!("RCODE {}", other)
+    }
+}
+
+/// Checks that `response` is a successful answer to the message `id`.
+pub fn check_response(id: u16, response: &[u8]) -> io::Result<()> {
+    if response.len() < 12 || ((response[0] as u16) << 8 | response[1] as u16) != id {
+        return Err(dns_error("Unexpected response from the DNS server"));
+    }
+    if response[2] & 0x80 == 0 {
+        return Err(dns_error("The DNS server sent a query instead of a response"));
+    }
+    match response[3] & 0x0f {
+        0 => Ok(()),
+        rcode => Err(dns_error(format!("The DNS server refused the update: {}", rcode_name(rcode))))
+    }
+}
+
+/// Updates the records of an authoritative server with RFC 2136 dynamic
+/// updates.
+pub struct Rfc2136DnsProvider {
+    server: SocketAddr,
+    zone: String,
+    key: TsigKey,
+    ttl: u32,
+}
+
+impl Rfc2136DnsProvider {
+    pub fn new(server: SocketAddr, zone: String, key: TsigKey, ttl: u32) -> Self {
+        Rfc2136DnsProvider {
+            server: server,
+            zone: zone,
+            key: key,
+            ttl: ttl,
+        }
+    }
+
+    fn send(&self, name: &str, record_type: u16, record: Option<(Vec<u8>, u32)>) -> io::Result<()> {
+        let id = rand::random::<u16>();
+        let mut message = try!(build_update(id, &self.zone, name, record_type, record));
+        try!(sign(&mut message, &self.key, time::get_time().sec as u64));
+
+        let local = if self.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.set_read_timeout(Some(Duration::from_secs(TIMEOUT_IN_SECONDS))));
+
+        let mut buffer = [0u8; 512];
+        let mut last_error = dns_error("The DNS server did not respond");
+        for _ in 0..ATTEMPTS {
+            try!(socket.send_to(&message, self.server));
+            match socket.recv_from(&mut buffer) {
+                Ok((size, from)) if from == self.server => {
+                    return check_response(id, &buffer[..size]);
+                },
+                Ok(_) => {},
+                Err(err) => last_error = err
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl DnsProvider for Rfc2136DnsProvider {
+    fn set_record(&self, record: &DnsRecord) -> io::Result<()> {
+        let (record_type, rdata) = try!(encode_rdata(record));
+        info!("Updating the {} records of {} on {}", record.record_type, record.name, self.server);
+        self.send(record.name, record_type, Some((rdata, self.ttl))).map_err(|err| {
+            error!("Could not update the {} records of {}: {}", record.record_type, record.name, err);
+            err
+        })
+    }
+
+    fn remove_record(&self, record_type: &str, name: &str) -> io::Result<()> {
+        let record_type = try!(record_type_code(record_type));
+        self.send(name, record_type, None)
+    }
+}
+
+#[cfg(test)]
+describe! rfc2136 {
+    before_each {
+        use tls::dns_client::DnsRecord;
+
+        let key = TsigKey {
+            name: "foxbox-key".to_owned(),
+            algorithm: TsigAlgorithm::HmacSha256,
+            secret: b"0123456789abcdef".to_vec(),
+        };
+    }
+
+    it "should encode the records" {
+        let (record_type, rdata) = encode_rdata(&DnsRecord {
+            record_type: "A", name: "local.box.org", value: "192.168.0.4"
+        }).unwrap();
+        assert_eq!(record_type, TYPE_A);
+        assert_eq!(rdata, vec![192, 168, 0, 4]);
+
+        let (record_type, rdata) = encode_rdata(&DnsRecord {
+            record_type: "AAAA", name: "local.box.org", value: "2001:db8::4"
+        }).unwrap();
+        assert_eq!(record_type, TYPE_AAAA);
+        assert_eq!(rdata, vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4]);
+
+        let (_, rdata) = encode_rdata(&DnsRecord {
+            record_type: "TXT", name: "_acme-challenge.box.org", value: "abc"
+        }).unwrap();
+        assert_eq!(rdata, b"\x03abc".to_vec());
+
+        assert!(encode_rdata(&DnsRecord { record_type: "MX", name: "box.org", value: "x" }).is_err());
+    }
+
+    it "should build and sign update messages" {
+        let mut message = build_update(0x1234, "box.org", "local.box.org", TYPE_A,
+                                       Some((vec![192, 168, 0, 4], 60))).unwrap();
+        // Id, UPDATE opcode, one zone, two updates.
+        assert_eq!(&message[..12], &[0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 0]);
+        assert_eq!(&message[12..21], b"\x03box\x03org\x00");
+
+        let unsigned_length = message.len();
+        sign(&mut message, &key, 1_500_000_000).unwrap();
+        assert_eq!(message[11], 1);
+        assert_eq!(&message[unsigned_length..unsigned_length + 12], b"\x0afoxbox-key\x00");
+        // Signing is deterministic for a given time.
+        let mut other = build_update(0x1234, "box.org", "local.box.org", TYPE_A,
+                                     Some((vec![192, 168, 0, 4], 60))).unwrap();
+        sign(&mut other, &key, 1_500_000_000).unwrap();
+        assert_eq!(message, other);
+    }
+
+    it "should check the responses" {
+        assert!(check_response(0x1234, &[0x12, 0x34, 0xa8, 0, 0, 1, 0, 0, 0, 0, 0, 0]).is_ok());
+        assert!(check_response(0x1234, &[0x12, 0x34, 0xa8, 5, 0, 1, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(check_response(0x4321, &[0x12, 0x34, 0xa8, 0, 0, 1, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(check_response(0x1234, &[0x12, 0x34]).is_err());
+    }
+}
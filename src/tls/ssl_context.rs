@@ -7,20 +7,27 @@ use openssl::x509::X509FileType;
 use openssl_sys;
 
 use std::collections::HashMap;
-use std::io::Error;
+use std::io::{ self, Error, ErrorKind };
 use std::path::Path;
 use std::sync::{ Arc, RwLock };
 
 use tls::certificate_record::CertificateRecord;
+use tls::client_auth::ClientAuth;
+use tls::tls_policy::TlsPolicy;
 
 pub trait SslContextProvider : Send + Sync {
     fn context(&self) -> Result<SslContext, Error>;
     fn update(&self, HashMap<String, CertificateRecord>) -> ();
+    fn tls_policy(&self) -> TlsPolicy;
 }
 
 #[derive(Clone)]
 pub struct SniSslContextProvider {
-    main_context: Arc<RwLock<SslContext>>
+    main_context: Arc<RwLock<SslContext>>,
+    // The contexts currently selected by SNI.
+    hosts: Arc<RwLock<HashMap<String, SslContext>>>,
+    client_auth: Option<ClientAuth>,
+    tls_policy: TlsPolicy
 }
 
 impl SslContextProvider for SniSslContextProvider {
@@ -31,25 +38,48 @@ impl SslContextProvider for SniSslContextProvider {
     fn update(&self, configured_hosts: HashMap<String, CertificateRecord>) -> () {
         debug!("Updating SniSslContextProvider");
 
+        let previous_ssl_hosts = checklock!(self.hosts.read()).clone();
         let mut new_ssl_hosts = HashMap::new();
 
         for record in configured_hosts.values() {
             debug!("Creating SslContext for {}", record.hostname);
             let ssl_context = create_ssl_context(
-                &record.cert_file, &record.private_key_file, &record.full_chain);
-
-            if ssl_context.is_ok() {
-                let ssl_context = ssl_context.unwrap();
-                new_ssl_hosts.insert(record.hostname.clone(), ssl_context);
-            } else {
-                error!("Failed to configure SslContext for: {:?}", record);
+                &record.cert_file, &record.private_key_file, &record.full_chain,
+                &self.tls_policy)
+                .map_err(|err| format!("{}", err))
+                .and_then(|mut ssl_context| match self.client_auth {
+                    Some(ref client_auth) => client_auth.configure(&mut ssl_context)
+                                                        .map(|_| ssl_context)
+                                                        .map_err(|err| format!("{}", err)),
+                    None => Ok(ssl_context)
+                });
+
+            match ssl_context {
+                Ok(ssl_context) => {
+                    new_ssl_hosts.insert(record.hostname.clone(), ssl_context);
+                },
+                // Files being replaced can't be loaded for a while, keep using
+                // the previous version of the certificate until then.
+                Err(err) => match previous_ssl_hosts.get(&record.hostname) {
+                    Some(previous) => {
+                        warn!("Failed to configure SslContext for {:?} ({}), keeping the current one",
+                              record, err);
+                        new_ssl_hosts.insert(record.hostname.clone(), previous.clone());
+                    },
+                    None => error!("Failed to configure SslContext for {:?}: {}", record, err)
+                }
             }
         }
 
         debug!("Update certificates");
+        *checklock!(self.hosts.write()) = new_ssl_hosts.clone();
         checklock!(self.main_context.write())
             .set_servername_callback_with_data(SniSslContextProvider::servername_callback, new_ssl_hosts);
     }
+
+    fn tls_policy(&self) -> TlsPolicy {
+        self.tls_policy.clone()
+    }
 }
 
 impl SniSslContextProvider {
@@ -59,10 +89,34 @@ impl SniSslContextProvider {
                               RwLock::new(
                                   SslContext::new(SslMethod::Sslv23).unwrap()
                               )
-                          )
+                          ),
+            hosts: Arc::new(RwLock::new(HashMap::new())),
+            client_auth: None,
+            tls_policy: TlsPolicy::default()
         }
     }
 
+    /// Creates a provider verifying the client certificates as configured
+    /// in `client_auth`, and enforcing `tls_policy`. The verification mode
+    /// and protocol versions of a connection are the ones of the main
+    /// context, whatever certificate is selected by SNI.
+    /// Fails if the policy or the client authentication can't be set up,
+    /// rather than serving with OpenSSL's defaults.
+    pub fn with_client_auth(client_auth: ClientAuth, tls_policy: TlsPolicy) -> io::Result<Self> {
+        let mut main_context = SslContext::new(SslMethod::Sslv23).unwrap();
+        try!(tls_policy.apply(&mut main_context).map_err(|err| {
+            Error::new(ErrorKind::Other, format!("Failed to apply the TLS policy: {}", err))
+        }));
+        try!(client_auth.configure(&mut main_context));
+
+        Ok(SniSslContextProvider {
+            main_context: Arc::new(RwLock::new(main_context)),
+            hosts: Arc::new(RwLock::new(HashMap::new())),
+            client_auth: Some(client_auth),
+            tls_policy: tls_policy
+        })
+    }
+
     fn servername_callback_impl<T>(ssl: &mut SslForSni<T>,
                                    configured_certs: &HashMap<String, T>) -> i32 {
         debug!("servername_callback invoked");
@@ -114,7 +168,7 @@ impl SslForSni<SslContext> for Ssl {
     }
 }
 
-pub fn create_ssl_context<C, K>(crt: &C, key: &K, chain: &Option<K>)
+pub fn create_ssl_context<C, K>(crt: &C, key: &K, chain: &Option<K>, tls_policy: &TlsPolicy)
         -> Result<SslContext, SslError>
     where C: AsRef<Path>, K: AsRef<Path> {
 
@@ -122,7 +176,7 @@ pub fn create_ssl_context<C, K>(crt: &C, key: &K, chain: &Option<K>)
             crt.as_ref().to_str(), key.as_ref().to_str());
 
     let mut ctx = try!(SslContext::new(SslMethod::Sslv23));
-    try!(ctx.set_cipher_list("DEFAULT"));
+    try!(tls_policy.apply(&mut ctx));
     try!(ctx.set_certificate_file(crt.as_ref(), X509FileType::PEM));
     try!(ctx.set_private_key_file(key.as_ref(), X509FileType::PEM));
 
@@ -0,0 +1,298 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The protocol versions, cipher suites, ECDH and session settings used by
+//! the TLS server, read from the "tls" config namespace.
+//!
+//! The ECDH curves and the session cache aren't wrapped by rust-openssl, and
+//! are set through the OpenSSL control calls.
+
+use config_store::ConfigService;
+use libc::{ c_int, c_long, c_uint, c_void };
+use openssl::ssl::{ SslContext, SslContextOptions, SslMethod, SSL_OP_CIPHER_SERVER_PREFERENCE,
+                    SSL_OP_NO_COMPRESSION, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TICKET,
+                    SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1 };
+use openssl::ssl::error::SslError;
+use openssl_sys::SSL_CTX;
+use std::ffi::CString;
+use std::fmt;
+use std::ptr;
+use std::str::FromStr;
+
+// The macros of ssl.h wrapping these controls.
+const SSL_CTRL_SET_SESS_CACHE_SIZE: c_int = 42;
+const SSL_CTRL_SET_SESS_CACHE_MODE: c_int = 44;
+const SSL_CTRL_SET_CURVES_LIST: c_int = 92;
+const SSL_SESS_CACHE_OFF: c_long = 0x0000;
+const SSL_SESS_CACHE_SERVER: c_long = 0x0002;
+
+// Sessions are only resumed in the context they were created in, which
+// OpenSSL requires to be named when client certificates are verified.
+const SESSION_ID_CONTEXT: &'static [u8] = b"foxbox";
+
+extern "C" {
+    fn SSL_CTX_ctrl(ctx: *mut SSL_CTX, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_CTX_set_timeout(ctx: *mut SSL_CTX, timeout: c_long) -> c_long;
+    fn SSL_CTX_set_session_id_context(ctx: *mut SSL_CTX, sid_ctx: *const u8, sid_ctx_len: c_uint) -> c_int;
+}
+
+// Defaults based on the "modern" profile of the Mozilla server side TLS
+// guidelines.
+const DEFAULT_CIPHERS: &'static str = "ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                                       ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                                       ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                                       ECDHE-ECDSA-AES256-SHA384:ECDHE-RSA-AES256-SHA384:\
+                                       ECDHE-ECDSA-AES128-SHA256:ECDHE-RSA-AES128-SHA256";
+const DEFAULT_ECDH_CURVES: &'static str = "P-256:P-384";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.0" => Ok(TlsVersion::Tls10),
+            "1.1" => Ok(TlsVersion::Tls11),
+            "1.2" => Ok(TlsVersion::Tls12),
+            _ => Err(format!("Unsupported TLS version: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TlsVersion::Tls10 => "TLSv1.0",
+            TlsVersion::Tls11 => "TLSv1.1",
+            TlsVersion::Tls12 => "TLSv1.2",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsPolicy {
+    pub min_version: TlsVersion,
+    /// An OpenSSL cipher list.
+    pub ciphers: String,
+    /// An OpenSSL curves list, or an empty one for OpenSSL's curves.
+    pub ecdh_curves: String,
+    pub session_tickets: bool,
+    /// Whether the server caches sessions for resumption, and how many for
+    /// how long.
+    pub session_cache: bool,
+    pub session_cache_size: i64,
+    pub session_timeout: i64,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        TlsPolicy {
+            min_version: TlsVersion::Tls12,
+            ciphers: DEFAULT_CIPHERS.to_owned(),
+            ecdh_curves: DEFAULT_ECDH_CURVES.to_owned(),
+            // Ticket keys are never rotated, which defeats forward secrecy.
+            session_tickets: false,
+            session_cache: true,
+            session_cache_size: 1024,
+            session_timeout: 300,
+        }
+    }
+}
+
+/// Whether OpenSSL accepts `ciphers` as a cipher list.
+fn is_valid_cipher_list(ciphers: &str) -> bool {
+    SslContext::new(SslMethod::Sslv23).and_then(|mut ctx| ctx.set_cipher_list(ciphers)).is_ok()
+}
+
+/// Whether OpenSSL accepts `curves` as a curves list. OpenSSL older than
+/// 1.0.2 doesn't accept any.
+fn is_valid_curves_list(curves: &str) -> bool {
+    SslContext::new(SslMethod::Sslv23).and_then(|mut ctx| set_curves_list(&mut ctx, curves)).is_ok()
+}
+
+fn ctrl(ctx: &mut SslContext, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long {
+    unsafe { SSL_CTX_ctrl(ctx.get_ctx(), cmd, larg, parg) }
+}
+
+fn set_curves_list(ctx: &mut SslContext, curves: &str) -> Result<(), SslError> {
+    let curves = match CString::new(curves) {
+        Ok(curves) => curves,
+        Err(_) => return Err(SslError::get())
+    };
+    if ctrl(ctx, SSL_CTRL_SET_CURVES_LIST, 0, curves.as_ptr() as *mut c_void) != 1 {
+        return Err(SslError::get());
+    }
+    Ok(())
+}
+
+impl TlsPolicy {
+    pub fn from_config(config: &ConfigService) -> Self {
+        let mut ciphers = config.get_or_set_default("tls", "ciphers", DEFAULT_CIPHERS);
+        if !is_valid_cipher_list(&ciphers) {
+            warn!("Invalid cipher list in tls;ciphers ({}), using the default one", ciphers);
+            ciphers = DEFAULT_CIPHERS.to_owned();
+        }
+
+        let mut ecdh_curves = config.get_or_set_default("tls", "ecdh_curves", DEFAULT_ECDH_CURVES);
+        if !ecdh_curves.is_empty() && !is_valid_curves_list(&ecdh_curves) {
+            warn!("Invalid curves list in tls;ecdh_curves ({}), using the default one", ecdh_curves);
+            ecdh_curves = DEFAULT_ECDH_CURVES.to_owned();
+            if !is_valid_curves_list(&ecdh_curves) {
+                warn!("OpenSSL can't set the ECDH curves, using its own");
+                ecdh_curves = String::new();
+            }
+        }
+
+        TlsPolicy {
+            min_version: config.get_or_set_default_parsed("tls", "min_version", "1.2"),
+            ciphers: ciphers,
+            ecdh_curves: ecdh_curves,
+            session_tickets: config.get_or_set_default("tls", "session_tickets", "false") == "true",
+            session_cache: config.get_or_set_default("tls", "session_cache", "true") == "true",
+            session_cache_size: config.get_or_set_default_parsed("tls", "session_cache_size", "1024"),
+            session_timeout: config.get_or_set_default_parsed("tls", "session_timeout_seconds", "300"),
+        }
+    }
+
+    fn options(&self) -> SslContextOptions {
+        let mut options = SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_COMPRESSION |
+                          SSL_OP_CIPHER_SERVER_PREFERENCE;
+        if self.min_version > TlsVersion::Tls10 {
+            options = options | SSL_OP_NO_TLSV1;
+        }
+        if self.min_version > TlsVersion::Tls11 {
+            options = options | SSL_OP_NO_TLSV1_1;
+        }
+        if !self.session_tickets {
+            options = options | SSL_OP_NO_TICKET;
+        }
+        options
+    }
+
+    fn apply_session_cache(&self, ctx: &mut SslContext) -> Result<(), SslError> {
+        if !self.session_cache {
+            ctrl(ctx, SSL_CTRL_SET_SESS_CACHE_MODE, SSL_SESS_CACHE_OFF, ptr::null_mut());
+            return Ok(());
+        }
+
+        ctrl(ctx, SSL_CTRL_SET_SESS_CACHE_MODE, SSL_SESS_CACHE_SERVER, ptr::null_mut());
+        ctrl(ctx, SSL_CTRL_SET_SESS_CACHE_SIZE, self.session_cache_size as c_long, ptr::null_mut());
+        unsafe {
+            SSL_CTX_set_timeout(ctx.get_ctx(), self.session_timeout as c_long);
+            if SSL_CTX_set_session_id_context(ctx.get_ctx(), SESSION_ID_CONTEXT.as_ptr(),
+                                              SESSION_ID_CONTEXT.len() as c_uint) != 1 {
+                return Err(SslError::get());
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the policy to `ctx`.
+    pub fn apply(&self, ctx: &mut SslContext) -> Result<(), SslError> {
+        try!(ctx.set_cipher_list(&self.ciphers));
+        ctx.set_options(self.options());
+        try!(ctx.set_ecdh_auto(true));
+        if !self.ecdh_curves.is_empty() {
+            try!(set_curves_list(ctx, &self.ecdh_curves));
+        }
+        self.apply_session_cache(ctx)
+    }
+}
+
+impl fmt::Display for TlsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "minimum version {}, ciphers {}, ECDH curves {}, session tickets {}, ",
+                    self.min_version, self.ciphers,
+                    if self.ecdh_curves.is_empty() { "OpenSSL's" } else { &self.ecdh_curves[..] },
+                    if self.session_tickets { "on" } else { "off" }));
+        if self.session_cache {
+            write!(f, "session cache of {} sessions for {}s", self.session_cache_size, self.session_timeout)
+        } else {
+            write!(f, "session cache off")
+        }
+    }
+}
+
+#[cfg(test)]
+describe! tls_policy {
+    before_each {
+        use config_store::ConfigService;
+        use openssl::ssl::{ SslContext, SslMethod };
+        use std::fs;
+        use uuid::Uuid;
+
+        let config_file_name = format!("conftest-{}.tmp", Uuid::new_v4().to_simple_string());
+        let config = ConfigService::new(&config_file_name);
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+    }
+
+    it "should default to a modern policy" {
+        let policy = TlsPolicy::from_config(&config);
+        assert_eq!(policy.min_version, TlsVersion::Tls12);
+        assert_eq!(policy.ecdh_curves, DEFAULT_ECDH_CURVES);
+        assert!(!policy.session_tickets);
+        assert!(policy.session_cache);
+        assert!(policy.options().contains(SSL_OP_NO_TLSV1_1));
+        assert!(policy.options().contains(SSL_OP_NO_TICKET));
+    }
+
+    it "should allow older versions when configured" {
+        config.set("tls", "min_version", "1.0");
+        config.set("tls", "session_tickets", "true");
+        let policy = TlsPolicy::from_config(&config);
+        assert_eq!(policy.min_version, TlsVersion::Tls10);
+        assert!(!policy.options().intersects(SSL_OP_NO_TLSV1 | SSL_OP_NO_TLSV1_1 | SSL_OP_NO_TICKET));
+        assert!(policy.options().contains(SSL_OP_NO_SSLV3));
+    }
+
+    it "should ignore invalid versions" {
+        config.set("tls", "min_version", "1.3-draft");
+        assert_eq!(TlsPolicy::from_config(&config).min_version, TlsVersion::Tls12);
+    }
+
+    it "should ignore invalid cipher lists" {
+        config.set("tls", "ciphers", "NOT-A-CIPHER");
+        assert_eq!(TlsPolicy::from_config(&config).ciphers, DEFAULT_CIPHERS);
+    }
+
+    it "should ignore invalid curves lists" {
+        config.set("tls", "ecdh_curves", "NOT-A-CURVE");
+        assert_eq!(TlsPolicy::from_config(&config).ecdh_curves, DEFAULT_ECDH_CURVES);
+    }
+
+    it "should read the session cache settings" {
+        config.set("tls", "session_cache_size", "10");
+        config.set("tls", "session_timeout_seconds", "60");
+        let policy = TlsPolicy::from_config(&config);
+        assert_eq!((policy.session_cache_size, policy.session_timeout), (10, 60));
+
+        config.set("tls", "session_cache", "false");
+        assert!(!TlsPolicy::from_config(&config).session_cache);
+    }
+
+    it "should apply to a context" {
+        let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        assert!(TlsPolicy::default().apply(&mut ctx).is_ok());
+
+        let mut policy = TlsPolicy::default();
+        policy.session_cache = false;
+        assert!(policy.apply(&mut ctx).is_ok());
+
+        policy.ecdh_curves = "NOT-A-CURVE".to_owned();
+        assert!(policy.apply(&mut ctx).is_err());
+
+        let mut policy = TlsPolicy::default();
+        policy.ciphers = "NOT-A-CIPHER".to_owned();
+        assert!(policy.apply(&mut ctx).is_err());
+    }
+}
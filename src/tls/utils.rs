@@ -17,7 +17,16 @@ use tls::certificate_record::CertificateRecord;
 /// Create a `HashMap` of `CertificateRecord`s (hashed by the hostname it was created for)
 pub fn create_records_from_directory(path: &PathBuf)
         -> Result<HashMap<String, CertificateRecord>, io::Error> {
+    load_records_from_directory(path).map(|(records, _)| records)
+}
+
+/// Create a `HashMap` of `CertificateRecord`s like `create_records_from_directory`,
+/// also returning the hostnames whose certificate couldn't be loaded. These
+/// are skipped rather than failing the whole directory.
+pub fn load_records_from_directory(path: &PathBuf)
+        -> Result<(HashMap<String, CertificateRecord>, Vec<String>), io::Error> {
     let mut records = HashMap::new();
+    let mut failed = vec![];
 
     // Ensure the directory exists.
     fs::create_dir_all(path).unwrap_or_else(|err| {
@@ -35,7 +44,7 @@ pub fn create_records_from_directory(path: &PathBuf)
             let file_type = try!(entry.file_type());
             if file_type.is_dir() || file_type.is_symlink() {
 
-                let hostname = entry.file_name().into_string().unwrap();
+                let hostname = entry.file_name().to_string_lossy().into_owned();
                 debug!("Loading certificate for host {}", hostname);
 
                 let mut host_path = path.clone();
@@ -56,16 +65,19 @@ pub fn create_records_from_directory(path: &PathBuf)
                     None
                 };
 
-                records.insert(hostname.clone(),
-                               try!(CertificateRecord::new(hostname,
-                                                      cert_path,
-                                                      private_key_file,
-                                                      fullchain)));
+                match CertificateRecord::new(hostname.clone(), cert_path, private_key_file,
+                                             fullchain) {
+                    Ok(record) => { records.insert(hostname, record); },
+                    Err(err) => {
+                        error!("Skipping the certificate for {}: {}", hostname, err);
+                        failed.push(hostname);
+                    }
+                }
             }
         }
 
         info!("Loaded certificates from directory: {:?}", path);
-        Ok(records)
+        Ok((records, failed))
     } else {
         Err(io::Error::new(
             io::ErrorKind::InvalidInput,
@@ -0,0 +1,337 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Hot reload of the certificates directory.
+//!
+//! Operators can drop their own certificates in the certificates directory:
+//! changes are picked up with inotify on Linux, and by polling the directory
+//! elsewhere. Changes are only acted upon once the directory has been quiet
+//! for a while, so that half-written files are not loaded, and certificates
+//! that can't be loaded keep their current version.
+
+use config_store::ConfigService;
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::thread;
+use std::time::{ Duration, SystemTime };
+use tls::CertificateManager;
+
+pub struct WatchSettings {
+    pub enabled: bool,
+    /// How long the directory must be quiet before reloading.
+    pub settle_delay: Duration,
+    /// How often the directory is checked when inotify is not available.
+    pub poll_interval: Duration,
+}
+
+impl WatchSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        WatchSettings {
+            enabled: config.get_or_set_default("certificates", "watch", "true") == "true",
+            settle_delay: Duration::from_secs(config.get_or_set_default_parsed("certificates", "reload_delay_seconds", "2")),
+            poll_interval: Duration::from_secs(config.get_or_set_default_parsed("certificates", "poll_interval_seconds", "10")),
+        }
+    }
+}
+
+/// Whether a change to the file `name` may affect the certificates. Temporary
+/// files, written before being renamed into place, and editor backups are
+/// ignored.
+pub fn is_relevant(name: &str) -> bool {
+    !(name.starts_with('.') || name.ends_with(".tmp") || name.ends_with('~') ||
+      name.ends_with(".swp"))
+}
+
+/// The relevant files of the directory and of its subdirectories, with their
+/// size and modification time.
+fn snapshot(directory: &Path) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+    let mut files = vec![];
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return files
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !is_relevant(&name) {
+            continue;
+        }
+        // Follows the symlinks of the subject alternative names.
+        if let Ok(metadata) = fs::metadata(entry.path()) {
+            if metadata.is_dir() {
+                files.extend(snapshot(&entry.path()));
+            } else {
+                files.push((entry.path(), metadata.len(), metadata.modified().ok()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn reload(certificate_manager: &CertificateManager) {
+    match certificate_manager.reload_checked() {
+        Ok(ref rejected) if rejected.is_empty() => info!("Reloaded the certificates"),
+        Ok(rejected) => {
+            warn!("Reloaded the certificates, keeping the current version of {:?}", rejected)
+        },
+        Err(err) => error!("Unable to reload the certificates, keeping the current ones: {}", err)
+    }
+}
+
+pub struct CertificateWatcher {
+    certificate_manager: CertificateManager,
+    settings: WatchSettings,
+}
+
+impl CertificateWatcher {
+    pub fn new(certificate_manager: CertificateManager, settings: WatchSettings) -> Self {
+        CertificateWatcher {
+            certificate_manager: certificate_manager,
+            settings: settings,
+        }
+    }
+
+    /// Spawns a thread watching the certificates directory until the process exits.
+    pub fn start(self) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        thread::Builder::new().name("CertificateWatcher".to_owned())
+            .spawn(move || {
+                if let Err(err) = self.watch() {
+                    warn!("Unable to watch the certificates directory ({}), polling it instead", err);
+                }
+                self.poll();
+            }).unwrap();
+    }
+
+    /// Waits for changes with inotify, returning only if it fails.
+    #[cfg(target_os = "linux")]
+    fn watch(&self) -> io::Result<()> {
+        let directory = self.certificate_manager.get_certs_dir();
+        let inotify = try!(inotify::Inotify::new());
+        try!(inotify.watch_tree(&directory));
+        info!("Watching the certificates in {:?}", directory);
+
+        loop {
+            let events = try!(inotify.wait(None));
+            if !events.iter().any(|name| is_relevant(name)) {
+                continue;
+            }
+            // Let the writes settle.
+            while try!(inotify.wait(Some(self.settings.settle_delay))).iter().any(|name| is_relevant(name)) {}
+
+            // New subdirectories need to be watched too.
+            try!(inotify.watch_tree(&directory));
+            reload(&self.certificate_manager);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn watch(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "inotify is only available on Linux"))
+    }
+
+    fn poll(&self) {
+        let directory = self.certificate_manager.get_certs_dir();
+        let mut current = snapshot(&directory);
+        loop {
+            thread::sleep(self.settings.poll_interval);
+            let mut latest = snapshot(&directory);
+            if latest == current {
+                continue;
+            }
+            // Let the writes settle.
+            loop {
+                thread::sleep(self.settings.settle_delay);
+                let settled = snapshot(&directory);
+                if settled == latest {
+                    break;
+                }
+                latest = settled;
+            }
+            current = latest;
+            reload(&self.certificate_manager);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    extern crate libc;
+
+    use std::ffi::CString;
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+    use std::time::Duration;
+
+    const IN_MODIFY: u32 = 0x0000_0002;
+    const IN_CLOSE_WRITE: u32 = 0x0000_0008;
+    const IN_MOVED_FROM: u32 = 0x0000_0040;
+    const IN_MOVED_TO: u32 = 0x0000_0080;
+    const IN_CREATE: u32 = 0x0000_0100;
+    const IN_DELETE: u32 = 0x0000_0200;
+    const WATCH_MASK: u32 = IN_MODIFY | IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO |
+                            IN_CREATE | IN_DELETE;
+
+    #[repr(C)]
+    struct InotifyEvent {
+        wd: libc::c_int,
+        mask: u32,
+        cookie: u32,
+        len: u32,
+        // Followed by `len` bytes holding the nul padded file name.
+    }
+
+    extern {
+        fn inotify_init() -> libc::c_int;
+        fn inotify_add_watch(fd: libc::c_int, pathname: *const libc::c_char, mask: u32) -> libc::c_int;
+    }
+
+    pub struct Inotify {
+        fd: libc::c_int,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { inotify_init() };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Inotify { fd: fd })
+        }
+
+        fn add_watch(&self, path: &Path) -> io::Result<()> {
+            let path = try!(CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid path")
+            }));
+            // Watching a path twice is harmless, the existing watch is updated.
+            if unsafe { inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Watches `directory` and all its subdirectories.
+        pub fn watch_tree(&self, directory: &Path) -> io::Result<()> {
+            try!(self.add_watch(directory));
+            for entry in try!(fs::read_dir(directory)) {
+                let entry = try!(entry);
+                // Symlinked directories are watched through their own entry,
+                // don't follow them to avoid loops.
+                if try!(entry.file_type()).is_dir() {
+                    try!(self.watch_tree(&entry.path()));
+                }
+            }
+            Ok(())
+        }
+
+        /// Waits for events, for at most `timeout` if given, and returns the
+        /// names of the files they are about.
+        pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<String>> {
+            let timeout = timeout.map_or(-1, |timeout| {
+                (timeout.as_secs() * 1000) as libc::c_int +
+                (timeout.subsec_nanos() / 1_000_000) as libc::c_int
+            });
+            let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+            if ready < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if ready == 0 {
+                return Ok(vec![]);
+            }
+
+            // A buffer of u32 keeps the events aligned.
+            let mut buffer = vec![0u32; 1024];
+            let size = unsafe {
+                libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void,
+                           buffer.len() * mem::size_of::<u32>())
+            };
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let bytes = buffer.as_ptr() as *const u8;
+            let header_size = mem::size_of::<InotifyEvent>();
+            let mut names = vec![];
+            let mut offset = 0;
+            while offset + header_size <= size as usize {
+                let event: InotifyEvent = unsafe { ptr::read(bytes.offset(offset as isize) as *const InotifyEvent) };
+                let name = unsafe {
+                    let start = bytes.offset((offset + header_size) as isize);
+                    let name: Vec<u8> = (0..event.len as isize).map(|i| *start.offset(i))
+                                                               .take_while(|byte| *byte != 0)
+                                                               .collect();
+                    String::from_utf8_lossy(&name).into_owned()
+                };
+                names.push(name);
+                offset += header_size + event.len as usize;
+            }
+            Ok(names)
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+#[cfg(test)]
+describe! certificate_watcher {
+    it "should ignore temporary files" {
+        assert!(is_relevant("cert.pem"));
+        assert!(is_relevant("local.box.knilxof.org"));
+        assert!(!is_relevant("cert.tmp"));
+        assert!(!is_relevant(".cert.pem.swp"));
+        assert!(!is_relevant("privkey.pem~"));
+    }
+
+    it "should detect changes in the subdirectories" {
+        use mktemp::Temp;
+        use std::fs::{ self, File };
+        use std::io::Write;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let directory = temp_dir.to_path_buf();
+        fs::create_dir_all(directory.join("foxbox.local")).unwrap();
+        let empty = snapshot(&directory);
+
+        File::create(directory.join("foxbox.local").join("cert.tmp")).unwrap();
+        assert_eq!(snapshot(&directory), empty);
+
+        File::create(directory.join("foxbox.local").join("cert.pem")).unwrap()
+            .write_all(b"cert").unwrap();
+        assert_eq!(snapshot(&directory).len(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    it "should watch the nested subdirectories" {
+        use mktemp::Temp;
+        use std::fs::{ self, File };
+        use std::time::Duration;
+        use tls::watcher::inotify::Inotify;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let directory = temp_dir.to_path_buf();
+        let nested = directory.join("foxbox.local").join("archive");
+        fs::create_dir_all(&nested).unwrap();
+
+        let inotify = Inotify::new().unwrap();
+        inotify.watch_tree(&directory).unwrap();
+        assert!(inotify.wait(Some(Duration::from_millis(10))).unwrap().is_empty());
+
+        File::create(nested.join("cert.pem")).unwrap();
+        let names = inotify.wait(Some(Duration::from_secs(5))).unwrap();
+        assert!(names.contains(&"cert.pem".to_owned()));
+    }
+}
@@ -2,9 +2,15 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use acl::Acl;
+use api_keys::ApiKeys;
+use audit::AuditLog;
 use config_store::ConfigService;
 use core::marker::Reflect;
+use foxbox_taxonomy::api::User;
 use foxbox_users::UsersManager;
+use guest_tokens::GuestTokens;
+use network_monitor::NetworkStatus;
 use profile_service::ProfileService;
 use serde_json;
 use std::io;
@@ -12,9 +18,12 @@ use std::net::SocketAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::vec::IntoIter;
-use tls::{ CertificateRecord, CertificateManager };
+use tls::{ CertificateRecord, CertificateManager, ClientAuth };
+use tunnel_controller::{ TunnelClients, TunnelStatus };
+use tunnel_credentials::TunnelCredentials;
 use upnp::UpnpManager;
 use ws;
+use ws_server::{ WsQueue, WsViewer };
 
 pub trait Controller : Send + Sync + Clone + Reflect + 'static {
     fn run(&mut self, shutdown_flag: &AtomicBool);
@@ -25,15 +34,36 @@ pub trait Controller : Send + Sync + Clone + Reflect + 'static {
 
     fn get_tls_enabled(&self) -> bool;
     fn get_certificate_manager(&self) -> CertificateManager;
+    fn get_client_auth(&self) -> ClientAuth;
     fn get_box_certificate(&self) -> io::Result<CertificateRecord>;
     fn get_hostname(&self) -> String;
 
-    fn add_websocket(&mut self, socket: ws::Sender);
+    fn get_network_status(&self) -> NetworkStatus;
+    fn set_network_status(&self, status: NetworkStatus);
+    /// The status of the tunnel, if it is enabled.
+    fn get_tunnel_status(&self) -> Option<TunnelStatus>;
+    fn set_tunnel_status(&self, status: Option<TunnelStatus>);
+    fn get_tunnel_credentials(&self) -> Arc<TunnelCredentials>;
+    /// The remote clients of the streams relayed by the tunnel.
+    fn get_tunnel_clients(&self) -> TunnelClients;
+
+    /// Adds a socket to the broadcasts of the events `viewer` may see, after
+    /// replaying to it the ones that followed `since`. The events are sent
+    /// through its `queue`.
+    fn add_websocket(&mut self, socket: ws::Sender, queue: WsQueue, viewer: WsViewer, since: Option<u64>)
+        -> ws::Result<()>;
     fn remove_websocket(&mut self, socket: ws::Sender);
     fn broadcast_to_websockets(&self, data: serde_json::value::Value);
+    fn websockets_count(&self) -> usize;
 
+    fn get_audit_log(&self) -> Arc<AuditLog>;
+    fn get_acl(&self) -> Arc<Acl>;
+    fn get_api_keys(&self) -> Arc<ApiKeys>;
+    fn get_guest_tokens(&self) -> Arc<GuestTokens>;
     fn get_config(&self) -> Arc<ConfigService>;
     fn get_upnp_manager(&self) -> Arc<UpnpManager>;
     fn get_users_manager(&self) -> Arc<UsersManager>;
+    /// Whether `user` can use the admin endpoints and see everything.
+    fn is_admin(&self, user: &User) -> bool;
     fn get_profile(&self) -> &ProfileService;
 }
@@ -2,25 +2,162 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-// Assumes Unix
+//! The tunnel making the box reachable from outside of its local network.
+//!
+//! Two backends are available, selected with `tunnel;backend`:
+//!
+//! - `builtin`, the default, connects to the tunnel frontend with TLS,
+//!   authenticates with the name and own credential of the box (see
+//!   `tunnel_credentials`), and then relays the client connections that the
+//!   frontend multiplexes over that single connection to the local HTTP and
+//!   WebSocket servers (see `tunnel_protocol` for the wire format). The
+//!   connection is reopened like a supervised process when it fails. It needs
+//!   a frontend implementing that protocol.
+//! - `pagekite`, for the frontends which don't, runs `pagekite.py` as a
+//!   supervised process (see `managed_process`), authenticated with the
+//!   secret shared with the frontend, `tunnel;pagekite_secret`. The secret is
+//!   handed to pagekite in an options file readable by the box only, rather
+//!   than on its command line.
+//!
+//! The state of the tunnel is reported through `Controller::set_tunnel_status`.
+
+use config_store::ConfigService;
+use managed_process::{ RestartPolicy, RestartTracker, Supervisor, SupervisorSettings,
+                       SupervisorStatus };
+use openssl::nid::Nid;
+use openssl::ssl::{ Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_PEER };
+use openssl::x509::{ X509, X509FileType };
+use serde_json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{ self, OpenOptions, Permissions };
+use std::io;
 use std::io::prelude::*;
-use std::process::{ Child, Command };
-use std::io::Result;
+use std::net::{ IpAddr, Shutdown, SocketAddr, TcpStream };
+use std::os::unix::fs::{ OpenOptionsExt, PermissionsExt };
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ sync_channel, Receiver, SyncSender, TrySendError };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+use time;
+use tls::{ CertificateManager, CertificateRecord };
+use traits::Controller;
+use tunnel_credentials::{ TunnelCredential, TunnelCredentials };
+use tunnel_protocol::{ auth_proof, parse_open, Frame, FrameReader, FrameType, MAX_PAYLOAD_SIZE };
 use url::{ SchemeData, Url };
-use managed_process::ManagedProcess;
 
-pub type TunnelProcess = ManagedProcess;
+/// How long reads from the frontend block, which bounds the latency of the
+/// data sent by the local servers.
+const POLL_INTERVAL_MS: u64 = 50;
+const HANDSHAKE_TIMEOUT_SECONDS: u64 = 15;
+const WRITE_TIMEOUT_SECONDS: u64 = 30;
+/// The frames read from the local streams and not sent to the frontend yet.
+/// The local streams aren't read while the queue is full.
+const OUTGOING_QUEUE_FRAMES: usize = 64;
+/// The frames received for a local stream and not written to it yet. A
+/// stream too slow to keep up is closed, rather than stalling the others.
+const STREAM_QUEUE_FRAMES: usize = 256;
 
-pub struct Tunnel {
-    config: TunnelConfig,
-    pub tunnel_process: Option<TunnelProcess>
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TunnelBackend {
+    Pagekite,
+    Builtin,
+}
+
+impl FromStr for TunnelBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, String> {
+        match backend {
+            "pagekite" => Ok(TunnelBackend::Pagekite),
+            "builtin" => Ok(TunnelBackend::Builtin),
+            _ => Err(format!("Unknown tunnel backend {}", backend))
+        }
+    }
+}
+
+pub struct TunnelSettings {
+    pub backend: TunnelBackend,
+    /// The secret shared with the pagekite frontend, which has no default.
+    pub pagekite_secret: String,
+    /// How the connection is reopened after a failure.
+    pub supervisor: SupervisorSettings,
+    /// How often the connection is checked with a ping, in seconds. It is
+    /// dropped when nothing was received for three times as long.
+    pub keepalive: u64,
+    /// The certificates trusted for the frontend, instead of the system ones.
+    pub ca_file: Option<String>,
+}
+
+impl TunnelSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        let ca_file = config.get_or_set_default("tunnel", "ca_file", "");
+
+        TunnelSettings {
+            backend: config.get_or_set_default_parsed("tunnel", "backend", "builtin"),
+            pagekite_secret: config.get("tunnel", "pagekite_secret").unwrap_or_else(String::new),
+            supervisor: SupervisorSettings::from_config(config, "tunnel", RestartPolicy::Always),
+            keepalive: config.get_or_set_default_parsed("tunnel", "keepalive_seconds", "30"),
+            ca_file: if ca_file.is_empty() { None } else { Some(ca_file) },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TunnelState {
+    Connecting,
+    Authenticating,
+    Connected,
+    /// Waiting before reconnecting.
+    Waiting,
+    Stopped,
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TunnelState::Connecting => "connecting",
+            TunnelState::Authenticating => "authenticating",
+            TunnelState::Connected => "connected",
+            TunnelState::Waiting => "waiting",
+            TunnelState::Stopped => "stopped",
+        })
+    }
+}
+
+/// The state of the tunnel, exposed for health checks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TunnelStatus {
+    pub state: String,
+    pub frontend: String,
+    /// Seconds since the epoch.
+    pub connected_since: Option<i64>,
+    /// The reconnections, failures counting the connections that failed.
+    pub supervisor: SupervisorStatus,
+    /// The traffic with the frontend, frame headers included.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub streams_opened: u64,
+    pub active_streams: u64,
+}
+
+impl TunnelStatus {
+    pub fn is_failing(&self) -> bool {
+        self.supervisor.is_failing()
+    }
+
+    fn set_state(&mut self, state: TunnelState) {
+        self.state = state.to_string();
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct TunnelConfig {
     /// The socket address that the box connects to to establish the tunnel.
     tunnel_url: Url,
-    tunnel_secret: String,
     local_http_port: u16,
     local_ws_port: u16,
     remote_name: String
@@ -28,7 +165,6 @@ pub struct TunnelConfig {
 
 impl TunnelConfig {
     pub fn new(tunnel_url: String,
-               tunnel_secret: String,
                local_http_port: u16,
                local_ws_port: u16,
                remote_name: String) -> Self {
@@ -63,51 +199,601 @@ impl TunnelConfig {
 
         TunnelConfig {
             tunnel_url: tunnel_url,
-            tunnel_secret: tunnel_secret,
             local_http_port: local_http_port,
             local_ws_port: local_ws_port,
             remote_name: remote_name
         }
     }
 
-    /// Describes how to spawn the pagekite process.
-    /// pagekite requires a user (remote_name) and a shared secret to be able
-    /// to connect us with the bridge. For the first prototype we will have a
-    /// secret common to all boxes, but in the end we will need a secret per
-    /// box. Unfortunately pagekite does not provide a way to add a new
-    /// domain/secret pair while the bridge is running, but it provides the
-    /// possibility to delegate the authentication to a dynamic DNS server.
-    /// XXX We will move to DNS authentication after the first prototype if
-    /// we keep using pagekite.
-    /// https://github.com/fxbox/foxbox/issues/177#issuecomment-194778308
-    pub fn spawn(&self) -> Result<Child> {
+    fn frontend(&self) -> (String, u16) {
         let domain = match self.tunnel_url.domain() {
-            Some(domain) => domain,
+            Some(domain) => domain.to_owned(),
             None => {
                 panic!("No tunnel domain found. Cannot start tunneling");
             }
         };
+        // The frontend listens on the HTTPS port unless told otherwise.
+        (domain, self.tunnel_url.port().unwrap_or(443))
+    }
+
+    /// The options of pagekite, which relays the HTTP and WebSocket servers
+    /// under the remote name of the box, in the format of its options files.
+    fn pagekite_options(&self, secret: &str) -> String {
+        let (domain, port) = self.frontend();
+        format!("frontend = {}:{}\n\
+                 service_on = http,https:{}:localhost:{}:{}\n\
+                 service_on = websocket:{}:localhost:{}:{}\n",
+                domain, port,
+                // XXX remove http service once we support https
+                self.remote_name, self.local_http_port, secret,
+                self.remote_name, self.local_ws_port, secret)
+    }
+
+    /// Describes how to spawn pagekite with the options written to
+    /// `options_file`, which keeps the secret out of its command line.
+    fn pagekite_command(options_file: &str) -> Command {
+        let mut command = Command::new("pagekite.py");
+        command.arg("--clean")
+               .arg(format!("--optfile={}", options_file));
+        command
+    }
+
+    /// The local port of a service opened by the frontend.
+    fn local_port(&self, service: &str) -> Option<u16> {
+        match service {
+            "http" => Some(self.local_http_port),
+            "ws" => Some(self.local_ws_port),
+            _ => None
+        }
+    }
+}
+
+/// Writes the pagekite options to `path`, readable by their owner only.
+fn write_pagekite_options(path: &str, options: &str) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true)
+                                          .mode(0o600).open(path));
+    // The file could have been created before, with other permissions.
+    try!(fs::set_permissions(path, Permissions::from_mode(0o600)));
+    try!(file.write_all(options.as_bytes()));
+    file.sync_all()
+}
+
+fn ssl_error<E: fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("TLS error: {}", err))
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether the name of a certificate, which can start with a wildcard,
+/// matches `host`.
+fn matches_hostname(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let host = host.to_lowercase();
+    if pattern.starts_with("*.") {
+        match host.find('.') {
+            Some(dot) => dot > 0 && host[dot..] == pattern[1..],
+            None => false
+        }
+    } else {
+        pattern == host
+    }
+}
+
+/// The DNS names a certificate is valid for: its subject alternative names,
+/// or its common name if it has none (RFC 6125).
+fn certificate_names(cert: &X509) -> Vec<String> {
+    let alt_names: Vec<String> = cert.subject_alt_names().map_or(vec![], |names| {
+        names.iter().filter_map(|name| name.dnsname().map(|name| name.to_owned())).collect()
+    });
+    if !alt_names.is_empty() {
+        return alt_names;
+    }
+    cert.subject_name().text_by_nid(Nid::CN).map_or(vec![], |name| vec![name.to_string()])
+}
+
+/// Opens the TLS connection to the frontend, checking its certificate
+/// against the trusted authorities and its names against the host.
+/// The box certificate is presented as client certificate, for the frontend
+/// to check the fingerprint of the credentials it enrolls.
+fn connect(host: &str, port: u16, settings: &TunnelSettings, box_certificate: &CertificateRecord)
+           -> io::Result<SslStream<TcpStream>> {
+    let tcp_stream = try!(TcpStream::connect((host, port)));
+    try!(tcp_stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS))));
+    try!(tcp_stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECONDS))));
+
+    let mut context = try!(SslContext::new(SslMethod::Sslv23).map_err(ssl_error));
+    context.set_verify(SSL_VERIFY_PEER, None);
+    match settings.ca_file {
+        Some(ref ca_file) => try!(context.set_CA_file(ca_file).map_err(ssl_error)),
+        None => try!(context.set_default_verify_paths().map_err(ssl_error))
+    }
+    try!(context.set_certificate_file(&box_certificate.cert_file, X509FileType::PEM).map_err(ssl_error));
+    try!(context.set_private_key_file(&box_certificate.private_key_file, X509FileType::PEM)
+                .map_err(ssl_error));
+    let ssl = try!(Ssl::new(&context).map_err(ssl_error));
+    try!(ssl.set_hostname(host).map_err(ssl_error));
+    let stream = try!(SslStream::connect(ssl, tcp_stream).map_err(ssl_error));
+
+    let names = stream.ssl().peer_certificate().map_or(vec![], |cert| certificate_names(&cert));
+    if names.iter().any(|name| matches_hostname(name, host)) {
+        Ok(stream)
+    } else {
+        Err(ssl_error(format!("the certificate of the frontend is for {:?}, not {}", names, host)))
+    }
+}
+
+/// The remote clients of the streams relayed by the tunnel, by the address
+/// the local servers see these streams coming from. They all come from the
+/// loopback, which doesn't tell the clients apart.
+#[derive(Clone, Default)]
+pub struct TunnelClients {
+    clients: Arc<Mutex<HashMap<SocketAddr, IpAddr>>>,
+}
+
+impl TunnelClients {
+    pub fn new() -> Self {
+        TunnelClients::default()
+    }
+
+    /// Records that the local stream coming from `local` relays `client`,
+    /// until the returned guard is dropped.
+    pub fn register(&self, local: SocketAddr, client: IpAddr) -> TunnelClientGuard {
+        self.clients.lock().unwrap().insert(local, client);
+        TunnelClientGuard {
+            local: local,
+            clients: self.clone(),
+        }
+    }
+
+    /// The remote client of the connection coming from `local`, if it is
+    /// relayed by the tunnel.
+    pub fn client_for(&self, local: &SocketAddr) -> Option<IpAddr> {
+        self.clients.lock().unwrap().get(local).cloned()
+    }
+}
+
+/// Keeps the client of a relayed stream known while the stream is open.
+pub struct TunnelClientGuard {
+    local: SocketAddr,
+    clients: TunnelClients,
+}
+
+impl Drop for TunnelClientGuard {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.local);
+    }
+}
+
+/// A stream opened by the frontend, connected to a local server. The data
+/// received for it is written by a dedicated thread.
+struct LocalStream {
+    stream: TcpStream,
+    writer: SyncSender<Vec<u8>>,
+    _client: Option<TunnelClientGuard>,
+}
+
+/// One connection to the frontend, and the local streams relayed over it.
+struct Session<S: Read + Write> {
+    stream: S,
+    reader: FrameReader,
+    local_streams: HashMap<u32, LocalStream>,
+    // The frames from the local streams, sent from the session thread.
+    outgoing: Receiver<Frame>,
+    outgoing_sender: SyncSender<Frame>,
+    clients: TunnelClients,
+}
+
+impl<S: Read + Write> Session<S> {
+    fn new(stream: S, clients: TunnelClients) -> Self {
+        let (sender, receiver) = sync_channel(OUTGOING_QUEUE_FRAMES);
+        Session {
+            stream: stream,
+            reader: FrameReader::new(),
+            local_streams: HashMap::new(),
+            outgoing: receiver,
+            outgoing_sender: sender,
+            clients: clients,
+        }
+    }
+
+    fn send(&mut self, frame: Frame, status: &mut TunnelStatus) -> io::Result<()> {
+        try!(self.stream.write_all(&frame.encode()));
+        status.bytes_sent += frame.size() as u64;
+        Ok(())
+    }
+
+    /// Waits for a frame, for at most the read timeout of the stream.
+    fn receive(&mut self, status: &mut TunnelStatus) -> io::Result<Option<Frame>> {
+        if let Some(frame) = try!(self.reader.next_frame()) {
+            status.bytes_received += frame.size() as u64;
+            return Ok(Some(frame));
+        }
+        try!(self.reader.fill(&mut self.stream));
+        let frame = try!(self.reader.next_frame());
+        if let Some(ref frame) = frame {
+            status.bytes_received += frame.size() as u64;
+        }
+        Ok(frame)
+    }
+
+    /// Waits for a frame of the handshake.
+    fn receive_handshake(&mut self, status: &mut TunnelStatus) -> io::Result<Frame> {
+        let deadline = Instant::now() + Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS);
+        while Instant::now() < deadline {
+            if let Some(frame) = try!(self.receive(status)) {
+                return Ok(frame);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "The frontend didn't complete the handshake"))
+    }
+
+    /// Proves the knowledge of the enrolled secret of the box. A secret that
+    /// isn't enrolled yet is sent along, with the proof of the previous one
+    /// when it replaces it, and returned once the frontend accepted it.
+    fn authenticate(&mut self, config: &TunnelConfig, credential: &TunnelCredential,
+                    status: &mut TunnelStatus) -> io::Result<Option<String>> {
+        let challenge = try!(self.receive_handshake(status));
+        if challenge.frame_type != FrameType::Challenge {
+            return Err(protocol_error(format!("Expected a challenge, got {:?}", challenge.frame_type)));
+        }
+
+        let proof_secret = if credential.enrolled {
+            Some(&credential.secret)
+        } else {
+            credential.previous_secret.as_ref()
+        };
+        let proof = proof_secret.map(|secret| {
+            auth_proof(secret.as_bytes(), &challenge.payload, &config.remote_name)
+        });
+        let enrolled_secret = if credential.enrolled { None } else { Some(&credential.secret) };
+        let auth = json!({
+            name: config.remote_name,
+            fingerprint: credential.fingerprint,
+            services: vec!["http", "ws"],
+            proof: proof,
+            credential: enrolled_secret
+        });
+        try!(self.send(Frame::control(FrameType::Auth, auth.into_bytes()), status));
+
+        let result = try!(self.receive_handshake(status));
+        match result.frame_type {
+            FrameType::AuthOk => Ok(enrolled_secret.cloned()),
+            FrameType::AuthFailed => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                   format!("Authentication failed: {}",
+                                           String::from_utf8_lossy(&result.payload))))
+            },
+            other => {
+                Err(protocol_error(format!("Expected an authentication result, got {:?}", other)))
+            }
+        }
+    }
+
+    /// Connects a stream opened by the frontend to the local server, and
+    /// relays what the server sends from a dedicated thread. Another thread
+    /// writes what the frontend sends, so that a slow server doesn't hold
+    /// the session up. The local servers can find `client` from the address
+    /// of the stream, through `TunnelClients`.
+    fn open_local_stream(&mut self, stream_id: u32, port: u16, client: Option<IpAddr>) -> io::Result<()> {
+        let local_stream = try!(TcpStream::connect(("localhost", port)));
+        try!(local_stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECONDS))));
+        // Known before anything is sent to the local server.
+        let client = match client {
+            Some(client) => Some(self.clients.register(try!(local_stream.local_addr()), client)),
+            None => None
+        };
+        let mut reader = try!(local_stream.try_clone());
+        let mut writer = try!(local_stream.try_clone());
+        let sender = self.outgoing_sender.clone();
+
+        try!(thread::Builder::new().name(format!("TunnelStream{}", stream_id)).spawn(move || {
+            let mut buffer = vec![0u8; MAX_PAYLOAD_SIZE];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => {
+                        let frame = Frame::new(FrameType::Data, stream_id, buffer[..size].to_vec());
+                        // Blocks while the session is behind.
+                        if sender.send(frame).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            sender.send(Frame::new(FrameType::Close, stream_id, vec![])).unwrap_or(());
+        }));
+
+        let (writer_sender, writer_receiver) = sync_channel::<Vec<u8>>(STREAM_QUEUE_FRAMES);
+        try!(thread::Builder::new().name(format!("TunnelStreamWriter{}", stream_id)).spawn(move || {
+            // Ends when the stream is closed, and its sender dropped.
+            for data in writer_receiver.iter() {
+                if let Err(err) = writer.write_all(&data) {
+                    debug!("Closing tunnel stream {}: {}", stream_id, err);
+                    // Also ends the thread reading from it, which reports the
+                    // closing to the frontend.
+                    writer.shutdown(Shutdown::Both).unwrap_or(());
+                    return;
+                }
+            }
+        }));
+
+        self.local_streams.insert(stream_id, LocalStream {
+            stream: local_stream,
+            writer: writer_sender,
+            _client: client,
+        });
+        Ok(())
+    }
+
+    fn close_local_stream(&mut self, stream_id: u32) -> bool {
+        match self.local_streams.remove(&stream_id) {
+            Some(local_stream) => {
+                // Also ends the threads reading from and writing to it.
+                local_stream.stream.shutdown(Shutdown::Both).unwrap_or(());
+                true
+            },
+            None => false
+        }
+    }
+
+    fn handle(&mut self, frame: Frame, config: &TunnelConfig, status: &mut TunnelStatus)
+              -> io::Result<()> {
+        match frame.frame_type {
+            FrameType::Open => {
+                let (service, client) = parse_open(&frame.payload);
+                let result = match config.local_port(&service) {
+                    Some(port) => self.open_local_stream(frame.stream_id, port, client),
+                    None => Err(protocol_error(format!("Unknown service {}", service)))
+                };
+                match result {
+                    Ok(_) => status.streams_opened += 1,
+                    Err(err) => {
+                        warn!("Unable to open tunnel stream {} to {}: {}", frame.stream_id, service, err);
+                        try!(self.send(Frame::new(FrameType::Close, frame.stream_id, vec![]), status));
+                    }
+                }
+            },
+            FrameType::Data => {
+                let result = match self.local_streams.get(&frame.stream_id) {
+                    Some(local_stream) => local_stream.writer.try_send(frame.payload),
+                    // Closed already, the frontend will get our Close.
+                    None => Ok(())
+                };
+                match result {
+                    Ok(_) => {},
+                    // The writer thread ended, the reader reports the closing.
+                    Err(TrySendError::Disconnected(_)) => {},
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Closing tunnel stream {}, the local server doesn't keep up",
+                              frame.stream_id);
+                        if self.close_local_stream(frame.stream_id) {
+                            try!(self.send(Frame::new(FrameType::Close, frame.stream_id, vec![]), status));
+                        }
+                    }
+                }
+            },
+            FrameType::Close => {
+                self.close_local_stream(frame.stream_id);
+            },
+            FrameType::Ping => {
+                try!(self.send(Frame::control(FrameType::Pong, frame.payload), status));
+            },
+            FrameType::Pong => {},
+            other => return Err(protocol_error(format!("Unexpected {:?} frame", other)))
+        }
+        status.active_streams = self.local_streams.len() as u64;
+        Ok(())
+    }
+
+    /// Relays the streams until the connection fails, `stop_flag` is set,
+    /// or the credential is rotated (and its generation changes). The
+    /// failures are forgotten by `tracker` once the connection stayed up for
+    /// `stable_seconds`.
+    fn run<F>(&mut self, config: &TunnelConfig, settings: &TunnelSettings, stop_flag: &AtomicBool,
+              credentials: &TunnelCredentials, generation: usize, tracker: &mut RestartTracker,
+              status: &mut TunnelStatus, publish: &mut F) -> io::Result<()>
+              where F: FnMut(&TunnelStatus) {
+        let keepalive = Duration::from_secs(settings.keepalive);
+        let stable_after = Duration::from_secs(settings.supervisor.stable_after);
+        let connected_at = Instant::now();
+        let mut stable = false;
+        let mut last_received = Instant::now();
+        let mut last_ping = Instant::now();
+        let mut last_publish = Instant::now();
+
+        while !stop_flag.load(Ordering::Acquire) {
+            if !stable && connected_at.elapsed() >= stable_after {
+                tracker.stable(&mut status.supervisor);
+                stable = true;
+            }
+            if credentials.generation() != generation {
+                info!("Reconnecting the tunnel with the rotated credential");
+                return Ok(());
+            }
+
+            while let Some(frame) = try!(self.receive(status)) {
+                last_received = Instant::now();
+                try!(self.handle(frame, config, status));
+            }
 
-        let port = match self.tunnel_url.port() {
-            Some(port) => port,
+            while let Ok(frame) = self.outgoing.try_recv() {
+                // Drops what the local streams send once they're closed.
+                let open = if frame.frame_type == FrameType::Close {
+                    self.close_local_stream(frame.stream_id)
+                } else {
+                    self.local_streams.contains_key(&frame.stream_id)
+                };
+                if open {
+                    try!(self.send(frame, status));
+                }
+            }
+            status.active_streams = self.local_streams.len() as u64;
+
+            if last_received.elapsed() >= keepalive * 3 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "The frontend stopped responding"));
+            }
+            if last_ping.elapsed() >= keepalive {
+                try!(self.send(Frame::control(FrameType::Ping, vec![]), status));
+                last_ping = Instant::now();
+            }
+            if last_publish.elapsed() >= Duration::from_secs(1) {
+                publish(status);
+                last_publish = Instant::now();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Drop for Session<S> {
+    fn drop(&mut self) {
+        let ids: Vec<u32> = self.local_streams.keys().cloned().collect();
+        for id in ids {
+            self.close_local_stream(id);
+        }
+    }
+}
+
+/// Connects to the frontend and relays the streams, reconnecting until
+/// `stop_flag` is set.
+fn run_tunnel<F>(config: TunnelConfig, settings: TunnelSettings, certificate_manager: CertificateManager,
+                 credentials: Arc<TunnelCredentials>, clients: TunnelClients, stop_flag: Arc<AtomicBool>,
+                 mut publish: F)
+                 where F: FnMut(&TunnelStatus) {
+    let (host, port) = config.frontend();
+    let mut status = TunnelStatus::default();
+    status.frontend = format!("{}:{}", host, port);
+    let mut tracker = RestartTracker::new(settings.supervisor.clone());
+
+    while !stop_flag.load(Ordering::Acquire) {
+        status.set_state(TunnelState::Connecting);
+        tracker.started(&mut status.supervisor, None);
+        publish(&status);
+
+        let result = certificate_manager.get_box_certificate().and_then(|box_certificate| {
+            // Read first, so that a rotation can't be missed.
+            let generation = credentials.generation();
+            let credential = try!(credentials.get(&box_certificate.get_certificate_fingerprint()));
+
+            let mut session = Session::new(try!(connect(&host, port, &settings, &box_certificate)),
+                                           clients.clone());
+            status.set_state(TunnelState::Authenticating);
+            publish(&status);
+            if let Some(secret) = try!(session.authenticate(&config, &credential, &mut status)) {
+                try!(credentials.set_enrolled(&credential.fingerprint, &secret));
+            }
+
+            info!("Tunnel to {} established for {}", status.frontend, config.remote_name);
+            try!(session.stream.get_ref().set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))));
+            status.set_state(TunnelState::Connected);
+            status.connected_since = Some(time::get_time().sec);
+            publish(&status);
+            session.run(&config, &settings, &stop_flag, &credentials, generation, &mut tracker,
+                        &mut status, &mut publish)
+        });
+
+        status.connected_since = None;
+        status.active_streams = 0;
+        if stop_flag.load(Ordering::Acquire) {
+            break;
+        }
+
+        let delay = match result {
+            // The credential was rotated.
+            Ok(_) => continue,
+            Err(err) => tracker.exited(&mut status.supervisor, Err(err.to_string()))
+        };
+        let error = status.supervisor.last_error.clone().unwrap_or_else(String::new);
+        let delay = match delay {
+            Some(delay) => delay,
             None => {
-                panic!("No tunnel port found. Cannot start tunneling");
+                error!("Tunnel to {} failed ({}), giving up", status.frontend, error);
+                status.set_state(TunnelState::Stopped);
+                publish(&status);
+                return;
             }
         };
+        warn!("Tunnel to {} failed ({}), reconnecting in {}s", status.frontend, error, delay.as_secs());
+        status.set_state(TunnelState::Waiting);
+        publish(&status);
 
-        Command::new("pagekite.py")
-                .arg(format!("--frontend={}", format!("{}:{}", domain, port)))
-                // XXX remove http service once we support https
-                .arg(format!("--service_on=http,https:{}:localhost:{}:{}",
-                             self.remote_name,
-                             self.local_http_port,
-                             self.tunnel_secret))
-                .arg(format!("--service_on=websocket:{}:localhost:{}:{}",
-                             self.remote_name,
-                             self.local_ws_port,
-                             self.tunnel_secret))
-                .spawn()
+        let retry_at = Instant::now() + delay;
+        while Instant::now() < retry_at && !stop_flag.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(100));
+        }
     }
+
+    status.set_state(TunnelState::Stopped);
+    tracker.stopped(&mut status.supervisor);
+    publish(&status);
+}
+
+/// Runs pagekite until `stop_flag` is set, publishing the state of the
+/// process every second. Its options are written to `options_file`.
+fn run_pagekite<F>(config: TunnelConfig, settings: TunnelSettings, options_file: String,
+                   stop_flag: Arc<AtomicBool>, mut publish: F)
+                   where F: FnMut(&TunnelStatus) {
+    let (host, port) = config.frontend();
+    let mut status = TunnelStatus::default();
+    status.frontend = format!("{}:{}", host, port);
+
+    if settings.pagekite_secret.is_empty() {
+        error!("Unable to start pagekite: set its secret with -c 'tunnel;pagekite_secret;<secret>'");
+        status.set_state(TunnelState::Stopped);
+        publish(&status);
+        return;
+    }
+    let options = config.pagekite_options(&settings.pagekite_secret);
+    if let Err(err) = write_pagekite_options(&options_file, &options) {
+        error!("Unable to write the pagekite options to {}: {}", options_file, err);
+        status.set_state(TunnelState::Stopped);
+        publish(&status);
+        return;
+    }
+
+    let supervisor = match Supervisor::start("pagekite", settings.supervisor.clone(), move || {
+        TunnelConfig::pagekite_command(&options_file)
+    }) {
+        Ok(supervisor) => supervisor,
+        Err(err) => {
+            error!("Unable to supervise pagekite: {}", err);
+            status.set_state(TunnelState::Stopped);
+            publish(&status);
+            return;
+        }
+    };
+
+    while !stop_flag.load(Ordering::Acquire) {
+        status.supervisor = supervisor.status();
+        // pagekite doesn't tell whether it reached the frontend.
+        status.set_state(if status.supervisor.pid.is_some() {
+            TunnelState::Connected
+        } else {
+            TunnelState::Waiting
+        });
+        status.connected_since = status.supervisor.running_since;
+        publish(&status);
+
+        let publish_at = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < publish_at && !stop_flag.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    if let Err(err) = supervisor.shutdown() {
+        error!("Unable to stop pagekite: {}", err);
+    }
+    status.set_state(TunnelState::Stopped);
+    status.connected_since = None;
+    publish(&status);
+}
+
+pub struct Tunnel {
+    config: TunnelConfig,
+    stop_flag: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
 }
 
 impl Tunnel {
@@ -116,32 +802,52 @@ impl Tunnel {
     pub fn new(config: TunnelConfig) -> Tunnel {
         Tunnel {
             config: config,
-            tunnel_process: None
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            thread: None,
         }
     }
 
-    /// Start the Tunnel process if it has not already been started
-    pub fn start(&mut self) -> Result<()> {
-        if let Some(_) = self.tunnel_process {
+    /// Start the tunnel if it has not already been started, reporting its
+    /// status to `controller`.
+    pub fn start<T: Controller>(&mut self, controller: &T) -> io::Result<()> {
+        if self.thread.is_some() {
             // Already started
-            Ok(())
-        } else {
-            let tunnel_config = self.config.clone();
+            return Ok(());
+        }
 
-            self.tunnel_process = Some(ManagedProcess::start(move || {
-                tunnel_config.spawn()
-            }).unwrap());
+        let config = self.config.clone();
+        let settings = TunnelSettings::from_config(&controller.get_config());
+        let certificate_manager = controller.get_certificate_manager();
+        let credentials = controller.get_tunnel_credentials();
+        let clients = controller.get_tunnel_clients();
+        let pagekite_options = controller.get_profile().path_for("pagekite.rc");
+        let controller = controller.clone();
+        self.stop_flag.store(false, Ordering::Release);
+        let stop_flag = self.stop_flag.clone();
 
-            Ok(())
-        }
+        self.thread = Some(try!(thread::Builder::new().name("Tunnel".to_owned()).spawn(move || {
+            let publish = |status: &TunnelStatus| {
+                controller.set_tunnel_status(Some(status.clone()));
+            };
+            match settings.backend {
+                TunnelBackend::Pagekite => run_pagekite(config, settings, pagekite_options,
+                                                        stop_flag, publish),
+                TunnelBackend::Builtin => run_tunnel(config, settings, certificate_manager,
+                                                     credentials, clients, stop_flag, publish),
+            }
+        })));
+        Ok(())
     }
 
-    /// Stop the tunnel process if it is runnnig
-    pub fn stop(&mut self) -> Result<()> {
-        match self.tunnel_process.take() {
-            None => Ok(()),
-            Some(process) => process.shutdown()
+    /// Stop the tunnel if it is running
+    pub fn stop(&mut self) -> io::Result<()> {
+        self.stop_flag.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            try!(thread.join().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "The tunnel thread panicked")
+            }));
         }
+        Ok(())
     }
 
     pub fn get_frontend_name(&self) -> Option<String> {
@@ -151,3 +857,249 @@ impl Tunnel {
         }
     }
 }
+
+#[cfg(test)]
+describe! tunnel {
+    it "should match the frontend certificate names" {
+        assert!(matches_hostname("knilxof.org", "KNILXOF.org"));
+        assert!(matches_hostname("*.knilxof.org", "tunnel.knilxof.org"));
+        assert!(!matches_hostname("*.knilxof.org", "knilxof.org"));
+        assert!(!matches_hostname("*.knilxof.org", "a.tunnel.knilxof.org"));
+        assert!(!matches_hostname("knilxof.org", "evil.org"));
+    }
+
+    it "should check the subject alternative names of the frontend certificate" {
+        use openssl::crypto::hash::Type;
+        use openssl::x509::X509Generator;
+        use openssl::x509::extension::{ AltNameOption, Extension };
+
+        let generator = || {
+            X509Generator::new()
+                .set_bitlength(1024)
+                .set_valid_period(1)
+                .add_name("CN".to_owned(), "knilxof.org".to_owned())
+                .set_sign_hash(Type::SHA256)
+        };
+        let (cert, _) = generator()
+            .add_extension(Extension::SubjectAltName(vec![
+                (AltNameOption::DNS, "tunnel.knilxof.org".to_owned()),
+                (AltNameOption::DNS, "*.tunnel.knilxof.org".to_owned())]))
+            .generate().unwrap();
+        assert_eq!(certificate_names(&cert), vec!["tunnel.knilxof.org".to_owned(),
+                                                  "*.tunnel.knilxof.org".to_owned()]);
+
+        let (cert, _) = generator().generate().unwrap();
+        assert_eq!(certificate_names(&cert), vec!["knilxof.org".to_owned()]);
+    }
+
+    it "should default to the builtin backend" {
+        use config_store::ConfigService;
+        use uuid::Uuid;
+
+        let config = ConfigService::new(&format!("/tmp/{}.conf", Uuid::new_v4()));
+        let settings = TunnelSettings::from_config(&config);
+        assert_eq!(settings.backend, TunnelBackend::Builtin);
+        assert_eq!(settings.pagekite_secret, "");
+        config.set("tunnel", "backend", "pagekite");
+        assert_eq!(TunnelSettings::from_config(&config).backend, TunnelBackend::Pagekite);
+    }
+
+    it "should keep the pagekite secret out of its command line" {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        use uuid::Uuid;
+
+        let config = TunnelConfig::new("knilxof.org:4443".to_owned(), 3000, 4000, "remote.box.knilxof.org".to_owned());
+        let options = config.pagekite_options("s3cr3t");
+        assert!(options.contains("frontend = knilxof.org:4443\n"));
+        assert!(options.contains("service_on = websocket:remote.box.knilxof.org:localhost:4000:s3cr3t\n"));
+
+        let path = format!("/tmp/{}.rc", Uuid::new_v4());
+        write_pagekite_options(&path, &options).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let command = format!("{:?}", TunnelConfig::pagekite_command(&path));
+        assert!(command.contains(&format!("--optfile={}", path)));
+        assert!(!command.contains("s3cr3t"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    it "should default to the https port" {
+        let config = TunnelConfig::new("http://knilxof.org".to_owned(), 3000, 4000, "remote.box.knilxof.org".to_owned());
+        assert_eq!(config.frontend(), ("knilxof.org".to_owned(), 443));
+
+        let config = TunnelConfig::new("knilxof.org:4443".to_owned(), 3000, 4000, "remote.box.knilxof.org".to_owned());
+        assert_eq!(config.frontend(), ("knilxof.org".to_owned(), 4443));
+        assert_eq!(config.local_port("ws"), Some(4000));
+        assert_eq!(config.local_port("ssh"), None);
+    }
+
+    it "should enroll a rotated credential" {
+        use serde_json;
+        use std::io::prelude::*;
+        use std::net::{ TcpListener, TcpStream };
+        use std::thread;
+        use tunnel_credentials::TunnelCredential;
+        use tunnel_protocol::{ auth_proof, Frame, FrameReader, FrameType };
+
+        let frontend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let frontend_addr = frontend.local_addr().unwrap();
+        let frontend_thread = thread::spawn(move || {
+            let (mut stream, _) = frontend.accept().unwrap();
+            stream.write_all(&Frame::control(FrameType::Challenge, b"nonce".to_vec()).encode()).unwrap();
+            let mut reader = FrameReader::new();
+            let mut auth = None;
+            while auth.is_none() {
+                reader.fill(&mut stream).unwrap();
+                auth = reader.next_frame().unwrap();
+            }
+            let auth = String::from_utf8(auth.unwrap().payload).unwrap();
+            let auth: serde_json::Value = serde_json::from_str(&auth).unwrap();
+            assert_eq!(auth.lookup("fingerprint").unwrap().as_string(), Some("abcd"));
+            assert_eq!(auth.lookup("credential").unwrap().as_string(), Some("new"));
+            assert_eq!(auth.lookup("proof").unwrap().as_string(),
+                       Some(&auth_proof(b"old", b"nonce", "remote.box.knilxof.org")[..]));
+            stream.write_all(&Frame::control(FrameType::AuthFailed, b"unknown box".to_vec()).encode()).unwrap();
+        });
+
+        let config = TunnelConfig::new(format!("localhost:{}", frontend_addr.port()), 3000, 4000,
+                                       "remote.box.knilxof.org".to_owned());
+        let credential = TunnelCredential {
+            fingerprint: "abcd".to_owned(),
+            secret: "new".to_owned(),
+            created: 0,
+            enrolled: false,
+            previous_secret: Some("old".to_owned()),
+        };
+        let mut session = Session::new(TcpStream::connect(frontend_addr).unwrap(), TunnelClients::new());
+        let mut status = TunnelStatus::default();
+        let result = session.authenticate(&config, &credential, &mut status);
+        frontend_thread.join().unwrap();
+        assert!(result.unwrap_err().to_string().contains("unknown box"));
+    }
+
+    it "should authenticate and relay streams" {
+        use managed_process::{ Backoff, RestartPolicy, SupervisorSettings };
+        use std::fs;
+        use std::io::prelude::*;
+        use std::net::{ TcpListener, TcpStream };
+        use std::sync::atomic::{ AtomicBool, Ordering };
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+        use tunnel_credentials::{ TunnelCredential, TunnelCredentials };
+        use tunnel_protocol::{ auth_proof, Frame, FrameReader, FrameType };
+        use uuid::Uuid;
+
+        // Local HTTP and WebSocket servers, echoing what they get.
+        let echo_server = || {
+            let local_server = TcpListener::bind("127.0.0.1:0").unwrap();
+            let local_port = local_server.local_addr().unwrap().port();
+            thread::spawn(move || {
+                let (mut stream, _) = local_server.accept().unwrap();
+                let mut buffer = [0u8; 4];
+                stream.read_exact(&mut buffer).unwrap();
+                stream.write_all(&buffer).unwrap();
+            });
+            local_port
+        };
+        let http_port = echo_server();
+        let ws_port = echo_server();
+
+        // The frontend, on a plain TCP connection.
+        let frontend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let frontend_addr = frontend.local_addr().unwrap();
+        let frontend_thread = thread::spawn(move || {
+            let (mut stream, _) = frontend.accept().unwrap();
+            let mut reader = FrameReader::new();
+            let mut next_frame = |stream: &mut TcpStream| {
+                loop {
+                    if let Some(frame) = reader.next_frame().unwrap() {
+                        return frame;
+                    }
+                    reader.fill(stream).unwrap();
+                }
+            };
+
+            stream.write_all(&Frame::control(FrameType::Challenge, b"nonce".to_vec()).encode()).unwrap();
+            let auth = next_frame(&mut stream);
+            let auth = String::from_utf8(auth.payload).unwrap();
+            assert!(auth.contains(&auth_proof(b"secret", b"nonce", "remote.box.knilxof.org")[..]));
+            stream.write_all(&Frame::control(FrameType::AuthOk, vec![]).encode()).unwrap();
+
+            stream.write_all(&Frame::new(FrameType::Open, 7, b"http".to_vec()).encode()).unwrap();
+            stream.write_all(&Frame::new(FrameType::Data, 7, b"ping".to_vec()).encode()).unwrap();
+            stream.write_all(&Frame::new(FrameType::Open, 9, b"ws".to_vec()).encode()).unwrap();
+            stream.write_all(&Frame::new(FrameType::Data, 9, b"pong".to_vec()).encode()).unwrap();
+
+            // The streams are relayed concurrently.
+            let mut frames = vec![];
+            for _ in 0..4 {
+                frames.push(next_frame(&mut stream));
+            }
+            let stream_frames = |id: u32| -> Vec<Frame> {
+                frames.iter().filter(|frame| frame.stream_id == id).cloned().collect()
+            };
+            assert_eq!(stream_frames(7), vec![Frame::new(FrameType::Data, 7, b"ping".to_vec()),
+                                              Frame::new(FrameType::Close, 7, vec![])]);
+            assert_eq!(stream_frames(9), vec![Frame::new(FrameType::Data, 9, b"pong".to_vec()),
+                                              Frame::new(FrameType::Close, 9, vec![])]);
+        });
+
+        let config = TunnelConfig::new(format!("localhost:{}", frontend_addr.port()), http_port, ws_port,
+                                       "remote.box.knilxof.org".to_owned());
+        let credential = TunnelCredential {
+            fingerprint: "abcd".to_owned(),
+            secret: "secret".to_owned(),
+            created: 0,
+            enrolled: true,
+            previous_secret: None,
+        };
+        let credentials_path = format!("tunnel-credential-{}.json", Uuid::new_v4().to_simple_string());
+        let credentials = TunnelCredentials::new(&credentials_path);
+        let settings = TunnelSettings {
+            backend: TunnelBackend::Builtin,
+            pagekite_secret: "secret".to_owned(),
+            supervisor: SupervisorSettings {
+                policy: RestartPolicy::Always,
+                backoff: Backoff {
+                    initial_delay: 1,
+                    max_delay: 1,
+                    jitter: 0.0,
+                },
+                max_restarts: 0,
+                crash_loop_restarts: 0,
+                crash_loop_window: 60,
+                stable_after: 10,
+                limits: vec![],
+            },
+            keepalive: 30,
+            ca_file: None,
+        };
+        let stream = TcpStream::connect(frontend_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let clients = TunnelClients::new();
+        let mut session = Session::new(stream, clients.clone());
+        let mut status = TunnelStatus::default();
+        session.authenticate(&config, &credential, &mut status).unwrap();
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let thread_stop_flag = stop_flag.clone();
+        let session_thread = thread::spawn(move || {
+            let mut status = TunnelStatus::default();
+            status.supervisor.failures = 1;
+            let mut tracker = RestartTracker::new(settings.supervisor.clone());
+            let result = session.run(&config, &settings, &thread_stop_flag, &credentials, 0,
+                                     &mut tracker, &mut status, &mut |_| {});
+            (result.is_ok(), status)
+        });
+
+        frontend_thread.join().unwrap();
+        stop_flag.store(true, Ordering::Release);
+        let (_, status) = session_thread.join().unwrap();
+        assert_eq!(status.streams_opened, 2);
+        // The connection didn't stay up for `stable_after`.
+        assert_eq!(status.supervisor.failures, 1);
+        assert_eq!(status.bytes_received, 4 * 7 + 4 + 4 + 2 + 4);
+        fs::remove_file(credentials_path).unwrap_or(());
+    }
+}
@@ -0,0 +1,250 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The credential authenticating the box to the tunnel frontend.
+//!
+//! Each box has its own random secret, bound to the fingerprint of its
+//! certificate, which is also the one of its tunnel name
+//! (remote.<fingerprint>.box.knilxof.org). The secret is stored in
+//! `tunnel_credential.json` in the profile, readable by its owner only.
+//!
+//! A new secret is enrolled by sending it once to the frontend, over a TLS
+//! connection made with the box certificate as client certificate. When the
+//! secret is rotated, the enrollment of the new one is authenticated with the
+//! previous secret, which is kept until the frontend accepts the new one.
+//! Regenerating the box certificate starts over with a new secret.
+
+use rand::Rng;
+use rand::os::OsRng;
+use rustc_serialize::hex::ToHex;
+use serde_json;
+use std::fs::{ self, File, OpenOptions, Permissions };
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::{ OpenOptionsExt, PermissionsExt };
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use time;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TunnelCredential {
+    /// The fingerprint of the box certificate the secret is bound to.
+    pub fingerprint: String,
+    /// Hex encoded.
+    pub secret: String,
+    /// Seconds since the epoch.
+    pub created: i64,
+    /// Whether the frontend accepted the secret.
+    pub enrolled: bool,
+    /// The secret being replaced, until the new one is enrolled.
+    pub previous_secret: Option<String>,
+}
+
+impl TunnelCredential {
+    fn new(fingerprint: &str, previous_secret: Option<String>) -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng::new().unwrap().fill_bytes(&mut bytes);
+        TunnelCredential {
+            fingerprint: fingerprint.to_owned(),
+            secret: bytes.to_hex(),
+            created: time::get_time().sec,
+            enrolled: false,
+            previous_secret: previous_secret,
+        }
+    }
+}
+
+/// What the API tells about the credential, without the secret.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TunnelCredentialInfo {
+    pub fingerprint: String,
+    pub created: i64,
+    pub enrolled: bool,
+}
+
+impl<'a> From<&'a TunnelCredential> for TunnelCredentialInfo {
+    fn from(credential: &'a TunnelCredential) -> Self {
+        TunnelCredentialInfo {
+            fingerprint: credential.fingerprint.clone(),
+            created: credential.created,
+            enrolled: credential.enrolled,
+        }
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+pub struct TunnelCredentials {
+    path: PathBuf,
+    credential: Mutex<Option<TunnelCredential>>,
+    /// Incremented with each rotation, so that the tunnel can reconnect with
+    /// the new secret.
+    generation: AtomicUsize,
+}
+
+impl TunnelCredentials {
+    pub fn new(path: &str) -> Self {
+        TunnelCredentials {
+            path: PathBuf::from(path),
+            credential: Mutex::new(None),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    fn load(&self) -> io::Result<Option<TunnelCredential>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err)
+        };
+        let mut source = String::new();
+        try!(file.read_to_string(&mut source));
+        serde_json::from_str(&source).map(Some).map_err(invalid_data)
+    }
+
+    fn save(&self, credential: &TunnelCredential) -> io::Result<()> {
+        let source = try!(serde_json::to_string(credential).map_err(invalid_data));
+        // Written aside first, so that a crash can't lose the credential.
+        let temporary_path = self.path.with_extension("tmp");
+        {
+            // Readable by its owner only from the start.
+            let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true)
+                                                  .mode(0o600).open(&temporary_path));
+            try!(fs::set_permissions(&temporary_path, Permissions::from_mode(0o600)));
+            try!(file.write_all(source.as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(&temporary_path, &self.path)
+    }
+
+    /// Returns the credential for the box certificate with `fingerprint`,
+    /// creating it if needed. Called with the lock on the credential held.
+    fn current(&self, current: &mut Option<TunnelCredential>, fingerprint: &str)
+               -> io::Result<TunnelCredential> {
+        if current.is_none() {
+            *current = try!(self.load());
+        }
+        if let Some(ref credential) = *current {
+            if credential.fingerprint == fingerprint {
+                return Ok(credential.clone());
+            }
+            info!("The box certificate changed, creating a new tunnel credential");
+        }
+
+        let credential = TunnelCredential::new(fingerprint, None);
+        try!(self.save(&credential));
+        *current = Some(credential.clone());
+        Ok(credential)
+    }
+
+    /// Returns the credential for the box certificate with `fingerprint`,
+    /// creating it if needed.
+    pub fn get(&self, fingerprint: &str) -> io::Result<TunnelCredential> {
+        let mut current = self.credential.lock().unwrap();
+        self.current(&mut current, fingerprint)
+    }
+
+    /// Replaces the secret. The tunnel reconnects to enroll the new one.
+    pub fn rotate(&self, fingerprint: &str) -> io::Result<TunnelCredential> {
+        let mut current = self.credential.lock().unwrap();
+        let old = try!(self.current(&mut current, fingerprint));
+
+        // Rotating again before the frontend saw the new secret keeps the
+        // one it knows.
+        let previous_secret = if old.enrolled { Some(old.secret) } else { old.previous_secret };
+        let credential = TunnelCredential::new(fingerprint, previous_secret);
+        try!(self.save(&credential));
+        *current = Some(credential.clone());
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        info!("Rotated the tunnel credential");
+        Ok(credential)
+    }
+
+    /// Records that the frontend accepted `secret`, sent for the box
+    /// certificate with `fingerprint`. Does nothing if the credential was
+    /// replaced meanwhile.
+    pub fn set_enrolled(&self, fingerprint: &str, secret: &str) -> io::Result<()> {
+        let mut current = self.credential.lock().unwrap();
+        let credential = match *current {
+            Some(ref credential) if credential.fingerprint == fingerprint &&
+                                    credential.secret == secret && !credential.enrolled => {
+                TunnelCredential {
+                    enrolled: true,
+                    previous_secret: None,
+                    ..credential.clone()
+                }
+            },
+            _ => return Ok(())
+        };
+        try!(self.save(&credential));
+        *current = Some(credential);
+        Ok(())
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+describe! tunnel_credentials {
+    before_each {
+        use std::fs;
+        use uuid::Uuid;
+
+        let path = format!("tunnel-credential-{}.json", Uuid::new_v4().to_simple_string());
+        let credentials = TunnelCredentials::new(&path);
+    }
+
+    after_each {
+        fs::remove_file(&path).unwrap_or(());
+    }
+
+    it "should create and persist a secret per certificate" {
+        let credential = credentials.get("abcd").unwrap();
+        assert_eq!(credential.secret.len(), 64);
+        assert!(!credential.enrolled);
+        assert_eq!(credentials.get("abcd").unwrap(), credential);
+        assert_eq!(TunnelCredentials::new(&path).get("abcd").unwrap(), credential);
+
+        let other = credentials.get("ef01").unwrap();
+        assert!(other.secret != credential.secret);
+        assert_eq!(other.fingerprint, "ef01");
+    }
+
+    it "should keep the enrolled secret while rotating" {
+        let first = credentials.get("abcd").unwrap();
+        credentials.set_enrolled("abcd", &first.secret).unwrap();
+        assert!(credentials.get("abcd").unwrap().enrolled);
+
+        let second = credentials.rotate("abcd").unwrap();
+        assert_eq!(credentials.generation(), 1);
+        assert_eq!(second.previous_secret, Some(first.secret.clone()));
+        let third = credentials.rotate("abcd").unwrap();
+        assert_eq!(third.previous_secret, Some(first.secret));
+
+        credentials.set_enrolled("abcd", &third.secret).unwrap();
+        let enrolled = TunnelCredentials::new(&path).get("abcd").unwrap();
+        assert!(enrolled.enrolled);
+        assert_eq!(enrolled.previous_secret, None);
+    }
+
+    it "should ignore the enrollment of a replaced secret" {
+        let first = credentials.get("abcd").unwrap();
+        let second = credentials.rotate("abcd").unwrap();
+        credentials.set_enrolled("abcd", &first.secret).unwrap();
+        credentials.set_enrolled("ef01", &second.secret).unwrap();
+        assert_eq!(credentials.get("abcd").unwrap(), second);
+    }
+
+    it "should only be readable by its owner" {
+        use std::os::unix::fs::{ OpenOptionsExt, PermissionsExt };
+
+        credentials.get("abcd").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,255 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The framing of the tunnel between the box and the frontend.
+//!
+//! The box keeps a single TLS connection open to the frontend, which carries
+//! every stream from the remote clients, HTTP and WebSocket alike. Each frame
+//! starts with a 7 bytes header:
+//!
+//! ```text
+//! +------+-----------+--------+---------+
+//! | type | stream id | length | payload |
+//! |  u8  |    u32    |  u16   |         |
+//! +------+-----------+--------+---------+
+//! ```
+//!
+//! Integers are big endian, and frames about the connection itself use the
+//! stream 0. The handshake goes:
+//!
+//! 1. the frontend sends a `Challenge` with a random nonce;
+//! 2. the box answers with `Auth`, a JSON object with its `name`, the
+//!    `fingerprint` of its certificate, the `services` it exposes and a
+//!    `proof`, the hex encoded HMAC-SHA256 of the nonce followed by the name,
+//!    keyed with the secret of the box. A box enrolling a new secret sends it
+//!    as `credential`, with a `proof` made with its previous secret if it had
+//!    one, and `null` otherwise;
+//! 3. the frontend replies with `AuthOk`, or `AuthFailed` and a reason.
+//!
+//! The frontend then sends `Open` with the name of the service ("http" or
+//! "ws") for each new client connection, followed by a space and the IP
+//! address of the client, and both sides exchange `Data` until one of them
+//! sends `Close`. `Ping` frames are answered with `Pong`.
+
+use openssl::crypto::hash::Type;
+use openssl::crypto::hmac::hmac;
+use rustc_serialize::hex::ToHex;
+use std::io;
+use std::io::prelude::*;
+use std::net::IpAddr;
+
+pub const HEADER_SIZE: usize = 7;
+pub const MAX_PAYLOAD_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameType {
+    Challenge,
+    Auth,
+    AuthOk,
+    AuthFailed,
+    Open,
+    Data,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl FrameType {
+    fn to_u8(&self) -> u8 {
+        match *self {
+            FrameType::Challenge => 0x01,
+            FrameType::Auth => 0x02,
+            FrameType::AuthOk => 0x03,
+            FrameType::AuthFailed => 0x04,
+            FrameType::Open => 0x10,
+            FrameType::Data => 0x11,
+            FrameType::Close => 0x12,
+            FrameType::Ping => 0x20,
+            FrameType::Pong => 0x21,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(FrameType::Challenge),
+            0x02 => Some(FrameType::Auth),
+            0x03 => Some(FrameType::AuthOk),
+            0x04 => Some(FrameType::AuthFailed),
+            0x10 => Some(FrameType::Open),
+            0x11 => Some(FrameType::Data),
+            0x12 => Some(FrameType::Close),
+            0x20 => Some(FrameType::Ping),
+            0x21 => Some(FrameType::Pong),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: FrameType, stream_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            frame_type: frame_type,
+            stream_id: stream_id,
+            payload: payload,
+        }
+    }
+
+    pub fn control(frame_type: FrameType, payload: Vec<u8>) -> Self {
+        Frame::new(frame_type, 0, payload)
+    }
+
+    /// The size of the frame on the wire.
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.payload.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        assert!(self.payload.len() <= MAX_PAYLOAD_SIZE);
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.push(self.frame_type.to_u8());
+        bytes.push((self.stream_id >> 24) as u8);
+        bytes.push((self.stream_id >> 16) as u8);
+        bytes.push((self.stream_id >> 8) as u8);
+        bytes.push(self.stream_id as u8);
+        bytes.push((self.payload.len() >> 8) as u8);
+        bytes.push(self.payload.len() as u8);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reassembles frames from a stream with a read timeout, which may return
+/// in the middle of a frame.
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        FrameReader { buffer: Vec::new() }
+    }
+
+    /// Reads what's available from `stream`. Timeouts aren't errors, but
+    /// the end of the stream is.
+    pub fn fill<R: Read>(&mut self, stream: &mut R) -> io::Result<()> {
+        let mut chunk = [0u8; 8192];
+        match stream.read(&mut chunk) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Tunnel closed by the frontend")),
+            Ok(size) => {
+                self.buffer.extend_from_slice(&chunk[..size]);
+                Ok(())
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => Ok(()),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Returns the next complete frame, if any.
+    pub fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let frame_type = try!(FrameType::from_u8(self.buffer[0]).ok_or_else(|| {
+            invalid_data(format!("Unknown tunnel frame type {}", self.buffer[0]))
+        }));
+        let stream_id = (self.buffer[1] as u32) << 24 | (self.buffer[2] as u32) << 16 |
+                        (self.buffer[3] as u32) << 8 | self.buffer[4] as u32;
+        let length = (self.buffer[5] as usize) << 8 | self.buffer[6] as usize;
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(invalid_data(format!("Tunnel frame too large: {} bytes", length)));
+        }
+        if self.buffer.len() < HEADER_SIZE + length {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + length].to_vec();
+        self.buffer.drain(..HEADER_SIZE + length);
+        Ok(Some(Frame::new(frame_type, stream_id, payload)))
+    }
+}
+
+/// The proof of the knowledge of `secret` sent in the `Auth` frame.
+pub fn auth_proof(secret: &[u8], nonce: &[u8], name: &str) -> String {
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(name.as_bytes());
+    hmac(Type::SHA256, secret, &data).to_hex()
+}
+
+/// The service and the address of the client of an `Open` frame. The
+/// address is optional, for the frontends which don't send it.
+pub fn parse_open(payload: &[u8]) -> (String, Option<IpAddr>) {
+    let payload = String::from_utf8_lossy(payload);
+    let mut parts = payload.split(' ');
+    let service = parts.next().unwrap_or("").to_owned();
+    (service, parts.next().and_then(|client| client.parse().ok()))
+}
+
+#[cfg(test)]
+describe! tunnel_protocol {
+    it "should encode and decode frames" {
+        use std::io::Cursor;
+
+        let frame = Frame::new(FrameType::Data, 0x01020304, b"hello".to_vec());
+        let encoded = frame.encode();
+        assert_eq!(&encoded[..7], &[0x11, 1, 2, 3, 4, 0, 5]);
+
+        let mut reader = FrameReader::new();
+        reader.fill(&mut Cursor::new(encoded)).unwrap();
+        assert_eq!(reader.next_frame().unwrap(), Some(frame));
+        assert_eq!(reader.next_frame().unwrap(), None);
+        assert!(reader.fill(&mut Cursor::new(vec![])).is_err());
+    }
+
+    it "should wait for partial frames" {
+        use std::io::Cursor;
+
+        let mut encoded = Frame::control(FrameType::Ping, vec![]).encode();
+        encoded.extend(Frame::new(FrameType::Close, 3, vec![]).encode());
+
+        let mut reader = FrameReader::new();
+        reader.fill(&mut Cursor::new(encoded[..10].to_vec())).unwrap();
+        assert_eq!(reader.next_frame().unwrap().unwrap().frame_type, FrameType::Ping);
+        assert_eq!(reader.next_frame().unwrap(), None);
+        reader.fill(&mut Cursor::new(encoded[10..].to_vec())).unwrap();
+        assert_eq!(reader.next_frame().unwrap().unwrap().stream_id, 3);
+    }
+
+    it "should reject unknown and oversized frames" {
+        use std::io::Cursor;
+
+        let mut reader = FrameReader::new();
+        reader.fill(&mut Cursor::new(vec![0xff, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert!(reader.next_frame().is_err());
+
+        let mut reader = FrameReader::new();
+        reader.fill(&mut Cursor::new(vec![0x11, 0, 0, 0, 1, 0xff, 0xff])).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    it "should bind the proof to the nonce and name" {
+        let proof = auth_proof(b"secret", b"nonce", "remote.box.knilxof.org");
+        assert_eq!(proof.len(), 64);
+        assert!(proof != auth_proof(b"secret", b"other nonce", "remote.box.knilxof.org"));
+        assert!(proof != auth_proof(b"secret", b"nonce", "another.box.knilxof.org"));
+    }
+
+    it "should parse the client address of the streams" {
+        assert_eq!(parse_open(b"http 203.0.113.5"), ("http".to_owned(), Some("203.0.113.5".parse().unwrap())));
+        assert_eq!(parse_open(b"ws 2001:db8::1"), ("ws".to_owned(), Some("2001:db8::1".parse().unwrap())));
+        assert_eq!(parse_open(b"http"), ("http".to_owned(), None));
+        assert_eq!(parse_open(b"http nowhere"), ("http".to_owned(), None));
+    }
+}
@@ -1,65 +1,53 @@
-extern crate libc;
-extern crate hyper;
-
+//! UPnP device discovery with SSDP.
+//!
+//! Searches are multicast with `M-SEARCH` from a socket of their own, which
+//! receives the unicast responses, while the announcements (`NOTIFY` alive
+//! and byebye) are received on the SSDP port, shared with the other daemons
+//! of the host. The description of each device that is found is fetched from
+//! its location, and handed to the listeners.
+//!
+//! Each device holds a lease for the `max-age` of its last advertisement. The
+//! listeners are told that it is gone when it says byebye, or when the lease
+//! expires without a new advertisement. The maintenance of the leases also
+//! lets the listeners forget the devices which stayed away for too long.
+//!
+//! Listeners can be limited to some device and service types with an
+//! `UpnpFilter`, and searches can be repeated periodically, for the devices
+//! which don't advertise themselves often.
+//!
+//! Adapters can also subscribe to the events of the services, see
+//! `upnp_events`.
+
+use hyper;
+use multicast::bind_multicast;
+use std::cmp;
 use std::collections::HashMap;
-use std::ffi::{ CString, CStr };
+use std::io;
 use std::io::{ Read, Cursor };
-use std::ptr;
+use std::net::{ Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket };
+use std::sync::{ Arc, Mutex, Weak };
+use std::sync::atomic::{ AtomicUsize, Ordering };
 use std::thread;
+use std::time::Duration;
+use time;
+use upnp_events::{ UpnpEventListener, UpnpEvents, UpnpSubscription };
 use utils::parse_simple_xml;
-use std::sync::{ Arc, Mutex };
 
-#[allow(dead_code)]
-#[derive(Debug)]
-#[repr(C)]
-enum EventType {
-    ControlActionRequest,
-    ControlActionComplete,
-    ControlGetVarRequest,
-    ControlGetVarComplete,
-    DiscoveryAdvertisementAlive,
-    DiscoveryAdvertisementByebye,
-    DiscoverySearchResult,
-    DiscoverySearchTimeout,
-    SubscriptionRequest,
-    Received,
-    RenewalComplete,
-    SubscribeComplete,
-    UnsubscribeComplete,
-    AutorenewalFailed,
-    SubscriptionExpired
+const SSDP_PORT: u16 = 1900;
+/// How long devices may wait before answering a search, in seconds.
+const SEARCH_MX: u32 = 1;
+/// SSDP messages fit in a datagram, usually well below that.
+const MAX_MESSAGE_SIZE: usize = 8192;
+/// The lease of the devices which don't tell their `max-age`, in seconds.
+const DEFAULT_MAX_AGE: i64 = 1800;
+/// How often the leases are checked, in seconds.
+const LEASE_CHECK_INTERVAL: u64 = 5;
+
+fn ssdp_group() -> Ipv4Addr {
+    Ipv4Addr::new(239, 255, 255, 250)
 }
 
-const LINE_SIZE: usize = 180;
-
-#[repr(C)]
-struct Discovery {
-    err_code: libc::c_int,
-    expires: libc::c_int,
-    device_id: [libc::c_char; LINE_SIZE],
-    device_type: [libc::c_char; LINE_SIZE],
-    service_type: [libc::c_char; LINE_SIZE],
-    service_ver: [libc::c_char; LINE_SIZE],
-    location: [libc::c_char; LINE_SIZE],
-    os: [libc::c_char; LINE_SIZE],
-    date: [libc::c_char; LINE_SIZE],
-    ext: [libc::c_char; LINE_SIZE],
-    dest_addr: *mut libc::sockaddr_in,
-}
-
-type ClientHandle = libc::c_int;
-
-type ClientCallbackPtr = extern fn(event_type: EventType, event: *const libc::c_void, cookie: *mut libc::c_void);
-
-#[link(name = "upnp")]
-extern {
-    fn UpnpInit(hostIp: *const libc::c_char, destPort: libc::c_ushort) -> libc::c_int;
-    fn UpnpRegisterClient(callback: ClientCallbackPtr, cookie: *mut libc::c_void, handle: *mut ClientHandle) -> libc::c_int;
-    fn UpnpUnRegisterClient(handle: ClientHandle) -> libc::c_int;
-    fn UpnpSearchAsync(handle: ClientHandle, maxAttempts: libc::c_int, target: *const libc::c_char, cookie: *const libc::c_void) -> libc::c_int;
-}
-
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct UpnpMsearchHeader {
     pub device_id: String,
     pub device_type: String,
@@ -80,71 +68,342 @@ pub struct UpnpService {
     pub description_data: String
 }
 
+/// A device known from its advertisements.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UpnpLease {
+    /// The `uuid:` of the device.
+    pub device_id: String,
+    pub location: String,
+    /// The device and service types it advertised.
+    pub types: Vec<String>,
+    /// Seconds since the epoch.
+    pub last_seen: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GoneReason {
+    /// The device said byebye.
+    Byebye,
+    /// The device didn't advertise itself again in time.
+    Expired,
+}
+
 pub trait UpnpListener : Send {
     fn upnp_discover(&self, service: &UpnpService) -> bool;
+
+    /// Called when a device leaves the network. Returns whether the listener
+    /// knew the device.
+    fn upnp_gone(&self, _lease: &UpnpLease, _reason: GoneReason) -> bool {
+        false
+    }
+
+    /// Called every few seconds from the lease maintenance, for the
+    /// listeners to expire what they keep about the departed devices.
+    fn upnp_maintain(&self, _now: i64) {
+    }
+}
+
+/// The device and service types a listener is told about. An empty filter
+/// lets every device through.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UpnpFilter {
+    pub device_types: Vec<String>,
+    pub service_types: Vec<String>,
 }
 
-type UpnpListeners = Arc<Mutex<HashMap<String, Box<UpnpListener>>>>;
+impl UpnpFilter {
+    pub fn any() -> Self {
+        UpnpFilter::default()
+    }
+
+    pub fn device_type(mut self, device_type: &str) -> Self {
+        self.device_types.push(device_type.to_owned());
+        self
+    }
+
+    pub fn service_type(mut self, service_type: &str) -> Self {
+        self.service_types.push(service_type.to_owned());
+        self
+    }
+
+    fn matches_type(&self, typ: &str) -> bool {
+        self.device_types.iter().chain(self.service_types.iter()).any(|wanted| wanted == typ)
+    }
+
+    fn matches_service(&self, service: &UpnpService) -> bool {
+        if self.device_types.is_empty() && self.service_types.is_empty() {
+            return true;
+        }
+        // The description tells the type of devices found with other targets.
+        let description_type = service.description.get("/root/device/deviceType");
+        self.matches_type(&service.msearch.device_type) ||
+            self.matches_type(&service.msearch.service_type) ||
+            description_type.map_or(false, |typ| self.matches_type(typ))
+    }
 
-struct UpnpHandle {
-    client: ClientHandle,
-    cookie: *mut UpnpListeners
+    fn matches_lease(&self, lease: &UpnpLease) -> bool {
+        (self.device_types.is_empty() && self.service_types.is_empty()) ||
+            lease.types.iter().any(|typ| self.matches_type(typ))
+    }
 }
 
-impl Drop for UpnpHandle {
+struct RegisteredListener {
+    filter: UpnpFilter,
+    listener: Box<UpnpListener>,
+    // Distinguishes the listeners registered with the same id.
+    token: usize,
+}
+
+type UpnpListeners = Arc<Mutex<HashMap<String, RegisteredListener>>>;
+
+/// Unregisters its listener when dropped.
+#[must_use]
+pub struct UpnpListenerGuard {
+    id: String,
+    token: usize,
+    listeners: Weak<Mutex<HashMap<String, RegisteredListener>>>,
+}
+
+impl Drop for UpnpListenerGuard {
     fn drop(&mut self) {
-        debug!("releasing handles (client={} cookie={:?})", self.client, self.cookie);
-        if self.client != 0 {
-            unsafe { UpnpUnRegisterClient(self.client); }
+        if let Some(listeners) = self.listeners.upgrade() {
+            let mut listeners = listeners.lock().unwrap();
+            // Unless it was replaced since.
+            if listeners.get(&self.id).map_or(false, |registered| registered.token == self.token) {
+                debug!("Unregistering UPnP listener {}", self.id);
+                listeners.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// A search repeated every `interval` seconds.
+struct ScheduledSearch {
+    interval: i64,
+    next_at: i64,
+}
+
+type ScheduledSearches = Arc<Mutex<HashMap<String, ScheduledSearch>>>;
+
+/// Returns the targets of the searches to run at `now`, and schedules their
+/// next run.
+fn due_searches(searches: &mut HashMap<String, ScheduledSearch>, now: i64) -> Vec<String> {
+    let mut due = vec![];
+    for (target, search) in searches.iter_mut() {
+        if search.next_at <= now {
+            search.next_at = now + search.interval;
+            due.push(target.clone());
+        }
+    }
+    due
+}
+
+/// The leases of the devices, by id.
+#[derive(Default)]
+struct Leases {
+    devices: HashMap<String, UpnpLease>,
+}
+
+impl Leases {
+    fn renew(&mut self, header: &UpnpMsearchHeader, now: i64) {
+        if header.device_id.is_empty() {
+            return;
+        }
+        let max_age = if header.expires > 0 { header.expires as i64 } else { DEFAULT_MAX_AGE };
+        let lease = self.devices.entry(header.device_id.clone()).or_insert_with(|| {
+            debug!("New UPnP device {}", header.device_id);
+            UpnpLease {
+                device_id: header.device_id.clone(),
+                location: String::new(),
+                types: vec![],
+                last_seen: now,
+                expires_at: now,
+            }
+        });
+        if !header.location.is_empty() {
+            lease.location = header.location.clone();
+        }
+        for typ in vec![&header.device_type, &header.service_type] {
+            if !typ.is_empty() && !lease.types.contains(typ) {
+                lease.types.push(typ.clone());
+            }
+        }
+        lease.last_seen = now;
+        // A device advertises each of its services, with the same lifetime.
+        lease.expires_at = cmp::max(lease.expires_at, now + max_age);
+    }
+
+    fn release(&mut self, device_id: &str) -> Option<UpnpLease> {
+        self.devices.remove(device_id)
+    }
+
+    fn expire(&mut self, now: i64) -> Vec<UpnpLease> {
+        let expired: Vec<String> = self.devices.values()
+                                               .filter(|lease| lease.expires_at <= now)
+                                               .map(|lease| lease.device_id.clone())
+                                               .collect();
+        expired.iter().filter_map(|device_id| self.devices.remove(device_id)).collect()
+    }
+}
+
+/// Splits an HTTP-like SSDP message into its start line and its headers,
+/// whose names are lower cased.
+fn parse_message(message: &str) -> Option<(String, HashMap<String, String>)> {
+    let mut lines = message.lines().map(|line| line.trim_right_matches('\r'));
+    let start_line = match lines.next() {
+        Some(line) => line.trim().to_owned(),
+        None => return None
+    };
+    let mut headers = HashMap::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
         }
-        if !self.cookie.is_null() {
-            unsafe { Box::from_raw(self.cookie); }
+        if let Some(colon) = line.find(':') {
+            headers.insert(line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_owned());
         }
     }
+    Some((start_line, headers))
+}
+
+/// The lifetime of an advertisement, from `CACHE-CONTROL: max-age=1800`.
+fn parse_max_age(cache_control: &str) -> Option<i32> {
+    cache_control.split(',').filter_map(|directive| {
+        let mut parts = directive.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if name.trim().to_lowercase() == "max-age" => {
+                value.trim().parse().ok()
+            },
+            _ => None
+        }
+    }).next()
+}
+
+/// Parses a search response or an announcement. Searches from other control
+/// points, and anything else, are ignored.
+fn parse_discovery(message: &[u8]) -> Option<UpnpMsearchHeader> {
+    let message = String::from_utf8_lossy(message);
+    let (start_line, headers) = match parse_message(&message) {
+        Some(parsed) => parsed,
+        None => return None
+    };
+
+    let (target, alive) = if start_line.starts_with("HTTP/1.1 200") || start_line.starts_with("HTTP/1.0 200") {
+        (headers.get("st"), true)
+    } else if start_line.starts_with("NOTIFY ") {
+        match headers.get("nts").map(|nts| &nts[..]) {
+            Some("ssdp:alive") | Some("ssdp:update") => (headers.get("nt"), true),
+            Some("ssdp:byebye") => (headers.get("nt"), false),
+            _ => return None
+        }
+    } else {
+        return None;
+    };
+
+    let header = |name: &str| headers.get(name).cloned().unwrap_or_else(String::new);
+    let target = target.cloned().unwrap_or_else(String::new);
+    let usn = header("usn");
+    let device_id = match usn.find("::") {
+        Some(end) => usn[..end].to_owned(),
+        None => usn.clone()
+    };
+    let (device_type, service_type, service_ver) = if target.contains(":device:") {
+        (target.clone(), String::new(), String::new())
+    } else if target.contains(":service:") {
+        let version = target.rsplit(':').next().unwrap_or("").to_owned();
+        (String::new(), target.clone(), version)
+    } else {
+        (String::new(), String::new(), String::new())
+    };
+
+    Some(UpnpMsearchHeader {
+        device_id: device_id,
+        device_type: device_type,
+        service_type: service_type,
+        service_ver: service_ver,
+        location: header("location"),
+        os: header("server"),
+        date: header("date"),
+        ext: header("ext"),
+        expires: headers.get("cache-control").and_then(|value| parse_max_age(value)).unwrap_or(0),
+        alive: alive,
+    })
+}
+
+fn send_search(socket: &UdpSocket, target: &str) -> io::Result<()> {
+    let destination = SocketAddr::V4(SocketAddrV4::new(ssdp_group(), SSDP_PORT));
+    let result = socket.send_to(search_request(target).as_bytes(), destination);
+
+    info!("UPnP search for devices matching {:?} ({:?})", target, result);
+    result.map(|_| ())
+}
+
+fn search_request(target: &str) -> String {
+    format!("M-SEARCH * HTTP/1.1\r\n\
+             HOST: {}:{}\r\n\
+             MAN: \"ssdp:discover\"\r\n\
+             MX: {}\r\n\
+             ST: {}\r\n\
+             \r\n", ssdp_group(), SSDP_PORT, SEARCH_MX, target)
 }
 
 pub struct UpnpManager {
     listeners: UpnpListeners,
-    handle: Arc<UpnpHandle>
+    leases: Arc<Mutex<Leases>>,
+    searches: ScheduledSearches,
+    next_token: AtomicUsize,
+    events: Arc<UpnpEvents>,
+    // Sends the searches and receives their responses, once started.
+    search_socket: Option<Arc<UdpSocket>>
 }
 
-unsafe impl Send for UpnpManager {}
-unsafe impl Sync for UpnpManager {}
-
 impl UpnpManager {
     pub fn new() -> Self {
         UpnpManager {
             listeners: Arc::new(Mutex::new(HashMap::new())),
-            handle: Arc::new(UpnpHandle { client: 0, cookie: ptr::null_mut() })
+            leases: Arc::new(Mutex::new(Leases::default())),
+            searches: Arc::new(Mutex::new(HashMap::new())),
+            next_token: AtomicUsize::new(0),
+            events: Arc::new(UpnpEvents::new()),
+            search_socket: None
         }
     }
 
     fn notify_service(listeners: UpnpListeners, service: UpnpService) {
         for l in listeners.lock().unwrap().values() {
-            l.upnp_discover(&service);
+            if l.filter.matches_service(&service) {
+                l.listener.upnp_discover(&service);
+            }
         }
     }
 
-    fn msearch_callback(listeners: UpnpListeners, data: &Discovery, alive: bool) {
-        let header = UpnpMsearchHeader {
-            device_id: unsafe { CStr::from_ptr(&data.device_id[0]).to_string_lossy().into_owned() },
-            device_type: unsafe { CStr::from_ptr(&data.device_type[0]).to_string_lossy().into_owned() },
-            service_type: unsafe { CStr::from_ptr(&data.service_type[0]).to_string_lossy().into_owned() },
-            service_ver: unsafe { CStr::from_ptr(&data.service_ver[0]).to_string_lossy().into_owned() },
-            location: unsafe { CStr::from_ptr(&data.location[0]).to_string_lossy().into_owned() },
-            os: unsafe { CStr::from_ptr(&data.os[0]).to_string_lossy().into_owned() },
-            date: unsafe { CStr::from_ptr(&data.date[0]).to_string_lossy().into_owned() },
-            ext: unsafe { CStr::from_ptr(&data.ext[0]).to_string_lossy().into_owned() },
-            expires: data.expires,
-            alive: alive,
-        };
+    fn notify_gone(listeners: &UpnpListeners, lease: &UpnpLease, reason: GoneReason) {
+        info!("UPnP device {} at {} is gone ({:?})", lease.device_id, lease.location, reason);
+        for l in listeners.lock().unwrap().values() {
+            if l.filter.matches_lease(lease) {
+                l.listener.upnp_gone(lease, reason);
+            }
+        }
+    }
 
-        trace!("UPnP msearch callback: header {:?}, alive {}", header, alive);
+    fn msearch_callback(listeners: UpnpListeners, leases: &Mutex<Leases>, header: UpnpMsearchHeader) {
+        trace!("UPnP msearch callback: header {:?}", header);
+
+        if header.alive {
+            leases.lock().unwrap().renew(&header, time::get_time().sec);
+        } else {
+            // Devices say byebye for each of their services.
+            let lease = leases.lock().unwrap().release(&header.device_id);
+            if let Some(lease) = lease {
+                UpnpManager::notify_gone(&listeners, &lease, GoneReason::Byebye);
+            }
+        }
 
         // No need to fetch the description XML if the device notified us
         // that it is disconnecting; should be even bother to tell adapters
         // about this?
-        if !alive {
+        if !header.alive {
             UpnpManager::notify_service(listeners, UpnpService {
                 msearch: header,
                 description: HashMap::new(),
@@ -153,6 +412,11 @@ impl UpnpManager {
             return;
         }
 
+        if header.location.is_empty() {
+            debug!("Ignoring UPnP device {} without location", header.device_id);
+            return;
+        }
+
         thread::spawn(move || {
             // Note we must be careful to actually handle these errors gracefully
             // since the network or end device can fail us easily.
@@ -189,75 +453,135 @@ impl UpnpManager {
         });
     }
 
-    extern fn callback(event_type: EventType, event: *const libc::c_void, cookie: *mut libc::c_void) {
-        let listeners: *mut UpnpListeners = cookie as *mut UpnpListeners;
-        if listeners == ptr::null_mut() { panic!("invalid cookie"); }
+    /// Hands the discoveries received on `socket` to the listeners.
+    fn receive(name: &str, socket: Arc<UdpSocket>, listeners: UpnpListeners, leases: Arc<Mutex<Leases>>)
+               -> io::Result<()> {
+        try!(thread::Builder::new().name(name.to_owned()).spawn(move || {
+            let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((size, from)) => {
+                        trace!("SSDP message from {}: {:?}", from, String::from_utf8_lossy(&buffer[..size]));
+                        if let Some(header) = parse_discovery(&buffer[..size]) {
+                            UpnpManager::msearch_callback(listeners.clone(), &leases, header);
+                        }
+                    },
+                    Err(err) => {
+                        warn!("Unable to receive SSDP messages: {}", err);
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
 
-        let data: *const Discovery;
-        let alive: bool;
-        match event_type {
-            EventType::DiscoverySearchResult | EventType::DiscoveryAdvertisementAlive => {
-                data = event as *const Discovery;
-                alive = true;
-            },
-            EventType::DiscoveryAdvertisementByebye => {
-                data = event as *const Discovery;
-                alive = false;
-            },
-            // Timeout really just lets us know the search is done, it may or may not
-            // have found devices
-            EventType::DiscoverySearchTimeout => { return; },
-            _ => { warn!("unhandled callback event {:?}", event_type); return; },
+    /// Tells the listeners about the devices whose lease expired, and runs
+    /// the scheduled searches.
+    fn maintain(listeners: UpnpListeners, leases: Arc<Mutex<Leases>>, searches: ScheduledSearches,
+                search_socket: Arc<UdpSocket>) -> io::Result<()> {
+        try!(thread::Builder::new().name("SsdpMaintenance".to_owned()).spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(LEASE_CHECK_INTERVAL));
+                let now = time::get_time().sec;
+                let expired = leases.lock().unwrap().expire(now);
+                for lease in expired {
+                    UpnpManager::notify_gone(&listeners, &lease, GoneReason::Expired);
+                }
+                for l in listeners.lock().unwrap().values() {
+                    l.listener.upnp_maintain(now);
+                }
+                let due = due_searches(&mut searches.lock().unwrap(), now);
+                for target in due {
+                    send_search(&search_socket, &target).unwrap_or(());
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    pub fn search(&self, target: Option<String>) -> io::Result<()> {
+        let socket = match self.search_socket {
+            Some(ref socket) => socket,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "UPnP isn't started"))
         };
+        send_search(socket, &target.unwrap_or_else(|| "ssdp:all".to_owned()))
+    }
 
-        if data == ptr::null() { panic!("null discovery"); }
-        unsafe { UpnpManager::msearch_callback((*listeners).clone(), &(*data), alive); }
+    /// Repeats the search for `target` every `interval`, starting with the
+    /// next maintenance of the leases.
+    pub fn schedule_search(&self, target: &str, interval: Duration) {
+        self.searches.lock().unwrap().insert(target.to_owned(), ScheduledSearch {
+            interval: interval.as_secs() as i64,
+            next_at: time::get_time().sec,
+        });
     }
 
-    fn initialize() -> Result<(), i32> {
-        let err = unsafe { UpnpInit(ptr::null(), 0) };
-        debug!("initialized ({})", err);
-        match err {
-            0 => Ok(()),
-            _ => Err(err)
+    /// Brings the next run of the scheduled search for `target` forward to
+    /// the next maintenance of the leases, so that the rescans requested
+    /// meanwhile make a single search. Returns whether the search is
+    /// scheduled.
+    pub fn rescan(&self, target: &str) -> bool {
+        match self.searches.lock().unwrap().get_mut(target) {
+            Some(search) => {
+                search.next_at = cmp::min(search.next_at, time::get_time().sec);
+                true
+            },
+            None => false
         }
     }
 
-    pub fn search(&self, target: Option<String>) -> Result<(), i32> {
-        let target = match target {
-            Some(x) => CString::new(x),
-            None => CString::new("ssdp:all")
-        }.unwrap();
-
-        let cookie = self.handle.cookie as *mut libc::c_void;
-        let err = unsafe { UpnpSearchAsync(self.handle.client, 1, target.as_ptr(), cookie) };
+    /// The devices currently on the network.
+    pub fn get_devices(&self) -> Vec<UpnpLease> {
+        let mut devices: Vec<UpnpLease> = self.leases.lock().unwrap().devices.values().cloned().collect();
+        devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        devices
+    }
 
-        info!("UPnP search for devices matching {:?} ({})", target, err);
-        match err {
-            0 => Ok(()),
-            _ => Err(err)
+    /// Adds a listener for the devices matching `filter`, until the returned
+    /// guard is dropped.
+    pub fn register_listener(&self, id: String, filter: UpnpFilter, listener: Box<UpnpListener>)
+                             -> UpnpListenerGuard {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        self.listeners.lock().unwrap().insert(id.clone(), RegisteredListener {
+            filter: filter,
+            listener: listener,
+            token: token,
+        });
+        UpnpListenerGuard {
+            id: id,
+            token: token,
+            listeners: Arc::downgrade(&self.listeners),
         }
     }
 
-    pub fn add_listener(&self, id: String, listener: Box<UpnpListener>) {
-        let mut listeners = self.listeners.lock().unwrap();
-        listeners.insert(id, listener);
+    /// Subscribes `listener` to the events of the service at `event_url`,
+    /// until the returned subscription is dropped. The first subscription
+    /// starts the server receiving the events.
+    pub fn subscribe(&self, event_url: &str, listener: Arc<UpnpEventListener>)
+                     -> io::Result<UpnpSubscription> {
+        UpnpEvents::subscribe(&self.events, event_url, listener)
     }
 
-    pub fn start(&mut self) -> Result<(), i32> {
-        UpnpManager::initialize().unwrap();
-
-        let handle = Arc::get_mut(&mut self.handle).unwrap();
-        handle.cookie = Box::into_raw(Box::new(self.listeners.clone()));
-        let cookie = handle.cookie as *mut libc::c_void;
-        let client: *mut ClientHandle = &mut handle.client as *mut ClientHandle;
-        let err = unsafe { UpnpRegisterClient(UpnpManager::callback, cookie, client) };
-
-        debug!("registered client ({})", err);
-        match err {
-            0 => Ok(()),
-            _ => Err(err)
-        }
+    /// Starts the discovery, and lets the subscriptions start the server
+    /// receiving the events on `event_port`, any port if 0.
+    pub fn start(&mut self, event_port: u16) -> io::Result<()> {
+        let announcements = Arc::new(try!(bind_multicast(ssdp_group(), SSDP_PORT)));
+        try!(UpnpManager::receive("SsdpAnnouncements", announcements, self.listeners.clone(),
+                                  self.leases.clone()));
+
+        let search_socket = Arc::new(try!(UdpSocket::bind("0.0.0.0:0")));
+        // Stays on the local network, as recommended by UPnP.
+        try!(search_socket.set_multicast_ttl_v4(2));
+        try!(UpnpManager::receive("SsdpSearch", search_socket.clone(), self.listeners.clone(),
+                                  self.leases.clone()));
+        try!(UpnpManager::maintain(self.listeners.clone(), self.leases.clone(), self.searches.clone(),
+                                   search_socket.clone()));
+        self.search_socket = Some(search_socket);
+        self.events.enable(event_port);
+
+        debug!("UPnP discovery started");
+        Ok(())
     }
 }
 
@@ -266,3 +590,143 @@ impl Default for UpnpManager {
         UpnpManager::new()
     }
 }
+
+#[cfg(test)]
+describe! ssdp {
+    it "should parse search responses" {
+        let location = format!("http://192.168.1.20:80/description.xml?{}", vec!["x"; 200].concat());
+        let response = format!("HTTP/1.1 200 OK\r\n\
+                                CACHE-CONTROL: max-age = 100\r\n\
+                                EXT:\r\n\
+                                LOCATION: {}\r\n\
+                                SERVER: FreeRTOS/6.0.5, UPnP/1.0, IpBridge/1.10.0\r\n\
+                                ST: urn:schemas-upnp-org:device:basic:1\r\n\
+                                USN: uuid:2f402f80-da50-11e1-9b23-00178825681a::urn:schemas-upnp-org:device:basic:1\r\n\
+                                \r\n", location);
+
+        let header = parse_discovery(response.as_bytes()).unwrap();
+        assert!(header.alive);
+        assert_eq!(header.location, location);
+        assert_eq!(header.device_id, "uuid:2f402f80-da50-11e1-9b23-00178825681a");
+        assert_eq!(header.device_type, "urn:schemas-upnp-org:device:basic:1");
+        assert_eq!(header.os, "FreeRTOS/6.0.5, UPnP/1.0, IpBridge/1.10.0");
+        assert_eq!(header.expires, 100);
+    }
+
+    it "should parse announcements" {
+        let alive = b"NOTIFY * HTTP/1.1\r\n\
+                      Host: 239.255.255.250:1900\r\n\
+                      Cache-Control: max-age=1800\r\n\
+                      Location: http://192.168.1.30:49152/rootDesc.xml\n\
+                      NT: urn:schemas-upnp-org:service:SwitchPower:1\r\n\
+                      NTS: ssdp:alive\r\n\
+                      USN: uuid:camera::urn:schemas-upnp-org:service:SwitchPower:1\r\n\r\n";
+        let header = parse_discovery(alive).unwrap();
+        assert!(header.alive);
+        assert_eq!(header.location, "http://192.168.1.30:49152/rootDesc.xml");
+        assert_eq!(header.service_type, "urn:schemas-upnp-org:service:SwitchPower:1");
+        assert_eq!(header.service_ver, "1");
+        assert_eq!(header.expires, 1800);
+
+        let byebye = b"NOTIFY * HTTP/1.1\r\n\
+                       NT: upnp:rootdevice\r\n\
+                       NTS: ssdp:byebye\r\n\
+                       USN: uuid:camera::upnp:rootdevice\r\n\r\n";
+        let header = parse_discovery(byebye).unwrap();
+        assert!(!header.alive);
+        assert_eq!(header.device_id, "uuid:camera");
+    }
+
+    it "should ignore searches" {
+        assert!(parse_discovery(search_request("ssdp:all").as_bytes()).is_none());
+        assert!(parse_discovery(b"").is_none());
+        assert!(search_request("upnp:rootdevice").contains("\r\nST: upnp:rootdevice\r\n"));
+        assert!(search_request("upnp:rootdevice").contains("\r\nMAN: \"ssdp:discover\"\r\n"));
+    }
+
+    it "should track the leases of the devices" {
+        let mut leases = Leases::default();
+        let mut header = parse_discovery(b"NOTIFY * HTTP/1.1\r\n\
+                                           Cache-Control: max-age=100\r\n\
+                                           Location: http://192.168.1.30/rootDesc.xml\r\n\
+                                           NT: upnp:rootdevice\r\n\
+                                           NTS: ssdp:alive\r\n\
+                                           USN: uuid:camera::upnp:rootdevice\r\n\r\n").unwrap();
+        leases.renew(&header, 1000);
+        header.expires = 0;
+        leases.renew(&header, 1010);
+        assert_eq!(leases.devices["uuid:camera"].expires_at, 1010 + 1800);
+        assert_eq!(leases.devices["uuid:camera"].last_seen, 1010);
+
+        assert!(leases.expire(2000).is_empty());
+        let expired = leases.expire(2810);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].location, "http://192.168.1.30/rootDesc.xml");
+        assert!(leases.devices.is_empty());
+
+        leases.renew(&header, 3000);
+        assert_eq!(leases.release("uuid:camera").unwrap().device_id, "uuid:camera");
+        assert_eq!(leases.release("uuid:camera"), None);
+    }
+
+    it "should filter the listeners" {
+        use std::sync::mpsc::{ channel, Sender };
+
+        struct CountingListener(Sender<String>);
+        impl UpnpListener for CountingListener {
+            fn upnp_discover(&self, service: &UpnpService) -> bool {
+                self.0.send(service.msearch.device_id.clone()).unwrap();
+                true
+            }
+        }
+
+        let manager = UpnpManager::new();
+        let (sender, receiver) = channel();
+        let guard = manager.register_listener("bridges".to_owned(),
+                                              UpnpFilter::any().device_type("urn:schemas-upnp-org:device:basic:1"),
+                                              Box::new(CountingListener(sender)));
+        let discover = |message: &[u8]| {
+            UpnpManager::notify_service(manager.listeners.clone(), UpnpService {
+                msearch: parse_discovery(message).unwrap(),
+                description: HashMap::new(),
+                description_data: String::new()
+            });
+        };
+
+        discover(b"NOTIFY * HTTP/1.1\r\nNT: urn:schemas-upnp-org:device:basic:1\r\nNTS: ssdp:byebye\r\n\
+                   USN: uuid:bridge\r\n\r\n");
+        discover(b"NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\nNTS: ssdp:byebye\r\n\
+                   USN: uuid:camera\r\n\r\n");
+        assert_eq!(receiver.try_recv().unwrap(), "uuid:bridge");
+        assert!(receiver.try_recv().is_err());
+
+        drop(guard);
+        discover(b"NOTIFY * HTTP/1.1\r\nNT: urn:schemas-upnp-org:device:basic:1\r\nNTS: ssdp:byebye\r\n\
+                   USN: uuid:bridge\r\n\r\n");
+        assert!(receiver.try_recv().is_err());
+        assert!(manager.listeners.lock().unwrap().is_empty());
+    }
+
+    it "should merge the rescans" {
+        let manager = UpnpManager::new();
+        assert!(!manager.rescan("ssdp:all"));
+        manager.schedule_search("ssdp:all", Duration::from_secs(300));
+        let now = time::get_time().sec;
+        assert_eq!(due_searches(&mut manager.searches.lock().unwrap(), now + 1), vec!["ssdp:all".to_owned()]);
+        assert!(due_searches(&mut manager.searches.lock().unwrap(), now + 1).is_empty());
+
+        assert!(manager.rescan("ssdp:all"));
+        assert!(manager.rescan("ssdp:all"));
+        assert_eq!(due_searches(&mut manager.searches.lock().unwrap(), now + 1).len(), 1);
+        assert!(due_searches(&mut manager.searches.lock().unwrap(), now + 1).is_empty());
+    }
+
+    it "should repeat the scheduled searches" {
+        let mut searches = HashMap::new();
+        searches.insert("upnp:rootdevice".to_owned(), ScheduledSearch { interval: 60, next_at: 1060 });
+        assert!(due_searches(&mut searches, 1000).is_empty());
+        assert_eq!(due_searches(&mut searches, 1061), vec!["upnp:rootdevice".to_owned()]);
+        assert!(due_searches(&mut searches, 1100).is_empty());
+        assert_eq!(due_searches(&mut searches, 1121).len(), 1);
+    }
+}
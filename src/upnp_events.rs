@@ -0,0 +1,794 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! UPnP event subscriptions with GENA.
+//!
+//! The services of a device push the changes of their state variables to
+//! their subscribers, instead of being polled. Subscriptions are made with
+//! `SUBSCRIBE` on the `eventSubURL` of the service, giving the URL of a small
+//! HTTP server of ours as callback, to which the device sends its `NOTIFY`
+//! requests. Each subscription has a random callback path of its own, which
+//! routes the notifications to its listener, and only the host of its
+//! `eventSubURL` may notify it. The server is started with the first
+//! subscription.
+//!
+//! Subscriptions expire after the timeout granted by the device. They are
+//! renewed once half of it is elapsed, made again when the device doesn't
+//! know them anymore, and cancelled with `UNSUBSCRIBE` once their
+//! `UpnpSubscription` is dropped. The requests to the devices are all sent
+//! from the renewal thread but the first `SUBSCRIBE`, so that a device which
+//! doesn't answer can't hold up the adapters.
+
+use hyper::Client;
+use hyper::client;
+use hyper::header::{ Connection, Headers };
+use hyper::method::Method;
+use hyper::server::{ Request, Response, Server };
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rand::Rng;
+use rand::os::OsRng;
+use rustc_serialize::hex::ToHex;
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::net::{ IpAddr, SocketAddr, ToSocketAddrs, UdpSocket };
+use std::sync::{ Arc, Mutex, Weak };
+use std::thread;
+use std::time::Duration;
+use time;
+use upnp::UpnpService;
+use url::{ Url, UrlParser };
+use xml::reader::{ EventReader, XmlEvent };
+
+const CALLBACK_PATH: &'static str = "/upnp/events/";
+// The timeout we ask for, and assume when the device doesn't say.
+const REQUESTED_TIMEOUT: i64 = 1800;
+// Used instead of `Second-infinite`, so that such subscriptions are renewed
+// too, should the device have forgotten them.
+const INFINITE_TIMEOUT: i64 = 86400;
+// Seconds before subscribing again, when the device failed us.
+const RETRY_DELAY: i64 = 30;
+const RENEWAL_CHECK_INTERVAL: u64 = 5;
+const MAX_EVENT_SIZE: u64 = 65536;
+const SERVER_THREADS: usize = 2;
+// Seconds to wait for a device to read our requests, and to answer them.
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// The state variables of a service which changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpnpEvent {
+    pub event_url: String,
+    pub sid: String,
+    pub seq: u32,
+    /// Whether events were missed since the previous one, in which case the
+    /// listener wasn't told about every change.
+    pub missed: bool,
+    pub properties: HashMap<String, String>
+}
+
+pub trait UpnpEventListener : Send + Sync {
+    fn upnp_event(&self, event: &UpnpEvent);
+}
+
+struct Subscription {
+    event_url: String,
+    // The address of the host of `event_url`, the only one allowed to notify.
+    device: IpAddr,
+    callback: String,
+    // Unknown until the device answers, and after it forgot us.
+    sid: Option<String>,
+    next_seq: u32,
+    renew_at: i64,
+    // Set once the `UpnpSubscription` is dropped, until the renewal thread
+    // unsubscribes.
+    cancelled: bool,
+    listener: Arc<UpnpEventListener>
+}
+
+impl Subscription {
+    /// Records the result of subscribing, or renewing the subscription.
+    fn update(&mut self, result: io::Result<(String, i64)>, now: i64) {
+        match result {
+            Ok((sid, timeout)) => {
+                if self.sid.as_ref() != Some(&sid) {
+                    // Events are counted again for each new subscription.
+                    self.next_seq = 0;
+                }
+                self.sid = Some(sid);
+                self.renew_at = now + timeout / 2;
+            },
+            Err(err) => {
+                warn!("Unable to subscribe to the events of {}: {}", self.event_url, err);
+                self.sid = None;
+                self.renew_at = now + RETRY_DELAY;
+            }
+        }
+    }
+}
+
+/// The subscriptions, by the token of their callback path.
+type Subscriptions = Arc<Mutex<HashMap<String, Subscription>>>;
+
+/// Keeps the subscription alive, and cancels it when dropped.
+#[must_use]
+pub struct UpnpSubscription {
+    token: String,
+    subscriptions: Weak<Mutex<HashMap<String, Subscription>>>
+}
+
+impl Drop for UpnpSubscription {
+    fn drop(&mut self) {
+        let subscriptions = match self.subscriptions.upgrade() {
+            Some(subscriptions) => subscriptions,
+            None => return
+        };
+        // Unsubscribing could block, the renewal thread does it.
+        if let Some(subscription) = subscriptions.lock().unwrap().get_mut(&self.token) {
+            subscription.cancelled = true;
+        }
+    }
+}
+
+/// Removes the cancelled subscriptions, and returns the event URL and SID
+/// of those to unsubscribe from.
+fn take_cancelled(subscriptions: &Subscriptions) -> Vec<(String, String)> {
+    let mut subscriptions = subscriptions.lock().unwrap();
+    let cancelled: Vec<String> = subscriptions.iter()
+                                              .filter(|&(_, subscription)| subscription.cancelled)
+                                              .map(|(token, _)| token.clone())
+                                              .collect();
+    cancelled.iter()
+             .filter_map(|token| subscriptions.remove(token))
+             .filter_map(|subscription| match subscription.sid {
+                 Some(sid) => Some((subscription.event_url, sid)),
+                 None => None
+             })
+             .collect()
+}
+
+/// Parses the `TIMEOUT` header of the responses, `Second-<seconds>` or
+/// `Second-infinite`.
+pub fn parse_timeout(value: &str) -> Option<i64> {
+    let value = value.trim().to_lowercase();
+    if !value.starts_with("second-") {
+        return None;
+    }
+    match &value["second-".len()..] {
+        "infinite" => Some(INFINITE_TIMEOUT),
+        seconds => seconds.parse().ok().and_then(|seconds| if seconds > 0 { Some(seconds) } else { None })
+    }
+}
+
+/// Parses the `propertyset` of a notification into the values of the state
+/// variables, by name.
+pub fn parse_property_set(body: &str) -> Result<HashMap<String, String>, String> {
+    let mut properties = HashMap::new();
+    let mut path: Vec<String> = vec![];
+    // <propertyset><property><Variable>value</Variable></property>...</propertyset>
+    let is_variable = |path: &[String]| path.len() == 3 && path[0] == "propertyset" && path[1] == "property";
+    for e in EventReader::new(body.as_bytes()) {
+        match e {
+            Ok(XmlEvent::StartElement { name, .. }) => {
+                path.push(name.local_name);
+                if is_variable(&path) {
+                    properties.insert(path[2].clone(), String::new());
+                }
+            },
+            Ok(XmlEvent::EndElement { .. }) => {
+                path.pop();
+            },
+            Ok(XmlEvent::Characters(text)) | Ok(XmlEvent::CData(text)) => {
+                if is_variable(&path) {
+                    if let Some(value) = properties.get_mut(&path[2]) {
+                        value.push_str(&text);
+                    }
+                }
+            },
+            Err(err) => return Err(format!("{}", err)),
+            _ => {}
+        }
+    }
+    Ok(properties)
+}
+
+fn resolve_url(base: &str, url: &str) -> Option<String> {
+    let base = match Url::parse(base) {
+        Ok(base) => base,
+        Err(_) => return None
+    };
+    UrlParser::new().base_url(&base).parse(url).ok().map(|url| url.to_string())
+}
+
+/// The URL to subscribe to for the events of the service of type
+/// `service_type`, from the description of the device.
+pub fn event_url(service: &UpnpService, service_type: &str) -> Option<String> {
+    let mut path: Vec<String> = vec![];
+    let mut base = service.msearch.location.clone();
+    let mut current_type = String::new();
+    let mut current_url = String::new();
+    let mut found = None;
+    for e in EventReader::new(service.description_data.as_bytes()) {
+        match e {
+            Ok(XmlEvent::StartElement { name, .. }) => {
+                if name.local_name == "service" {
+                    current_type.clear();
+                    current_url.clear();
+                }
+                path.push(name.local_name);
+            },
+            Ok(XmlEvent::EndElement { .. }) => {
+                if found.is_none() && path.last().map_or(false, |name| name == "service") &&
+                   current_type.trim() == service_type {
+                    found = Some(current_url.trim().to_owned());
+                }
+                path.pop();
+            },
+            Ok(XmlEvent::Characters(text)) => {
+                match path.last().map(|name| &name[..]) {
+                    Some("serviceType") => current_type.push_str(&text),
+                    Some("eventSubURL") => current_url.push_str(&text),
+                    Some("URLBase") if path.len() == 2 => base = text.trim().to_owned(),
+                    _ => {}
+                }
+            },
+            Err(err) => {
+                warn!("Unable to parse the description at {}: {}", service.msearch.location, err);
+                return None;
+            },
+            _ => {}
+        }
+    }
+    found.and_then(|url| resolve_url(&base, &url))
+}
+
+/// The address of the device at `url`, and the address of ours which it can
+/// reach.
+fn addresses_for(url: &str) -> io::Result<(IpAddr, IpAddr)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid event URL {}", url));
+    let url = try!(Url::parse(url).map_err(|_| invalid()));
+    let host = match url.host() {
+        Some(host) => host.to_string(),
+        None => return Err(invalid())
+    };
+    let port = url.port_or_default().unwrap_or(80);
+    let device = match try!((host.trim_matches(|c: char| c == '[' || c == ']'), port).to_socket_addrs()).next() {
+        Some(device) => device,
+        None => return Err(invalid())
+    };
+    // Nothing is sent, this only picks the route to the device.
+    let socket = try!(UdpSocket::bind("0.0.0.0:0"));
+    try!(socket.connect(device));
+    Ok((device.ip(), try!(socket.local_addr()).ip()))
+}
+
+/// A callback token which can't be guessed, so that only the device we gave
+/// it to can notify us.
+fn callback_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    try!(OsRng::new()).fill_bytes(&mut bytes);
+    Ok(bytes.to_hex())
+}
+
+fn raw_header(headers: &Headers, name: &str) -> Option<String> {
+    headers.get_raw(name)
+           .and_then(|values| values.first())
+           .map(|value| String::from_utf8_lossy(value).trim().to_owned())
+}
+
+fn gena_request(method: &str, url: &str, mut headers: Headers) -> io::Result<client::Response> {
+    headers.set(Connection::close());
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)));
+    let res = try!(client.request(Method::Extension(method.to_owned()), url)
+                         .headers(headers)
+                         .send()
+                         .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string())));
+    if res.status != StatusCode::Ok {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("{} {} answered {}", method, url, res.status)));
+    }
+    Ok(res)
+}
+
+fn granted_timeout(res: &client::Response) -> i64 {
+    raw_header(&res.headers, "TIMEOUT").and_then(|timeout| parse_timeout(&timeout))
+                                       .unwrap_or(REQUESTED_TIMEOUT)
+}
+
+/// Subscribes `callback` to the events at `event_url`, and returns the SID
+/// of the subscription with its timeout.
+fn send_subscribe(event_url: &str, callback: &str) -> io::Result<(String, i64)> {
+    let mut headers = Headers::new();
+    headers.set_raw("CALLBACK", vec![format!("<{}>", callback).into_bytes()]);
+    headers.set_raw("NT", vec![b"upnp:event".to_vec()]);
+    headers.set_raw("TIMEOUT", vec![format!("Second-{}", REQUESTED_TIMEOUT).into_bytes()]);
+    let res = try!(gena_request("SUBSCRIBE", event_url, headers));
+    match raw_header(&res.headers, "SID") {
+        Some(sid) => Ok((sid, granted_timeout(&res))),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("No SID in the answer of {}", event_url)))
+    }
+}
+
+fn send_renewal(event_url: &str, sid: &str) -> io::Result<(String, i64)> {
+    let mut headers = Headers::new();
+    headers.set_raw("SID", vec![sid.as_bytes().to_vec()]);
+    headers.set_raw("TIMEOUT", vec![format!("Second-{}", REQUESTED_TIMEOUT).into_bytes()]);
+    let res = try!(gena_request("SUBSCRIBE", event_url, headers));
+    Ok((sid.to_owned(), granted_timeout(&res)))
+}
+
+fn send_unsubscribe(event_url: &str, sid: &str) -> io::Result<()> {
+    let mut headers = Headers::new();
+    headers.set_raw("SID", vec![sid.as_bytes().to_vec()]);
+    gena_request("UNSUBSCRIBE", event_url, headers).map(|_| ())
+}
+
+/// The headers of a `NOTIFY` request which matter to us.
+struct Notification {
+    nt: Option<String>,
+    nts: Option<String>,
+    sid: Option<String>,
+    seq: Option<String>
+}
+
+/// The state of the callback server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerPort {
+    /// UPnP isn't started, there is nothing to subscribe to.
+    Stopped,
+    /// To listen on this port, any if 0, with the first subscription.
+    Requested(u16),
+    Listening(u16)
+}
+
+pub struct UpnpEvents {
+    subscriptions: Subscriptions,
+    port: Mutex<ServerPort>
+}
+
+impl UpnpEvents {
+    pub fn new() -> Self {
+        UpnpEvents {
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            port: Mutex::new(ServerPort::Stopped)
+        }
+    }
+
+    /// Hands the notification received from `remote` on `path` to the
+    /// listener of its subscription, and returns the status to answer with.
+    fn notify(&self, path: &str, remote: IpAddr, notification: Notification, body: &str) -> StatusCode {
+        // As required by GENA.
+        if notification.nt.as_ref().map(|nt| &nt[..]) != Some("upnp:event") ||
+           notification.nts.as_ref().map(|nts| &nts[..]) != Some("upnp:propchange") {
+            return StatusCode::BadRequest;
+        }
+        let sid = match notification.sid {
+            Some(sid) => sid,
+            None => return StatusCode::PreconditionFailed
+        };
+        let seq = match notification.seq.and_then(|seq| seq.parse::<u32>().ok()) {
+            Some(seq) => seq,
+            None => return StatusCode::BadRequest
+        };
+        let token = if path.starts_with(CALLBACK_PATH) {
+            &path[CALLBACK_PATH.len()..]
+        } else {
+            return StatusCode::NotFound;
+        };
+        let properties = match parse_property_set(body) {
+            Ok(properties) => properties,
+            Err(err) => {
+                warn!("Invalid UPnP event for subscription {}: {}", sid, err);
+                return StatusCode::BadRequest;
+            }
+        };
+
+        let (event, listener) = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            let subscription = match subscriptions.get_mut(token) {
+                Some(subscription) if !subscription.cancelled => subscription,
+                _ => return StatusCode::PreconditionFailed
+            };
+            if remote != subscription.device {
+                warn!("Rejected a UPnP event for {} from {}", subscription.event_url, remote);
+                return StatusCode::Forbidden;
+            }
+            if subscription.sid.as_ref().map_or(false, |known| *known != sid) {
+                return StatusCode::PreconditionFailed;
+            }
+            // The initial event can come before the answer to `SUBSCRIBE`.
+            subscription.sid = Some(sid.clone());
+
+            let missed = seq != subscription.next_seq;
+            if missed {
+                debug!("Missed UPnP events of {}: expected {}, got {}", subscription.event_url,
+                       subscription.next_seq, seq);
+            }
+            // Wraps to 1, 0 being the initial event.
+            subscription.next_seq = if seq == u32::max_value() { 1 } else { seq + 1 };
+            (UpnpEvent {
+                event_url: subscription.event_url.clone(),
+                sid: sid,
+                seq: seq,
+                missed: missed,
+                properties: properties
+            }, subscription.listener.clone())
+        };
+
+        trace!("UPnP event: {:?}", event);
+        listener.upnp_event(&event);
+        StatusCode::Ok
+    }
+
+    fn handle(&self, req: &mut Request) -> StatusCode {
+        if req.method != Method::Extension("NOTIFY".to_owned()) {
+            return StatusCode::MethodNotAllowed;
+        }
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => return StatusCode::NotFound
+        };
+        let notification = Notification {
+            nt: raw_header(&req.headers, "NT"),
+            nts: raw_header(&req.headers, "NTS"),
+            sid: raw_header(&req.headers, "SID"),
+            seq: raw_header(&req.headers, "SEQ")
+        };
+        let mut body = String::new();
+        let read = req.by_ref().take(MAX_EVENT_SIZE).read_to_string(&mut body);
+        if let Err(err) = read {
+            warn!("Unable to read the UPnP event from {}: {}", req.remote_addr, err);
+            return StatusCode::BadRequest;
+        }
+        self.notify(&path, req.remote_addr.ip(), notification, &body)
+    }
+
+    /// Unsubscribes from the cancelled subscriptions, and renews those whose
+    /// time came, or makes them again.
+    fn renew(subscriptions: Subscriptions) -> io::Result<()> {
+        try!(thread::Builder::new().name("UpnpEventRenewal".to_owned()).spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(RENEWAL_CHECK_INTERVAL));
+                for (event_url, sid) in take_cancelled(&subscriptions) {
+                    // Devices drop the subscriptions which aren't renewed anyway.
+                    if let Err(err) = send_unsubscribe(&event_url, &sid) {
+                        debug!("Unable to unsubscribe from the events of {}: {}", event_url, err);
+                    }
+                }
+
+                let now = time::get_time().sec;
+                let due: Vec<(String, String, String, Option<String>)> =
+                    subscriptions.lock().unwrap().iter()
+                                 .filter(|&(_, subscription)| !subscription.cancelled &&
+                                                              subscription.renew_at <= now)
+                                 .map(|(token, subscription)| (token.clone(), subscription.event_url.clone(),
+                                                               subscription.callback.clone(),
+                                                               subscription.sid.clone()))
+                                 .collect();
+                for (token, event_url, callback, sid) in due {
+                    let renewed = match sid {
+                        Some(sid) => send_renewal(&event_url, &sid).or_else(|err| {
+                            // Devices forget the subscriptions when they restart.
+                            debug!("Unable to renew the subscription to {}: {}", event_url, err);
+                            send_subscribe(&event_url, &callback)
+                        }),
+                        None => send_subscribe(&event_url, &callback)
+                    };
+                    if let Some(subscription) = subscriptions.lock().unwrap().get_mut(&token) {
+                        subscription.update(renewed, now);
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Lets the subscriptions start the callback server on `port`, any port
+    /// if 0.
+    pub fn enable(&self, port: u16) {
+        let mut current = self.port.lock().unwrap();
+        if *current == ServerPort::Stopped {
+            *current = ServerPort::Requested(port);
+        }
+    }
+
+    /// Starts the callback server on `port`, any port if 0, and returns the
+    /// port it listens on.
+    fn listen(events: &Arc<UpnpEvents>, port: u16) -> io::Result<u16> {
+        let handler_events = events.clone();
+        let listening = try!(Server::http(("0.0.0.0", port))
+            .and_then(|server| server.handle_threads(move |mut req: Request, mut res: Response| {
+                *res.status_mut() = handler_events.handle(&mut req);
+                res.send(b"").unwrap_or(());
+            }, SERVER_THREADS))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string())));
+        let port = listening.socket.port();
+        debug!("UPnP event server listening on {}", listening.socket);
+        // The server runs for as long as we do, while dropping it would wait
+        // for its threads.
+        mem::forget(listening);
+
+        try!(UpnpEvents::renew(events.subscriptions.clone()));
+        Ok(port)
+    }
+
+    /// Subscribes `listener` to the events of the service at `event_url`,
+    /// until the returned subscription is dropped.
+    pub fn subscribe(events: &Arc<UpnpEvents>, event_url: &str, listener: Arc<UpnpEventListener>)
+                     -> io::Result<UpnpSubscription> {
+        let port = {
+            let mut port = events.port.lock().unwrap();
+            match *port {
+                ServerPort::Stopped =>
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, "UPnP isn't started")),
+                ServerPort::Requested(requested) => {
+                    let listening = try!(UpnpEvents::listen(events, requested));
+                    *port = ServerPort::Listening(listening);
+                    listening
+                },
+                ServerPort::Listening(listening) => listening
+            }
+        };
+        let (device, local) = try!(addresses_for(event_url));
+        let token = try!(callback_token());
+        let callback = format!("http://{}{}{}", SocketAddr::new(local, port), CALLBACK_PATH, token);
+
+        // Registered first, for the initial event which can come before the
+        // answer.
+        events.subscriptions.lock().unwrap().insert(token.clone(), Subscription {
+            event_url: event_url.to_owned(),
+            device: device,
+            callback: callback.clone(),
+            sid: None,
+            next_seq: 0,
+            renew_at: i64::max_value(),
+            cancelled: false,
+            listener: listener
+        });
+        match send_subscribe(event_url, &callback) {
+            Ok(subscribed) => {
+                if let Some(subscription) = events.subscriptions.lock().unwrap().get_mut(&token) {
+                    subscription.update(Ok(subscribed), time::get_time().sec);
+                }
+            },
+            Err(err) => {
+                events.subscriptions.lock().unwrap().remove(&token);
+                return Err(err);
+            }
+        }
+
+        debug!("Subscribed to the UPnP events of {}", event_url);
+        Ok(UpnpSubscription {
+            token: token,
+            subscriptions: Arc::downgrade(&events.subscriptions)
+        })
+    }
+}
+
+impl Default for UpnpEvents {
+    fn default() -> Self {
+        UpnpEvents::new()
+    }
+}
+
+#[cfg(test)]
+describe! gena {
+    it "should parse the timeouts" {
+        assert_eq!(parse_timeout("Second-1800"), Some(1800));
+        assert_eq!(parse_timeout(" second-300 "), Some(300));
+        assert_eq!(parse_timeout("Second-infinite"), Some(INFINITE_TIMEOUT));
+        assert_eq!(parse_timeout("Second-0"), None);
+        assert_eq!(parse_timeout("1800"), None);
+    }
+
+    it "should parse the property sets" {
+        let body = "<?xml version=\"1.0\"?>\
+                    <e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\">\
+                    <e:property><Status>1</Status></e:property>\
+                    <e:property><Target></Target></e:property>\
+                    <e:property><LastChange>&lt;Event/&gt;</LastChange></e:property>\
+                    </e:propertyset>";
+        let properties = parse_property_set(body).unwrap();
+        assert_eq!(properties.len(), 3);
+        assert_eq!(properties["Status"], "1");
+        assert_eq!(properties["Target"], "");
+        assert_eq!(properties["LastChange"], "<Event/>");
+
+        assert!(parse_property_set("<e:propertyset>").is_err());
+    }
+
+    it "should find the event URLs" {
+        use upnp::UpnpMsearchHeader;
+
+        let description = "<?xml version=\"1.0\"?>\
+                           <root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+                           <device><serviceList>\
+                           <service>\
+                           <serviceType>urn:schemas-upnp-org:service:SwitchPower:1</serviceType>\
+                           <eventSubURL>/upnp/event/power</eventSubURL>\
+                           </service>\
+                           <service>\
+                           <serviceType>urn:schemas-upnp-org:service:Dimming:1</serviceType>\
+                           <eventSubURL>dimming</eventSubURL>\
+                           </service>\
+                           </serviceList></device>\
+                           </root>";
+        let mut service = UpnpService {
+            msearch: UpnpMsearchHeader::default(),
+            description: HashMap::new(),
+            description_data: description.to_owned()
+        };
+        service.msearch.location = "http://192.168.1.30:49152/desc/rootDesc.xml".to_owned();
+
+        assert_eq!(event_url(&service, "urn:schemas-upnp-org:service:SwitchPower:1").unwrap(),
+                   "http://192.168.1.30:49152/upnp/event/power");
+        assert_eq!(event_url(&service, "urn:schemas-upnp-org:service:Dimming:1").unwrap(),
+                   "http://192.168.1.30:49152/desc/dimming");
+        assert_eq!(event_url(&service, "urn:schemas-upnp-org:service:AVTransport:1"), None);
+
+        service.description_data = description.replace("<device>", "<URLBase>http://192.168.1.31/</URLBase><device>");
+        assert_eq!(event_url(&service, "urn:schemas-upnp-org:service:Dimming:1").unwrap(),
+                   "http://192.168.1.31/dimming");
+    }
+
+    it "should deliver the notifications" {
+        use std::sync::mpsc::{ channel, Sender };
+
+        struct ForwardingListener(Mutex<Sender<UpnpEvent>>);
+        impl UpnpEventListener for ForwardingListener {
+            fn upnp_event(&self, event: &UpnpEvent) {
+                self.0.lock().unwrap().send(event.clone()).unwrap();
+            }
+        }
+
+        let events = UpnpEvents::new();
+        let (sender, receiver) = channel();
+        let device: IpAddr = "192.168.1.30".parse().unwrap();
+        events.subscriptions.lock().unwrap().insert("3f2a".to_owned(), Subscription {
+            event_url: "http://192.168.1.30/upnp/event/power".to_owned(),
+            device: device,
+            callback: "http://192.168.1.2:4000/upnp/events/3f2a".to_owned(),
+            sid: None,
+            next_seq: 0,
+            renew_at: 1000,
+            cancelled: false,
+            listener: Arc::new(ForwardingListener(Mutex::new(sender)))
+        });
+        let body = "<e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\">\
+                    <e:property><Status>1</Status></e:property>\
+                    </e:propertyset>";
+        let notification = |sid: &str, seq: &str| Notification {
+            nt: Some("upnp:event".to_owned()),
+            nts: Some("upnp:propchange".to_owned()),
+            sid: Some(sid.to_owned()),
+            seq: Some(seq.to_owned())
+        };
+
+        assert_eq!(events.notify("/upnp/events/3f2a", device, notification("uuid:sub", "0"), body),
+                   StatusCode::Ok);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.sid, "uuid:sub");
+        assert_eq!(event.seq, 0);
+        assert!(!event.missed);
+        assert_eq!(event.properties["Status"], "1");
+
+        assert_eq!(events.notify("/upnp/events/3f2a", device, notification("uuid:sub", "2"), body),
+                   StatusCode::Ok);
+        assert!(receiver.try_recv().unwrap().missed);
+
+        assert_eq!(events.notify("/upnp/events/3f2a", device, notification("uuid:other", "3"), body),
+                   StatusCode::PreconditionFailed);
+        assert_eq!(events.notify("/upnp/events/3f2b", device, notification("uuid:sub", "3"), body),
+                   StatusCode::PreconditionFailed);
+        assert_eq!(events.notify("/elsewhere", device, notification("uuid:sub", "3"), body),
+                   StatusCode::NotFound);
+        let mut invalid = notification("uuid:sub", "3");
+        invalid.nts = None;
+        assert_eq!(events.notify("/upnp/events/3f2a", device, invalid, body), StatusCode::BadRequest);
+        let intruder: IpAddr = "192.168.1.66".parse().unwrap();
+        assert_eq!(events.notify("/upnp/events/3f2a", intruder, notification("uuid:sub", "3"), body),
+                   StatusCode::Forbidden);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    it "should unsubscribe once the subscriptions are dropped" {
+        struct IgnoringListener;
+        impl UpnpEventListener for IgnoringListener {
+            fn upnp_event(&self, _: &UpnpEvent) {}
+        }
+
+        let events = UpnpEvents::new();
+        for (token, sid) in vec![("a1", Some("uuid:sub")), ("b2", None), ("c3", Some("uuid:kept"))] {
+            events.subscriptions.lock().unwrap().insert(token.to_owned(), Subscription {
+                event_url: format!("http://192.168.1.30/upnp/event/{}", token),
+                device: "192.168.1.30".parse().unwrap(),
+                callback: format!("http://192.168.1.2:4000/upnp/events/{}", token),
+                sid: sid.map(|sid| sid.to_owned()),
+                next_seq: 0,
+                renew_at: 1000,
+                cancelled: false,
+                listener: Arc::new(IgnoringListener)
+            });
+        }
+        let subscription = |token: &str| UpnpSubscription {
+            token: token.to_owned(),
+            subscriptions: Arc::downgrade(&events.subscriptions)
+        };
+        let kept = subscription("c3");
+        drop(subscription("a1"));
+        drop(subscription("b2"));
+
+        // Dropping doesn't wait for the device.
+        assert_eq!(events.subscriptions.lock().unwrap().len(), 3);
+        let notification = Notification {
+            nt: Some("upnp:event".to_owned()),
+            nts: Some("upnp:propchange".to_owned()),
+            sid: Some("uuid:sub".to_owned()),
+            seq: Some("0".to_owned())
+        };
+        assert_eq!(events.notify("/upnp/events/a1", "192.168.1.30".parse().unwrap(), notification,
+                                 "<e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\"/>"),
+                   StatusCode::PreconditionFailed);
+
+        assert_eq!(take_cancelled(&events.subscriptions),
+                   vec![("http://192.168.1.30/upnp/event/a1".to_owned(), "uuid:sub".to_owned())]);
+        assert_eq!(events.subscriptions.lock().unwrap().keys().collect::<Vec<_>>(), vec!["c3"]);
+        assert!(take_cancelled(&events.subscriptions).is_empty());
+        drop(kept);
+    }
+
+    it "should only subscribe once started" {
+        struct IgnoringListener;
+        impl UpnpEventListener for IgnoringListener {
+            fn upnp_event(&self, _: &UpnpEvent) {}
+        }
+
+        let events = Arc::new(UpnpEvents::new());
+        assert_eq!(UpnpEvents::subscribe(&events, "http://127.0.0.1:1/event", Arc::new(IgnoringListener))
+                       .err().unwrap().kind(),
+                   io::ErrorKind::NotConnected);
+        events.enable(0);
+        assert_eq!(*events.port.lock().unwrap(), ServerPort::Requested(0));
+    }
+
+    it "should use unguessable callback tokens" {
+        let token = callback_token().unwrap();
+        assert_eq!(token.len(), 32);
+        assert!(token != callback_token().unwrap());
+    }
+
+    it "should renew the subscriptions" {
+        struct IgnoringListener;
+        impl UpnpEventListener for IgnoringListener {
+            fn upnp_event(&self, _: &UpnpEvent) {}
+        }
+
+        let mut subscription = Subscription {
+            event_url: "http://192.168.1.30/upnp/event/power".to_owned(),
+            device: "192.168.1.30".parse().unwrap(),
+            callback: "http://192.168.1.2:4000/upnp/events/3f2a".to_owned(),
+            sid: Some("uuid:sub".to_owned()),
+            next_seq: 5,
+            renew_at: 1000,
+            cancelled: false,
+            listener: Arc::new(IgnoringListener)
+        };
+        subscription.update(Ok(("uuid:sub".to_owned(), 300)), 1000);
+        assert_eq!(subscription.renew_at, 1150);
+        assert_eq!(subscription.next_seq, 5);
+
+        subscription.update(Err(io::Error::new(io::ErrorKind::Other, "412")), 1150);
+        assert_eq!(subscription.sid, None);
+        assert_eq!(subscription.renew_at, 1150 + RETRY_DELAY);
+
+        subscription.update(Ok(("uuid:new".to_owned(), 1800)), 1180);
+        assert_eq!(subscription.sid, Some("uuid:new".to_owned()));
+        assert_eq!(subscription.next_seq, 0);
+        assert_eq!(subscription.renew_at, 1180 + 900);
+    }
+}
@@ -3,41 +3,260 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 extern crate url;
 
+use acl::{ Access, Acl, Scope };
+use api_keys::API_KEY_PREFIX;
+use config_store::ConfigService;
+use event_log::EventReplay;
+use foxbox_taxonomy::api::User;
+use foxbox_users::SessionToken;
+use guest_tokens::GUEST_TOKEN_PREFIX;
+use mio::tcp::TcpStream;
+use openssl::ssl::SslStream;
 use self::url::Url;
+use serde_json;
+use serde_json::value::Value;
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
 use std::thread;
+use std::time::{ Duration, Instant };
+use time;
 use traits::Controller;
 use ws;
-use ws::{ Handler, Sender, Result, Message, Handshake, CloseCode, Error };
-use ws::listen;
+use ws::{ Builder, Frame, Handler, OpCode, Sender, Settings, Result, Message, Handshake,
+          CloseCode, Error };
+use ws::util::Token;
+
+const PING_TIMEOUT: Token = Token(1);
+const FLUSH_TIMEOUT: Token = Token(2);
+
+/// What to do with a websocket when its queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Drop the message for this socket only and keep the connection.
+    Drop,
+    /// Close the connection, letting the client reconnect.
+    Disconnect,
+}
+
+impl SlowConsumerPolicy {
+    fn from_str(value: &str) -> Self {
+        match value {
+            "disconnect" => SlowConsumerPolicy::Disconnect,
+            "drop" => SlowConsumerPolicy::Drop,
+            _ => {
+                warn!("Unknown websocket slow consumer policy `{}`, using `drop`", value);
+                SlowConsumerPolicy::Drop
+            }
+        }
+    }
+}
+
+/// Websocket settings, read from the "websocket" config namespace.
+#[derive(Clone, Debug)]
+pub struct WsSettings {
+    /// Interval between two pings sent to a client.
+    pub ping_interval: Duration,
+    /// A client that didn't answer any ping for that long is evicted.
+    pub idle_timeout: Duration,
+    /// Number of events queued for a connection, waiting for the event loop
+    /// to send them. The slow consumer policy applies to the connections
+    /// whose queue is full.
+    pub events_per_connection: usize,
+    pub max_connections: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Number of past events kept for clients reconnecting with `?since=`.
+    pub replay_buffer_size: usize,
+}
+
+impl WsSettings {
+    pub fn from_config(config: &ConfigService) -> Self {
+        let ping_interval: u64 = config.get_or_set_default_parsed("websocket", "ping_interval_seconds", "30");
+        let idle_timeout: u64 = config.get_or_set_default_parsed("websocket", "idle_timeout_seconds", "90");
+
+        WsSettings {
+            ping_interval: Duration::from_secs(ping_interval),
+            idle_timeout: Duration::from_secs(idle_timeout),
+            events_per_connection: config.get_or_set_default_parsed("websocket", "events_per_connection", "32"),
+            max_connections: config.get_or_set_default_parsed("websocket", "max_connections", "100"),
+            slow_consumer: SlowConsumerPolicy::from_str(
+                &config.get_or_set_default("websocket", "slow_consumer_policy", "drop")),
+            replay_buffer_size: config.get_or_set_default_parsed("websocket", "replay_buffer_size", "256"),
+        }
+    }
+}
+
+/// Who listens on a websocket, so that it only gets the events it may see.
+///
+/// Events about a service or channel carry its id in a `service` or `channel`
+/// field (or both), and optionally its tags in a `tags` field. They go to the
+/// viewers with read access to it. The other events go to users only, not to
+/// guests or to API keys limited to some targets.
+#[derive(Clone, Debug)]
+pub struct WsViewer {
+    /// The user the credentials belong to, if any.
+    pub user: Option<i32>,
+    pub admin: bool,
+    /// The targets the credentials are limited to, if any.
+    pub scope: Option<Scope>,
+}
+
+impl WsViewer {
+    pub fn may_receive(&self, acl: &Acl, event: &Value) -> bool {
+        if self.admin {
+            return true;
+        }
+
+        let ids: Vec<String> = ["channel", "service"].iter().filter_map(|field| {
+            event.find(field).and_then(|id| id.as_string()).map(|id| id.to_owned())
+        }).collect();
+        if ids.is_empty() {
+            return self.user.is_some() && self.scope.is_none();
+        }
+        let tags: Vec<String> = match event.find("tags").and_then(|tags| tags.as_array()) {
+            Some(tags) => tags.iter().filter_map(|tag| tag.as_string().map(|tag| tag.to_owned())).collect(),
+            None => vec![]
+        };
+
+        let in_scope = self.scope.as_ref().map_or(true, |scope| {
+            scope.allows(Access::Read, &ids, &tags)
+        });
+        in_scope && self.user.map_or(true, |user| {
+            acl.is_unrestricted() || acl.access(user, &ids, &tags) >= Access::Read
+        })
+    }
+}
+
+/// What became of an event pushed to a `WsQueue`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Queued {
+    /// The queue was empty, and the socket has to be asked to flush it.
+    First,
+    /// A flush is already pending.
+    Pending,
+    /// The queue is full, the event was dropped.
+    Full,
+}
+
+/// The events waiting to be sent to a websocket. Each socket has its own,
+/// bounded queue, flushed by its handler on the event loop, so that a socket
+/// lagging behind can't fill the queue of the event loop for the others.
+#[derive(Clone)]
+pub struct WsQueue {
+    events: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl WsQueue {
+    pub fn new(capacity: usize) -> Self {
+        WsQueue {
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            capacity: capacity,
+        }
+    }
+
+    pub fn push(&self, event: String) -> Queued {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= self.capacity {
+            return Queued::Full;
+        }
+        events.push_back(event);
+        if events.len() == 1 { Queued::First } else { Queued::Pending }
+    }
+
+    /// Queues `event` for `out`, scheduling a flush when needed. Returns
+    /// false if the queue is full.
+    pub fn send(&self, out: &Sender, event: String) -> Result<bool> {
+        match self.push(event) {
+            Queued::First => out.timeout(0, FLUSH_TIMEOUT).map(|_| true),
+            Queued::Pending => Ok(true),
+            Queued::Full => Ok(false),
+        }
+    }
+
+    fn take(&self) -> Vec<String> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+}
 
 pub struct WsServer;
 
 pub struct WsHandler<T> {
     pub out: Sender,
-    pub controller: T
+    pub controller: T,
+    settings: WsSettings,
+    queue: WsQueue,
+    last_pong: Instant,
 }
 
 impl WsServer {
 
     pub fn start<T: Controller>(controller: T) {
         let addrs: Vec<_> = controller.ws_as_addrs().unwrap().collect();
+        let settings = WsSettings::from_config(&controller.get_config());
         thread::Builder::new().name("WsServer".to_owned()).spawn(move || {
 
-            listen(addrs[0], |out| {
+            let tls_enabled = controller.get_tls_enabled();
+            let ws_settings = Settings {
+                max_connections: settings.max_connections,
+                // The events flushed from the queue of a connection, with
+                // room for its flush, ping and close commands.
+                queue_size: settings.events_per_connection + 3,
+                // When TLS is enabled we serve wss:// with the same certificates
+                // as the HTTPS server, since browsers refuse plain ws:// connections
+                // from HTTPS pages.
+                encrypt_server: tls_enabled,
+                .. Settings::default()
+            };
+
+            let server = Builder::new().with_settings(ws_settings).build(|out| {
                 WsHandler {
                     out: out,
                     controller: controller.clone(),
+                    settings: settings.clone(),
+                    queue: WsQueue::new(settings.events_per_connection),
+                    last_pong: Instant::now(),
                 }
             }).unwrap();
+
+            info!("Websocket server listening on {} ({})", addrs[0],
+                  if tls_enabled { "wss" } else { "ws" });
+            server.listen(addrs[0]).unwrap();
         }).unwrap();
     }
 }
 
+/// Sends to `out` the events that followed `since` and pass `filter`, telling
+/// it when some of them were lost.
+pub fn replay_events<F>(out: &Sender, replay: EventReplay, since: u64, filter: F) -> Result<()>
+    where F: Fn(&str) -> bool {
+    let events = match replay {
+        EventReplay::Complete(events) => events,
+        EventReplay::Gap { oldest, events } => {
+            info!("Events lost for ws client ({:?}) since {}", out.token(), since);
+            try!(out.send(json!({ type: "core/events/gap", since: since, oldest: oldest })));
+            events
+        }
+    };
+
+    for event in events.into_iter().filter(|event| filter(event)) {
+        try!(out.send(event));
+    }
+    Ok(())
+}
+
+fn duration_to_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
 impl<T: Controller> WsHandler<T> {
 
     fn close_with_error(&mut self, reason: &'static str) -> Result<()> {
         self.out.close_with_reason(ws::CloseCode::Error, reason)
     }
+
+    fn schedule_ping(&mut self) -> Result<()> {
+        self.out.timeout(duration_to_ms(self.settings.ping_interval), PING_TIMEOUT)
+    }
 }
 
 impl<T: Controller> Handler for WsHandler<T> {
@@ -53,27 +272,62 @@ impl<T: Controller> Handler for WsHandler<T> {
             _ => return self.close_with_error("Invalid path"),
         };
 
-        let auth = match url.query_pairs() {
-            Some(pairs) => {
-                pairs.iter()
-                    .find(|ref set| set.0.to_lowercase() == "auth")
-                    .map(|ref set| set.1.clone())
-            },
-            _ => return self.close_with_error("Missing authorization"),
+        let pairs = url.query_pairs().unwrap_or_else(Vec::new);
+        let query_param = |name: &str| {
+            pairs.iter()
+                 .find(|ref set| set.0.to_lowercase() == name)
+                 .map(|ref set| set.1.clone())
         };
 
-        let token = match auth {
+        let token = match query_param("auth") {
             Some(val) => val,
             _ => return self.close_with_error("Missing authorization"),
         };
 
-        if let Err(_) = self.controller.get_users_manager().verify_token(&token) {
-            return self.close_with_error("Authorization failed");
-        }
+        let now = time::get_time().sec;
+        let viewer = if token.starts_with(API_KEY_PREFIX) {
+            let client = handshake.peer_addr.map(|addr| addr.ip());
+            self.controller.get_api_keys().verify(&token, client, "websocket", now).ok().map(|api_key| {
+                WsViewer {
+                    user: Some(api_key.user),
+                    admin: false,
+                    scope: api_key.scope()
+                }
+            })
+        } else if token.starts_with(GUEST_TOKEN_PREFIX) {
+            self.controller.get_guest_tokens().verify(&token, now).ok().map(|pass| {
+                WsViewer {
+                    user: None,
+                    admin: false,
+                    scope: Some(Scope { read: pass.getters, write: pass.setters })
+                }
+            })
+        } else if self.controller.get_users_manager().verify_token(&token).is_ok() {
+            SessionToken::from_string(&token).ok().map(|token| {
+                WsViewer {
+                    user: Some(token.claims.id),
+                    admin: self.controller.is_admin(&User::Id(token.claims.id)),
+                    scope: None
+                }
+            })
+        } else {
+            None
+        };
+        let viewer = match viewer {
+            Some(viewer) => viewer,
+            None => return self.close_with_error("Authorization failed"),
+        };
 
-        self.controller.add_websocket(self.out.clone());
+        let since = match query_param("since").map(|since| since.parse::<u64>()) {
+            None => None,
+            Some(Ok(since)) => Some(since),
+            Some(Err(_)) => return self.close_with_error("Invalid since parameter"),
+        };
+        // The replayed events come before any live one.
+        try!(self.controller.add_websocket(self.out.clone(), self.queue.clone(), viewer, since));
 
-        Ok(())
+        self.last_pong = Instant::now();
+        self.schedule_ping()
     }
 
     fn on_message(&mut self, msg: Message) -> Result<()> {
@@ -82,6 +336,35 @@ impl<T: Controller> Handler for WsHandler<T> {
         Ok(())
     }
 
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Pong {
+            self.last_pong = Instant::now();
+        }
+
+        Ok(Some(frame))
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event == FLUSH_TIMEOUT {
+            for event in self.queue.take() {
+                try!(self.out.send(event));
+            }
+            return Ok(());
+        }
+        if event != PING_TIMEOUT {
+            return Ok(());
+        }
+
+        if self.last_pong.elapsed() > self.settings.idle_timeout {
+            info!("Evicting unresponsive ws connection ({:?})", self.out.token());
+            self.controller.remove_websocket(self.out.clone());
+            return self.out.close(CloseCode::Away);
+        }
+
+        try!(self.out.ping(Vec::new()));
+        self.schedule_ping()
+    }
+
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         match code {
             CloseCode::Normal => info!("The ws client is done with the connection."),
@@ -95,4 +378,117 @@ impl<T: Controller> Handler for WsHandler<T> {
     fn on_error(&mut self, err: Error) {
         error!("The ws server encountered an error: {:?}", err);
     }
+
+    /// Only called when `encrypt_server` is set. The context comes from the
+    /// `SniSslContextProvider`, so the certificate is selected by SNI exactly
+    /// like for the HTTPS server.
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> Result<SslStream<TcpStream>> {
+        let context = try!(self.controller.get_certificate_manager()
+                                          .get_context_provider()
+                                          .context());
+        SslStream::accept(&context, sock).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+describe! ws_viewer {
+    before_each {
+        use acl::{ Acl, Grant, Scope };
+        use config_store::ConfigService;
+        use serde_json;
+        use std::fs;
+        use uuid::Uuid;
+
+        let id = Uuid::new_v4().to_simple_string();
+        let config_file_name = format!("conftest-{}.tmp", id);
+        let db_file_name = format!("acl_test-{}.sqlite", id);
+        let config = ConfigService::new(&config_file_name);
+        let acl = Acl::new(&db_file_name, &config);
+
+        let user = WsViewer { user: Some(1), admin: false, scope: None };
+        let guest = WsViewer {
+            user: None,
+            admin: false,
+            scope: Some(Scope { read: vec!["getter:door".to_owned()], write: vec![] })
+        };
+        let door_event = json_value!({ type: "core/test", channel: "getter:door", service: "service:door" });
+        let core_event = json_value!({ type: "core/test" });
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+        fs::remove_file(db_file_name).unwrap_or(());
+    }
+
+    it "should send the events of a service to the viewers allowed to read it" {
+        assert!(user.may_receive(&acl, &door_event));
+        assert!(guest.may_receive(&acl, &door_event));
+        assert!(!guest.may_receive(&acl, &json_value!({ type: "core/test", service: "service:door" })));
+
+        acl.set_grant(&Grant { user: 1, target: "service:door".to_owned(), access: "none".to_owned() })
+           .unwrap();
+        assert!(!user.may_receive(&acl, &door_event));
+    }
+
+    it "should only send the other events to users" {
+        assert!(user.may_receive(&acl, &core_event));
+        assert!(!guest.may_receive(&acl, &core_event));
+    }
+}
+
+#[cfg(test)]
+describe! ws_queue {
+    it "should bound the events queued for a socket" {
+        let queue = WsQueue::new(2);
+        let other = WsQueue::new(2);
+        assert_eq!(queue.push("1".to_owned()), Queued::First);
+        assert_eq!(queue.push("2".to_owned()), Queued::Pending);
+        assert_eq!(queue.push("3".to_owned()), Queued::Full);
+        // The other sockets aren't affected.
+        assert_eq!(other.push("1".to_owned()), Queued::First);
+
+        assert_eq!(queue.take(), vec!["1".to_owned(), "2".to_owned()]);
+        assert_eq!(queue.push("4".to_owned()), Queued::First);
+    }
+}
+
+#[cfg(test)]
+describe! ws_settings {
+    before_each {
+        use config_store::ConfigService;
+        use std::fs;
+        use std::time::Duration;
+        use uuid::Uuid;
+
+        let config_file_name = format!("conftest-{}.tmp", Uuid::new_v4().to_simple_string());
+        let config = ConfigService::new(&config_file_name);
+    }
+
+    after_each {
+        fs::remove_file(config_file_name).unwrap_or(());
+    }
+
+    it "should use defaults when nothing is configured" {
+        let settings = WsSettings::from_config(&config);
+        assert_eq!(settings.ping_interval, Duration::from_secs(30));
+        assert_eq!(settings.idle_timeout, Duration::from_secs(90));
+        assert_eq!(settings.events_per_connection, 32);
+        assert_eq!(settings.slow_consumer, SlowConsumerPolicy::Drop);
+    }
+
+    it "should read configured values" {
+        config.set("websocket", "ping_interval_seconds", "5");
+        config.set("websocket", "slow_consumer_policy", "disconnect");
+        let settings = WsSettings::from_config(&config);
+        assert_eq!(settings.ping_interval, Duration::from_secs(5));
+        assert_eq!(settings.slow_consumer, SlowConsumerPolicy::Disconnect);
+    }
+
+    it "should fall back to defaults on invalid values" {
+        config.set("websocket", "events_per_connection", "lots");
+        config.set("websocket", "slow_consumer_policy", "whatever");
+        let settings = WsSettings::from_config(&config);
+        assert_eq!(settings.events_per_connection, 32);
+        assert_eq!(settings.slow_consumer, SlowConsumerPolicy::Drop);
+    }
 }
@@ -21,7 +21,7 @@ EOF
         g++-arm-linux-gnueabihf
 
     sudo apt-get install -y --no-install-recommends libasound2:armhf \
-        libssl-dev:armhf libespeak-dev:armhf libupnp6-dev:armhf \
+        libssl-dev:armhf libespeak-dev:armhf \
         libudev-dev:armhf libavahi-client-dev:armhf libsqlite3-dev:armhf
 }
 
@@ -7,7 +7,7 @@ export EXTRA_LDFLAGS=-L/usr/local/opt/openssl/lib
 
 install_dependencies() {
     brew update
-    brew install libupnp openssl sqlite
+    brew install openssl sqlite
 }
 
 build() {